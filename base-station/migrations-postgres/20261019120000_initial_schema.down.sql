@@ -0,0 +1,7 @@
+-- sqlfluff:dialect:postgres
+
+DROP TABLE IF EXISTS retention_policies;
+DROP TABLE IF EXISTS reading_rollups;
+DROP TABLE IF EXISTS reading_values;
+DROP TABLE IF EXISTS sensor_readings;
+DROP TABLE IF EXISTS metrics;
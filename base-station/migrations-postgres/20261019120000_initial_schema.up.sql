@@ -0,0 +1,74 @@
+-- sqlfluff:dialect:postgres
+
+CREATE TABLE IF NOT EXISTS metrics (
+    name TEXT PRIMARY KEY,
+    unit TEXT,
+    description TEXT
+);
+
+INSERT INTO metrics (name, unit, description) VALUES
+    ('temperature', '°C', 'Air temperature'),
+    ('pressure', 'Pa', 'Barometric pressure'),
+    ('humidity', '%', 'Relative humidity'),
+    ('co2', 'ppm', 'Carbon dioxide concentration'),
+    ('voc', 'index', 'Volatile organic compounds index'),
+    ('pm2_5', 'µg/m³', 'Fine particulate matter concentration'),
+    ('lux', 'lx', 'Illuminance'),
+    ('wind_speed', 'm/s', 'Wind speed');
+
+-- The timestamp is part of the key so the table can become a hypertable
+CREATE TABLE IF NOT EXISTS sensor_readings (
+    id BIGINT GENERATED ALWAYS AS IDENTITY,
+    sensor_id TEXT NOT NULL,
+    topic TEXT NOT NULL,
+    timestamp TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (id, timestamp)
+);
+
+CREATE INDEX idx_sensor_time ON sensor_readings (sensor_id, timestamp DESC, id DESC);
+CREATE INDEX idx_time ON sensor_readings (timestamp, id);
+
+-- Hypertables cannot be referenced by foreign keys, values of removed
+-- readings are deleted alongside them by the repository
+CREATE TABLE IF NOT EXISTS reading_values (
+    reading_id BIGINT NOT NULL,
+    metric TEXT NOT NULL REFERENCES metrics (name),
+    value DOUBLE PRECISION NOT NULL,
+    PRIMARY KEY (reading_id, metric)
+);
+
+CREATE INDEX idx_metric_value ON reading_values (metric, value);
+
+CREATE TABLE IF NOT EXISTS reading_rollups (
+    resolution TEXT NOT NULL CHECK (resolution IN ('hour', 'day')),
+    sensor_id TEXT NOT NULL,
+    metric TEXT NOT NULL REFERENCES metrics (name),
+    bucket TIMESTAMPTZ NOT NULL,
+    min_value DOUBLE PRECISION NOT NULL,
+    max_value DOUBLE PRECISION NOT NULL,
+    sum_value DOUBLE PRECISION NOT NULL,
+    sample_count BIGINT NOT NULL,
+    PRIMARY KEY (resolution, sensor_id, metric, bucket)
+);
+
+CREATE INDEX idx_rollup_metric_bucket ON reading_rollups (resolution, metric, bucket);
+
+-- A NULL sensor_id is the policy for every sensor without one of its own,
+-- a NULL keep_days keeps the data forever
+CREATE TABLE IF NOT EXISTS retention_policies (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    sensor_id TEXT,
+    resolution TEXT NOT NULL CHECK (resolution IN ('raw', 'hour', 'day')),
+    keep_days INTEGER CHECK (keep_days > 0)
+);
+
+CREATE UNIQUE INDEX idx_retention_scope ON retention_policies (COALESCE(sensor_id, ''), resolution);
+
+-- Partition readings by time when TimescaleDB has been enabled on the database
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb') THEN
+        PERFORM create_hypertable('sensor_readings', 'timestamp');
+    END IF;
+END
+$$;
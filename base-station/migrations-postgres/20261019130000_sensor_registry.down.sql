@@ -0,0 +1,4 @@
+-- sqlfluff:dialect:postgres
+
+DROP TABLE IF EXISTS quarantined_readings;
+DROP TABLE IF EXISTS sensors;
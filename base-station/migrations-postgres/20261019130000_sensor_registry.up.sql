@@ -0,0 +1,27 @@
+-- sqlfluff:dialect:postgres
+
+CREATE TABLE IF NOT EXISTS sensors (
+    sensor_id TEXT PRIMARY KEY,
+    name TEXT,
+    location TEXT,
+    altitude_m DOUBLE PRECISION,
+    installed_on DATE,
+    notes TEXT,
+    status TEXT NOT NULL DEFAULT 'unapproved' CHECK (status IN ('unapproved', 'approved')),
+    first_seen TIMESTAMPTZ
+);
+
+-- Sensors that published before the registry existed are trusted
+INSERT INTO sensors (sensor_id, status, first_seen)
+SELECT sensor_id, 'approved', MIN(timestamp) FROM sensor_readings GROUP BY sensor_id;
+
+-- Readings of unapproved sensors
+CREATE TABLE IF NOT EXISTS quarantined_readings (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    sensor_id TEXT NOT NULL REFERENCES sensors (sensor_id) ON DELETE CASCADE,
+    topic TEXT NOT NULL,
+    timestamp TIMESTAMPTZ NOT NULL,
+    metrics JSONB NOT NULL
+);
+
+CREATE INDEX idx_quarantine_sensor ON quarantined_readings (sensor_id, timestamp);
@@ -0,0 +1,6 @@
+-- sqlfluff:dialect:postgres
+
+-- Readings go back to what the sensors sent, rebuild the rollups afterwards
+UPDATE reading_values SET value = raw_value WHERE raw_value IS NOT NULL;
+ALTER TABLE reading_values DROP COLUMN raw_value;
+DROP TABLE IF EXISTS calibrations;
@@ -0,0 +1,19 @@
+-- sqlfluff:dialect:postgres
+
+CREATE TABLE IF NOT EXISTS calibrations (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    sensor_id TEXT NOT NULL,
+    metric TEXT NOT NULL,
+    gain DOUBLE PRECISION NOT NULL DEFAULT 1.0 CHECK (gain <> 0),
+    offset_value DOUBLE PRECISION NOT NULL DEFAULT 0.0,
+    valid_from TIMESTAMPTZ,
+    valid_to TIMESTAMPTZ
+);
+
+CREATE INDEX idx_calibration_sensor ON calibrations (sensor_id, metric);
+
+-- Values as reported by the sensor, `value` holds the calibrated one.
+-- Everything stored so far is uncalibrated.
+ALTER TABLE reading_values ADD COLUMN raw_value DOUBLE PRECISION;
+UPDATE reading_values SET raw_value = value;
+ALTER TABLE reading_values ALTER COLUMN raw_value SET NOT NULL;
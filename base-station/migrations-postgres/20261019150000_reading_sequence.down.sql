@@ -0,0 +1,6 @@
+-- sqlfluff:dialect:postgres
+
+DROP INDEX idx_reading_key;
+ALTER TABLE quarantined_readings DROP COLUMN sequence;
+ALTER TABLE sensor_readings DROP COLUMN copy;
+ALTER TABLE sensor_readings DROP COLUMN sequence;
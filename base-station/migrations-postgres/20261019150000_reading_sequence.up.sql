@@ -0,0 +1,21 @@
+-- sqlfluff:dialect:postgres
+
+-- Readings are told apart by sensor, timestamp and sequence number, a
+-- missing sequence number counting as a value of its own. Copies the
+-- conflict policy keeps as well are numbered from 0 in the order stored.
+ALTER TABLE sensor_readings ADD COLUMN sequence BIGINT;
+ALTER TABLE sensor_readings ADD COLUMN copy INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE quarantined_readings ADD COLUMN sequence BIGINT;
+
+UPDATE sensor_readings SET copy = numbered.copy
+FROM (
+    SELECT
+        id,
+        ROW_NUMBER() OVER (PARTITION BY sensor_id, timestamp ORDER BY id) - 1 AS copy
+    FROM sensor_readings
+) AS numbered
+WHERE numbered.id = sensor_readings.id AND numbered.copy > 0;
+
+CREATE UNIQUE INDEX idx_reading_key ON sensor_readings (
+    timestamp, sensor_id, sequence, copy
+) NULLS NOT DISTINCT;
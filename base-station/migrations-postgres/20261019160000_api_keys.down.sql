@@ -0,0 +1,3 @@
+-- sqlfluff:dialect:postgres
+
+DROP TABLE IF EXISTS api_keys;
@@ -0,0 +1,13 @@
+-- sqlfluff:dialect:postgres
+
+CREATE TABLE IF NOT EXISTS api_keys (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    name TEXT NOT NULL,
+    -- Start of the secret, the secret itself is only stored hashed
+    prefix TEXT NOT NULL,
+    secret_hash TEXT NOT NULL UNIQUE,
+    -- Separated by spaces
+    scopes TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ
+);
@@ -1 +1,3 @@
--- Add down migration script here
+-- sqlfluff:dialect:sqlite
+
+DROP TABLE IF EXISTS sensor_readings;
@@ -0,0 +1,47 @@
+-- sqlfluff:dialect:sqlite
+
+-- Readings without all three of the original metrics cannot be represented
+-- in the wide layout, the revert is refused until they are deleted
+CREATE TEMP TABLE revert_check (lossy_readings INTEGER NOT NULL);
+CREATE TEMP TRIGGER refuse_lossy_revert BEFORE INSERT ON revert_check
+WHEN new.lossy_readings > 0
+BEGIN
+    SELECT RAISE(
+        ABORT,
+        'Readings without temperature, pressure or humidity would be lost, delete them first'
+    );
+END;
+
+INSERT INTO revert_check (lossy_readings)
+SELECT COUNT(*)
+FROM sensor_readings r
+WHERE (
+    SELECT COUNT(*) FROM reading_values v
+    WHERE v.reading_id = r.id AND v.metric IN ('temperature', 'pressure', 'humidity')
+) < 3;
+DROP TABLE revert_check;
+
+CREATE TABLE sensor_readings_wide (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    sensor_id TEXT NOT NULL,
+    topic TEXT NOT NULL,
+    timestamp DATETIME NOT NULL,
+    temperature REAL NOT NULL,
+    pressure REAL NOT NULL,
+    humidity REAL NOT NULL
+);
+
+INSERT INTO sensor_readings_wide (id, sensor_id, topic, timestamp, temperature, pressure, humidity)
+SELECT r.id, r.sensor_id, r.topic, r.timestamp, t.value, p.value, h.value
+FROM sensor_readings r
+JOIN reading_values t ON t.reading_id = r.id AND t.metric = 'temperature'
+JOIN reading_values p ON p.reading_id = r.id AND p.metric = 'pressure'
+JOIN reading_values h ON h.reading_id = r.id AND h.metric = 'humidity';
+
+DROP TABLE reading_values;
+DROP INDEX idx_sensor_time;
+DROP TABLE sensor_readings;
+ALTER TABLE sensor_readings_wide RENAME TO sensor_readings;
+CREATE INDEX idx_sensor_time ON sensor_readings (sensor_id, timestamp DESC);
+
+DROP TABLE metrics;
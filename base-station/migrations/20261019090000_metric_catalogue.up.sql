@@ -0,0 +1,52 @@
+-- sqlfluff:dialect:sqlite
+
+CREATE TABLE IF NOT EXISTS metrics (
+    name TEXT PRIMARY KEY NOT NULL,
+    unit TEXT,
+    description TEXT
+);
+
+INSERT INTO metrics (name, unit, description) VALUES
+    ('temperature', '°C', 'Air temperature'),
+    ('pressure', 'Pa', 'Barometric pressure'),
+    ('humidity', '%', 'Relative humidity'),
+    ('co2', 'ppm', 'Carbon dioxide concentration'),
+    ('voc', 'index', 'Volatile organic compounds index'),
+    ('pm2_5', 'µg/m³', 'Fine particulate matter concentration'),
+    ('lux', 'lx', 'Illuminance'),
+    ('wind_speed', 'm/s', 'Wind speed');
+
+-- Move the fixed-column readings aside and split them into
+-- reading headers and one narrow row per metric value
+DROP INDEX idx_sensor_time;
+ALTER TABLE sensor_readings RENAME TO sensor_readings_wide;
+
+CREATE TABLE sensor_readings (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    sensor_id TEXT NOT NULL,
+    topic TEXT NOT NULL,
+    timestamp DATETIME NOT NULL
+);
+
+CREATE INDEX idx_sensor_time ON sensor_readings (sensor_id, timestamp DESC);
+
+CREATE TABLE reading_values (
+    reading_id INTEGER NOT NULL REFERENCES sensor_readings (id) ON DELETE CASCADE,
+    metric TEXT NOT NULL REFERENCES metrics (name),
+    value REAL NOT NULL,
+    PRIMARY KEY (reading_id, metric)
+) WITHOUT ROWID;
+
+CREATE INDEX idx_metric_value ON reading_values (metric, value);
+
+INSERT INTO sensor_readings (id, sensor_id, topic, timestamp)
+SELECT id, sensor_id, topic, timestamp FROM sensor_readings_wide;
+
+INSERT INTO reading_values (reading_id, metric, value)
+SELECT id, 'temperature', temperature FROM sensor_readings_wide
+UNION ALL
+SELECT id, 'pressure', pressure FROM sensor_readings_wide
+UNION ALL
+SELECT id, 'humidity', humidity FROM sensor_readings_wide;
+
+DROP TABLE sensor_readings_wide;
@@ -0,0 +1,3 @@
+-- sqlfluff:dialect:sqlite
+
+DROP TABLE reading_rollups;
@@ -0,0 +1,46 @@
+-- sqlfluff:dialect:sqlite
+
+CREATE TABLE IF NOT EXISTS reading_rollups (
+    resolution TEXT NOT NULL CHECK (resolution IN ('hour', 'day')),
+    sensor_id TEXT NOT NULL,
+    metric TEXT NOT NULL REFERENCES metrics (name),
+    bucket DATETIME NOT NULL,
+    min_value REAL NOT NULL,
+    max_value REAL NOT NULL,
+    sum_value REAL NOT NULL,
+    sample_count INTEGER NOT NULL,
+    PRIMARY KEY (resolution, sensor_id, metric, bucket)
+) WITHOUT ROWID;
+
+CREATE INDEX idx_rollup_metric_bucket ON reading_rollups (resolution, metric, bucket);
+
+-- Backfill from the readings stored so far
+INSERT INTO reading_rollups
+    (resolution, sensor_id, metric, bucket, min_value, max_value, sum_value, sample_count)
+SELECT
+    'hour',
+    r.sensor_id,
+    v.metric,
+    strftime('%Y-%m-%dT%H:00:00+00:00', r.timestamp),
+    MIN(v.value),
+    MAX(v.value),
+    SUM(v.value),
+    COUNT(*)
+FROM sensor_readings r
+JOIN reading_values v ON v.reading_id = r.id
+GROUP BY 2, 3, 4;
+
+INSERT INTO reading_rollups
+    (resolution, sensor_id, metric, bucket, min_value, max_value, sum_value, sample_count)
+SELECT
+    'day',
+    r.sensor_id,
+    v.metric,
+    strftime('%Y-%m-%dT00:00:00+00:00', r.timestamp),
+    MIN(v.value),
+    MAX(v.value),
+    SUM(v.value),
+    COUNT(*)
+FROM sensor_readings r
+JOIN reading_values v ON v.reading_id = r.id
+GROUP BY 2, 3, 4;
@@ -0,0 +1,12 @@
+-- sqlfluff:dialect:sqlite
+
+-- A NULL sensor_id is the policy for every sensor without one of its own,
+-- a NULL keep_days keeps the data forever
+CREATE TABLE IF NOT EXISTS retention_policies (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    sensor_id TEXT,
+    resolution TEXT NOT NULL CHECK (resolution IN ('raw', 'hour', 'day')),
+    keep_days INTEGER CHECK (keep_days > 0)
+);
+
+CREATE UNIQUE INDEX idx_retention_scope ON retention_policies (IFNULL(sensor_id, ''), resolution);
@@ -0,0 +1,4 @@
+-- sqlfluff:dialect:sqlite
+
+DROP TABLE IF EXISTS quarantined_readings;
+DROP TABLE IF EXISTS sensors;
@@ -0,0 +1,27 @@
+-- sqlfluff:dialect:sqlite
+
+CREATE TABLE IF NOT EXISTS sensors (
+    sensor_id TEXT PRIMARY KEY NOT NULL,
+    name TEXT,
+    location TEXT,
+    altitude_m REAL,
+    installed_on DATE,
+    notes TEXT,
+    status TEXT NOT NULL DEFAULT 'unapproved' CHECK (status IN ('unapproved', 'approved')),
+    first_seen DATETIME
+);
+
+-- Sensors that published before the registry existed are trusted
+INSERT INTO sensors (sensor_id, status, first_seen)
+SELECT sensor_id, 'approved', MIN(timestamp) FROM sensor_readings GROUP BY sensor_id;
+
+-- Readings of unapproved sensors, metrics are kept as a JSON object
+CREATE TABLE IF NOT EXISTS quarantined_readings (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    sensor_id TEXT NOT NULL REFERENCES sensors (sensor_id) ON DELETE CASCADE,
+    topic TEXT NOT NULL,
+    timestamp DATETIME NOT NULL,
+    metrics TEXT NOT NULL
+);
+
+CREATE INDEX idx_quarantine_sensor ON quarantined_readings (sensor_id, timestamp);
@@ -0,0 +1,18 @@
+-- sqlfluff:dialect:sqlite
+
+CREATE TABLE IF NOT EXISTS calibrations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    sensor_id TEXT NOT NULL,
+    metric TEXT NOT NULL,
+    gain REAL NOT NULL DEFAULT 1.0 CHECK (gain <> 0),
+    offset_value REAL NOT NULL DEFAULT 0.0,
+    valid_from DATETIME,
+    valid_to DATETIME
+);
+
+CREATE INDEX idx_calibration_sensor ON calibrations (sensor_id, metric);
+
+-- Values as reported by the sensor, `value` holds the calibrated one.
+-- Everything stored so far is uncalibrated.
+ALTER TABLE reading_values ADD COLUMN raw_value REAL NOT NULL DEFAULT 0;
+UPDATE reading_values SET raw_value = value;
@@ -0,0 +1,3 @@
+-- sqlfluff:dialect:sqlite
+
+DROP TABLE IF EXISTS api_keys;
@@ -0,0 +1,340 @@
+//! API keys sent as bearer tokens.
+//!
+//! Every operation takes the security scheme of the scope it needs. Its
+//! checker looks the key up through the [`Authenticator`] among the request
+//! data and answers `401` for missing, unknown or revoked keys and `403` for
+//! keys without the scope. Admin keys have every scope.
+//!
+//! Browsers can't set headers on an `EventSource` or a `WebSocket`. They
+//! exchange their key for a short-lived stream token, sent as the
+//! `access_token` query parameter of `/v1/stream` and `/v1/live`, or offer
+//! `bearer` and the key as WebSocket subprotocols.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, TimeDelta, Utc};
+use hmac::{Hmac, Mac};
+use poem::http::{StatusCode, header};
+use poem::{Request, Response};
+use poem_openapi::auth::Bearer;
+use poem_openapi::{Object, SecurityScheme};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::db::{ApiKey, Repository, Scope};
+use crate::error::BsError;
+
+/// Start of every stream token
+const TOKEN_PREFIX: &str = "bst_";
+/// How long a stream token may be used to open a stream
+const TOKEN_LIFETIME: TimeDelta = TimeDelta::seconds(60);
+/// Bytes of the signature kept in a stream token
+const TOKEN_MAC_LEN: usize = 16;
+/// WebSocket subprotocol followed by the key, answered back on upgrades
+pub(super) const BEARER_PROTOCOL: &str = "bearer";
+
+/// Looks up the keys requests carry, added to the routes as data
+#[derive(Clone)]
+pub struct Authenticator {
+    keys: Arc<dyn KeyStore>,
+    /// Signs stream tokens, they stop working after a restart
+    token_key: Arc<[u8; 32]>,
+}
+
+#[async_trait]
+trait KeyStore: Send + Sync {
+    async fn authenticate(&self, secret: &str) -> Result<Option<ApiKey>, BsError>;
+
+    async fn api_key(&self, id: i64) -> Result<Option<ApiKey>, BsError>;
+}
+
+#[async_trait]
+impl<R: Repository> KeyStore for R {
+    async fn authenticate(&self, secret: &str) -> Result<Option<ApiKey>, BsError> {
+        Repository::authenticate(self, secret).await
+    }
+
+    async fn api_key(&self, id: i64) -> Result<Option<ApiKey>, BsError> {
+        let keys = self.fetch_api_keys().await?;
+        Ok(keys
+            .into_iter()
+            .find(|key| key.id == id && key.revoked_at.is_none()))
+    }
+}
+
+impl Authenticator {
+    pub fn new<R: Repository + 'static>(repository: R) -> Self {
+        Self {
+            keys: Arc::new(repository),
+            token_key: Arc::new(rand::random()),
+        }
+    }
+
+    fn mac(&self, payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.token_key.as_slice())
+            .expect("HMAC accepts keys of any length");
+        mac.update(payload);
+        mac
+    }
+
+    /// A stream token standing in for `key` until it expires
+    pub(super) fn issue_token(&self, key: &ApiKey) -> StreamToken {
+        let expires_at = Utc::now() + TOKEN_LIFETIME;
+        let mut buf = Vec::with_capacity(16 + TOKEN_MAC_LEN);
+        buf.extend_from_slice(&key.id.to_be_bytes());
+        buf.extend_from_slice(&expires_at.timestamp().to_be_bytes());
+        let mac = self.mac(&buf).finalize().into_bytes();
+        buf.extend_from_slice(&mac[..TOKEN_MAC_LEN]);
+        StreamToken {
+            token: format!("{TOKEN_PREFIX}{}", URL_SAFE_NO_PAD.encode(buf)),
+            expires_at,
+        }
+    }
+
+    /// The key a stream token was issued for, while the token is valid
+    async fn redeem(&self, token: &str) -> Result<Option<ApiKey>, BsError> {
+        let Some(buf) = token
+            .strip_prefix(TOKEN_PREFIX)
+            .and_then(|encoded| URL_SAFE_NO_PAD.decode(encoded).ok())
+            .filter(|buf| buf.len() == 16 + TOKEN_MAC_LEN)
+        else {
+            return Ok(None);
+        };
+        let (payload, tag) = buf.split_at(16);
+        if self.mac(payload).verify_truncated_left(tag).is_err() {
+            return Ok(None);
+        }
+        let id = i64::from_be_bytes(payload[..8].try_into().expect("8 bytes"));
+        let expires_at = i64::from_be_bytes(payload[8..].try_into().expect("8 bytes"));
+        if expires_at <= Utc::now().timestamp() {
+            return Ok(None);
+        }
+        self.keys.api_key(id).await
+    }
+}
+
+/// Stands in for an API key when opening a stream from a browser
+#[derive(Debug, Clone, PartialEq, Object)]
+pub struct StreamToken {
+    /// Sent as the `access_token` query parameter of `/v1/stream` and
+    /// `/v1/live`
+    pub token: String,
+    /// Streams have to be opened by then, open streams outlive it
+    pub expires_at: DateTime<Utc>,
+}
+
+/// API key sent as `Authorization: Bearer <secret>`, issued with
+/// `base-station api-keys issue` or `POST /v1/api-keys`. The `/v1/live`
+/// WebSocket also takes it as the subprotocols `bearer, <secret>`.
+#[derive(SecurityScheme)]
+#[oai(rename = "ApiKey", ty = "bearer", checker = "read_readings")]
+pub struct ReadReadings(pub ApiKey);
+
+/// Stream token from `POST /v1/stream-tokens`, for browsers that can't send
+/// the `Authorization` header. Only `/v1/stream` and `/v1/live` take it, and
+/// only to open a stream before the token expires.
+#[derive(SecurityScheme)]
+#[oai(
+    rename = "StreamToken",
+    ty = "api_key",
+    key_in = "query",
+    key_name = "access_token",
+    checker = "stream_token"
+)]
+pub struct StreamTokenKey(pub ApiKey);
+
+/// Streams are opened with an API key or, from browsers, a stream token
+#[derive(SecurityScheme)]
+pub enum ReadStream {
+    Token(StreamTokenKey),
+    ApiKey(ReadReadings),
+}
+
+/// API key sent as `Authorization: Bearer <secret>`, issued with
+/// `base-station api-keys issue` or `POST /v1/api-keys`
+#[derive(SecurityScheme)]
+#[oai(rename = "ApiKey", ty = "bearer", checker = "write_config")]
+pub struct WriteConfig(pub ApiKey);
+
+/// API key sent as `Authorization: Bearer <secret>`, issued with
+/// `base-station api-keys issue` or `POST /v1/api-keys`
+#[derive(SecurityScheme)]
+#[oai(rename = "ApiKey", ty = "bearer", checker = "admin")]
+pub struct Admin(pub ApiKey);
+
+async fn read_readings(request: &Request, bearer: Bearer) -> poem::Result<ApiKey> {
+    authorize(request, &bearer.token, Scope::ReadReadings).await
+}
+
+async fn stream_token(
+    request: &Request,
+    token: poem_openapi::auth::ApiKey,
+) -> poem::Result<ApiKey> {
+    authorize_token(request, &token.key).await
+}
+
+async fn write_config(request: &Request, bearer: Bearer) -> poem::Result<ApiKey> {
+    authorize(request, &bearer.token, Scope::WriteConfig).await
+}
+
+async fn admin(request: &Request, bearer: Bearer) -> poem::Result<ApiKey> {
+    authorize(request, &bearer.token, Scope::Admin).await
+}
+
+/// Secret of the `Authorization` header, for endpoints outside the API
+pub(super) fn bearer(request: &Request) -> Option<&str> {
+    let (scheme, secret) = request.header(header::AUTHORIZATION)?.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| secret.trim())
+}
+
+/// Secret offered after the `bearer` subprotocol of a WebSocket upgrade
+pub(super) fn protocol_bearer(request: &Request) -> Option<&str> {
+    let protocols = request.header(header::SEC_WEBSOCKET_PROTOCOL)?;
+    let mut protocols = protocols.split(',').map(str::trim);
+    protocols.find(|protocol| *protocol == BEARER_PROTOCOL)?;
+    protocols.next()
+}
+
+/// Stream token of the `access_token` query parameter
+pub(super) fn access_token(request: &Request) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Params {
+        access_token: Option<String>,
+    }
+    request.params::<Params>().ok()?.access_token
+}
+
+/// Id of the key the request carries, when it is known and not revoked
+pub(super) async fn key_id(request: &Request) -> Option<i64> {
+    let secret = bearer(request)?;
+    let authenticator = request.data::<Authenticator>()?;
+    match authenticator.keys.authenticate(secret).await {
+        Ok(key) => key.map(|key| key.id),
+        Err(e) => {
+            tracing::error!("Authentication failed: {e}");
+            None
+        }
+    }
+}
+
+/// The key `secret` belongs to when it has `scope`
+pub(super) async fn authorize(
+    request: &Request,
+    secret: &str,
+    scope: Scope,
+) -> poem::Result<ApiKey> {
+    let authenticator = authenticator(request)?;
+    allowed(authenticator.keys.authenticate(secret).await, scope)
+}
+
+/// The key a stream token was issued for when it may read readings
+pub(super) async fn authorize_token(request: &Request, token: &str) -> poem::Result<ApiKey> {
+    let authenticator = authenticator(request)?;
+    allowed(authenticator.redeem(token).await, Scope::ReadReadings)
+}
+
+fn authenticator(request: &Request) -> poem::Result<&Authenticator> {
+    request.data::<Authenticator>().ok_or_else(|| {
+        tracing::error!("No authenticator among the request data");
+        refused(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+    })
+}
+
+fn allowed(key: Result<Option<ApiKey>, BsError>, scope: Scope) -> poem::Result<ApiKey> {
+    match key {
+        Ok(Some(key)) if key.allows(scope) => Ok(key),
+        Ok(Some(key)) => Err(refused(
+            StatusCode::FORBIDDEN,
+            &format!("API key {} lacks the {} scope", key.id, scope.as_str()),
+        )),
+        Ok(None) => Err(unauthorized()),
+        Err(e) => {
+            tracing::error!("Authentication failed: {e}");
+            Err(refused(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error",
+            ))
+        }
+    }
+}
+
+pub(super) fn unauthorized() -> poem::Error {
+    refused(
+        StatusCode::UNAUTHORIZED,
+        "Missing, unknown or revoked API key",
+    )
+}
+
+/// Error answered like the other API errors, with a JSON message
+pub(super) fn refused(status: StatusCode, message: &str) -> poem::Error {
+    let mut response = Response::builder()
+        .status(status)
+        .content_type("application/json; charset=utf-8");
+    if status == StatusCode::UNAUTHORIZED {
+        response = response.header(header::WWW_AUTHENTICATE, "Bearer");
+    }
+    poem::Error::from_response(response.body(serde_json::json!({ "message": message }).to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{InMemoryRepository, NewApiKey};
+
+    #[test]
+    fn bearer_secrets_are_read_from_the_header() {
+        let secret = |value: &str| {
+            let request = Request::builder()
+                .header(header::AUTHORIZATION, value)
+                .finish();
+            bearer(&request).map(String::from)
+        };
+
+        assert_eq!(secret("Bearer bs_abc").as_deref(), Some("bs_abc"));
+        assert_eq!(secret("bearer  bs_abc ").as_deref(), Some("bs_abc"));
+        assert_eq!(secret("Basic dXNlcjpwdw=="), None);
+        assert_eq!(bearer(&Request::default()), None);
+    }
+
+    #[test]
+    fn websocket_secrets_follow_the_bearer_protocol() {
+        let secret = |value: &str| {
+            let request = Request::builder()
+                .header(header::SEC_WEBSOCKET_PROTOCOL, value)
+                .finish();
+            protocol_bearer(&request).map(String::from)
+        };
+
+        assert_eq!(secret("bearer, bs_abc").as_deref(), Some("bs_abc"));
+        assert_eq!(secret("json,bearer,bs_abc").as_deref(), Some("bs_abc"));
+        assert_eq!(secret("bs_abc"), None);
+        assert_eq!(secret("bearer"), None);
+    }
+
+    #[tokio::test]
+    async fn stream_tokens_stand_in_for_their_key() {
+        let repo = InMemoryRepository::new();
+        let issued = repo
+            .issue_api_key(NewApiKey {
+                name: "dashboard".to_string(),
+                scopes: vec![Scope::ReadReadings],
+            })
+            .await
+            .unwrap();
+        let authenticator = Authenticator::new(repo.clone());
+        let token = authenticator.issue_token(&issued.key).token;
+
+        let key = authenticator.redeem(&token).await.unwrap();
+        assert_eq!(key, Some(issued.key.clone()));
+        // Signed with the key of another base station
+        let other = Authenticator::new(repo.clone());
+        assert_eq!(other.redeem(&token).await.unwrap(), None);
+        assert_eq!(authenticator.redeem("bst_abc").await.unwrap(), None);
+
+        repo.revoke_api_key(issued.key.id).await.unwrap();
+        assert_eq!(authenticator.redeem(&token).await.unwrap(), None);
+    }
+}
@@ -1,17 +1,88 @@
-use poem_openapi::{payload::Json, types::ToJSON, ApiResponse, Object};
+use std::collections::BTreeMap;
+
+use poem_openapi::{ApiResponse, Object, payload::Json, types::ToJSON};
 use serde::Serialize;
 
+use crate::db::SensorReading;
+
+/// Why a request was not served
+#[derive(Debug, Serialize, Object)]
+pub struct ApiError {
+    pub message: String,
+}
+
+/// A page of readings together with the units of the requested metrics
 #[derive(Debug, Serialize, Object)]
-pub struct EnvironmentApiData;
+pub struct ReadingsPage {
+    pub rows: Vec<SensorReading>,
+    /// Unit of every requested metric that has one
+    pub units: BTreeMap<String, String>,
+    /// Pass as `after` to fetch the following page
+    pub next: Option<String>,
+    /// Pass as `before` to fetch the preceding page
+    pub previous: Option<String>,
+}
+
+/// Newest reading of a sensor
+#[derive(Debug, Serialize, Object)]
+pub struct LatestReading {
+    pub reading: SensorReading,
+    /// Unit of every metric of the reading that has one
+    pub units: BTreeMap<String, String>,
+}
+
+/// Newest reading of every sensor
+#[derive(Debug, Serialize, Object)]
+pub struct LatestReadings {
+    pub rows: Vec<SensorReading>,
+    /// Unit of every metric of the readings that has one
+    pub units: BTreeMap<String, String>,
+}
 
 #[derive(Debug, ApiResponse)]
-pub enum EnvironmentApiResponse<T: ToJSON + Send>{
+pub enum EnvironmentApiResponse<T: ToJSON + Send> {
     #[oai(status = 200)]
     Ok(Json<T>),
+    /// The request is invalid, the body tells why
     #[oai(status = 400)]
-    ClientError,
+    ClientError(Json<ApiError>),
+    /// The requested resource does not exist
     #[oai(status = 404)]
-    NotFound,
+    NotFound(Json<ApiError>),
+    /// Too many heavy queries are running, retry after the given seconds
+    #[oai(status = 429)]
+    TooManyRequests(Json<ApiError>, #[oai(header = "Retry-After")] u64),
+    /// The request failed on the base station, the cause is logged
     #[oai(status = 500)]
-    InternalServerError,
+    InternalServerError(Json<ApiError>),
+}
+
+impl<T: ToJSON + Send> EnvironmentApiResponse<T> {
+    pub fn client_error(message: impl Into<String>) -> Self {
+        Self::ClientError(Json(ApiError {
+            message: message.into(),
+        }))
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::NotFound(Json(ApiError {
+            message: message.into(),
+        }))
+    }
+
+    pub fn too_many_requests(retry_after: u64) -> Self {
+        Self::TooManyRequests(
+            Json(ApiError {
+                message: "The base station is busy with other queries, retry later".to_string(),
+            }),
+            retry_after,
+        )
+    }
+
+    /// The cause is only logged, it may hold details of the database
+    pub fn internal_server_error() -> Self {
+        Self::InternalServerError(Json(ApiError {
+            message: "Internal server error".to_string(),
+        }))
+    }
 }
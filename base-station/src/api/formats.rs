@@ -0,0 +1,361 @@
+//! Readings served in the format a client asks for, through the `format`
+//! parameter or the `Accept` header.
+//!
+//! JSON is served a page at a time. CSV, NDJSON and Arrow IPC carry every
+//! reading matching the filters and are streamed as they are read from the
+//! repository, so only a single page of readings is held in memory however
+//! long the requested span is.
+
+use std::io;
+
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::SchemaRef;
+use futures_util::stream::{self, StreamExt};
+use poem::Body;
+use poem_openapi::payload::{Binary, Json};
+use poem_openapi::types::ToJSON;
+use poem_openapi::{ApiResponse, Enum, ResponseContent};
+use serde::Deserialize;
+use tokio::sync::OwnedSemaphorePermit;
+
+use super::env_api_response::{ApiError, EnvironmentApiResponse, ReadingsPage};
+use crate::db::{
+    MeasurementQuery, Pagination, Repository, SensorReading, SensorReadingsPage, SortOrder,
+};
+use crate::error::BsError;
+use crate::export::{arrow_schema, csv_record, record_batch};
+
+/// Readings fetched from the repository per round trip while streaming
+const DOWNLOAD_PAGE_SIZE: usize = 5000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Enum)]
+#[serde(rename_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+pub enum ReadingsFormat {
+    /// A page of readings together with the cursors of its neighbours
+    #[default]
+    Json,
+    /// Every matching reading, a header row and a column per requested column
+    Csv,
+    /// Every matching reading, a JSON object per line
+    Ndjson,
+    /// Every matching reading as an Apache Arrow IPC stream
+    Arrow,
+}
+
+impl ReadingsFormat {
+    /// The format requested by `format`, or else the one the `Accept` header
+    /// prefers. None when the header accepts none of them.
+    pub(super) fn negotiate(format: Option<Self>, accept: Option<&str>) -> Option<Self> {
+        match (format, accept) {
+            (Some(format), _) => Some(format),
+            (None, Some(accept)) if !accept.trim().is_empty() => Self::from_accept(accept),
+            (None, _) => Some(Self::Json),
+        }
+    }
+
+    /// Highest quality media range we serve, the first one wins a tie
+    fn from_accept(accept: &str) -> Option<Self> {
+        let mut best: Option<(f32, Self)> = None;
+        for range in accept.split(',') {
+            let mut parameters = range.split(';').map(str::trim);
+            let media_type = parameters.next().unwrap_or_default().to_ascii_lowercase();
+            let quality = parameters
+                .filter_map(|p| p.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let format = match media_type.as_str() {
+                "*/*" | "application/*" | "application/json" => Self::Json,
+                "text/*" | "text/csv" => Self::Csv,
+                "application/x-ndjson" | "application/ndjson" => Self::Ndjson,
+                "application/vnd.apache.arrow.stream" => Self::Arrow,
+                _ => continue,
+            };
+            if quality > 0.0 && best.is_none_or(|(q, _)| quality > q) {
+                best = Some((quality, format));
+            }
+        }
+        best.map(|(_, format)| format)
+    }
+}
+
+#[derive(ResponseContent)]
+pub enum ReadingsContent {
+    Json(Json<ReadingsPage>),
+    #[oai(content_type = "text/csv; charset=utf-8")]
+    Csv(Binary<Body>),
+    #[oai(content_type = "application/x-ndjson")]
+    Ndjson(Binary<Body>),
+    #[oai(content_type = "application/vnd.apache.arrow.stream")]
+    Arrow(Binary<Body>),
+}
+
+#[derive(ApiResponse)]
+pub enum ReadingsResponse {
+    #[oai(status = 200)]
+    Ok(ReadingsContent),
+    /// The request is invalid, the body tells why
+    #[oai(status = 400)]
+    ClientError(Json<ApiError>),
+    /// The requested resource does not exist
+    #[oai(status = 404)]
+    NotFound(Json<ApiError>),
+    /// None of the formats in the `Accept` header is served
+    #[oai(status = 406)]
+    NotAcceptable(Json<ApiError>),
+    /// Too many heavy queries are running, retry after the given seconds
+    #[oai(status = 429)]
+    TooManyRequests(Json<ApiError>, #[oai(header = "Retry-After")] u64),
+    /// The request failed on the base station, the cause is logged
+    #[oai(status = 500)]
+    InternalServerError(Json<ApiError>),
+}
+
+impl ReadingsResponse {
+    pub(super) fn not_acceptable() -> Self {
+        Self::NotAcceptable(Json(ApiError {
+            message: "Readings are served as application/json, text/csv, \
+                      application/x-ndjson or application/vnd.apache.arrow.stream"
+                .to_string(),
+        }))
+    }
+
+    fn error(e: BsError) -> Self {
+        match e {
+            BsError::InvalidQuery(message) => {
+                tracing::debug!("Rejected download: {message}");
+                Self::ClientError(Json(ApiError { message }))
+            }
+            e => {
+                tracing::error!("Download failed: {e}");
+                Self::InternalServerError(Json(ApiError {
+                    message: "Internal server error".to_string(),
+                }))
+            }
+        }
+    }
+}
+
+impl From<EnvironmentApiResponse<ReadingsPage>> for ReadingsResponse {
+    fn from(response: EnvironmentApiResponse<ReadingsPage>) -> Self {
+        match response {
+            EnvironmentApiResponse::Ok(page) => Self::Ok(ReadingsContent::Json(page)),
+            EnvironmentApiResponse::ClientError(e) => Self::ClientError(e),
+            EnvironmentApiResponse::NotFound(e) => Self::NotFound(e),
+            EnvironmentApiResponse::TooManyRequests(e, retry_after) => {
+                Self::TooManyRequests(e, retry_after)
+            }
+            EnvironmentApiResponse::InternalServerError(e) => Self::InternalServerError(e),
+        }
+    }
+}
+
+/// Stream every reading matching `query` in `format`, starting after its
+/// `after` cursor. The first page is read right away so invalid queries are
+/// reported as such, a failure later on cuts the body short. The turn of the
+/// query queue is held until the body is sent.
+pub(super) async fn download<R>(
+    repository: R,
+    format: ReadingsFormat,
+    mut query: MeasurementQuery,
+    turn: OwnedSemaphorePermit,
+) -> ReadingsResponse
+where
+    R: Repository + 'static,
+{
+    if query.pagination.before.is_some() {
+        return ReadingsResponse::error(BsError::InvalidQuery(
+            "before only applies to JSON pages".to_string(),
+        ));
+    }
+    query.pagination.page_size = DOWNLOAD_PAGE_SIZE;
+    let columns = query.columns.clone();
+    let order = query.pagination.order;
+    let page = match repository.fetch_sensor_readings_page(query).await {
+        Ok(page) => page,
+        Err(e) => return ReadingsResponse::error(e),
+    };
+    let encoder = match Encoder::new(format, &columns) {
+        Ok(encoder) => encoder,
+        Err(e) => return ReadingsResponse::error(e),
+    };
+    let mut download = Download {
+        repository,
+        encoder,
+        following: None,
+        finished: false,
+        columns,
+        order,
+        _turn: turn,
+    };
+    let first = match download.encode(page) {
+        Ok(first) => first,
+        Err(e) => return ReadingsResponse::error(e),
+    };
+
+    let body = stream::once(async { Ok(first) }).chain(stream::unfold(download, Download::next));
+    let body = Binary(Body::from_bytes_stream(body));
+    ReadingsResponse::Ok(match format {
+        ReadingsFormat::Json => unreachable!("JSON is served a page at a time"),
+        ReadingsFormat::Csv => ReadingsContent::Csv(body),
+        ReadingsFormat::Ndjson => ReadingsContent::Ndjson(body),
+        ReadingsFormat::Arrow => ReadingsContent::Arrow(body),
+    })
+}
+
+/// Pages of readings still to be streamed
+struct Download<R> {
+    repository: R,
+    encoder: Encoder,
+    /// Query of the following page, none once the last page is encoded
+    following: Option<MeasurementQuery>,
+    finished: bool,
+    columns: Vec<String>,
+    order: SortOrder,
+    _turn: OwnedSemaphorePermit,
+}
+
+impl<R: Repository> Download<R> {
+    /// Encode a page and remember where the following one starts
+    fn encode(&mut self, page: SensorReadingsPage) -> Result<Vec<u8>, BsError> {
+        self.following = page.next.map(|next| MeasurementQuery {
+            filters: page.filters,
+            pagination: Pagination {
+                after: Some(next),
+                page_size: DOWNLOAD_PAGE_SIZE,
+                order: self.order,
+                ..Default::default()
+            },
+            columns: self.columns.clone(),
+        });
+        self.encoder.encode(&page.rows)
+    }
+
+    async fn next(mut self) -> Option<(Result<Vec<u8>, io::Error>, Self)> {
+        if self.finished {
+            return None;
+        }
+        let chunk = match self.following.take() {
+            Some(query) => match self.repository.fetch_sensor_readings_page(query).await {
+                Ok(page) => self.encode(page),
+                Err(e) => Err(e),
+            },
+            None => {
+                self.finished = true;
+                self.encoder.finish()
+            }
+        };
+        match chunk {
+            Ok(bytes) => Some((Ok(bytes), self)),
+            Err(e) => {
+                // The body ends without its trailer, so clients see the download is incomplete
+                tracing::error!("Download of readings failed: {e}");
+                self.finished = true;
+                Some((Err(io::Error::other(e)), self))
+            }
+        }
+    }
+}
+
+enum Encoder {
+    /// Header row not written yet
+    Csv {
+        columns: Vec<String>,
+        header: bool,
+    },
+    Ndjson,
+    Arrow {
+        schema: SchemaRef,
+        writer: StreamWriter<Vec<u8>>,
+    },
+}
+
+impl Encoder {
+    fn new(format: ReadingsFormat, columns: &[String]) -> Result<Self, BsError> {
+        Ok(match format {
+            ReadingsFormat::Json => unreachable!("JSON is served a page at a time"),
+            ReadingsFormat::Csv => Self::Csv {
+                columns: columns.to_vec(),
+                header: true,
+            },
+            ReadingsFormat::Ndjson => Self::Ndjson,
+            ReadingsFormat::Arrow => {
+                let schema = arrow_schema(columns);
+                let writer = StreamWriter::try_new(Vec::new(), &schema)?;
+                Self::Arrow { schema, writer }
+            }
+        })
+    }
+
+    fn encode(&mut self, readings: &[SensorReading]) -> Result<Vec<u8>, BsError> {
+        match self {
+            Self::Csv { columns, header } => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                if std::mem::take(header) {
+                    writer.write_record(columns.iter())?;
+                }
+                for reading in readings {
+                    writer.write_record(csv_record(columns, reading))?;
+                }
+                Ok(writer.into_inner().map_err(|e| e.into_error())?)
+            }
+            Self::Ndjson => {
+                let mut bytes = Vec::new();
+                for reading in readings {
+                    bytes.extend_from_slice(reading.to_json_string().as_bytes());
+                    bytes.push(b'\n');
+                }
+                Ok(bytes)
+            }
+            Self::Arrow { schema, writer } => {
+                // The first chunk starts with the schema
+                if !readings.is_empty() {
+                    writer.write(&record_batch(schema.clone(), readings)?)?;
+                }
+                Ok(std::mem::take(writer.get_mut()))
+            }
+        }
+    }
+
+    /// Bytes that close the stream
+    fn finish(&mut self) -> Result<Vec<u8>, BsError> {
+        match self {
+            Self::Csv { .. } | Self::Ndjson => Ok(Vec::new()),
+            Self::Arrow { writer, .. } => {
+                writer.finish()?;
+                Ok(std::mem::take(writer.get_mut()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_are_negotiated() {
+        let negotiate = |accept| ReadingsFormat::negotiate(None, Some(accept));
+
+        assert_eq!(
+            ReadingsFormat::negotiate(None, None),
+            Some(ReadingsFormat::Json)
+        );
+        assert_eq!(negotiate(""), Some(ReadingsFormat::Json));
+        assert_eq!(negotiate("*/*"), Some(ReadingsFormat::Json));
+        assert_eq!(negotiate("text/csv"), Some(ReadingsFormat::Csv));
+        assert_eq!(
+            negotiate("text/html, application/json;q=0.5, application/x-ndjson;q=0.9"),
+            Some(ReadingsFormat::Ndjson)
+        );
+        assert_eq!(
+            negotiate("application/vnd.apache.arrow.stream, text/csv"),
+            Some(ReadingsFormat::Arrow)
+        );
+        assert_eq!(negotiate("text/csv;q=0, application/xml"), None);
+        assert_eq!(
+            ReadingsFormat::negotiate(Some(ReadingsFormat::Csv), Some("application/json")),
+            Some(ReadingsFormat::Csv)
+        );
+    }
+}
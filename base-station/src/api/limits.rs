@@ -0,0 +1,299 @@
+//! Limits keeping a single client from monopolising the base station.
+//!
+//! [`RateLimiter`] gives every client address and every API key a token
+//! bucket refilled at a steady rate per minute and answers `429` with
+//! `Retry-After` once a bucket is empty. [`QueryQueue`] lets a few heavy
+//! queries run at once, the others wait their turn for a while before they
+//! are refused the same way.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use poem::http::{HeaderValue, StatusCode, header};
+use poem::{Endpoint, IntoResponse, Middleware, Request, Response};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::auth;
+
+/// Clients tracked at most. Once full, idle buckets are forgotten and new
+/// clients refused until there is room again.
+const MAX_CLIENTS: usize = 10_000;
+
+/// Shortest time between two searches for idle buckets
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Who a bucket belongs to, a key by its id once authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Client {
+    Key(i64),
+    Address(IpAddr),
+}
+
+/// Tokens left to a client, refilled continuously up to a minute's worth
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(per_minute: u32, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(per_minute),
+            updated: now,
+        }
+    }
+
+    /// Top up the tokens for the time passed, then tell how long until the
+    /// next one when none is left
+    fn refill(&mut self, per_minute: u32, now: Instant) -> Result<(), Duration> {
+        let capacity = f64::from(per_minute);
+        let per_second = capacity / 60.0;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / per_second))
+        }
+    }
+
+    /// Take a token, or tell how long until the next one
+    fn take(&mut self, per_minute: u32, now: Instant) -> Result<(), Duration> {
+        self.refill(per_minute, now)?;
+        self.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Idle for a minute, so full again and no different from a new bucket
+    fn is_idle(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.updated) >= Duration::from_secs(60)
+    }
+}
+
+/// Buckets of the clients seen lately
+#[derive(Debug)]
+struct Clients {
+    buckets: HashMap<Client, Bucket>,
+    pruned: Instant,
+}
+
+impl Clients {
+    /// Whether another client can be tracked. Idle buckets are forgotten
+    /// once full, searched for at most every [`PRUNE_INTERVAL`].
+    fn has_room(&mut self, now: Instant) -> bool {
+        if self.buckets.len() < MAX_CLIENTS {
+            return true;
+        }
+        if now.saturating_duration_since(self.pruned) >= PRUNE_INTERVAL {
+            self.buckets.retain(|_, bucket| !bucket.is_idle(now));
+            self.pruned = now;
+        }
+        self.buckets.len() < MAX_CLIENTS
+    }
+}
+
+/// Middleware answering `429` to clients sending more requests per minute
+/// than they are allowed. Every request counts against its address and, when
+/// it carries a valid one, against its API key. A limit of 0 turns it off.
+#[derive(Clone)]
+pub struct RateLimiter {
+    per_key: u32,
+    per_address: u32,
+    clients: Arc<Mutex<Clients>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_key: u32, per_address: u32) -> Self {
+        Self {
+            per_key,
+            per_address,
+            clients: Arc::new(Mutex::new(Clients {
+                buckets: HashMap::new(),
+                pruned: Instant::now(),
+            })),
+        }
+    }
+
+    /// Count a request against its address and key, or tell how long it has
+    /// to wait. Either both buckets give a token or neither does.
+    fn admit(
+        &self,
+        address: Option<IpAddr>,
+        key_id: Option<i64>,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let counted: Vec<(Client, u32)> = [
+            (address.map(Client::Address), self.per_address),
+            (key_id.map(Client::Key), self.per_key),
+        ]
+        .into_iter()
+        .filter(|&(_, per_minute)| per_minute > 0)
+        .filter_map(|(client, per_minute)| Some((client?, per_minute)))
+        .collect();
+
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        for (client, per_minute) in &counted {
+            if !clients.buckets.contains_key(client) && !clients.has_room(now) {
+                tracing::warn!("Tracking {MAX_CLIENTS} clients, refusing new ones");
+                return Err(PRUNE_INTERVAL);
+            }
+            clients
+                .buckets
+                .entry(*client)
+                .or_insert_with(|| Bucket::full(*per_minute, now))
+                .refill(*per_minute, now)?;
+        }
+        for (client, per_minute) in &counted {
+            if let Some(bucket) = clients.buckets.get_mut(client) {
+                bucket.take(*per_minute, now)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for RateLimiter {
+    type Output = RateLimited<E>;
+
+    fn transform(&self, endpoint: E) -> Self::Output {
+        RateLimited {
+            endpoint,
+            limiter: self.clone(),
+        }
+    }
+}
+
+/// Endpoint behind a [`RateLimiter`]
+pub struct RateLimited<E> {
+    endpoint: E,
+    limiter: RateLimiter,
+}
+
+impl<E: Endpoint> Endpoint for RateLimited<E> {
+    type Output = Response;
+
+    async fn call(&self, request: Request) -> poem::Result<Response> {
+        let address = request.remote_addr().as_socket_addr().map(|a| a.ip());
+        // Unknown keys get no bucket, the endpoint refuses them anyway
+        let key_id = match self.limiter.per_key {
+            0 => None,
+            _ => auth::key_id(&request).await,
+        };
+        if let Err(wait) = self.limiter.admit(address, key_id, Instant::now()) {
+            tracing::debug!("Rate limited {} for {wait:?}", request.remote_addr());
+            return Ok(too_many_requests(
+                "Too many requests, slow down",
+                retry_after(wait),
+            ));
+        }
+        self.endpoint
+            .call(request)
+            .await
+            .map(IntoResponse::into_response)
+    }
+}
+
+/// Heavy queries waiting their turn: downloads, aggregates, series, exports
+/// and imports. Queries still waiting after `wait` are refused.
+#[derive(Clone)]
+pub struct QueryQueue {
+    turns: Arc<Semaphore>,
+    wait: Duration,
+}
+
+impl QueryQueue {
+    /// Runs up to `concurrent` heavy queries at once
+    pub fn new(concurrent: usize, wait: Duration) -> Self {
+        Self {
+            turns: Arc::new(Semaphore::new(concurrent)),
+            wait,
+        }
+    }
+
+    /// Wait for a turn, it lasts until the permit is dropped. Err holds the
+    /// seconds to wait before retrying.
+    pub(super) async fn turn(&self) -> Result<OwnedSemaphorePermit, u64> {
+        match tokio::time::timeout(self.wait, self.turns.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            // The semaphore is never closed
+            Ok(Err(_)) | Err(_) => {
+                tracing::debug!("Refused a heavy query after waiting {:?}", self.wait);
+                Err(retry_after(self.wait))
+            }
+        }
+    }
+}
+
+/// Whole seconds to wait, at least one
+fn retry_after(wait: Duration) -> u64 {
+    wait.as_secs_f64().ceil().max(1.0) as u64
+}
+
+fn too_many_requests(message: &str, retry_after: u64) -> Response {
+    let mut response = auth::refused(StatusCode::TOO_MANY_REQUESTS, message).into_response();
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let start = Instant::now();
+        let mut bucket = Bucket::full(60, start);
+        for _ in 0..60 {
+            assert_eq!(bucket.take(60, start), Ok(()));
+        }
+        assert_eq!(bucket.take(60, start), Err(Duration::from_secs(1)));
+
+        let later = start + Duration::from_millis(2500);
+        assert_eq!(bucket.take(60, later), Ok(()));
+        assert_eq!(bucket.take(60, later), Ok(()));
+        assert!(bucket.take(60, later).is_err());
+        assert!(!bucket.is_idle(later));
+        assert!(bucket.is_idle(later + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn refused_requests_take_no_token() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(1, 2);
+        let address = Some(IpAddr::from([192, 0, 2, 1]));
+        assert_eq!(limiter.admit(address, Some(1), start), Ok(()));
+        // The key is out of tokens, so the address keeps its last one
+        assert!(limiter.admit(address, Some(1), start).is_err());
+        assert!(limiter.admit(address, Some(1), start).is_err());
+        assert_eq!(limiter.admit(address, Some(2), start), Ok(()));
+        assert!(limiter.admit(address, Some(3), start).is_err());
+    }
+
+    #[test]
+    fn busy_limiters_refuse_new_clients() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(1, 0);
+        for id in 0..MAX_CLIENTS as i64 {
+            assert_eq!(limiter.admit(None, Some(id), start), Ok(()));
+        }
+        let new = MAX_CLIENTS as i64;
+        assert_eq!(limiter.admit(None, Some(new), start), Err(PRUNE_INTERVAL));
+        // Known clients are still served, and idle ones make room
+        assert!(limiter.admit(None, Some(0), start).is_err());
+        let later = start + Duration::from_secs(120);
+        assert_eq!(limiter.admit(None, Some(new), later), Ok(()));
+    }
+
+    #[test]
+    fn waits_are_rounded_up_to_seconds() {
+        assert_eq!(retry_after(Duration::from_millis(10)), 1);
+        assert_eq!(retry_after(Duration::from_millis(1500)), 2);
+        assert_eq!(retry_after(Duration::from_secs(10)), 10);
+    }
+}
@@ -1,20 +1,1552 @@
-use env_api_response::{EnvironmentApiData, EnvironmentApiResponse};
-use poem::web::Json;
-use poem_openapi::OpenApi;
+use std::collections::BTreeMap;
+use std::io::BufReader;
+use std::path::PathBuf;
 
-use crate::db::{MeasurementQuery, Repository};
+use chrono::{DateTime, Utc};
+use env_api_response::{EnvironmentApiResponse, LatestReading, LatestReadings, ReadingsPage};
+use poem::web::Data;
+use poem_openapi::param::{Header, Path, Query};
+use poem_openapi::payload::Json as PoemJson;
+use poem_openapi::types::ToJSON;
+use poem_openapi::types::multipart::{JsonField, Upload};
+use poem_openapi::{Multipart, OpenApi};
 
+use crate::db::{
+    AggregateFunction, AggregateQuery, Aggregation, ApiKey, Calibration, GapFill, IssuedApiKey,
+    MeasurementQuery, Metric, NewApiKey, Pagination, QueryFilter, Repository, Resolution,
+    RetentionPolicy, RetentionReport, Sensor, SensorMetadata, SensorStatus, Series, SeriesQuery,
+    SortOrder, StoredCalibration, parse_bucket_width,
+};
+use crate::error::BsError;
+use crate::export::{self, ExportReport, ExportRequest};
+use crate::import::{self, ImportOptions, ImportReport};
+use crate::live::ReadingNotifier;
+use auth::{Admin, Authenticator, ReadReadings, ReadStream, StreamToken, WriteConfig};
+use formats::{ReadingsFormat, ReadingsResponse};
+use limits::QueryQueue;
+use stream::{StreamPosition, StreamResponse};
+
+pub mod auth;
 mod env_api_response;
+mod formats;
+pub mod limits;
+mod stream;
+mod websocket;
+
+/// File of readings and how its columns map onto readings
+#[derive(Debug, Multipart)]
+struct ImportUpload {
+    options: Option<JsonField<ImportOptions>>,
+    file: Upload,
+}
 
-pub struct EnvironmentApi<R>{
-    pub repository: R
+pub struct EnvironmentApi<R> {
+    pub repository: R,
+    /// Where `/exports` writes its files, exports are disabled without it
+    pub export_directory: Option<PathBuf>,
+    /// Wakes up `/stream` clients when the ingestion stores readings
+    pub notifier: ReadingNotifier,
+    /// Downloads, aggregates, series, exports and imports wait their turn in it
+    pub query_queue: QueryQueue,
 }
 
 #[OpenApi(prefix_path = "/v1")]
-impl<R> EnvironmentApi<R> where R: Repository + 'static{
-    #[oai(method="get", path = "/")]
-    async fn get(&self, query: Json<MeasurementQuery>)->EnvironmentApiResponse<EnvironmentApiData>{
-        todo!()
+impl<R> EnvironmentApi<R>
+where
+    R: Repository + Clone + 'static,
+{
+    /// Readings matching the filters, a page at a time. `columns` takes
+    /// reading columns and metrics, repeated or separated by commas, and
+    /// defaults to all of them. CSV, NDJSON and Arrow IPC, picked with
+    /// `format` or the `Accept` header, stream every matching reading.
+    #[allow(clippy::too_many_arguments)]
+    #[oai(method = "get", path = "/readings")]
+    async fn readings(
+        &self,
+        _auth: ReadReadings,
+        sensor_id: Query<Option<String>>,
+        /// Metric the `min` and `max` bounds apply to
+        metric: Query<Option<String>>,
+        min: Query<Option<f64>>,
+        max: Query<Option<f64>>,
+        /// Readings taken at or after this instant
+        from: Query<Option<DateTime<Utc>>>,
+        /// Readings taken before this instant
+        to: Query<Option<DateTime<Utc>>>,
+        /// Cursor returned as `next` by the previous page
+        after: Query<Option<String>>,
+        /// Cursor returned as `previous` by the following page
+        before: Query<Option<String>>,
+        /// Only applies to JSON, the other formats carry every reading
+        page_size: Query<Option<usize>>,
+        order: Query<Option<SortOrder>>,
+        columns: Query<Vec<String>>,
+        /// Takes precedence over the `Accept` header
+        format: Query<Option<ReadingsFormat>>,
+        #[oai(name = "Accept")] accept: Header<Option<String>>,
+    ) -> ReadingsResponse {
+        let Some(format) = ReadingsFormat::negotiate(format.0, accept.as_deref()) else {
+            return ReadingsResponse::not_acceptable();
+        };
+        let filters = QueryFilter {
+            sensor_id: sensor_id.0,
+            metric: metric.0,
+            min: min.0,
+            max: max.0,
+            from: from.0,
+            to: to.0,
+            ..Default::default()
+        };
+        let pagination = pagination(after.0, before.0, page_size.0, order.0);
+        self.readings_as(format, filters, pagination, columns.0)
+            .await
+    }
+
+    /// Server-sent events of the readings in the order they are stored, a
+    /// reading stored late with an older timestamp included. Streams start
+    /// with new readings, or with the stored readings taken from `from` on.
+    /// The event id resumes a stream through `Last-Event-ID`, comment lines
+    /// are sent while nothing happens. Browsers open it with a stream token.
+    #[allow(clippy::too_many_arguments)]
+    #[oai(method = "get", path = "/stream")]
+    async fn stream(
+        &self,
+        _auth: ReadStream,
+        sensor_id: Query<Option<String>>,
+        /// Only readings carrying this metric
+        metric: Query<Option<String>>,
+        from: Query<Option<DateTime<Utc>>>,
+        columns: Query<Vec<String>>,
+        #[oai(name = "Last-Event-ID")] last_event_id: Header<Option<String>>,
+        /// Resumes like `Last-Event-ID` does, for streams opened again with a
+        /// new stream token
+        #[oai(name = "last_event_id")]
+        resume_after: Query<Option<String>>,
+    ) -> StreamResponse {
+        let position = match (last_event_id.0.or(resume_after.0), from.0) {
+            (Some(id), _) => id.parse(),
+            // Readings taken from `from` on are filtered from the first one stored
+            (None, Some(_)) => Ok(StreamPosition::START),
+            (None, None) => StreamPosition::latest(&self.repository).await,
+        };
+        let position = match position {
+            Ok(position) => position,
+            Err(e) => return StreamResponse::error(e),
+        };
+        let catalogue = match self.repository.fetch_metrics().await {
+            Ok(catalogue) => catalogue,
+            Err(e) => return StreamResponse::error(e),
+        };
+        let filters = QueryFilter {
+            sensor_id: sensor_id.0,
+            metric: metric.0,
+            from: from.0,
+            ..Default::default()
+        };
+        stream::follow(
+            self.repository.clone(),
+            self.notifier.subscribe(),
+            filters,
+            requested_columns(columns.0, &catalogue),
+            position,
+        )
+        .await
+    }
+
+    /// A token opening `/v1/stream` and `/v1/live` from browsers, which can't
+    /// send the `Authorization` header. It is taken for a minute.
+    #[oai(method = "post", path = "/stream-tokens")]
+    async fn stream_token(
+        &self,
+        auth: ReadReadings,
+        authenticator: Data<&Authenticator>,
+    ) -> EnvironmentApiResponse<StreamToken> {
+        respond(Ok(authenticator.issue_token(&auth.0)))
+    }
+
+    /// Newest reading of every sensor with stored readings
+    #[oai(method = "get", path = "/latest")]
+    async fn latest(&self, _auth: ReadReadings) -> EnvironmentApiResponse<LatestReadings> {
+        let (rows, catalogue) = match (
+            self.repository.fetch_latest_readings().await,
+            self.repository.fetch_metrics().await,
+        ) {
+            (Ok(rows), Ok(catalogue)) => (rows, catalogue),
+            (Err(e), _) | (_, Err(e)) => return respond(Err(e)),
+        };
+        let units = units(
+            &catalogue,
+            rows.iter()
+                .flat_map(|r| r.metrics.keys().map(String::as_str)),
+        );
+        respond(Ok(LatestReadings { rows, units }))
+    }
+
+    /// Readings of a time span aggregated into buckets of `bucket`, e.g. `5m`,
+    /// `1h` or `1d`. `metrics` and `functions` are repeated or separated by
+    /// commas. Buckets without values are left out unless `fill` is given.
+    #[allow(clippy::too_many_arguments)]
+    #[oai(method = "get", path = "/aggregates")]
+    async fn aggregates(
+        &self,
+        _auth: ReadReadings,
+        metrics: Query<Vec<String>>,
+        /// Any of min, max, avg, last, count, p50 and p95
+        functions: Query<Vec<String>>,
+        bucket: Query<String>,
+        from: Query<DateTime<Utc>>,
+        to: Query<DateTime<Utc>>,
+        sensor_id: Query<Option<String>>,
+        /// A row per sensor and bucket instead of one per bucket
+        group_by_sensor: Query<Option<bool>>,
+        fill: Query<Option<GapFill>>,
+    ) -> EnvironmentApiResponse<Aggregation> {
+        let query = list(functions.0)
+            .iter()
+            .map(|function| function.parse::<AggregateFunction>())
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|functions| {
+                Ok(AggregateQuery {
+                    sensor_id: sensor_id.0,
+                    metrics: list(metrics.0),
+                    functions,
+                    from: from.0,
+                    to: to.0,
+                    bucket: parse_bucket_width(&bucket)?,
+                    group_by_sensor: group_by_sensor.0.unwrap_or_default(),
+                    fill: fill.0,
+                })
+            });
+        let query = match query {
+            Ok(query) => query,
+            Err(e) => return respond(Err(e)),
+        };
+        let _turn = match self.query_queue.turn().await {
+            Ok(turn) => turn,
+            Err(retry_after) => return EnvironmentApiResponse::too_many_requests(retry_after),
+        };
+        respond(self.repository.aggregate_readings(query).await)
+    }
+
+    /// List the metrics sensors can report together with their units
+    #[oai(method = "get", path = "/metrics")]
+    async fn metrics(&self, _auth: ReadReadings) -> EnvironmentApiResponse<Vec<Metric>> {
+        respond(self.repository.fetch_metrics().await)
+    }
+
+    /// Every sensor that has been registered or has published, or only
+    /// those with the given status
+    #[oai(method = "get", path = "/sensors")]
+    async fn sensors(
+        &self,
+        _auth: ReadReadings,
+        status: Query<Option<SensorStatus>>,
+    ) -> EnvironmentApiResponse<Vec<Sensor>> {
+        let sensors = self.repository.fetch_sensors().await.map(|sensors| {
+            sensors
+                .into_iter()
+                .filter(|sensor| status.0.is_none_or(|status| sensor.status == status))
+                .collect()
+        });
+        respond(sensors)
+    }
+
+    #[oai(method = "get", path = "/sensors/:sensor_id")]
+    async fn sensor(
+        &self,
+        _auth: ReadReadings,
+        sensor_id: Path<String>,
+    ) -> EnvironmentApiResponse<Sensor> {
+        self.sensor_or_not_found(&sensor_id).await
+    }
+
+    /// Readings of a sensor, filtered, paged and formatted like `/readings`
+    #[allow(clippy::too_many_arguments)]
+    #[oai(method = "get", path = "/sensors/:sensor_id/readings")]
+    async fn sensor_readings(
+        &self,
+        _auth: ReadReadings,
+        sensor_id: Path<String>,
+        /// Metric the `min` and `max` bounds apply to
+        metric: Query<Option<String>>,
+        min: Query<Option<f64>>,
+        max: Query<Option<f64>>,
+        /// Readings taken at or after this instant
+        from: Query<Option<DateTime<Utc>>>,
+        /// Readings taken before this instant
+        to: Query<Option<DateTime<Utc>>>,
+        /// Cursor returned as `next` by the previous page
+        after: Query<Option<String>>,
+        /// Cursor returned as `previous` by the following page
+        before: Query<Option<String>>,
+        /// Only applies to JSON, the other formats carry every reading
+        page_size: Query<Option<usize>>,
+        order: Query<Option<SortOrder>>,
+        columns: Query<Vec<String>>,
+        /// Takes precedence over the `Accept` header
+        format: Query<Option<ReadingsFormat>>,
+        #[oai(name = "Accept")] accept: Header<Option<String>>,
+    ) -> ReadingsResponse {
+        let Some(format) = ReadingsFormat::negotiate(format.0, accept.as_deref()) else {
+            return ReadingsResponse::not_acceptable();
+        };
+        match self.repository.fetch_sensor(&sensor_id).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return unknown_sensor::<ReadingsPage>(&sensor_id).into(),
+            Err(e) => return respond::<ReadingsPage>(Err(e)).into(),
+        }
+        let filters = QueryFilter {
+            sensor_id: Some(sensor_id.0),
+            metric: metric.0,
+            min: min.0,
+            max: max.0,
+            from: from.0,
+            to: to.0,
+            ..Default::default()
+        };
+        let pagination = pagination(after.0, before.0, page_size.0, order.0);
+        self.readings_as(format, filters, pagination, columns.0)
+            .await
+    }
+
+    /// Newest reading of a sensor
+    #[oai(method = "get", path = "/sensors/:sensor_id/latest")]
+    async fn sensor_latest(
+        &self,
+        _auth: ReadReadings,
+        sensor_id: Path<String>,
+    ) -> EnvironmentApiResponse<LatestReading> {
+        match self.repository.fetch_sensor(&sensor_id).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return unknown_sensor(&sensor_id),
+            Err(e) => return respond(Err(e)),
+        }
+        let (reading, catalogue) = match (
+            self.repository.fetch_latest_reading(&sensor_id).await,
+            self.repository.fetch_metrics().await,
+        ) {
+            (Ok(Some(reading)), Ok(catalogue)) => (reading, catalogue),
+            (Ok(None), _) => {
+                return EnvironmentApiResponse::not_found(format!(
+                    "Sensor {} has no stored readings",
+                    sensor_id.0
+                ));
+            }
+            (Err(e), _) | (_, Err(e)) => return respond(Err(e)),
+        };
+        let units = units(&catalogue, reading.metrics.keys().map(String::as_str));
+        respond(Ok(LatestReading { reading, units }))
+    }
+
+    /// Register a sensor or update its details. Sensors registered here are
+    /// approved right away.
+    #[oai(method = "put", path = "/sensors/:sensor_id")]
+    async fn put_sensor(
+        &self,
+        _auth: WriteConfig,
+        sensor_id: Path<String>,
+        metadata: PoemJson<SensorMetadata>,
+    ) -> EnvironmentApiResponse<Sensor> {
+        if let Err(e) = self.repository.upsert_sensor(&sensor_id, metadata.0).await {
+            return respond(Err(e));
+        }
+        self.sensor_or_not_found(&sensor_id).await
+    }
+
+    /// Forget a sensor and drop its quarantined readings. Stored readings are kept.
+    #[oai(method = "delete", path = "/sensors/:sensor_id")]
+    async fn delete_sensor(
+        &self,
+        _auth: WriteConfig,
+        sensor_id: Path<String>,
+    ) -> EnvironmentApiResponse<Vec<Sensor>> {
+        match self.repository.delete_sensor(&sensor_id).await {
+            Ok(true) => respond(self.repository.fetch_sensors().await),
+            Ok(false) => unknown_sensor(&sensor_id),
+            Err(e) => respond(Err(e)),
+        }
+    }
+
+    /// Accept a sensor and store the readings held in quarantine
+    #[oai(method = "post", path = "/sensors/:sensor_id/approve")]
+    async fn approve_sensor(
+        &self,
+        _auth: WriteConfig,
+        sensor_id: Path<String>,
+    ) -> EnvironmentApiResponse<Sensor> {
+        match self.repository.approve_sensor(&sensor_id).await {
+            Ok(Some(released)) => {
+                tracing::info!(
+                    "Approved sensor {}, released {released} readings",
+                    sensor_id.0
+                );
+                self.sensor_or_not_found(&sensor_id).await
+            }
+            Ok(None) => unknown_sensor(&sensor_id),
+            Err(e) => respond(Err(e)),
+        }
+    }
+
+    /// Metric series between two instants. Long spans are served from hourly
+    /// or daily rollups unless a resolution is requested.
+    #[oai(method = "get", path = "/series")]
+    async fn series(
+        &self,
+        _auth: ReadReadings,
+        metric: Query<String>,
+        from: Query<DateTime<Utc>>,
+        to: Query<DateTime<Utc>>,
+        sensor_id: Query<Option<String>>,
+        resolution: Query<Option<Resolution>>,
+    ) -> EnvironmentApiResponse<Series> {
+        let query = SeriesQuery {
+            sensor_id: sensor_id.0,
+            metric: metric.0,
+            from: from.0,
+            to: to.0,
+            resolution: resolution.0,
+        };
+        let _turn = match self.query_queue.turn().await {
+            Ok(turn) => turn,
+            Err(retry_after) => return EnvironmentApiResponse::too_many_requests(retry_after),
+        };
+        respond(self.repository.fetch_series(query).await)
+    }
+
+    /// Calibrations of every sensor, or only of `sensor_id`
+    #[oai(method = "get", path = "/calibrations")]
+    async fn calibrations(
+        &self,
+        _auth: ReadReadings,
+        sensor_id: Query<Option<String>>,
+    ) -> EnvironmentApiResponse<Vec<StoredCalibration>> {
+        respond(self.repository.fetch_calibrations(sensor_id.0).await)
+    }
+
+    /// Add a calibration, readings stored from now on are corrected with it.
+    /// Returns the calibrations of the sensor.
+    #[oai(method = "post", path = "/calibrations")]
+    async fn post_calibration(
+        &self,
+        _auth: WriteConfig,
+        calibration: PoemJson<Calibration>,
+    ) -> EnvironmentApiResponse<Vec<StoredCalibration>> {
+        let sensor_id = calibration.sensor_id.clone();
+        if let Err(e) = self.repository.insert_calibration(calibration.0).await {
+            return respond(Err(e));
+        }
+        respond(self.repository.fetch_calibrations(Some(sensor_id)).await)
+    }
+
+    #[oai(method = "delete", path = "/calibrations/:id")]
+    async fn delete_calibration(
+        &self,
+        _auth: WriteConfig,
+        id: Path<i64>,
+    ) -> EnvironmentApiResponse<Vec<StoredCalibration>> {
+        match self.repository.delete_calibration(id.0).await {
+            Ok(true) => respond(self.repository.fetch_calibrations(None).await),
+            Ok(false) => EnvironmentApiResponse::not_found(format!("Unknown calibration {}", id.0)),
+            Err(e) => respond(Err(e)),
+        }
+    }
+
+    /// Correct stored readings with the current calibrations. Returns the
+    /// number of values recomputed.
+    #[oai(method = "post", path = "/calibrations/recompute")]
+    async fn recompute_calibrations(
+        &self,
+        _auth: WriteConfig,
+        sensor_id: Query<Option<String>>,
+    ) -> EnvironmentApiResponse<u64> {
+        respond(self.repository.recompute_calibrations(sensor_id.0).await)
+    }
+
+    /// Export readings to Parquet or CSV files in the `name` directory of
+    /// the configured export directory
+    #[oai(method = "post", path = "/exports/:name")]
+    async fn export(
+        &self,
+        _auth: Admin,
+        name: Path<String>,
+        request: PoemJson<ExportRequest>,
+    ) -> EnvironmentApiResponse<ExportReport> {
+        let Some(export_directory) = &self.export_directory else {
+            return EnvironmentApiResponse::not_found("Exports are not configured");
+        };
+        // The name ends up in a path so keep it to a single plain component
+        let is_plain = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if name.is_empty() || !is_plain {
+            return respond(Err(BsError::InvalidQuery(format!(
+                "Invalid export name: {}",
+                name.0
+            ))));
+        }
+        let directory = export_directory.join(&name.0);
+        let _turn = match self.query_queue.turn().await {
+            Ok(turn) => turn,
+            Err(retry_after) => return EnvironmentApiResponse::too_many_requests(retry_after),
+        };
+        respond(export::export(&self.repository, request.0, &directory).await)
+    }
+
+    /// Import historical readings from an uploaded CSV or JSON Lines file.
+    /// Invalid rows are skipped and reported.
+    #[oai(method = "post", path = "/imports")]
+    async fn import(
+        &self,
+        _auth: Admin,
+        upload: ImportUpload,
+    ) -> EnvironmentApiResponse<ImportReport> {
+        let options = upload.options.map(|options| options.0).unwrap_or_default();
+        // The upload is spooled to a temporary file, read it a buffer at a
+        // time rather than holding years of readings in memory
+        let file = upload.file.into_file().into_std().await;
+        let _turn = match self.query_queue.turn().await {
+            Ok(turn) => turn,
+            Err(retry_after) => return EnvironmentApiResponse::too_many_requests(retry_after),
+        };
+        respond(import::import(&self.repository, BufReader::new(file), &options).await)
+    }
+
+    /// Retention policies currently in force
+    #[oai(method = "get", path = "/retention")]
+    async fn retention_policies(
+        &self,
+        _auth: ReadReadings,
+    ) -> EnvironmentApiResponse<Vec<RetentionPolicy>> {
+        respond(self.repository.fetch_retention_policies().await)
+    }
+
+    /// Create or replace the policy for a sensor and resolution
+    #[oai(method = "put", path = "/retention")]
+    async fn put_retention_policy(
+        &self,
+        _auth: WriteConfig,
+        policy: PoemJson<RetentionPolicy>,
+    ) -> EnvironmentApiResponse<Vec<RetentionPolicy>> {
+        if let Err(e) = self.repository.upsert_retention_policy(policy.0).await {
+            return respond(Err(e));
+        }
+        respond(self.repository.fetch_retention_policies().await)
+    }
+
+    /// Remove a policy, data of that scope falls back to the global policy
+    #[oai(method = "delete", path = "/retention")]
+    async fn delete_retention_policy(
+        &self,
+        _auth: WriteConfig,
+        resolution: Query<Resolution>,
+        sensor_id: Query<Option<String>>,
+    ) -> EnvironmentApiResponse<Vec<RetentionPolicy>> {
+        match self
+            .repository
+            .delete_retention_policy(sensor_id.0, resolution.0)
+            .await
+        {
+            Ok(true) => respond(self.repository.fetch_retention_policies().await),
+            Ok(false) => EnvironmentApiResponse::not_found("No such retention policy"),
+            Err(e) => respond(Err(e)),
+        }
+    }
+
+    /// What the next retention run would remove, without removing anything
+    #[oai(method = "get", path = "/retention/report")]
+    async fn retention_report(
+        &self,
+        _auth: ReadReadings,
+    ) -> EnvironmentApiResponse<RetentionReport> {
+        respond(self.repository.enforce_retention(true).await)
+    }
+
+    /// Every issued API key, revoked ones included. Secrets are never shown.
+    #[oai(method = "get", path = "/api-keys")]
+    async fn api_keys(&self, _auth: Admin) -> EnvironmentApiResponse<Vec<ApiKey>> {
+        respond(self.repository.fetch_api_keys().await)
+    }
+
+    /// Issue an API key. Its secret is part of this response only.
+    #[oai(method = "post", path = "/api-keys")]
+    async fn issue_api_key(
+        &self,
+        auth: Admin,
+        key: PoemJson<NewApiKey>,
+    ) -> EnvironmentApiResponse<IssuedApiKey> {
+        let issued = self.repository.issue_api_key(key.0).await;
+        if let Ok(issued) = &issued {
+            tracing::info!("API key {} issued API key {}", auth.0.id, issued.key.id);
+        }
+        respond(issued)
+    }
+
+    /// Refuse requests carrying an API key from now on
+    #[oai(method = "delete", path = "/api-keys/:id")]
+    async fn revoke_api_key(
+        &self,
+        auth: Admin,
+        id: Path<i64>,
+    ) -> EnvironmentApiResponse<Vec<ApiKey>> {
+        match self.repository.revoke_api_key(id.0).await {
+            Ok(true) => {
+                tracing::info!("API key {} revoked API key {}", auth.0.id, id.0);
+                respond(self.repository.fetch_api_keys().await)
+            }
+            Ok(false) => EnvironmentApiResponse::not_found(format!("Unknown API key {}", id.0)),
+            Err(e) => respond(Err(e)),
+        }
+    }
+}
+
+impl<R> EnvironmentApi<R>
+where
+    R: Repository + Clone + 'static,
+{
+    /// WebSocket endpoint pushing readings as they are stored, to be routed
+    /// next to the API
+    pub fn websocket(&self) -> impl poem::Endpoint + use<R> {
+        websocket::endpoint(self.repository.clone(), self.notifier.clone())
+    }
+
+    /// Checks the API keys of requests, to be added as data to the routes of
+    /// the API and the WebSocket
+    pub fn authenticator(&self) -> Authenticator {
+        Authenticator::new(self.repository.clone())
+    }
+
+    /// A page of JSON readings, or every matching reading streamed in one of
+    /// the other formats
+    async fn readings_as(
+        &self,
+        format: ReadingsFormat,
+        filters: QueryFilter,
+        pagination: Pagination,
+        columns: Vec<String>,
+    ) -> ReadingsResponse {
+        if format == ReadingsFormat::Json {
+            return self
+                .readings_page(filters, pagination, columns)
+                .await
+                .into();
+        }
+        let turn = match self.query_queue.turn().await {
+            Ok(turn) => turn,
+            Err(retry_after) => {
+                return EnvironmentApiResponse::<ReadingsPage>::too_many_requests(retry_after)
+                    .into();
+            }
+        };
+        let catalogue = match self.repository.fetch_metrics().await {
+            Ok(catalogue) => catalogue,
+            Err(e) => return respond::<ReadingsPage>(Err(e)).into(),
+        };
+        let query = MeasurementQuery {
+            filters,
+            pagination,
+            columns: requested_columns(columns, &catalogue),
+        };
+        formats::download(self.repository.clone(), format, query, turn).await
+    }
+
+    /// A page of readings with the units of the requested metrics
+    async fn readings_page(
+        &self,
+        filters: QueryFilter,
+        pagination: Pagination,
+        columns: Vec<String>,
+    ) -> EnvironmentApiResponse<ReadingsPage> {
+        let catalogue = match self.repository.fetch_metrics().await {
+            Ok(catalogue) => catalogue,
+            Err(e) => return respond(Err(e)),
+        };
+        let query = MeasurementQuery {
+            filters,
+            pagination,
+            columns: requested_columns(columns, &catalogue),
+        };
+        let units = units(&catalogue, query.metric_columns());
+        let page = self.repository.fetch_sensor_readings_page(query).await;
+        respond(page.map(|page| ReadingsPage {
+            rows: page.rows,
+            units,
+            next: page.next,
+            previous: page.previous,
+        }))
+    }
+
+    async fn sensor_or_not_found(&self, sensor_id: &str) -> EnvironmentApiResponse<Sensor> {
+        match self.repository.fetch_sensor(sensor_id).await {
+            Ok(Some(sensor)) => EnvironmentApiResponse::Ok(PoemJson(sensor)),
+            Ok(None) => unknown_sensor(sensor_id),
+            Err(e) => respond(Err(e)),
+        }
+    }
+}
+
+/// Values given repeated or separated by commas
+fn list(values: Vec<String>) -> Vec<String> {
+    values
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Columns given repeated or separated by commas. None stands for every
+/// reading column and metric.
+fn requested_columns(columns: Vec<String>, catalogue: &[Metric]) -> Vec<String> {
+    let columns = list(columns);
+    if !columns.is_empty() {
+        return columns;
+    }
+    MeasurementQuery::BASE_COLUMNS
+        .iter()
+        .map(|c| c.to_string())
+        .chain(catalogue.iter().map(|m| m.name.clone()))
+        .collect()
+}
+
+fn pagination(
+    after: Option<String>,
+    before: Option<String>,
+    page_size: Option<usize>,
+    order: Option<SortOrder>,
+) -> Pagination {
+    let mut pagination = Pagination {
+        after,
+        before,
+        order: order.unwrap_or_default(),
+        ..Default::default()
+    };
+    if let Some(page_size) = page_size {
+        pagination.page_size = page_size;
+    }
+    pagination
+}
+
+/// Units of the given metrics that have one in the catalogue
+fn units<'a>(
+    catalogue: &[Metric],
+    metrics: impl IntoIterator<Item = &'a str>,
+) -> BTreeMap<String, String> {
+    metrics
+        .into_iter()
+        .filter_map(|name| {
+            let metric = catalogue.iter().find(|m| m.name == name)?;
+            Some((metric.name.clone(), metric.unit.clone()?))
+        })
+        .collect()
+}
+
+fn unknown_sensor<T: ToJSON + Send>(sensor_id: &str) -> EnvironmentApiResponse<T> {
+    EnvironmentApiResponse::not_found(format!("Unknown sensor {sensor_id}"))
+}
+
+fn respond<T: ToJSON + Send>(result: Result<T, BsError>) -> EnvironmentApiResponse<T> {
+    match result {
+        Ok(data) => EnvironmentApiResponse::Ok(PoemJson(data)),
+        Err(BsError::InvalidQuery(e)) => {
+            tracing::debug!("Rejected query: {e}");
+            EnvironmentApiResponse::client_error(e)
+        }
+        Err(e) => {
+            tracing::error!("Request failed: {e}");
+            EnvironmentApiResponse::internal_server_error()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::{Stream, StreamExt};
+    use poem::http::StatusCode;
+    use poem::test::{TestClient, TestForm, TestFormField};
+    use poem::web::sse::Event;
+    use poem::{Endpoint, EndpointExt, Route};
+    use poem_openapi::OpenApiService;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::SensorReadingEvent;
+    use crate::db::{InMemoryRepository, Scope};
+
+    fn service(
+        repository: InMemoryRepository,
+        notifier: ReadingNotifier,
+    ) -> OpenApiService<EnvironmentApi<InMemoryRepository>, ()> {
+        let api = EnvironmentApi {
+            repository,
+            export_directory: None,
+            notifier,
+            query_queue: QueryQueue::new(2, Duration::from_secs(1)),
+        };
+        OpenApiService::new(api, "Environment Api", "1.0")
     }
 
+    /// The API routed as it is served, without a key
+    fn app(repository: InMemoryRepository, notifier: ReadingNotifier) -> impl Endpoint {
+        let authenticator = Authenticator::new(repository.clone());
+        Route::new()
+            .nest("/", service(repository, notifier))
+            .data(authenticator)
+    }
+
+    async fn issue(repository: &InMemoryRepository, scopes: &[Scope]) -> String {
+        let key = NewApiKey {
+            name: "tests".to_string(),
+            scopes: scopes.to_vec(),
+        };
+        repository.issue_api_key(key).await.unwrap().secret
+    }
+
+    /// Client sending an admin key with every request
+    async fn client_with(
+        repository: InMemoryRepository,
+        notifier: ReadingNotifier,
+    ) -> TestClient<impl Endpoint> {
+        let secret = issue(&repository, &[Scope::Admin]).await;
+        TestClient::new(app(repository, notifier))
+            .default_header("Authorization", format!("Bearer {secret}"))
+    }
+
+    async fn client(repository: InMemoryRepository) -> TestClient<impl Endpoint> {
+        client_with(repository, ReadingNotifier::new()).await
+    }
+
+    async fn insert(repo: &InMemoryRepository, minute: u32) {
+        let reading = SensorReadingEvent {
+            sensor_id: "attic".to_string(),
+            timestamp: format!("2026-01-01T10:{minute:02}:00Z").parse().unwrap(),
+            sequence: None,
+            metrics: [
+                ("temperature".to_string(), 20.0 + f64::from(minute)),
+                ("humidity".to_string(), 40.0),
+            ]
+            .into(),
+        };
+        repo.insert_sensor_reading("sensor/update".to_string(), reading)
+            .await
+            .unwrap();
+    }
+
+    async fn repository_with_readings() -> InMemoryRepository {
+        let repo = InMemoryRepository::new();
+        repo.upsert_sensor("attic", SensorMetadata::default())
+            .await
+            .unwrap();
+        for minute in 0..5 {
+            insert(&repo, minute).await;
+        }
+        repo
+    }
+
+    /// Id and data of the next event
+    async fn next_event(events: &mut (impl Stream<Item = Event> + Unpin)) -> (String, Value) {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await
+            .expect("No event within 5s")
+            .expect("Stream ended");
+        match event {
+            Event::Message { id, data, .. } => (id, serde_json::from_str(&data).unwrap()),
+            Event::Retry { .. } => panic!("Unexpected retry event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn readings_are_served_a_page_at_a_time() {
+        let client = client(repository_with_readings().await).await;
+
+        let response = client
+            .get("/v1/readings")
+            .query("sensor_id", &"attic")
+            .query("columns", &"timestamp,temperature")
+            .query("page_size", &3)
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let page: Value = response.json().await.value().deserialize();
+        assert_eq!(page["rows"].as_array().unwrap().len(), 3);
+        assert_eq!(page["rows"][0]["timestamp"], "2026-01-01T10:00:00+00:00");
+        assert_eq!(
+            page["rows"][0]["metrics"],
+            serde_json::json!({"temperature": 20.0})
+        );
+        assert_eq!(page["units"], serde_json::json!({"temperature": "°C"}));
+        let next = page["next"].as_str().unwrap();
+
+        let response = client
+            .get("/v1/readings")
+            .query("sensor_id", &"attic")
+            .query("columns", &"timestamp")
+            .query("columns", &"temperature")
+            .query("page_size", &3)
+            .query("after", &next)
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let page: Value = response.json().await.value().deserialize();
+        let temperatures: Vec<_> = page["rows"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row["metrics"]["temperature"].as_f64().unwrap())
+            .collect();
+        assert_eq!(temperatures, vec![23.0, 24.0]);
+        assert!(page["next"].is_null());
+        assert!(page["previous"].is_string());
+    }
+
+    #[tokio::test]
+    async fn readings_default_to_every_column() {
+        let client = client(repository_with_readings().await).await;
+
+        let response = client
+            .get("/v1/readings")
+            .query("order", &"desc")
+            .query("page_size", &1)
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let page: Value = response.json().await.value().deserialize();
+        let row = &page["rows"][0];
+        assert_eq!(row["sensor_id"], "attic");
+        assert_eq!(row["topic"], "sensor/update");
+        assert_eq!(row["timestamp"], "2026-01-01T10:04:00+00:00");
+        assert_eq!(
+            row["metrics"],
+            serde_json::json!({"humidity": 40.0, "temperature": 24.0})
+        );
+        assert_eq!(page["units"]["humidity"], "%");
+    }
+
+    #[tokio::test]
+    async fn sensor_resources_are_navigable() {
+        let repo = repository_with_readings().await;
+        repo.upsert_sensor("cellar", SensorMetadata::default())
+            .await
+            .unwrap();
+        let client = client(repo).await;
+
+        let response = client
+            .get("/v1/sensors")
+            .query("status", &"approved")
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let sensors: Value = response.json().await.value().deserialize();
+        assert_eq!(sensors.as_array().unwrap().len(), 2);
+
+        let response = client
+            .get("/v1/sensors/attic/readings")
+            .query("columns", &"temperature")
+            .query("metric", &"temperature")
+            .query("min", &22)
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let page: Value = response.json().await.value().deserialize();
+        assert_eq!(page["rows"].as_array().unwrap().len(), 3);
+
+        let response = client.get("/v1/sensors/attic/latest").send().await;
+        response.assert_status_is_ok();
+        let latest: Value = response.json().await.value().deserialize();
+        assert_eq!(latest["reading"]["timestamp"], "2026-01-01T10:04:00+00:00");
+        assert_eq!(latest["reading"]["metrics"]["temperature"], 24.0);
+        assert_eq!(
+            latest["units"],
+            serde_json::json!({"humidity": "%", "temperature": "°C"})
+        );
+
+        // Registered but without readings yet
+        let response = client.get("/v1/sensors/cellar/latest").send().await;
+        response.assert_status(StatusCode::NOT_FOUND);
+        response
+            .assert_json(serde_json::json!({"message": "Sensor cellar has no stored readings"}))
+            .await;
+        for path in ["/v1/sensors/garage/readings", "/v1/sensors/garage/latest"] {
+            let response = client.get(path).send().await;
+            response.assert_status(StatusCode::NOT_FOUND);
+            response
+                .assert_json(serde_json::json!({"message": "Unknown sensor garage"}))
+                .await;
+        }
+
+        let response = client.get("/v1/latest").send().await;
+        response.assert_status_is_ok();
+        let latest: Value = response.json().await.value().deserialize();
+        assert_eq!(latest["rows"].as_array().unwrap().len(), 1);
+        assert_eq!(latest["rows"][0]["sensor_id"], "attic");
+        assert_eq!(latest["units"]["temperature"], "°C");
+    }
+
+    #[tokio::test]
+    async fn stream_pushes_readings_as_they_are_stored() {
+        let repo = repository_with_readings().await;
+        let notifier = ReadingNotifier::new();
+        let client = client_with(repo.clone(), notifier.clone()).await;
+
+        let response = client
+            .get("/v1/stream")
+            .query("from", &"2026-01-01T10:03:00Z")
+            .query("columns", &"temperature")
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let mut events = response.sse_stream();
+        let (_, reading) = next_event(&mut events).await;
+        assert_eq!(reading["metrics"], serde_json::json!({"temperature": 23.0}));
+        assert_eq!(reading["timestamp"], "2026-01-01T10:03:00+00:00");
+        let (id, reading) = next_event(&mut events).await;
+        assert_eq!(reading["metrics"]["temperature"], 24.0);
+
+        insert(&repo, 5).await;
+        notifier.notify();
+        let (_, reading) = next_event(&mut events).await;
+        assert_eq!(reading["metrics"]["temperature"], 25.0);
+
+        // A reconnecting client picks up after the last event it received
+        let response = client
+            .get("/v1/stream")
+            .header("Last-Event-ID", id)
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let mut events = response.sse_stream();
+        let (_, reading) = next_event(&mut events).await;
+        assert_eq!(reading["metrics"]["temperature"], 25.0);
+        assert_eq!(reading["sensor_id"], "attic");
+    }
+
+    #[tokio::test]
+    async fn stream_pushes_readings_stored_late() {
+        let repo = repository_with_readings().await;
+        let notifier = ReadingNotifier::new();
+        let client = client_with(repo.clone(), notifier.clone()).await;
+
+        let response = client
+            .get("/v1/stream")
+            .query("columns", &"temperature")
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let mut events = response.sse_stream();
+        insert(&repo, 10).await;
+        // Taken before the reading stored right before it
+        insert(&repo, 7).await;
+        notifier.notify();
+        let (id, reading) = next_event(&mut events).await;
+        assert_eq!(reading["metrics"]["temperature"], 30.0);
+        let (_, reading) = next_event(&mut events).await;
+        assert_eq!(reading["metrics"]["temperature"], 27.0);
+
+        let response = client
+            .get("/v1/stream")
+            .header("Last-Event-ID", id)
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let mut events = response.sse_stream();
+        let (_, reading) = next_event(&mut events).await;
+        assert_eq!(reading["metrics"]["temperature"], 27.0);
+    }
+
+    #[tokio::test]
+    async fn browsers_open_streams_with_a_stream_token() {
+        let repo = repository_with_readings().await;
+        let notifier = ReadingNotifier::new();
+        let reader = format!("Bearer {}", issue(&repo, &[Scope::ReadReadings]).await);
+        let client = TestClient::new(app(repo.clone(), notifier.clone()));
+
+        let response = client
+            .post("/v1/stream-tokens")
+            .header("Authorization", &reader)
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let issued: Value = response.json().await.value().deserialize();
+        let token = issued["token"].as_str().unwrap().to_string();
+
+        let response = client
+            .get("/v1/stream")
+            .query("access_token", &token)
+            .query("columns", &"temperature")
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let mut events = response.sse_stream();
+        insert(&repo, 5).await;
+        insert(&repo, 6).await;
+        notifier.notify();
+        let (id, _) = next_event(&mut events).await;
+
+        // Opened again with a new token, the stream resumes from the query
+        let response = client
+            .get("/v1/stream")
+            .query("access_token", &token)
+            .query("last_event_id", &id)
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let mut events = response.sse_stream();
+        let (_, reading) = next_event(&mut events).await;
+        assert_eq!(reading["metrics"]["temperature"], 26.0);
+
+        // Another key id under the same signature
+        let forged = token.replacen("bst_AAAAAAAAAA", "bst_AAAAAAAAAB", 1);
+        assert_ne!(forged, token);
+        let response = client
+            .get("/v1/stream")
+            .query("access_token", &forged)
+            .send()
+            .await;
+        response.assert_status(StatusCode::UNAUTHORIZED);
+        // Tokens only stand in for keys on streams
+        let response = client
+            .get("/v1/latest")
+            .query("access_token", &token)
+            .send()
+            .await;
+        response.assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn stream_filters_are_checked_up_front() {
+        let client = client(repository_with_readings().await).await;
+
+        let response = client
+            .get("/v1/stream")
+            .header("Last-Event-ID", "yesterday")
+            .send()
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+        response
+            .assert_json(serde_json::json!({"message": "Invalid event id: yesterday"}))
+            .await;
+
+        let response = client
+            .get("/v1/stream")
+            .query("metric", &"wind_chill")
+            .send()
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn readings_are_served_in_the_requested_format() {
+        let client = client(repository_with_readings().await).await;
+
+        let response = client
+            .get("/v1/readings")
+            .query("format", &"csv")
+            .query("columns", &"timestamp,temperature")
+            .query("from", &"2026-01-01T10:03:00Z")
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        response.assert_content_type("text/csv; charset=utf-8");
+        response
+            .assert_text(
+                "timestamp,temperature\n2026-01-01T10:03:00Z,23\n2026-01-01T10:04:00Z,24\n",
+            )
+            .await;
+
+        let response = client
+            .get("/v1/sensors/attic/readings")
+            .header("Accept", "application/x-ndjson, application/json;q=0.5")
+            .query("columns", &"temperature")
+            .query("order", &"desc")
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        response.assert_content_type("application/x-ndjson");
+        let text = response.0.into_body().into_string().await.unwrap();
+        let temperatures: Vec<_> = text
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<Value>(line).unwrap()["metrics"]["temperature"]
+                    .as_f64()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(temperatures, vec![24.0, 23.0, 22.0, 21.0, 20.0]);
+
+        let response = client
+            .get("/v1/readings")
+            .header("Accept", "application/vnd.apache.arrow.stream")
+            .query("columns", &"sensor_id,timestamp,humidity")
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let bytes = response.0.into_body().into_vec().await.unwrap();
+        let batches: Vec<_> = arrow_ipc::reader::StreamReader::try_new(bytes.as_slice(), None)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let schema = batches[0].schema();
+        let names: Vec<_> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        assert_eq!(names, vec!["sensor_id", "timestamp", "humidity"]);
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 5);
+
+        let response = client
+            .get("/v1/readings")
+            .header("Accept", "application/xml")
+            .send()
+            .await;
+        response.assert_status(StatusCode::NOT_ACCEPTABLE);
+
+        let response = client
+            .get("/v1/readings")
+            .query("format", &"csv")
+            .query("columns", &"wind_chill")
+            .send()
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+        response
+            .assert_json(serde_json::json!({"message": "Invalid columns"}))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn downloads_carry_every_page() {
+        let repo = InMemoryRepository::new();
+        let start: DateTime<Utc> = "2026-01-01T00:00:00Z".parse().unwrap();
+        for second in 0..12_000 {
+            let reading = SensorReadingEvent {
+                sensor_id: "attic".to_string(),
+                timestamp: start + chrono::Duration::seconds(second),
+                sequence: None,
+                metrics: [("temperature".to_string(), second as f64)].into(),
+            };
+            repo.insert_sensor_reading("sensor/update".to_string(), reading)
+                .await
+                .unwrap();
+        }
+        let client = client(repo).await;
+
+        let response = client
+            .get("/v1/readings")
+            .query("format", &"csv")
+            .query("columns", &"temperature")
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let text = response.0.into_body().into_string().await.unwrap();
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some("temperature"));
+        let temperatures: Vec<u32> = lines.map(|line| line.parse().unwrap()).collect();
+        assert_eq!(temperatures, (0..12_000).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn readings_are_aggregated_into_buckets() {
+        let client = client(repository_with_readings().await).await;
+
+        let response = client
+            .get("/v1/aggregates")
+            .query("metrics", &"temperature")
+            .query("functions", &"min,max")
+            .query("bucket", &"2m")
+            .query("from", &"2026-01-01T09:58:00Z")
+            .query("to", &"2026-01-01T10:04:00Z")
+            .query("fill", &"previous")
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        response
+            .assert_json(serde_json::json!({"rows": [
+                {
+                    "timestamp": "2026-01-01T09:58:00+00:00",
+                    "metrics": {"temperature": {"min": null, "max": null}},
+                },
+                {
+                    "timestamp": "2026-01-01T10:00:00+00:00",
+                    "metrics": {"temperature": {"min": 20.0, "max": 21.0}},
+                },
+                {
+                    "timestamp": "2026-01-01T10:02:00+00:00",
+                    "metrics": {"temperature": {"min": 22.0, "max": 23.0}},
+                },
+            ]}))
+            .await;
+
+        let response = client
+            .get("/v1/aggregates")
+            .query("metrics", &"temperature")
+            .query("functions", &"p99")
+            .query("bucket", &"1h")
+            .query("from", &"2026-01-01T00:00:00Z")
+            .query("to", &"2026-01-02T00:00:00Z")
+            .send()
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+        response
+            .assert_json(serde_json::json!({"message": "Unknown aggregate function: p99"}))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn uploaded_files_are_imported() {
+        let repo = InMemoryRepository::new();
+        let client = client(repo.clone()).await;
+        let csv = "sensor_id,timestamp,t\n\
+                   attic,2026-01-01T10:00:00Z,21.5\n\
+                   attic,2026-01-01T11:00:00Z,warm\n";
+
+        let response = client
+            .post("/v1/imports")
+            .multipart(
+                TestForm::new().field(
+                    TestFormField::bytes(csv.as_bytes().to_vec())
+                        .name("file")
+                        .filename("readings.csv"),
+                ),
+            )
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let report: Value = response.json().await.value().deserialize();
+        assert_eq!(
+            (report["imported"].as_u64(), report["failed"].as_u64()),
+            (Some(1), Some(1))
+        );
+        assert!(repo.fetch_latest_reading("attic").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn invalid_queries_are_explained() {
+        let client = client(repository_with_readings().await).await;
+
+        let response = client
+            .get("/v1/readings")
+            .query("columns", &"wind_chill")
+            .send()
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+        response
+            .assert_json(serde_json::json!({"message": "Invalid columns"}))
+            .await;
+
+        let response = client
+            .get("/v1/readings")
+            .query("after", &"not-a-cursor")
+            .send()
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+        response
+            .assert_json(serde_json::json!({"message": "Invalid cursor"}))
+            .await;
+
+        let response = client
+            .get("/v1/readings")
+            .query("page_size", &1_000_000)
+            .send()
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+        response
+            .assert_json(serde_json::json!({
+                "message": "Invalid page size, at most 5000 readings fit on a page"
+            }))
+            .await;
+
+        let response = client
+            .get("/v1/aggregates")
+            .query("metrics", &"temperature")
+            .query("functions", &"avg")
+            .query("bucket", &"1d")
+            .query("from", &"2020-01-01T00:00:00Z")
+            .query("to", &"2026-01-01T00:00:00Z")
+            .send()
+            .await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+        response
+            .assert_json(serde_json::json!({"message": "Time span longer than 366 days"}))
+            .await;
+
+        let response = client.get("/v1/sensors/cellar").send().await;
+        response.assert_status(StatusCode::NOT_FOUND);
+        response
+            .assert_json(serde_json::json!({"message": "Unknown sensor cellar"}))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn requests_need_a_key_with_the_scope() {
+        let repo = repository_with_readings().await;
+        let reader = format!("Bearer {}", issue(&repo, &[Scope::ReadReadings]).await);
+        let admin = client(repo.clone()).await;
+        let client = TestClient::new(app(repo, ReadingNotifier::new()));
+
+        let response = client.get("/v1/latest").send().await;
+        response.assert_status(StatusCode::UNAUTHORIZED);
+        let response = client
+            .get("/v1/latest")
+            .header("Authorization", "Bearer bs_forged")
+            .send()
+            .await;
+        response.assert_status(StatusCode::UNAUTHORIZED);
+        response.assert_header("WWW-Authenticate", "Bearer");
+        response
+            .assert_json(serde_json::json!({"message": "Missing, unknown or revoked API key"}))
+            .await;
+
+        let response = client
+            .get("/v1/latest")
+            .header("Authorization", &reader)
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let response = client
+            .post("/v1/sensors/attic/approve")
+            .header("Authorization", &reader)
+            .send()
+            .await;
+        response.assert_status(StatusCode::FORBIDDEN);
+        response
+            .assert_json(serde_json::json!({"message": "API key 1 lacks the write:config scope"}))
+            .await;
+
+        // Keys are managed with an admin key, the secret is only shown once
+        let response = admin
+            .post("/v1/api-keys")
+            .body_json(&serde_json::json!({"name": "cron", "scopes": ["write:config"]}))
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let issued: Value = response.json().await.value().deserialize();
+        let writer = format!("Bearer {}", issued["secret"].as_str().unwrap());
+        let response = client
+            .post("/v1/sensors/attic/approve")
+            .header("Authorization", &writer)
+            .send()
+            .await;
+        response.assert_status_is_ok();
+
+        let response = admin.get("/v1/api-keys").send().await;
+        response.assert_status_is_ok();
+        let keys: Value = response.json().await.value().deserialize();
+        let names: Vec<_> = keys
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|k| k["name"].clone())
+            .collect();
+        assert_eq!(names, vec!["tests", "tests", "cron"]);
+        assert!(keys[2].get("secret").is_none());
+
+        let response = admin
+            .delete(format!("/v1/api-keys/{}", issued["id"]))
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        let response = client
+            .post("/v1/sensors/attic/approve")
+            .header("Authorization", &writer)
+            .send()
+            .await;
+        response.assert_status(StatusCode::UNAUTHORIZED);
+        let response = admin.delete("/v1/api-keys/99").send().await;
+        response.assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn busy_clients_are_told_to_retry() {
+        let repo = repository_with_readings().await;
+        let reader = format!("Bearer {}", issue(&repo, &[Scope::ReadReadings]).await);
+        let other = format!("Bearer {}", issue(&repo, &[Scope::ReadReadings]).await);
+        let api = EnvironmentApi {
+            repository: repo.clone(),
+            export_directory: None,
+            notifier: ReadingNotifier::new(),
+            query_queue: QueryQueue::new(1, Duration::from_millis(50)),
+        };
+        let client = TestClient::new(
+            Route::new()
+                .nest("/", OpenApiService::new(api, "Environment Api", "1.0"))
+                .with(limits::RateLimiter::new(2, 0))
+                .data(Authenticator::new(repo)),
+        );
+        let download = || {
+            client
+                .get("/v1/readings")
+                .query("format", &"csv")
+                .header("Authorization", &reader)
+        };
+
+        // A download keeps its turn until its body is sent
+        let first = download().send().await;
+        first.assert_status_is_ok();
+        let response = download().send().await;
+        response.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        response.assert_header("Retry-After", "1");
+        drop(first);
+
+        // Two requests a minute, so the next token comes in 30 seconds
+        let response = download().send().await;
+        response.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        response.assert_header("Retry-After", "30");
+        response
+            .assert_json(serde_json::json!({"message": "Too many requests, slow down"}))
+            .await;
+        let response = client
+            .get("/v1/latest")
+            .header("Authorization", &other)
+            .send()
+            .await;
+        response.assert_status_is_ok();
+    }
+
+    #[test]
+    fn readings_response_is_described() {
+        let spec: Value = serde_json::from_str(
+            &service(InMemoryRepository::new(), ReadingNotifier::new()).spec(),
+        )
+        .unwrap();
+
+        let readings = &spec["paths"]["/v1/readings"]["get"];
+        let parameters: Vec<_> = readings["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|parameter| parameter["name"].as_str().unwrap())
+            .collect();
+        assert!(parameters.contains(&"sensor_id"));
+        assert!(parameters.contains(&"columns"));
+        assert!(parameters.contains(&"after"));
+        assert!(parameters.contains(&"format"));
+        let content = &readings["responses"]["200"]["content"];
+        for media_type in [
+            "application/json; charset=utf-8",
+            "text/csv; charset=utf-8",
+            "application/x-ndjson",
+            "application/vnd.apache.arrow.stream",
+        ] {
+            assert!(content[media_type].is_object(), "{media_type} is missing");
+        }
+        let error = &readings["responses"]["400"]["content"]["application/json; charset=utf-8"];
+        assert_eq!(error["schema"]["$ref"], "#/components/schemas/ApiError");
+
+        assert_eq!(readings["security"], serde_json::json!([{"ApiKey": []}]));
+        let scheme = &spec["components"]["securitySchemes"]["ApiKey"];
+        assert_eq!(scheme["type"], "http");
+        assert_eq!(scheme["scheme"], "bearer");
+        let stream = &spec["paths"]["/v1/stream"]["get"];
+        assert_eq!(
+            stream["security"],
+            serde_json::json!([{"StreamToken": []}, {"ApiKey": []}])
+        );
+        let scheme = &spec["components"]["securitySchemes"]["StreamToken"];
+        assert_eq!(scheme["type"], "apiKey");
+        assert_eq!(scheme["in"], "query");
+        assert_eq!(scheme["name"], "access_token");
+
+        let page = &spec["components"]["schemas"]["ReadingsPage"]["properties"];
+        for property in ["rows", "units", "next", "previous"] {
+            assert!(page[property].is_object(), "{property} is missing");
+        }
+    }
 }
@@ -0,0 +1,254 @@
+//! Readings pushed to clients as server-sent events while they are stored.
+//!
+//! A stream follows the readings in the order they are stored, so a reading
+//! stored late with an older timestamp is pushed as well. Each stream queries
+//! the database itself whenever the ingestion reports stored readings, so a
+//! slow client only falls behind in the database and never holds up the MQTT
+//! read loop. The event id is the position in the stream, a client
+//! reconnecting with `Last-Event-ID` resumes right after the last reading it
+//! received.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures_util::stream::{self, BoxStream, StreamExt};
+use poem::web::sse::Event;
+use poem_openapi::payload::{EventStream, Json};
+use poem_openapi::types::ToJSON;
+use poem_openapi::{ApiResponse, Object};
+use tokio::sync::watch;
+
+use super::env_api_response::ApiError;
+use crate::db::{MeasurementQuery, Pagination, QueryFilter, Repository, SensorReading};
+use crate::error::BsError;
+
+/// Readings fetched per query
+const STREAM_BATCH: usize = 100;
+/// Comment lines sent while nothing is stored keep proxies from closing the stream
+const HEARTBEAT: Duration = Duration::from_secs(15);
+/// Wait before querying again after the database failed
+pub(super) const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A reading together with its event id
+#[derive(Debug, Object)]
+pub struct StreamedReading {
+    #[oai(skip)]
+    pub id: String,
+    #[oai(flatten)]
+    pub reading: SensorReading,
+}
+
+#[derive(ApiResponse)]
+pub enum StreamResponse {
+    #[oai(status = 200)]
+    Ok(EventStream<BoxStream<'static, StreamedReading>>),
+    /// The request is invalid, the body tells why
+    #[oai(status = 400)]
+    ClientError(Json<ApiError>),
+    /// The request failed on the base station, the cause is logged
+    #[oai(status = 500)]
+    InternalServerError(Json<ApiError>),
+}
+
+impl StreamResponse {
+    pub(super) fn error(e: BsError) -> Self {
+        match e {
+            BsError::InvalidQuery(message) => {
+                tracing::debug!("Rejected stream: {message}");
+                Self::ClientError(Json(ApiError { message }))
+            }
+            e => {
+                tracing::error!("Stream failed: {e}");
+                Self::InternalServerError(Json(ApiError {
+                    message: "Internal server error".to_string(),
+                }))
+            }
+        }
+    }
+}
+
+/// Where a stream stands: the position of the last reading sent in the order
+/// readings were stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) struct StreamPosition(i64);
+
+impl StreamPosition {
+    /// Before the first reading ever stored
+    pub(super) const START: Self = Self(0);
+
+    /// Past the readings stored so far, streams starting here only get new ones
+    pub(super) async fn latest(repository: &impl Repository) -> Result<Self, BsError> {
+        Ok(Self(repository.fetch_last_stored_position().await?))
+    }
+}
+
+impl fmt::Display for StreamPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for StreamPosition {
+    type Err = BsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<i64>()
+            .ok()
+            .filter(|position| *position >= 0)
+            .map(Self)
+            .ok_or_else(|| BsError::InvalidQuery(format!("Invalid event id: {s}")))
+    }
+}
+
+/// Fetches the readings past the position of a stream. It holds a single
+/// batch of readings at a time, readings a client has not taken yet wait in
+/// the database.
+pub(super) struct Follower<R> {
+    repository: R,
+    filters: QueryFilter,
+    columns: Vec<String>,
+    position: StreamPosition,
+    stored: watch::Receiver<u64>,
+    pending: VecDeque<StreamedReading>,
+    /// Whether the last query returned every reading there was
+    caught_up: bool,
+}
+
+impl<R: Repository> Follower<R> {
+    pub(super) fn new(
+        repository: R,
+        stored: watch::Receiver<u64>,
+        filters: QueryFilter,
+        mut columns: Vec<String>,
+        position: StreamPosition,
+    ) -> Self {
+        // Readings pushed live always tell when they were taken
+        if !columns.iter().any(|c| c == "timestamp") {
+            columns.push("timestamp".to_string());
+        }
+        Self {
+            repository,
+            filters,
+            columns,
+            position,
+            stored,
+            pending: VecDeque::new(),
+            caught_up: false,
+        }
+    }
+
+    /// Whether every reading stored so far has been fetched
+    pub(super) fn is_caught_up(&self) -> bool {
+        self.caught_up && self.pending.is_empty()
+    }
+
+    /// Follow the readings matching `filters` from the current position on
+    pub(super) fn set_filters(&mut self, filters: QueryFilter) {
+        self.filters = filters;
+    }
+
+    pub(super) fn pop(&mut self) -> Option<StreamedReading> {
+        self.pending.pop_front()
+    }
+
+    /// Wait until more readings are stored. False once the notifier is gone,
+    /// which only happens when the base station shuts down.
+    pub(super) async fn stored(&mut self) -> bool {
+        let stored = self.stored.changed().await.is_ok();
+        self.caught_up = false;
+        stored
+    }
+
+    /// Queue the next batch of readings past the position
+    pub(super) async fn fetch(&mut self) -> Result<(), BsError> {
+        // Readings stored from here on are picked up by the next query
+        self.stored.borrow_and_update();
+        let stored = self.repository.fetch_last_stored_position().await?;
+        let query = MeasurementQuery {
+            filters: self.filters.clone(),
+            pagination: Pagination {
+                page_size: STREAM_BATCH,
+                ..Default::default()
+            },
+            columns: self.columns.clone(),
+        };
+        let readings = self
+            .repository
+            .fetch_stored_readings(self.position.0, query)
+            .await?;
+        self.caught_up = readings.len() < STREAM_BATCH;
+        for (position, reading) in readings {
+            self.position = StreamPosition(position);
+            self.pending.push_back(StreamedReading {
+                id: self.position.to_string(),
+                reading,
+            });
+        }
+        // Every reading stored before the query has been seen, those the
+        // filters left out are not read again
+        if self.caught_up {
+            self.position = self.position.max(StreamPosition(stored));
+        }
+        Ok(())
+    }
+
+    async fn next(mut self) -> Option<(StreamedReading, Self)> {
+        loop {
+            if let Some(reading) = self.pop() {
+                return Some((reading, self));
+            }
+            if self.caught_up && !self.stored().await {
+                return None;
+            }
+            if let Err(e) = self.fetch().await {
+                tracing::warn!("Stream failed to fetch readings, retrying in {RETRY_DELAY:?}: {e}");
+                self.caught_up = false;
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Follow the readings matching `filters` from `position` on. The first
+/// query runs right away so invalid filters are reported as such.
+pub(super) async fn follow<R>(
+    repository: R,
+    stored: watch::Receiver<u64>,
+    filters: QueryFilter,
+    columns: Vec<String>,
+    position: StreamPosition,
+) -> StreamResponse
+where
+    R: Repository + 'static,
+{
+    let mut follower = Follower::new(repository, stored, filters, columns, position);
+    if let Err(e) = follower.fetch().await {
+        return StreamResponse::error(e);
+    }
+
+    let readings = stream::unfold(follower, Follower::next).boxed();
+    StreamResponse::Ok(
+        EventStream::new(readings)
+            .keep_alive(HEARTBEAT)
+            .to_event(|streamed| Event::message(streamed.reading.to_json_string()).id(streamed.id)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_round_trip() {
+        let position = StreamPosition(42);
+
+        let id = position.to_string();
+        assert_eq!(id, "42");
+        assert_eq!(id.parse::<StreamPosition>().unwrap(), position);
+        assert!("2026-01-01T10:00:00Z,1".parse::<StreamPosition>().is_err());
+        assert!("yesterday".parse::<StreamPosition>().is_err());
+        assert!("-1".parse::<StreamPosition>().is_err());
+    }
+}
@@ -0,0 +1,529 @@
+//! WebSocket for live readings whose subscriptions change without
+//! reconnecting.
+//!
+//! Clients send JSON messages tagged with a `type`:
+//!
+//! - `subscribe` and `unsubscribe` take `sensors` and `metrics`. A reading is
+//!   pushed when its sensor or one of its metrics is subscribed.
+//! - `latest` answers with the newest reading of `sensor_id`, or of every
+//!   sensor without one.
+//! - `ping` answers with a `pong`.
+//!
+//! The base station answers with `subscribed`, `reading`, `latest`, `pong` and
+//! `error` messages. Readings are pushed as the ingestion stores them, each
+//! connection following the database like `/v1/stream` does and reading only
+//! the readings its subscriptions match.
+//!
+//! The upgrade request carries an API key with the `read:readings` scope in
+//! its `Authorization` header, like requests to the API do. Browsers offer
+//! the subprotocols `bearer` and the key instead, or send a stream token as
+//! the `access_token` query parameter.
+
+use std::collections::BTreeSet;
+
+use futures_util::{SinkExt, StreamExt};
+use poem::web::websocket::{Message, WebSocket, WebSocketConfig, WebSocketStream};
+use poem::{Endpoint, FromRequest, IntoResponse, Request};
+use serde::{Deserialize, Serialize};
+
+use super::auth;
+use super::stream::{Follower, RETRY_DELAY, StreamPosition};
+use crate::db::{MeasurementQuery, QueryFilter, Repository, Scope, SensorReading, Subscriptions};
+use crate::error::BsError;
+use crate::live::ReadingNotifier;
+
+/// Largest message a client may send
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+/// Outgoing bytes a connection may hold before sending fails
+const MAX_WRITE_BUFFER: usize = 1024 * 1024;
+/// Sensors and metrics a connection may subscribe to
+const MAX_SUBSCRIPTIONS: usize = 256;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe {
+        #[serde(default)]
+        sensors: Vec<String>,
+        #[serde(default)]
+        metrics: Vec<String>,
+    },
+    Unsubscribe {
+        #[serde(default)]
+        sensors: Vec<String>,
+        #[serde(default)]
+        metrics: Vec<String>,
+    },
+    Latest {
+        sensor_id: Option<String>,
+    },
+    Ping,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Subscribed {
+        sensors: BTreeSet<String>,
+        metrics: BTreeSet<String>,
+    },
+    Reading {
+        reading: SensorReading,
+    },
+    Latest {
+        readings: Vec<SensorReading>,
+    },
+    Pong,
+    Error {
+        message: String,
+    },
+}
+
+fn subscribed(subscriptions: &Subscriptions) -> ServerMessage {
+    ServerMessage::Subscribed {
+        sensors: subscriptions.sensors.clone(),
+        metrics: subscriptions.metrics.clone(),
+    }
+}
+
+/// Upgrade requests to connections pushing the readings `repository` stores
+pub(super) fn endpoint<R>(repository: R, notifier: ReadingNotifier) -> impl Endpoint
+where
+    R: Repository + Clone + 'static,
+{
+    poem::endpoint::make(move |request: Request| {
+        let repository = repository.clone();
+        let notifier = notifier.clone();
+        async move {
+            if let Some(token) = auth::access_token(&request) {
+                auth::authorize_token(&request, &token).await?;
+            } else {
+                let secret = auth::bearer(&request)
+                    .or_else(|| auth::protocol_bearer(&request))
+                    .ok_or_else(auth::unauthorized)?;
+                auth::authorize(&request, secret, Scope::ReadReadings).await?;
+            }
+            let config = WebSocketConfig::default()
+                .max_message_size(Some(MAX_MESSAGE_SIZE))
+                .max_write_buffer_size(MAX_WRITE_BUFFER);
+            let websocket = WebSocket::from_request_without_body(&request).await?;
+            let response = websocket
+                .protocols([auth::BEARER_PROTOCOL])
+                .config(config)
+                .on_upgrade(move |socket| serve(socket, repository, notifier))
+                .into_response();
+            Ok::<_, poem::Error>(response)
+        }
+    })
+}
+
+async fn serve<R: Repository + Clone>(
+    socket: WebSocketStream,
+    repository: R,
+    notifier: ReadingNotifier,
+) {
+    let (mut sink, mut incoming) = socket.split();
+    let mut connection = match Connection::open(repository, &notifier).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            tracing::error!("Failed to open live connection: {e}");
+            let _ = sink.send(Message::close()).await;
+            return;
+        }
+    };
+
+    loop {
+        let replies = tokio::select! {
+            message = incoming.next() => match message {
+                Some(Ok(Message::Text(text))) => vec![connection.handle(&text).await],
+                Some(Ok(Message::Binary(_))) => vec![ServerMessage::Error {
+                    message: "Messages are JSON text".to_string(),
+                }],
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    tracing::debug!("Live connection failed: {e}");
+                    break;
+                }
+            },
+            stored = connection.follower.stored(), if connection.follower.is_caught_up() => {
+                if !stored {
+                    break;
+                }
+                continue;
+            }
+            _ = std::future::ready(()), if !connection.follower.is_caught_up() => {
+                connection.readings().await
+            }
+        };
+        for reply in replies {
+            let text = serde_json::to_string(&reply).expect("Messages are always serializable");
+            if sink.send(Message::Text(text)).await.is_err() {
+                return;
+            }
+        }
+    }
+    let _ = sink.close().await;
+}
+
+/// Readings pushed for `subscriptions`
+fn follow(subscriptions: &Subscriptions) -> QueryFilter {
+    QueryFilter {
+        subscriptions: Some(subscriptions.clone()),
+        ..Default::default()
+    }
+}
+
+struct Connection<R> {
+    repository: R,
+    subscriptions: Subscriptions,
+    follower: Follower<R>,
+}
+
+impl<R: Repository + Clone> Connection<R> {
+    /// Follow the readings stored from now on, none until something is
+    /// subscribed
+    async fn open(repository: R, notifier: &ReadingNotifier) -> Result<Self, BsError> {
+        let catalogue = repository.fetch_metrics().await?;
+        let columns = MeasurementQuery::BASE_COLUMNS
+            .iter()
+            .map(|c| c.to_string())
+            .chain(catalogue.into_iter().map(|m| m.name))
+            .collect();
+        let subscriptions = Subscriptions::default();
+        let follower = Follower::new(
+            repository.clone(),
+            notifier.subscribe(),
+            follow(&subscriptions),
+            columns,
+            StreamPosition::latest(&repository).await?,
+        );
+        Ok(Self {
+            repository,
+            subscriptions,
+            follower,
+        })
+    }
+
+    fn set_subscriptions(&mut self, subscriptions: Subscriptions) -> ServerMessage {
+        self.follower.set_filters(follow(&subscriptions));
+        self.subscriptions = subscriptions;
+        subscribed(&self.subscriptions)
+    }
+
+    async fn handle(&mut self, text: &str) -> ServerMessage {
+        let message = match serde_json::from_str(text) {
+            Ok(message) => message,
+            Err(e) => {
+                return ServerMessage::Error {
+                    message: format!("Invalid message: {e}"),
+                };
+            }
+        };
+        match message {
+            ClientMessage::Subscribe { sensors, metrics } => {
+                let subscriptions = Subscriptions {
+                    sensors: self
+                        .subscriptions
+                        .sensors
+                        .iter()
+                        .cloned()
+                        .chain(sensors)
+                        .collect(),
+                    metrics: self
+                        .subscriptions
+                        .metrics
+                        .iter()
+                        .cloned()
+                        .chain(metrics)
+                        .collect(),
+                };
+                if subscriptions.len() > MAX_SUBSCRIPTIONS {
+                    return ServerMessage::Error {
+                        message: format!("At most {MAX_SUBSCRIPTIONS} subscriptions"),
+                    };
+                }
+                self.set_subscriptions(subscriptions)
+            }
+            ClientMessage::Unsubscribe { sensors, metrics } => {
+                let mut subscriptions = self.subscriptions.clone();
+                for sensor in sensors {
+                    subscriptions.sensors.remove(&sensor);
+                }
+                for metric in metrics {
+                    subscriptions.metrics.remove(&metric);
+                }
+                self.set_subscriptions(subscriptions)
+            }
+            ClientMessage::Latest { sensor_id } => {
+                let latest = match sensor_id {
+                    Some(sensor_id) => self
+                        .repository
+                        .fetch_latest_reading(&sensor_id)
+                        .await
+                        .map(|reading| reading.into_iter().collect()),
+                    None => self.repository.fetch_latest_readings().await,
+                };
+                match latest {
+                    Ok(readings) => ServerMessage::Latest { readings },
+                    Err(e) => {
+                        tracing::error!("Live connection failed to fetch latest readings: {e}");
+                        ServerMessage::Error {
+                            message: "Failed to fetch the latest readings".to_string(),
+                        }
+                    }
+                }
+            }
+            ClientMessage::Ping => ServerMessage::Pong,
+        }
+    }
+
+    /// The next batch of stored readings, the subscriptions filter them
+    async fn readings(&mut self) -> Vec<ServerMessage> {
+        if let Err(e) = self.follower.fetch().await {
+            tracing::warn!(
+                "Live connection failed to fetch readings, retrying in {RETRY_DELAY:?}: {e}"
+            );
+            tokio::time::sleep(RETRY_DELAY).await;
+            return Vec::new();
+        }
+        let mut readings = Vec::new();
+        while let Some(streamed) = self.follower.pop() {
+            readings.push(ServerMessage::Reading {
+                reading: streamed.reading,
+            });
+        }
+        readings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeDelta, Utc};
+    use poem::listener::{Acceptor, Listener, TcpListener};
+    use poem::{EndpointExt, Route};
+    use tokio_tungstenite::tungstenite;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    use super::*;
+    use crate::SensorReadingEvent;
+    use crate::api::auth::Authenticator;
+    use crate::db::{InMemoryRepository, NewApiKey};
+
+    async fn insert(repo: &InMemoryRepository, sensor_id: &str, metric: &str, value: f64) {
+        let reading = SensorReadingEvent {
+            sensor_id: sensor_id.to_string(),
+            timestamp: Utc::now() + TimeDelta::seconds(1),
+            sequence: None,
+            metrics: [(metric.to_string(), value)].into(),
+        };
+        repo.insert_sensor_reading("sensor/update".to_string(), reading)
+            .await
+            .unwrap();
+    }
+
+    fn pushed(messages: Vec<ServerMessage>) -> Vec<(String, f64)> {
+        messages
+            .into_iter()
+            .map(|message| match message {
+                ServerMessage::Reading { reading } => {
+                    let (metric, value) = reading.metrics.into_iter().next().unwrap();
+                    (format!("{}/{metric}", reading.sensor_id.unwrap()), value)
+                }
+                other => panic!("Unexpected message {other:?}"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn subscriptions_pick_the_pushed_readings() {
+        let repo = InMemoryRepository::new();
+        let notifier = ReadingNotifier::new();
+        let mut connection = Connection::open(repo.clone(), &notifier).await.unwrap();
+
+        let reply = connection
+            .handle(r#"{"type":"subscribe","sensors":["attic"],"metrics":["co2"]}"#)
+            .await;
+        assert_eq!(
+            reply,
+            ServerMessage::Subscribed {
+                sensors: ["attic".to_string()].into(),
+                metrics: ["co2".to_string()].into(),
+            }
+        );
+
+        insert(&repo, "attic", "temperature", 21.0).await;
+        insert(&repo, "cellar", "temperature", 12.0).await;
+        insert(&repo, "office", "co2", 900.0).await;
+        notifier.notify();
+        assert!(connection.follower.stored().await);
+        assert_eq!(
+            pushed(connection.readings().await),
+            vec![
+                ("attic/temperature".to_string(), 21.0),
+                ("office/co2".to_string(), 900.0),
+            ]
+        );
+        assert!(connection.follower.is_caught_up());
+
+        connection
+            .handle(r#"{"type":"unsubscribe","sensors":["attic"]}"#)
+            .await;
+        insert(&repo, "attic", "temperature", 22.0).await;
+        insert(&repo, "office", "co2", 950.0).await;
+        assert_eq!(
+            pushed(connection.readings().await),
+            vec![("office/co2".to_string(), 950.0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_subscribed_readings_are_fetched() {
+        let repo = InMemoryRepository::new();
+        let notifier = ReadingNotifier::new();
+        let mut connection = Connection::open(repo.clone(), &notifier).await.unwrap();
+        connection
+            .handle(r#"{"type":"subscribe","metrics":["co2"]}"#)
+            .await;
+
+        // More than a batch of readings nobody subscribed to
+        for i in 0..150 {
+            insert(&repo, &format!("sensor-{i}"), "temperature", 20.0).await;
+        }
+        insert(&repo, "office", "co2", 900.0).await;
+        assert_eq!(
+            pushed(connection.readings().await),
+            vec![("office/co2".to_string(), 900.0)]
+        );
+        assert!(connection.follower.is_caught_up());
+
+        // Readings stored while unsubscribed are not pushed later on
+        connection
+            .handle(r#"{"type":"unsubscribe","metrics":["co2"]}"#)
+            .await;
+        insert(&repo, "office", "co2", 950.0).await;
+        assert_eq!(pushed(connection.readings().await), vec![]);
+        connection
+            .handle(r#"{"type":"subscribe","sensors":["office"]}"#)
+            .await;
+        insert(&repo, "office", "co2", 1000.0).await;
+        assert_eq!(
+            pushed(connection.readings().await),
+            vec![("office/co2".to_string(), 1000.0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_are_answered() {
+        let repo = InMemoryRepository::new();
+        repo.upsert_sensor("attic", Default::default())
+            .await
+            .unwrap();
+        insert(&repo, "attic", "temperature", 21.0).await;
+        let mut connection = Connection::open(repo, &ReadingNotifier::new())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            connection.handle(r#"{"type":"ping"}"#).await,
+            ServerMessage::Pong
+        );
+        let ServerMessage::Latest { readings } = connection.handle(r#"{"type":"latest"}"#).await
+        else {
+            panic!("Expected the latest readings");
+        };
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].metrics["temperature"], 21.0);
+        let reply = connection
+            .handle(r#"{"type":"latest","sensor_id":"cellar"}"#)
+            .await;
+        assert_eq!(reply, ServerMessage::Latest { readings: vec![] });
+
+        let reply = connection.handle(r#"{"type":"shout"}"#).await;
+        assert!(matches!(reply, ServerMessage::Error { .. }));
+        let sensors: Vec<_> = (0..=MAX_SUBSCRIPTIONS)
+            .map(|i| format!("sensor-{i}"))
+            .collect();
+        let subscribe = serde_json::json!({"type": "subscribe", "sensors": sensors});
+        let reply = connection.handle(&subscribe.to_string()).await;
+        assert!(matches!(reply, ServerMessage::Error { .. }));
+        assert!(connection.subscriptions.sensors.is_empty());
+    }
+
+    async fn receive(
+        socket: &mut (
+                 impl futures_util::Stream<Item = tungstenite::Result<tungstenite::Message>> + Unpin
+             ),
+    ) -> serde_json::Value {
+        let message = tokio::time::timeout(std::time::Duration::from_secs(5), socket.next())
+            .await
+            .expect("No message within 5s")
+            .unwrap()
+            .unwrap();
+        serde_json::from_str(message.to_text().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn readings_are_pushed_over_the_socket() {
+        let repo = InMemoryRepository::new();
+        let notifier = ReadingNotifier::new();
+        let secret = repo
+            .issue_api_key(NewApiKey {
+                name: "dashboard".to_string(),
+                scopes: vec![Scope::ReadReadings],
+            })
+            .await
+            .unwrap()
+            .secret;
+        let app = Route::new()
+            .at(
+                "/v1/live",
+                poem::get(endpoint(repo.clone(), notifier.clone())),
+            )
+            .data(Authenticator::new(repo.clone()));
+        let acceptor = TcpListener::bind("127.0.0.1:0")
+            .into_acceptor()
+            .await
+            .unwrap();
+        let address = acceptor.local_addr()[0].as_socket_addr().copied().unwrap();
+        let server = tokio::spawn(poem::Server::new_with_acceptor(acceptor).run(app));
+
+        let url = format!("ws://{address}/v1/live");
+        let refused = tokio_tungstenite::connect_async(&url).await.unwrap_err();
+        assert!(matches!(
+            refused,
+            tungstenite::Error::Http(response) if response.status() == 401
+        ));
+
+        let mut request = url.as_str().into_client_request().unwrap();
+        let authorization = format!("Bearer {secret}").parse().unwrap();
+        request.headers_mut().insert("Authorization", authorization);
+        let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        socket.close(None).await.unwrap();
+
+        // Browsers offer the key as a subprotocol, the first one is picked
+        let mut request = url.as_str().into_client_request().unwrap();
+        let protocols = format!("bearer, {secret}").parse().unwrap();
+        request
+            .headers_mut()
+            .insert("Sec-WebSocket-Protocol", protocols);
+        let (mut socket, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        assert_eq!(response.headers()["Sec-WebSocket-Protocol"], "bearer");
+        // The reply tells the subscription is in place
+        let subscribe = r#"{"type":"subscribe","sensors":["attic"]}"#;
+        socket
+            .send(tungstenite::Message::text(subscribe))
+            .await
+            .unwrap();
+        assert_eq!(receive(&mut socket).await["type"], "subscribed");
+
+        insert(&repo, "attic", "temperature", 21.5).await;
+        notifier.notify();
+        let message = receive(&mut socket).await;
+        assert_eq!(message["type"], "reading");
+        assert_eq!(message["reading"]["sensor_id"], "attic");
+        assert_eq!(message["reading"]["metrics"]["temperature"], 21.5);
+
+        server.abort();
+    }
+}
@@ -1,14 +1,247 @@
-use base_station::{api::EnvironmentApi, db::SqliteRepository, error::BsError, mqtt::MqttClient};
-use poem::{Route, Server, listener::TcpListener};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base_station::api::EnvironmentApi;
+use base_station::api::limits::{QueryQueue, RateLimiter};
+use base_station::db::schema::{self, POSTGRES_MIGRATOR, SQLITE_MIGRATOR};
+use base_station::db::{
+    ColumnarRepository, ConflictPolicy, CursorKey, InMemoryRepository, NewApiKey, PartitionPeriod,
+    PartitionedSqliteRepository, PostgresRepository, QueryFilter, Repository, Scope,
+    SqliteRepository, spawn_retention_task,
+};
+use base_station::error::BsError;
+use base_station::export::{self, ExportFormat, ExportRequest, Partitioning};
+use base_station::import::{self, ImportFormat, ImportOptions};
+use base_station::live::ReadingNotifier;
+use base_station::mqtt::{IngestJournal, MqttClient, RawArchive, archive, spawn_journal_task};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use poem::listener::TcpListener;
+use poem::{EndpointExt, Route, Server};
 use poem_openapi::OpenApiService;
-use sqlx::SqlitePool;
-use tracing::info;
+use sqlx::migrate::{Migrate, Migration, Migrator};
+use sqlx::{PgPool, SqlitePool};
+use tracing::{info, warn};
 use tracing_appender::rolling;
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
-use tracing_subscriber::{fmt, layer::{Layer, SubscriberExt}, EnvFilter, Registry};
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::{EnvFilter, Registry, fmt};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Collects sensor readings and serves them over HTTP")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Ingest readings from the broker and serve the API (default)
+    Serve,
+    /// Maintain the hourly and daily rollups
+    Rollups {
+        #[command(subcommand)]
+        action: RollupCommand,
+    },
+    /// Apply the data retention policies
+    Retention {
+        #[command(subcommand)]
+        action: RetentionCommand,
+    },
+    /// Find readings stored more than once
+    Duplicates {
+        #[command(subcommand)]
+        action: DuplicateCommand,
+    },
+    /// Manage sensor calibrations
+    Calibrations {
+        #[command(subcommand)]
+        action: CalibrationCommand,
+    },
+    /// Issue, list and revoke API keys
+    ApiKeys {
+        #[command(subcommand)]
+        action: ApiKeyCommand,
+    },
+    /// Write readings to Parquet or CSV files
+    Export {
+        /// Directory the files are written to
+        #[arg(long)]
+        output: PathBuf,
+        #[arg(long, default_value = "parquet")]
+        format: ExportFormat,
+        /// Split the files by the day or month readings were taken
+        #[arg(long, default_value = "none")]
+        partitioning: Partitioning,
+        /// Only export readings taken since the previous incremental export
+        #[arg(long)]
+        incremental: bool,
+        /// Metrics to export, defaults to the whole catalogue
+        #[arg(long, value_delimiter = ',')]
+        metrics: Vec<String>,
+        #[arg(long)]
+        sensor_id: Option<String>,
+        /// Metric the `--min` and `--max` bounds apply to
+        #[arg(long)]
+        filter_metric: Option<String>,
+        #[arg(long)]
+        min: Option<f64>,
+        #[arg(long)]
+        max: Option<f64>,
+        /// Readings taken at or after this instant
+        #[arg(long)]
+        from: Option<DateTime<Utc>>,
+        /// Readings taken before this instant, defaults to now
+        #[arg(long)]
+        to: Option<DateTime<Utc>>,
+    },
+    /// Inspect and change the database schema version
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateCommand,
+    },
+    /// Ingest archived MQTT messages again
+    Replay {
+        /// Directory of the archive, defaults to RAW_ARCHIVE_DIRECTORY
+        #[arg(long)]
+        archive: Option<PathBuf>,
+        /// Messages received at or after this instant
+        #[arg(long)]
+        from: Option<DateTime<Utc>>,
+        /// Messages received before this instant
+        #[arg(long)]
+        to: Option<DateTime<Utc>>,
+        /// Database to replay into instead of DATABASE_URL, e.g. a scratch
+        /// sqlite:///tmp/replay.db?mode=rwc
+        #[arg(long)]
+        database_url: Option<String>,
+    },
+    /// Import historical readings from a CSV or JSON Lines file
+    Import {
+        file: PathBuf,
+        #[arg(long, default_value = "csv")]
+        format: ImportFormat,
+        #[arg(long, default_value = "sensor_id")]
+        sensor_id_column: String,
+        /// Sensor of rows without a sensor id column
+        #[arg(long)]
+        sensor_id: Option<String>,
+        #[arg(long, default_value = "timestamp")]
+        timestamp_column: String,
+        /// strftime layout of the timestamps
+        #[arg(long)]
+        timestamp_format: Option<String>,
+        /// IANA time zone of timestamps without an offset, defaults to UTC
+        #[arg(long)]
+        timezone: Option<String>,
+        /// COLUMN=METRIC, every column but the sensor id and timestamp is a
+        /// metric when not given
+        #[arg(long = "metric", value_parser = key_value)]
+        metrics: Vec<(String, String)>,
+        /// METRIC=UNIT the file uses, converted to the unit of the catalogue
+        #[arg(long = "unit", value_parser = key_value)]
+        units: Vec<(String, String)>,
+        #[arg(long, default_value = "import")]
+        topic: String,
+    },
+}
+
+fn key_value(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("Expected KEY=VALUE, got {arg}"))
+}
+
+#[derive(Debug, Subcommand)]
+enum RollupCommand {
+    /// Recompute rollups from the raw readings
+    Rebuild {
+        /// Defaults to the oldest raw reading
+        #[arg(long)]
+        from: Option<DateTime<Utc>>,
+        /// Defaults to now
+        #[arg(long)]
+        to: Option<DateTime<Utc>>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum RetentionCommand {
+    /// Remove data past its retention and print what was removed
+    Run {
+        /// Only report what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum DuplicateCommand {
+    /// Keep the first stored copy of every reading and rebuild the affected rollups
+    Remove {
+        /// Only count the duplicates
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum CalibrationCommand {
+    /// Recompute stored values from the raw readings with the current calibrations
+    Recompute {
+        /// Defaults to every sensor
+        #[arg(long)]
+        sensor_id: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ApiKeyCommand {
+    /// Issue a key and print it together with its secret, which is not shown again
+    Issue {
+        /// Who or what uses the key
+        #[arg(long)]
+        name: String,
+        /// read:readings, write:config or admin, repeated or separated by commas
+        #[arg(long = "scope", value_delimiter = ',', required = true)]
+        scopes: Vec<Scope>,
+    },
+    /// List the issued keys, revoked ones included
+    List,
+    /// Refuse requests carrying a key from now on
+    Revoke { id: i64 },
+}
+
+#[derive(Debug, Subcommand)]
+enum MigrateCommand {
+    /// List the known and applied migrations
+    Status,
+    /// Apply pending migrations
+    Up {
+        /// Version to stop at, defaults to the newest
+        #[arg(long)]
+        to: Option<i64>,
+        /// Only print the SQL that would run
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Revert the migrations newer than a version
+    Down {
+        /// Version to go back to, 0 reverts every migration
+        #[arg(long)]
+        to: i64,
+        /// Only print the SQL that would run
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<(), BsError> {
+    let cli = Cli::parse();
     dotenvy::from_filename("../.env")?;
 
     let log_directory = dotenvy::var("LOG_DIRECTORY")?;
@@ -17,29 +250,392 @@ async fn main() -> Result<(), BsError> {
     let console_layer = fmt::layer()
         .with_target(true)
         .with_thread_names(true)
-        .with_filter(
-            EnvFilter::try_from_default_env()
-            .unwrap_or_else(|_| EnvFilter::new("debug"))
-    );
+        .with_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("debug")));
     let subscriber = Registry::default()
         .with(JsonStorageLayer)
         .with(formatting_layer)
         .with(console_layer);
 
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("Failed to set subscriber");
+    tracing::subscriber::set_global_default(subscriber).expect("Failed to set subscriber");
+
+    let command = cli.command.unwrap_or(Command::Serve);
+    let cursor_key = dotenvy::var("CURSOR_SECRET").ok().map(CursorKey::new);
+    let conflict_policy: ConflictPolicy = match dotenvy::var("DUPLICATE_POLICY") {
+        Ok(policy) => policy.parse()?,
+        Err(_) => ConflictPolicy::default(),
+    };
+
+    // The backend is picked by the scheme of the database URL
+    let database_url = match &command {
+        Command::Replay {
+            database_url: Some(database_url),
+            ..
+        } => database_url.clone(),
+        _ => dotenvy::var("DATABASE_URL")?,
+    };
+    if database_url.starts_with("postgres:") || database_url.starts_with("postgresql:") {
+        let db_pool = PgPool::connect(&database_url).await?;
+        let mut conn = db_pool.acquire().await?;
+        if let Command::Migrate { action } = command {
+            return migrate(&POSTGRES_MIGRATOR, &mut *conn, action).await;
+        }
+        schema::migrate_up(&POSTGRES_MIGRATOR, &mut *conn, None).await?;
+        drop(conn);
+
+        let mut repository = PostgresRepository::new(db_pool).with_conflict_policy(conflict_policy);
+        if let Some(cursor_key) = cursor_key {
+            repository = repository.with_cursor_key(cursor_key);
+        }
+        run(command, repository).await
+    } else if database_url.starts_with("sqlite:") {
+        let db_pool = SqlitePool::connect(&database_url).await?;
+        let mut conn = db_pool.acquire().await?;
+        if let Command::Migrate { action } = command {
+            return migrate(&SQLITE_MIGRATOR, &mut *conn, action).await;
+        }
+        schema::migrate_up(&SQLITE_MIGRATOR, &mut *conn, None).await?;
+        drop(conn);
+
+        // Readings are partitioned by period or compressed into chunks once a
+        // directory is configured, a database given on the command line is
+        // never split up
+        let (partition_directory, chunk_directory) = match &command {
+            Command::Replay {
+                database_url: Some(_),
+                ..
+            } => (None, None),
+            _ => (
+                dotenvy::var("SQLITE_PARTITION_DIRECTORY").ok(),
+                dotenvy::var("SQLITE_CHUNK_DIRECTORY").ok(),
+            ),
+        };
+        if partition_directory.is_some() && chunk_directory.is_some() {
+            return Err(BsError::Other(
+                "Only one of SQLITE_PARTITION_DIRECTORY and SQLITE_CHUNK_DIRECTORY can be set"
+                    .to_string(),
+            ));
+        }
+        if let Some(chunk_directory) = chunk_directory {
+            info!("Compressing readings into chunks in {chunk_directory}");
+            let mut repository = ColumnarRepository::open(db_pool, chunk_directory)
+                .await?
+                .with_conflict_policy(conflict_policy);
+            if let Some(cursor_key) = cursor_key {
+                repository = repository.with_cursor_key(cursor_key);
+            }
+            return run(command, repository).await;
+        }
+        if let Some(partition_directory) = partition_directory {
+            let period: PartitionPeriod = match dotenvy::var("SQLITE_PARTITION_PERIOD") {
+                Ok(period) => period.parse()?,
+                Err(_) => PartitionPeriod::default(),
+            };
+            info!(
+                "Partitioning readings by {} in {partition_directory}",
+                period.as_str()
+            );
+            let mut repository =
+                PartitionedSqliteRepository::open(db_pool, partition_directory, period)
+                    .await?
+                    .with_conflict_policy(conflict_policy);
+            if let Some(cursor_key) = cursor_key {
+                repository = repository.with_cursor_key(cursor_key);
+            }
+            return run(command, repository).await;
+        }
+
+        let mut repository = SqliteRepository::new(db_pool).with_conflict_policy(conflict_policy);
+        if let Some(cursor_key) = cursor_key {
+            repository = repository.with_cursor_key(cursor_key);
+        }
+        if let Command::Serve = command {
+            repository.ensure_incremental_vacuum().await?;
+        }
+        run(command, repository).await
+    } else if database_url.starts_with("memory:") {
+        info!("Keeping readings in memory, they are lost on exit");
+        let mut repository = InMemoryRepository::new().with_conflict_policy(conflict_policy);
+        if let Some(cursor_key) = cursor_key {
+            repository = repository.with_cursor_key(cursor_key);
+        }
+        if let Command::Serve = command {
+            // Keys issued on the command line are gone by now, so every run gets its own
+            let key = NewApiKey {
+                name: "memory".to_string(),
+                scopes: vec![Scope::Admin],
+            };
+            let issued = repository.issue_api_key(key).await?;
+            println!("Admin API key of this run: {}", issued.secret);
+        }
+        run(command, repository).await
+    } else {
+        Err(BsError::Other(
+            "DATABASE_URL must start with sqlite:, postgres:, postgresql: or memory:".to_string(),
+        ))
+    }
+}
+
+async fn migrate<C>(
+    migrator: &Migrator,
+    conn: &mut C,
+    action: MigrateCommand,
+) -> Result<(), BsError>
+where
+    C: Migrate + Send,
+{
+    match action {
+        MigrateCommand::Status => {
+            let statuses = schema::status(migrator, conn).await?;
+            println!("{}", serde_json::to_string_pretty(&statuses)?);
+        }
+        MigrateCommand::Up { to, dry_run: true } => {
+            print_sql(&schema::plan_up(migrator, conn, to).await?);
+        }
+        MigrateCommand::Up { to, dry_run: false } => {
+            let applied = schema::migrate_up(migrator, conn, to).await?;
+            println!("{}", serde_json::to_string_pretty(&applied)?);
+        }
+        MigrateCommand::Down { to, dry_run: true } => {
+            print_sql(&schema::plan_down(migrator, conn, to).await?);
+        }
+        MigrateCommand::Down { to, dry_run: false } => {
+            let reverted = schema::migrate_down(migrator, conn, to).await?;
+            println!("{}", serde_json::to_string_pretty(&reverted)?);
+        }
+    }
+    Ok(())
+}
+
+fn print_sql(migrations: &[&Migration]) {
+    for migration in migrations {
+        println!("-- {} {}", migration.version, migration.description);
+        println!("{}", migration.sql.trim_end());
+    }
+}
+
+async fn run<R>(command: Command, repository: R) -> Result<(), BsError>
+where
+    R: Repository + Clone + 'static,
+{
+    match command {
+        Command::Serve => serve(repository).await,
+        Command::Rollups {
+            action: RollupCommand::Rebuild { from, to },
+        } => {
+            let rebuilt = repository.rebuild_rollups(from, to).await?;
+            info!("Rebuilt {rebuilt} rollup buckets");
+            Ok(())
+        }
+        Command::Retention {
+            action: RetentionCommand::Run { dry_run },
+        } => {
+            let report = repository.enforce_retention(dry_run).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+        Command::Duplicates {
+            action: DuplicateCommand::Remove { dry_run },
+        } => {
+            let duplicates = repository.remove_duplicate_readings(dry_run).await?;
+            if dry_run {
+                info!("Found {duplicates} duplicate readings");
+            } else {
+                info!("Removed {duplicates} duplicate readings");
+            }
+            Ok(())
+        }
+        Command::Calibrations {
+            action: CalibrationCommand::Recompute { sensor_id },
+        } => {
+            let recomputed = repository.recompute_calibrations(sensor_id).await?;
+            info!("Recomputed {recomputed} values");
+            Ok(())
+        }
+        Command::ApiKeys {
+            action: ApiKeyCommand::Issue { name, scopes },
+        } => {
+            let issued = repository.issue_api_key(NewApiKey { name, scopes }).await?;
+            println!("{}", serde_json::to_string_pretty(&issued)?);
+            Ok(())
+        }
+        Command::ApiKeys {
+            action: ApiKeyCommand::List,
+        } => {
+            let keys = repository.fetch_api_keys().await?;
+            println!("{}", serde_json::to_string_pretty(&keys)?);
+            Ok(())
+        }
+        Command::ApiKeys {
+            action: ApiKeyCommand::Revoke { id },
+        } => {
+            if !repository.revoke_api_key(id).await? {
+                return Err(BsError::Other(format!("Unknown API key {id}")));
+            }
+            info!("Revoked API key {id}");
+            Ok(())
+        }
+        Command::Export {
+            output,
+            format,
+            partitioning,
+            incremental,
+            metrics,
+            sensor_id,
+            filter_metric,
+            min,
+            max,
+            from,
+            to,
+        } => {
+            let request = ExportRequest {
+                filters: QueryFilter {
+                    sensor_id,
+                    metric: filter_metric,
+                    min,
+                    max,
+                    from,
+                    to,
+                    ..Default::default()
+                },
+                metrics,
+                format,
+                partitioning,
+                incremental,
+            };
+            let report = export::export(&repository, request, &output).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+        Command::Import {
+            file,
+            format,
+            sensor_id_column,
+            sensor_id,
+            timestamp_column,
+            timestamp_format,
+            timezone,
+            metrics,
+            units,
+            topic,
+        } => {
+            let options = ImportOptions {
+                format,
+                sensor_id_column,
+                sensor_id,
+                timestamp_column,
+                timestamp_format,
+                timezone,
+                metrics: metrics.into_iter().collect(),
+                units: units.into_iter().collect(),
+                topic,
+            };
+            let input = BufReader::new(File::open(file)?);
+            let report = import::import(&repository, input, &options).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+        Command::Replay {
+            archive: directory,
+            from,
+            to,
+            ..
+        } => {
+            let directory = match directory {
+                Some(directory) => directory,
+                None => PathBuf::from(dotenvy::var("RAW_ARCHIVE_DIRECTORY")?),
+            };
+            let report = archive::replay(&repository, &directory, from, to).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+        // SQL backends handle it before the repository is built
+        Command::Migrate { .. } => Err(BsError::Other(
+            "The in-memory backend has no schema to migrate".to_string(),
+        )),
+    }
+}
+
+/// Limit read from the environment, `default` when unset
+fn limit<T>(name: &str, default: T) -> Result<T, BsError>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match dotenvy::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|e| BsError::Other(format!("Invalid {name}: {e}"))),
+        Err(_) => Ok(default),
+    }
+}
+
+async fn serve<R>(repository: R) -> Result<(), BsError>
+where
+    R: Repository + Clone + 'static,
+{
+    let retention_minutes = match dotenvy::var("RETENTION_INTERVAL_MINUTES") {
+        Ok(minutes) => minutes
+            .parse()
+            .map_err(|e| BsError::Other(format!("Invalid RETENTION_INTERVAL_MINUTES: {e}")))?,
+        Err(_) => 60,
+    };
+    let retention_handle = spawn_retention_task(
+        repository.clone(),
+        Duration::from_secs(retention_minutes * 60),
+    );
 
     let broker_ip = dotenvy::var("BASE_STATION_ADDRESS")?;
     let broker_port = dotenvy::var("BASE_STATION_PORT")?;
     let broker_addr = format!("{broker_ip}:{broker_port}");
-    let sqlite_db_file = dotenvy::var("DATABASE_URL")?;
-    let db_pool = SqlitePool::connect(&sqlite_db_file).await?;
-
-    sqlx::migrate!("./migrations").run(&db_pool).await?;
-
-    let repository = SqliteRepository::new(db_pool);
-    let (mqtt_client, handle) =
-        MqttClient::run_forever(broker_addr, "base-station".to_string(), repository.clone()).await;
+    let archive = match dotenvy::var("RAW_ARCHIVE_DIRECTORY") {
+        Ok(directory) => {
+            let keep_days =
+                match dotenvy::var("RAW_ARCHIVE_KEEP_DAYS") {
+                    Ok(days) => Some(days.parse().map_err(|e| {
+                        BsError::Other(format!("Invalid RAW_ARCHIVE_KEEP_DAYS: {e}"))
+                    })?),
+                    Err(_) => None,
+                };
+            info!("Archiving raw messages in {directory}");
+            Some(RawArchive::open(directory, keep_days)?)
+        }
+        Err(_) => None,
+    };
+    // On unless turned off, next to the default SQLite database
+    let journal_directory =
+        dotenvy::var("INGEST_JOURNAL_DIRECTORY").unwrap_or_else(|_| "journal".to_string());
+    let journal = match journal_directory.as_str() {
+        "off" => {
+            warn!("Ingestion journal is off, messages are lost while the database fails");
+            None
+        }
+        directory => {
+            let max_mb: u64 = match dotenvy::var("INGEST_JOURNAL_MAX_MB") {
+                Ok(mb) => mb
+                    .parse()
+                    .map_err(|e| BsError::Other(format!("Invalid INGEST_JOURNAL_MAX_MB: {e}")))?,
+                Err(_) => 64,
+            };
+            info!("Journaling messages in {directory}");
+            Some(Arc::new(IngestJournal::open(
+                directory,
+                max_mb * 1024 * 1024,
+            )?))
+        }
+    };
+    let notifier = ReadingNotifier::new();
+    let journal_handle = journal
+        .clone()
+        .map(|journal| spawn_journal_task(journal, repository.clone(), Some(notifier.clone())));
+    let (mqtt_client, handle) = MqttClient::run_forever(
+        broker_addr,
+        "base-station".to_string(),
+        repository.clone(),
+        archive,
+        journal,
+        Some(notifier.clone()),
+    )
+    .await;
 
     info!("waiting for MQTT server setup");
     mqtt_client.wait_for_server_setup().await;
@@ -48,16 +644,46 @@ async fn main() -> Result<(), BsError> {
     let server_port = dotenvy::var("API_SERVER_PORT")?;
     let server_addr = format!("{server_ip}:{server_port}");
 
-    let env_api = EnvironmentApi{repository};
-    let api_service =
-        OpenApiService::new(env_api, "Environment Api", "1.0");
+    if repository
+        .fetch_api_keys()
+        .await?
+        .iter()
+        .all(|key| key.revoked_at.is_some())
+    {
+        warn!("No API key can be used, issue one with `base-station api-keys issue`");
+    }
+    let export_directory = dotenvy::var("EXPORT_DIRECTORY").ok().map(PathBuf::from);
+    let rate_limiter = RateLimiter::new(
+        limit("API_RATE_LIMIT", 600)?,
+        limit("API_ADDRESS_RATE_LIMIT", 1200)?,
+    );
+    let query_queue = QueryQueue::new(
+        limit("API_HEAVY_QUERIES", 2)?,
+        Duration::from_secs(limit("API_QUEUE_WAIT_SECONDS", 10)?),
+    );
+    let env_api = EnvironmentApi {
+        repository,
+        export_directory,
+        notifier,
+        query_queue,
+    };
+    let websocket = env_api.websocket();
+    let authenticator = env_api.authenticator();
+    let api_service = OpenApiService::new(env_api, "Environment Api", "1.0");
     let ui = api_service.swagger_ui();
-    let app = Route::new().nest("/", api_service).nest("/meta/swagger", ui);
+    let app = Route::new()
+        .at("/v1/live", poem::get(websocket))
+        .nest("/", api_service)
+        .nest("/meta/swagger", ui)
+        .with(rate_limiter)
+        .data(authenticator);
 
-    Server::new(TcpListener::bind(server_addr))
-        .run(app)
-        .await?;
+    Server::new(TcpListener::bind(server_addr)).run(app).await?;
 
+    retention_handle.abort();
+    if let Some(journal_handle) = journal_handle {
+        journal_handle.abort();
+    }
     handle.await?;
 
     Ok(())
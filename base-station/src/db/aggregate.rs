@@ -0,0 +1,555 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+use super::{MeasurementQuery, Metric, Pagination, QueryFilter, Repository, SensorReading};
+use crate::error::BsError;
+
+/// Most buckets a query may span per sensor
+pub const MAX_BUCKETS: i64 = 10_000;
+/// Longest time span a query may aggregate, it is read from the raw readings
+pub const MAX_AGGREGATE_SPAN: TimeDelta = TimeDelta::days(366);
+/// Readings fetched per page when aggregating from the raw readings
+const AGGREGATE_PAGE_SIZE: usize = 5000;
+
+/// What is computed over the values of a metric in a bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+pub enum AggregateFunction {
+    Min,
+    Max,
+    Avg,
+    /// Value of the newest reading
+    Last,
+    /// Number of values, zero for empty buckets whatever the gap filling
+    Count,
+    /// Median, interpolated between the closest values
+    P50,
+    /// 95th percentile, interpolated between the closest values
+    P95,
+}
+
+impl AggregateFunction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AggregateFunction::Min => "min",
+            AggregateFunction::Max => "max",
+            AggregateFunction::Avg => "avg",
+            AggregateFunction::Last => "last",
+            AggregateFunction::Count => "count",
+            AggregateFunction::P50 => "p50",
+            AggregateFunction::P95 => "p95",
+        }
+    }
+
+    fn percentile(self) -> Option<f64> {
+        match self {
+            AggregateFunction::P50 => Some(0.5),
+            AggregateFunction::P95 => Some(0.95),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for AggregateFunction {
+    type Err = BsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "min" => Ok(AggregateFunction::Min),
+            "max" => Ok(AggregateFunction::Max),
+            "avg" => Ok(AggregateFunction::Avg),
+            "last" => Ok(AggregateFunction::Last),
+            "count" => Ok(AggregateFunction::Count),
+            "p50" => Ok(AggregateFunction::P50),
+            "p95" => Ok(AggregateFunction::P95),
+            other => Err(BsError::InvalidQuery(format!(
+                "Unknown aggregate function: {other}"
+            ))),
+        }
+    }
+}
+
+/// How buckets without values are filled in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+pub enum GapFill {
+    /// Empty buckets are returned with null values
+    Null,
+    /// Empty buckets repeat the values of the bucket before them
+    Previous,
+    /// Empty buckets are interpolated between the buckets around them
+    Linear,
+}
+
+/// Parse a bucket width such as `30s`, `5m`, `1h` or `1d`
+pub fn parse_bucket_width(width: &str) -> Result<TimeDelta, BsError> {
+    let invalid = || BsError::InvalidQuery(format!("Invalid bucket width: {width}"));
+    let split = width
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (count, unit) = width.split_at(split);
+    let count: i64 = count.parse().map_err(|_| invalid())?;
+    let width = match unit {
+        "s" => TimeDelta::try_seconds(count),
+        "m" => TimeDelta::try_minutes(count),
+        "h" => TimeDelta::try_hours(count),
+        "d" => TimeDelta::try_days(count),
+        _ => None,
+    };
+    width.filter(|w| *w > TimeDelta::zero()).ok_or_else(invalid)
+}
+
+#[derive(Debug, Clone)]
+pub struct AggregateQuery {
+    pub sensor_id: Option<String>,
+    pub metrics: Vec<String>,
+    pub functions: Vec<AggregateFunction>,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    /// Buckets are aligned to the Unix epoch, daily buckets start at midnight UTC
+    pub bucket: TimeDelta,
+    /// A row per sensor and bucket instead of one per bucket
+    pub group_by_sensor: bool,
+    /// Buckets without values are left out unless set
+    pub fill: Option<GapFill>,
+}
+
+impl AggregateQuery {
+    pub fn validate(&self, catalogue: &[Metric]) -> Result<(), BsError> {
+        if self.from >= self.to {
+            return Err(BsError::InvalidQuery("Empty time span".to_string()));
+        }
+        if self.to - self.from > MAX_AGGREGATE_SPAN {
+            return Err(BsError::InvalidQuery(format!(
+                "Time span longer than {} days",
+                MAX_AGGREGATE_SPAN.num_days()
+            )));
+        }
+        if self.metrics.is_empty()
+            || !self
+                .metrics
+                .iter()
+                .all(|metric| catalogue.iter().any(|m| &m.name == metric))
+        {
+            return Err(BsError::InvalidQuery("Invalid metrics".to_string()));
+        }
+        if self.functions.is_empty() {
+            return Err(BsError::InvalidQuery("No aggregate functions".to_string()));
+        }
+        if self.bucket < TimeDelta::seconds(1) || self.bucket > TimeDelta::days(366) {
+            return Err(BsError::InvalidQuery("Invalid bucket width".to_string()));
+        }
+        let start = self.bucket_start(self.from);
+        if (self.to - start).num_seconds() / self.bucket.num_seconds() >= MAX_BUCKETS {
+            return Err(BsError::InvalidQuery(format!(
+                "More than {MAX_BUCKETS} buckets, use wider buckets or a shorter time span"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Start of the bucket the timestamp falls into
+    pub fn bucket_start(&self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        timestamp
+            .duration_trunc(self.bucket)
+            .expect("Reading timestamps are within the representable range")
+    }
+}
+
+/// Aggregates of one bucket, of every sensor or of one when grouped by sensor
+#[derive(Debug, Clone, PartialEq, Serialize, Object)]
+pub struct AggregateRow {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[oai(skip_serializing_if_is_none)]
+    pub sensor_id: Option<String>,
+    /// Start of the bucket
+    pub timestamp: DateTime<Utc>,
+    /// Requested functions of every requested metric, null without values
+    pub metrics: BTreeMap<String, BTreeMap<String, Option<f64>>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Object)]
+pub struct Aggregation {
+    /// Ordered by sensor, then by bucket
+    pub rows: Vec<AggregateRow>,
+}
+
+/// Running aggregates of a metric in the open bucket
+#[derive(Debug, Default)]
+struct Accumulator {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u64,
+    last: f64,
+    /// Only kept when a percentile is requested
+    values: Vec<f64>,
+}
+
+impl Accumulator {
+    fn add(&mut self, value: f64, keep_values: bool) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.sum += value;
+        self.count += 1;
+        self.last = value;
+        if keep_values {
+            self.values.push(value);
+        }
+    }
+
+    /// Value of a function over the values added so far, at least one
+    fn value(&mut self, function: AggregateFunction) -> f64 {
+        match function {
+            AggregateFunction::Min => self.min,
+            AggregateFunction::Max => self.max,
+            AggregateFunction::Avg => self.sum / self.count as f64,
+            AggregateFunction::Last => self.last,
+            AggregateFunction::Count => self.count as f64,
+            AggregateFunction::P50 | AggregateFunction::P95 => {
+                let fraction = function.percentile().unwrap_or_default();
+                self.values.sort_by(f64::total_cmp);
+                let rank = fraction * (self.values.len() - 1) as f64;
+                let below = self.values[rank.floor() as usize];
+                let above = self.values[rank.ceil() as usize];
+                below + (above - below) * rank.fract()
+            }
+        }
+    }
+}
+
+/// Start of a bucket and the accumulators of its metrics
+type OpenBucket = (DateTime<Utc>, BTreeMap<String, Accumulator>);
+/// Function values of a closed bucket by metric
+type BucketValues = BTreeMap<String, BTreeMap<AggregateFunction, f64>>;
+
+/// Folds values into buckets. Values have to arrive in the order of their
+/// timestamps within each group, a bucket is closed once a value of a later
+/// bucket arrives so only the open bucket keeps its values.
+pub(crate) struct Aggregator {
+    query: AggregateQuery,
+    keep_values: bool,
+    /// Open bucket of every group
+    open: BTreeMap<Option<String>, OpenBucket>,
+    closed: BTreeMap<Option<String>, BTreeMap<DateTime<Utc>, BucketValues>>,
+}
+
+impl Aggregator {
+    pub(crate) fn new(query: AggregateQuery) -> Self {
+        let keep_values = query.functions.iter().any(|f| f.percentile().is_some());
+        Self {
+            query,
+            keep_values,
+            open: BTreeMap::new(),
+            closed: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn push(
+        &mut self,
+        sensor_id: &str,
+        timestamp: DateTime<Utc>,
+        metric: &str,
+        value: f64,
+    ) {
+        let group = self.query.group_by_sensor.then(|| sensor_id.to_string());
+        let bucket = self.query.bucket_start(timestamp);
+        if self
+            .open
+            .get(&group)
+            .is_none_or(|(start, _)| *start != bucket)
+            && let Some(previous) = self.open.insert(group.clone(), (bucket, BTreeMap::new()))
+        {
+            self.close(group.clone(), previous);
+        }
+        if let Some((_, accumulators)) = self.open.get_mut(&group) {
+            accumulators
+                .entry(metric.to_string())
+                .or_default()
+                .add(value, self.keep_values);
+        }
+    }
+
+    pub(crate) fn push_reading(&mut self, reading: &SensorReading) {
+        let (Some(sensor_id), Some(timestamp)) = (&reading.sensor_id, reading.timestamp) else {
+            return;
+        };
+        for (metric, value) in &reading.metrics {
+            self.push(sensor_id, timestamp, metric, *value);
+        }
+    }
+
+    fn close(&mut self, group: Option<String>, (start, accumulators): OpenBucket) {
+        let values = accumulators
+            .into_iter()
+            .map(|(metric, mut accumulator)| {
+                let functions = self
+                    .query
+                    .functions
+                    .iter()
+                    .map(|function| (*function, accumulator.value(*function)))
+                    .collect();
+                (metric, functions)
+            })
+            .collect();
+        self.closed.entry(group).or_default().insert(start, values);
+    }
+
+    pub(crate) fn finish(mut self) -> Aggregation {
+        for (group, open) in std::mem::take(&mut self.open) {
+            self.close(group, open);
+        }
+        if !self.query.group_by_sensor {
+            // Without grouping there is a series even when nothing was stored
+            self.closed.entry(None).or_default();
+        }
+        let mut rows = Vec::new();
+        for (group, buckets) in std::mem::take(&mut self.closed) {
+            rows.extend(self.rows(group, buckets));
+        }
+        Aggregation { rows }
+    }
+
+    /// Rows of a group with the gaps filled in
+    fn rows(
+        &self,
+        group: Option<String>,
+        buckets: BTreeMap<DateTime<Utc>, BucketValues>,
+    ) -> Vec<AggregateRow> {
+        let mut starts = Vec::new();
+        let mut start = self.query.bucket_start(self.query.from);
+        while start < self.query.to {
+            starts.push(start);
+            start += self.query.bucket;
+        }
+
+        // Every series of a metric and function over all buckets, gaps are None
+        let mut series: BTreeMap<(&str, AggregateFunction), Vec<Option<f64>>> = BTreeMap::new();
+        for metric in &self.query.metrics {
+            for function in &self.query.functions {
+                let values = starts
+                    .iter()
+                    .map(|start| buckets.get(start)?.get(metric)?.get(function).copied())
+                    .collect();
+                series.insert((metric.as_str(), *function), values);
+            }
+        }
+        for ((_, function), values) in series.iter_mut() {
+            match (function, self.query.fill) {
+                (AggregateFunction::Count, _) => {
+                    values.iter_mut().for_each(|v| *v = Some(v.unwrap_or(0.0)))
+                }
+                (_, Some(GapFill::Previous)) => fill_previous(values),
+                (_, Some(GapFill::Linear)) => fill_linear(values),
+                (_, Some(GapFill::Null) | None) => {}
+            }
+        }
+
+        starts
+            .iter()
+            .enumerate()
+            .filter(|(_, start)| self.query.fill.is_some() || buckets.contains_key(start))
+            .map(|(i, start)| {
+                let mut metrics: BTreeMap<String, BTreeMap<String, Option<f64>>> = BTreeMap::new();
+                for ((metric, function), values) in &series {
+                    metrics
+                        .entry(metric.to_string())
+                        .or_default()
+                        .insert(function.as_str().to_string(), values[i]);
+                }
+                AggregateRow {
+                    sensor_id: group.clone(),
+                    timestamp: *start,
+                    metrics,
+                }
+            })
+            .collect()
+    }
+}
+
+fn fill_previous(values: &mut [Option<f64>]) {
+    let mut previous = None;
+    for value in values {
+        match value {
+            Some(v) => previous = Some(*v),
+            None => *value = previous,
+        }
+    }
+}
+
+/// Interpolate gaps between known values, gaps at either end stay empty
+fn fill_linear(values: &mut [Option<f64>]) {
+    let known: Vec<(usize, f64)> = values
+        .iter()
+        .enumerate()
+        .filter_map(|(i, v)| Some((i, (*v)?)))
+        .collect();
+    for pair in known.windows(2) {
+        let ((start, from), (end, to)) = (pair[0], pair[1]);
+        for (i, value) in values.iter_mut().enumerate().take(end).skip(start + 1) {
+            let progress = (i - start) as f64 / (end - start) as f64;
+            *value = Some(from + (to - from) * progress);
+        }
+    }
+}
+
+/// Aggregate by scanning the raw readings a page at a time in timestamp order
+pub(super) async fn aggregate_pages<R: Repository + ?Sized>(
+    repository: &R,
+    query: AggregateQuery,
+) -> Result<Aggregation, BsError> {
+    query.validate(&repository.fetch_metrics().await?)?;
+    let columns: Vec<String> = ["sensor_id", "timestamp"]
+        .into_iter()
+        .map(String::from)
+        .chain(query.metrics.iter().cloned())
+        .collect();
+    let mut page_query = MeasurementQuery {
+        filters: QueryFilter {
+            sensor_id: query.sensor_id.clone(),
+            from: Some(query.from),
+            to: Some(query.to),
+            ..Default::default()
+        },
+        pagination: Pagination {
+            page_size: AGGREGATE_PAGE_SIZE,
+            ..Default::default()
+        },
+        columns: columns.clone(),
+    };
+    let mut aggregator = Aggregator::new(query);
+    loop {
+        let page = repository.fetch_sensor_readings_page(page_query).await?;
+        for reading in &page.rows {
+            aggregator.push_reading(reading);
+        }
+        match page.next {
+            Some(next) => {
+                page_query = MeasurementQuery {
+                    filters: page.filters,
+                    pagination: Pagination {
+                        after: Some(next),
+                        page_size: AGGREGATE_PAGE_SIZE,
+                        ..Default::default()
+                    },
+                    columns: columns.clone(),
+                };
+            }
+            None => break,
+        }
+    }
+    Ok(aggregator.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(fill: Option<GapFill>) -> AggregateQuery {
+        AggregateQuery {
+            sensor_id: None,
+            metrics: vec!["temperature".to_string()],
+            functions: vec![
+                AggregateFunction::Avg,
+                AggregateFunction::P95,
+                AggregateFunction::Count,
+            ],
+            from: "2026-01-01T10:00:00Z".parse().unwrap(),
+            to: "2026-01-01T10:04:00Z".parse().unwrap(),
+            bucket: TimeDelta::minutes(1),
+            group_by_sensor: false,
+            fill,
+        }
+    }
+
+    fn aggregate(fill: Option<GapFill>) -> Vec<AggregateRow> {
+        let mut aggregator = Aggregator::new(query(fill));
+        for (timestamp, value) in [
+            ("2026-01-01T10:00:10Z", 1.0),
+            ("2026-01-01T10:00:20Z", 2.0),
+            ("2026-01-01T10:00:30Z", 3.0),
+            ("2026-01-01T10:03:00Z", 8.0),
+        ] {
+            aggregator.push("outside", timestamp.parse().unwrap(), "temperature", value);
+        }
+        aggregator.finish().rows
+    }
+
+    fn values(rows: &[AggregateRow], function: &str) -> Vec<Option<f64>> {
+        rows.iter()
+            .map(|row| row.metrics["temperature"][function])
+            .collect()
+    }
+
+    #[test]
+    fn bucket_widths_are_parsed() {
+        assert_eq!(parse_bucket_width("30s").unwrap(), TimeDelta::seconds(30));
+        assert_eq!(parse_bucket_width("5m").unwrap(), TimeDelta::minutes(5));
+        assert_eq!(parse_bucket_width("1h").unwrap(), TimeDelta::hours(1));
+        assert_eq!(parse_bucket_width("1d").unwrap(), TimeDelta::days(1));
+        for invalid in ["", "h", "0m", "5", "1w", "-1h"] {
+            assert!(
+                parse_bucket_width(invalid).is_err(),
+                "{invalid} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn empty_buckets_are_filled() {
+        let rows = aggregate(None);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(values(&rows, "avg"), vec![Some(2.0), Some(8.0)]);
+        assert_eq!(values(&rows, "p95"), vec![Some(2.9), Some(8.0)]);
+
+        let rows = aggregate(Some(GapFill::Null));
+        assert_eq!(values(&rows, "avg"), vec![Some(2.0), None, None, Some(8.0)]);
+        assert_eq!(
+            values(&rows, "count"),
+            vec![Some(3.0), Some(0.0), Some(0.0), Some(1.0)]
+        );
+
+        let rows = aggregate(Some(GapFill::Previous));
+        assert_eq!(
+            values(&rows, "avg"),
+            vec![Some(2.0), Some(2.0), Some(2.0), Some(8.0)]
+        );
+
+        let rows = aggregate(Some(GapFill::Linear));
+        assert_eq!(
+            values(&rows, "avg"),
+            vec![Some(2.0), Some(4.0), Some(6.0), Some(8.0)]
+        );
+        assert_eq!(
+            values(&rows, "count"),
+            vec![Some(3.0), Some(0.0), Some(0.0), Some(1.0)]
+        );
+    }
+
+    #[test]
+    fn bucket_count_and_span_are_capped() {
+        let catalogue = [Metric {
+            name: "temperature".to_string(),
+            unit: None,
+            description: None,
+        }];
+        let mut query = query(None);
+        query.to = query.from + TimeDelta::minutes(MAX_BUCKETS);
+        assert!(query.validate(&catalogue).is_err());
+        query.bucket = TimeDelta::hours(1);
+        assert!(query.validate(&catalogue).is_ok());
+        query.to = query.from + MAX_AGGREGATE_SPAN + TimeDelta::days(1);
+        query.bucket = TimeDelta::days(1);
+        assert!(query.validate(&catalogue).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, SubsecRound, Utc};
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::BsError;
+
+/// Start of every secret, tells API keys apart from other credentials
+const SECRET_PREFIX: &str = "bs_";
+/// Characters of a secret kept to recognise the key in listings
+const DISPLAYED_PREFIX_LEN: usize = 10;
+
+/// What an API key may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Enum)]
+pub enum Scope {
+    /// Read readings, sensors and settings
+    #[serde(rename = "read:readings")]
+    #[oai(rename = "read:readings")]
+    ReadReadings,
+    /// Change sensors, calibrations and retention policies
+    #[serde(rename = "write:config")]
+    #[oai(rename = "write:config")]
+    WriteConfig,
+    /// Everything, including imports, exports and API keys
+    #[serde(rename = "admin")]
+    #[oai(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::ReadReadings => "read:readings",
+            Scope::WriteConfig => "write:config",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = BsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read:readings" => Ok(Scope::ReadReadings),
+            "write:config" => Ok(Scope::WriteConfig),
+            "admin" => Ok(Scope::Admin),
+            other => Err(BsError::Other(format!("Unknown scope: {other}"))),
+        }
+    }
+}
+
+/// Key to issue
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Object)]
+pub struct NewApiKey {
+    /// Who or what uses the key
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl NewApiKey {
+    pub fn validate(&self) -> Result<(), BsError> {
+        if self.name.trim().is_empty() {
+            return Err(BsError::InvalidQuery("API keys need a name".to_string()));
+        }
+        if self.scopes.is_empty() {
+            return Err(BsError::InvalidQuery(
+                "API keys need at least one scope".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// An issued API key, its secret is only known to whoever it was issued to
+#[derive(Debug, Clone, PartialEq, Serialize, Object)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    /// Start of the secret, to recognise the key
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    /// Requests carrying the key are refused from then on
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    /// Admin keys may do everything
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.revoked_at.is_none()
+            && (self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin))
+    }
+}
+
+/// A key as returned once when issued
+#[derive(Debug, Clone, PartialEq, Serialize, Object)]
+pub struct IssuedApiKey {
+    #[serde(flatten)]
+    #[oai(flatten)]
+    pub key: ApiKey,
+    /// Sent as `Authorization: Bearer <secret>`. It is not stored and can't
+    /// be shown again.
+    pub secret: String,
+}
+
+impl IssuedApiKey {
+    /// A key with a fresh random secret, its id is assigned once stored
+    pub(super) fn generate(key: NewApiKey) -> Result<Self, BsError> {
+        key.validate()?;
+        let secret = format!(
+            "{SECRET_PREFIX}{}",
+            URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+        );
+        let mut scopes = key.scopes;
+        scopes.sort();
+        scopes.dedup();
+        Ok(IssuedApiKey {
+            key: ApiKey {
+                id: 0,
+                name: key.name.trim().to_string(),
+                prefix: secret[..DISPLAYED_PREFIX_LEN].to_string(),
+                scopes,
+                // Microseconds, what every backend stores
+                created_at: Utc::now().trunc_subsecs(6),
+                revoked_at: None,
+            },
+            secret,
+        })
+    }
+}
+
+/// What is stored in place of a secret
+pub(super) fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// Scopes as stored by the SQL backends, separated by spaces
+pub(crate) fn join_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(|scope| scope.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// API key as stored by the SQL backends
+#[derive(Debug, sqlx::FromRow)]
+pub(crate) struct ApiKeyRow {
+    pub id: i64,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<ApiKeyRow> for ApiKey {
+    type Error = BsError;
+
+    fn try_from(row: ApiKeyRow) -> Result<Self, Self::Error> {
+        Ok(ApiKey {
+            id: row.id,
+            name: row.name,
+            prefix: row.prefix,
+            scopes: row
+                .scopes
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+            created_at: row.created_at,
+            revoked_at: row.revoked_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_keys_allow_everything() {
+        let issued = IssuedApiKey::generate(NewApiKey {
+            name: " dashboard ".to_string(),
+            scopes: vec![Scope::WriteConfig, Scope::ReadReadings, Scope::WriteConfig],
+        })
+        .unwrap();
+        let mut key = issued.key;
+        assert_eq!(key.name, "dashboard");
+        assert_eq!(key.scopes, vec![Scope::ReadReadings, Scope::WriteConfig]);
+        assert!(issued.secret.starts_with(&key.prefix));
+        assert!(!key.allows(Scope::Admin));
+
+        key.scopes = vec![Scope::Admin];
+        assert!(key.allows(Scope::ReadReadings));
+        assert!(key.allows(Scope::WriteConfig));
+
+        key.revoked_at = Some(Utc::now());
+        assert!(!key.allows(Scope::ReadReadings));
+    }
+
+    #[test]
+    fn secrets_are_stored_hashed() {
+        let hash = hash_secret("bs_secret");
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, hash_secret("bs_secret"));
+        assert_ne!(hash, hash_secret("bs_secreT"));
+    }
+}
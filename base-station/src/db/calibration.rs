@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+use crate::error::BsError;
+use crate::is_valid_metric_name;
+
+/// Linear correction of one metric of one sensor, `value = raw * gain + offset`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Object)]
+pub struct Calibration {
+    pub sensor_id: String,
+    pub metric: String,
+    #[serde(default = "default_gain")]
+    #[oai(default = "default_gain")]
+    pub gain: f64,
+    #[serde(default)]
+    #[oai(default)]
+    pub offset: f64,
+    /// Applies from the beginning of time when missing
+    pub valid_from: Option<DateTime<Utc>>,
+    /// Applies until further notice when missing, exclusive
+    pub valid_to: Option<DateTime<Utc>>,
+}
+
+fn default_gain() -> f64 {
+    1.0
+}
+
+impl Calibration {
+    pub fn validate(&self) -> Result<(), BsError> {
+        if !is_valid_metric_name(&self.metric) {
+            return Err(BsError::InvalidQuery(format!(
+                "Invalid metric name: {}",
+                self.metric
+            )));
+        }
+        if !self.gain.is_finite() || self.gain == 0.0 || !self.offset.is_finite() {
+            return Err(BsError::InvalidQuery(
+                "gain must be finite and non-zero, offset finite".to_string(),
+            ));
+        }
+        if !starts_before(self.valid_from, self.valid_to) {
+            return Err(BsError::InvalidQuery(
+                "valid_from must be before valid_to".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn is_valid_at(&self, timestamp: DateTime<Utc>) -> bool {
+        self.valid_from.is_none_or(|from| from <= timestamp)
+            && self.valid_to.is_none_or(|to| timestamp < to)
+    }
+
+    /// Only one calibration may apply to a reading
+    pub fn overlaps(&self, other: &Calibration) -> bool {
+        self.sensor_id == other.sensor_id
+            && self.metric == other.metric
+            && starts_before(self.valid_from, other.valid_to)
+            && starts_before(other.valid_from, self.valid_to)
+    }
+
+    pub fn apply(&self, raw: f64) -> f64 {
+        raw * self.gain + self.offset
+    }
+}
+
+/// Missing bounds are open ends
+fn starts_before(from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> bool {
+    match (from, to) {
+        (Some(from), Some(to)) => from < to,
+        _ => true,
+    }
+}
+
+/// Reject a calibration overlapping one that is already stored
+pub fn check_overlaps(new: &Calibration, stored: &[StoredCalibration]) -> Result<(), BsError> {
+    match stored.iter().find(|s| s.calibration.overlaps(new)) {
+        Some(existing) => Err(BsError::InvalidQuery(format!(
+            "Calibration overlaps calibration {}",
+            existing.id
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Calibrated values of a reading, raw values of metrics without a calibration
+pub fn calibrate(
+    calibrations: &[Calibration],
+    timestamp: DateTime<Utc>,
+    raw: &BTreeMap<String, f64>,
+) -> BTreeMap<String, f64> {
+    raw.iter()
+        .map(|(metric, value)| {
+            let calibrated = calibrations
+                .iter()
+                .find(|c| &c.metric == metric && c.is_valid_at(timestamp))
+                .map_or(*value, |c| c.apply(*value));
+            (metric.clone(), calibrated)
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Object)]
+pub struct StoredCalibration {
+    pub id: i64,
+    #[serde(flatten)]
+    #[oai(flatten)]
+    pub calibration: Calibration,
+}
+
+/// Calibration as stored by the SQL backends
+#[derive(Debug, sqlx::FromRow)]
+pub(crate) struct CalibrationRow {
+    pub id: i64,
+    pub sensor_id: String,
+    pub metric: String,
+    pub gain: f64,
+    pub offset_value: f64,
+    pub valid_from: Option<DateTime<Utc>>,
+    pub valid_to: Option<DateTime<Utc>>,
+}
+
+impl From<CalibrationRow> for StoredCalibration {
+    fn from(row: CalibrationRow) -> Self {
+        StoredCalibration {
+            id: row.id,
+            calibration: Calibration {
+                sensor_id: row.sensor_id,
+                metric: row.metric,
+                gain: row.gain,
+                offset: row.offset_value,
+                valid_from: row.valid_from,
+                valid_to: row.valid_to,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibration(from: Option<&str>, to: Option<&str>) -> Calibration {
+        Calibration {
+            sensor_id: "balcony".to_string(),
+            metric: "temperature".to_string(),
+            gain: 1.0,
+            offset: -0.5,
+            valid_from: from.map(|f| f.parse().unwrap()),
+            valid_to: to.map(|t| t.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn validity_ranges_overlap() {
+        let until_march = calibration(None, Some("2026-03-01T00:00:00Z"));
+        let from_march = calibration(Some("2026-03-01T00:00:00Z"), None);
+        let summer = calibration(Some("2026-06-01T00:00:00Z"), Some("2026-09-01T00:00:00Z"));
+
+        assert!(!until_march.overlaps(&from_march));
+        assert!(from_march.overlaps(&summer));
+        assert!(!until_march.overlaps(&summer));
+        assert!(calibration(None, None).overlaps(&summer));
+    }
+
+    #[test]
+    fn only_valid_calibrations_are_applied() {
+        let calibrations = [calibration(Some("2026-03-01T00:00:00Z"), None)];
+        let raw = BTreeMap::from([
+            ("temperature".to_string(), 21.0),
+            ("humidity".to_string(), 40.0),
+        ]);
+
+        let before = calibrate(&calibrations, "2026-02-01T00:00:00Z".parse().unwrap(), &raw);
+        assert_eq!(before, raw);
+
+        let after = calibrate(&calibrations, "2026-04-01T00:00:00Z".parse().unwrap(), &raw);
+        assert_eq!(after["temperature"], 20.5);
+        assert_eq!(after["humidity"], 40.0);
+    }
+}
@@ -0,0 +1,1899 @@
+//! Readings stored per sensor in compressed chunk files, with everything
+//! else in a SQLite catalogue.
+//!
+//! New readings of a sensor collect in its head, kept in memory and in an
+//! append-only log so a crash loses nothing. Once the head holds
+//! `CHUNK_ROWS` readings it is sealed into an immutable chunk holding one
+//! column per field: ids and timestamps as deltas of deltas, values XORed
+//! with their predecessor (see `gorilla`). A regular series comes down to a
+//! few bytes per reading, about a tenth of what SQLite needs.
+//!
+//! The span, ids and metrics of every chunk are kept in memory, so queries
+//! only read the chunks that can hold matching readings and only decompress
+//! the columns they ask for. Chunks are rewritten whole when readings are
+//! removed or recalibrated, and deleted once retention expires all of them.
+//! Rollups, sensors, calibrations and retention policies stay in the
+//! catalogue.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Row as _, SqlitePool};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use super::calibration::calibrate;
+use super::gorilla;
+use super::rollup::{self, Aggregate, RollupKey};
+use super::{
+    ApiKey, Calibration, ConflictPolicy, CursorKey, Keyset, MeasurementQuery, Metric, QueryFilter,
+    Repository, Resolution, RetentionPolicy, RetentionReport, RetentionReportEntry,
+    RetentionTarget, Sensor, SensorMetadata, SensorReading, SensorReadingsPage, Series,
+    SeriesPoint, SeriesQuery, SortOrder, SqliteRepository, StoredCalibration,
+};
+use crate::SensorReadingEvent;
+use crate::error::BsError;
+
+/// Readings sealed into one chunk
+const CHUNK_ROWS: usize = 1024;
+const CHUNK_MAGIC: &[u8; 4] = b"BSCH";
+const CHUNK_VERSION: u8 = 1;
+const HEAD_LOG: &str = "head.log";
+/// Readings moved out of the catalogue per statement
+const MOVE_BATCH_SIZE: i64 = 5000;
+
+/// A stored reading. Rows read from a chunk only carry the columns asked for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Row {
+    id: i64,
+    timestamp: DateTime<Utc>,
+    topic: String,
+    sequence: Option<i64>,
+    /// Calibrated values
+    values: BTreeMap<String, f64>,
+    /// Values as reported by the sensor
+    raw: BTreeMap<String, f64>,
+}
+
+impl Row {
+    fn keyset(&self) -> Keyset {
+        Keyset {
+            timestamp: self.timestamp,
+            id: self.id,
+        }
+    }
+}
+
+/// Columns of a chunk to decompress. Ids, timestamps and sequence numbers
+/// are always read.
+#[derive(Debug, Clone, Copy)]
+struct Projection<'a> {
+    topic: bool,
+    /// Every metric when missing
+    metrics: Option<&'a [String]>,
+    raw: bool,
+}
+
+impl Projection<'static> {
+    const ALL: Self = Self {
+        topic: true,
+        metrics: None,
+        raw: true,
+    };
+    const VALUES: Self = Self {
+        topic: false,
+        metrics: None,
+        raw: false,
+    };
+    const KEYS: Self = Self {
+        topic: false,
+        metrics: Some(&[]),
+        raw: false,
+    };
+}
+
+impl Projection<'_> {
+    fn wants(&self, metric: &str) -> bool {
+        self.metrics
+            .is_none_or(|metrics| metrics.iter().any(|m| m == metric))
+    }
+}
+
+fn nanos(timestamp: DateTime<Utc>) -> i64 {
+    timestamp
+        .timestamp_nanos_opt()
+        .expect("Reading timestamps fit in i64 nanoseconds")
+}
+
+fn corrupt() -> BsError {
+    BsError::Other("Corrupt chunk".to_string())
+}
+
+/// Encode a chunk: a header describing it, followed by length prefixed
+/// sections so columns nobody asked for can be skipped
+fn encode_chunk(rows: &[Row]) -> Vec<u8> {
+    let (first, last) = (&rows[0], &rows[rows.len() - 1]);
+    let metrics: BTreeSet<&String> = rows.iter().flat_map(|row| row.values.keys()).collect();
+
+    let mut out = Vec::new();
+    out.extend_from_slice(CHUNK_MAGIC);
+    out.push(CHUNK_VERSION);
+    out.extend_from_slice(&(rows.len() as u32).to_le_bytes());
+    for keyset in [first.keyset(), last.keyset()] {
+        out.extend_from_slice(&nanos(keyset.timestamp).to_le_bytes());
+        out.extend_from_slice(&keyset.id.to_le_bytes());
+    }
+    let max_id = rows.iter().map(|row| row.id).max().unwrap_or(first.id);
+    out.extend_from_slice(&max_id.to_le_bytes());
+    out.extend_from_slice(&(metrics.len() as u16).to_le_bytes());
+    for metric in &metrics {
+        put_string(&mut out, metric);
+    }
+
+    let ids: Vec<i64> = rows.iter().map(|row| row.id).collect();
+    put_section(&mut out, &gorilla::encode_integers(&ids));
+    let timestamps: Vec<i64> = rows.iter().map(|row| nanos(row.timestamp)).collect();
+    put_section(&mut out, &gorilla::encode_integers(&timestamps));
+    let has_sequence: Vec<bool> = rows.iter().map(|row| row.sequence.is_some()).collect();
+    put_section(&mut out, &gorilla::encode_flags(&has_sequence));
+    let sequences: Vec<i64> = rows.iter().filter_map(|row| row.sequence).collect();
+    put_section(&mut out, &gorilla::encode_integers(&sequences));
+    put_section(&mut out, &encode_topics(rows));
+
+    for metric in metrics {
+        let present: Vec<bool> = rows
+            .iter()
+            .map(|row| row.values.contains_key(metric))
+            .collect();
+        let values: Vec<f64> = rows
+            .iter()
+            .filter_map(|row| row.values.get(metric))
+            .copied()
+            .collect();
+        let raw: Vec<f64> = rows
+            .iter()
+            .filter_map(|row| {
+                row.values
+                    .get(metric)
+                    .map(|value| row.raw.get(metric).unwrap_or(value))
+            })
+            .copied()
+            .collect();
+        put_section(&mut out, &gorilla::encode_flags(&present));
+        put_section(&mut out, &gorilla::encode_floats(&values));
+        // Left empty for the usual uncalibrated metric
+        let calibrated = raw
+            .iter()
+            .zip(&values)
+            .any(|(r, v)| r.to_bits() != v.to_bits());
+        put_section(
+            &mut out,
+            &if calibrated {
+                gorilla::encode_floats(&raw)
+            } else {
+                Vec::new()
+            },
+        );
+    }
+    out
+}
+
+/// Topics as a dictionary and runs of dictionary entries, readings of a
+/// sensor rarely change topic
+fn encode_topics(rows: &[Row]) -> Vec<u8> {
+    let mut dictionary: Vec<&str> = Vec::new();
+    let mut runs: Vec<(u32, u16)> = Vec::new();
+    for row in rows {
+        let index = match dictionary.iter().position(|topic| *topic == row.topic) {
+            Some(index) => index,
+            None => {
+                dictionary.push(&row.topic);
+                dictionary.len() - 1
+            }
+        } as u16;
+        match runs.last_mut() {
+            Some((len, last)) if *last == index => *len += 1,
+            _ => runs.push((1, index)),
+        }
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&(dictionary.len() as u16).to_le_bytes());
+    for topic in dictionary {
+        put_string(&mut out, topic);
+    }
+    out.extend_from_slice(&(runs.len() as u32).to_le_bytes());
+    for (len, index) in runs {
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&index.to_le_bytes());
+    }
+    out
+}
+
+fn put_string(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn put_section(out: &mut Vec<u8>, section: &[u8]) {
+    out.extend_from_slice(&(section.len() as u32).to_le_bytes());
+    out.extend_from_slice(section);
+}
+
+struct ChunkReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ChunkReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BsError> {
+        let end = self.position.checked_add(len).ok_or_else(corrupt)?;
+        let taken = self.bytes.get(self.position..end).ok_or_else(corrupt)?;
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], BsError> {
+        self.take(N)?.try_into().map_err(|_| corrupt())
+    }
+
+    fn u16(&mut self) -> Result<u16, BsError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, BsError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> Result<i64, BsError> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    fn keyset(&mut self) -> Result<Keyset, BsError> {
+        let timestamp = DateTime::from_timestamp_nanos(self.i64()?);
+        Ok(Keyset {
+            timestamp,
+            id: self.i64()?,
+        })
+    }
+
+    fn string(&mut self) -> Result<String, BsError> {
+        let len = self.u16()?.into();
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| corrupt())
+    }
+
+    fn section(&mut self) -> Result<&'a [u8], BsError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ChunkHeader {
+    rows: usize,
+    first: Keyset,
+    last: Keyset,
+    max_id: i64,
+    metrics: Vec<String>,
+}
+
+fn read_header(reader: &mut ChunkReader<'_>) -> Result<ChunkHeader, BsError> {
+    if reader.take(4)? != CHUNK_MAGIC || reader.take(1)? != [CHUNK_VERSION] {
+        return Err(corrupt());
+    }
+    let rows = reader.u32()? as usize;
+    let first = reader.keyset()?;
+    let last = reader.keyset()?;
+    let max_id = reader.i64()?;
+    let metrics = (0..reader.u16()?)
+        .map(|_| reader.string())
+        .collect::<Result<_, _>>()?;
+    Ok(ChunkHeader {
+        rows,
+        first,
+        last,
+        max_id,
+        metrics,
+    })
+}
+
+fn decode_chunk(bytes: &[u8], projection: Projection<'_>) -> Result<Vec<Row>, BsError> {
+    let mut reader = ChunkReader { bytes, position: 0 };
+    let header = read_header(&mut reader)?;
+    let count = header.rows;
+
+    let ids = gorilla::decode_integers(reader.section()?, count)?;
+    let timestamps = gorilla::decode_integers(reader.section()?, count)?;
+    let has_sequence = gorilla::decode_flags(reader.section()?, count)?;
+    let present = has_sequence.iter().filter(|has| **has).count();
+    let mut sequences = gorilla::decode_integers(reader.section()?, present)?.into_iter();
+    let topics = reader.section()?;
+    let topics = if projection.topic {
+        decode_topics(topics, count)?
+    } else {
+        vec![String::new(); count]
+    };
+
+    let mut rows: Vec<Row> = ids
+        .into_iter()
+        .zip(timestamps)
+        .zip(has_sequence)
+        .zip(topics)
+        .map(|(((id, timestamp), has_sequence), topic)| Row {
+            id,
+            timestamp: DateTime::from_timestamp_nanos(timestamp),
+            topic,
+            sequence: if has_sequence { sequences.next() } else { None },
+            values: BTreeMap::new(),
+            raw: BTreeMap::new(),
+        })
+        .collect();
+
+    for metric in &header.metrics {
+        let (present, values, raw) = (reader.section()?, reader.section()?, reader.section()?);
+        if !projection.wants(metric) {
+            continue;
+        }
+        let present = gorilla::decode_flags(present, count)?;
+        let len = present.iter().filter(|present| **present).count();
+        let values = gorilla::decode_floats(values, len)?;
+        let raw = if projection.raw && !raw.is_empty() {
+            gorilla::decode_floats(raw, len)?
+        } else {
+            values.clone()
+        };
+        let with_metric = rows.iter_mut().zip(present).filter(|(_, present)| *present);
+        for ((row, _), (value, raw)) in with_metric.zip(values.into_iter().zip(raw)) {
+            row.values.insert(metric.clone(), value);
+            if projection.raw {
+                row.raw.insert(metric.clone(), raw);
+            }
+        }
+    }
+    Ok(rows)
+}
+
+fn decode_topics(bytes: &[u8], count: usize) -> Result<Vec<String>, BsError> {
+    let mut reader = ChunkReader { bytes, position: 0 };
+    let dictionary = (0..reader.u16()?)
+        .map(|_| reader.string())
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut topics = Vec::with_capacity(count);
+    for _ in 0..reader.u32()? {
+        let len = reader.u32()? as usize;
+        let topic = dictionary
+            .get(usize::from(reader.u16()?))
+            .ok_or_else(corrupt)?;
+        topics.extend(std::iter::repeat_n(topic.clone(), len));
+    }
+    if topics.len() != count {
+        return Err(corrupt());
+    }
+    Ok(topics)
+}
+
+/// Replaced in one rename so a crash leaves either the old or the new file
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<(), BsError> {
+    let temporary = path.with_extension("tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(temporary, path)?;
+    Ok(())
+}
+
+fn remove_file(path: &Path) -> Result<(), BsError> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// A sealed chunk as kept in the in-memory index
+#[derive(Debug, Clone)]
+struct ChunkInfo {
+    path: PathBuf,
+    header: ChunkHeader,
+}
+
+impl ChunkInfo {
+    fn write(directory: &Path, rows: &[Row]) -> Result<Self, BsError> {
+        let bytes = encode_chunk(rows);
+        let first = rows[0].keyset();
+        let path = directory.join(format!("{}-{}.chunk", nanos(first.timestamp), first.id));
+        write_atomically(&path, &bytes)?;
+        let header = read_header(&mut ChunkReader {
+            bytes: &bytes,
+            position: 0,
+        })?;
+        Ok(Self { path, header })
+    }
+
+    fn read(path: PathBuf) -> Result<Self, BsError> {
+        let bytes = fs::read(&path)?;
+        let header = read_header(&mut ChunkReader {
+            bytes: &bytes,
+            position: 0,
+        })?;
+        Ok(Self { path, header })
+    }
+
+    fn rows(&self, projection: Projection<'_>) -> Result<Vec<Row>, BsError> {
+        decode_chunk(&fs::read(&self.path)?, projection)
+    }
+}
+
+/// Readings of one sensor, either sealed in a chunk or in the head
+#[derive(Debug, Clone, Copy)]
+enum Source<'a> {
+    Chunk(&'a ChunkInfo),
+    Head(&'a [Row]),
+}
+
+impl Source<'_> {
+    fn first(&self) -> Keyset {
+        match self {
+            Source::Chunk(chunk) => chunk.header.first,
+            Source::Head(rows) => rows[0].keyset(),
+        }
+    }
+
+    fn last(&self) -> Keyset {
+        match self {
+            Source::Chunk(chunk) => chunk.header.last,
+            Source::Head(rows) => rows[rows.len() - 1].keyset(),
+        }
+    }
+
+    /// Highest id, the reading stored last
+    fn max_id(&self) -> i64 {
+        match self {
+            Source::Chunk(chunk) => chunk.header.max_id,
+            Source::Head(rows) => rows.iter().map(|row| row.id).max().unwrap_or(0),
+        }
+    }
+
+    /// Whether it may hold readings taken within `from..to`
+    fn overlaps(&self, from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> bool {
+        from.is_none_or(|from| self.last().timestamp >= from)
+            && to.is_none_or(|to| self.first().timestamp < to)
+    }
+
+    fn has_metric(&self, metric: &str) -> bool {
+        match self {
+            Source::Chunk(chunk) => chunk.header.metrics.iter().any(|m| m == metric),
+            Source::Head(_) => true,
+        }
+    }
+
+    fn rows(&self, projection: Projection<'_>) -> Result<Vec<Row>, BsError> {
+        match self {
+            Source::Chunk(chunk) => chunk.rows(projection),
+            Source::Head(rows) => Ok(rows.to_vec()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SensorSeries {
+    directory: PathBuf,
+    /// Ordered by their first reading. Chunks may overlap when readings
+    /// arrive late.
+    chunks: Vec<ChunkInfo>,
+    /// Readings not sealed yet, in `(timestamp, id)` order
+    head: Vec<Row>,
+}
+
+impl SensorSeries {
+    fn open(directory: PathBuf) -> Result<Self, BsError> {
+        fs::create_dir_all(&directory)?;
+        let mut chunks = Vec::new();
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("chunk") => chunks.push(ChunkInfo::read(path)?),
+                // Left by a crash halfway through a write
+                Some("tmp") => remove_file(&path)?,
+                _ => {}
+            }
+        }
+        chunks.sort_by_key(|chunk| chunk.header.first);
+        let sealed = chunks.iter().map(|chunk| chunk.header.max_id).max();
+
+        let mut head = Vec::new();
+        let mut rewrite = false;
+        match File::open(directory.join(HEAD_LOG)) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    match serde_json::from_str::<Row>(&line?) {
+                        // Rows sealed right before a crash are still logged
+                        Ok(row) if sealed.is_some_and(|sealed| row.id <= sealed) => rewrite = true,
+                        Ok(row) => head.push(row),
+                        Err(e) => {
+                            warn!("Dropping a torn reading in {}: {e}", directory.display());
+                            rewrite = true;
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        head.sort_by_key(Row::keyset);
+
+        let series = Self {
+            directory,
+            chunks,
+            head,
+        };
+        if rewrite {
+            series.write_head()?;
+        }
+        Ok(series)
+    }
+
+    fn max_id(&self) -> Option<i64> {
+        let sealed = self.chunks.iter().map(|chunk| chunk.header.max_id);
+        sealed.chain(self.head.iter().map(|row| row.id)).max()
+    }
+
+    fn sources(&self) -> impl Iterator<Item = Source<'_>> {
+        let head = (!self.head.is_empty()).then_some(Source::Head(&self.head));
+        self.chunks.iter().map(Source::Chunk).chain(head)
+    }
+
+    fn oldest(&self) -> Option<DateTime<Utc>> {
+        self.sources().map(|source| source.first().timestamp).min()
+    }
+
+    /// Newest reading, decoding only the sources that may hold it
+    fn latest(&self, projection: Projection<'_>) -> Result<Option<Row>, BsError> {
+        let Some(newest) = self.sources().map(|source| source.last()).max() else {
+            return Ok(None);
+        };
+        Ok(self.scan(Some(newest.timestamp), None, projection)?.pop())
+    }
+
+    /// Readings taken within `from..to` in `(timestamp, id)` order
+    fn scan(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        projection: Projection<'_>,
+    ) -> Result<Vec<Row>, BsError> {
+        let mut rows = Vec::new();
+        for source in self.sources().filter(|source| source.overlaps(from, to)) {
+            rows.extend(source.rows(projection)?.into_iter().filter(|row| {
+                from.is_none_or(|from| row.timestamp >= from)
+                    && to.is_none_or(|to| row.timestamp < to)
+            }));
+        }
+        rows.sort_by_key(Row::keyset);
+        Ok(rows)
+    }
+
+    /// Log readings and add them to the head, sealing it once full
+    fn append(&mut self, rows: Vec<Row>) -> Result<(), BsError> {
+        let mut lines = Vec::new();
+        for row in &rows {
+            serde_json::to_writer(&mut lines, row)?;
+            lines.push(b'\n');
+        }
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.directory.join(HEAD_LOG))?;
+        log.write_all(&lines)?;
+        log.sync_data()?;
+
+        for row in rows {
+            let at = self
+                .head
+                .partition_point(|stored| stored.keyset() < row.keyset());
+            self.head.insert(at, row);
+        }
+        if self.head.len() >= CHUNK_ROWS {
+            self.seal()?;
+        }
+        Ok(())
+    }
+
+    fn seal(&mut self) -> Result<(), BsError> {
+        let chunk = ChunkInfo::write(&self.directory, &self.head)?;
+        let at = self
+            .chunks
+            .partition_point(|sealed| sealed.header.first < chunk.header.first);
+        self.chunks.insert(at, chunk);
+        self.head.clear();
+        self.write_head()
+    }
+
+    fn write_head(&self) -> Result<(), BsError> {
+        let mut lines = Vec::new();
+        for row in &self.head {
+            serde_json::to_writer(&mut lines, row)?;
+            lines.push(b'\n');
+        }
+        write_atomically(&self.directory.join(HEAD_LOG), &lines)
+    }
+
+    /// Let `edit` change the readings of every chunk, and of the head,
+    /// that may hold readings taken within `from..to`. Chunks `edit`
+    /// reports changed are rewritten. Returns whether anything changed.
+    fn rewrite(
+        &mut self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        mut edit: impl FnMut(&mut Vec<Row>) -> bool,
+    ) -> Result<bool, BsError> {
+        let mut changed = false;
+        let mut chunks = Vec::with_capacity(self.chunks.len());
+        for chunk in std::mem::take(&mut self.chunks) {
+            if !Source::Chunk(&chunk).overlaps(from, to) {
+                chunks.push(chunk);
+                continue;
+            }
+            let mut rows = match chunk.rows(Projection::ALL) {
+                Ok(rows) => rows,
+                Err(e) => {
+                    self.restore(chunks, chunk);
+                    return Err(e);
+                }
+            };
+            if !edit(&mut rows) {
+                chunks.push(chunk);
+                continue;
+            }
+            changed = true;
+            rows.sort_by_key(Row::keyset);
+            let written = match rows.is_empty() {
+                true => Ok(None),
+                false => ChunkInfo::write(&self.directory, &rows).map(Some),
+            };
+            match written {
+                Ok(written) => {
+                    let replaced = written
+                        .as_ref()
+                        .is_some_and(|written| written.path == chunk.path);
+                    chunks.extend(written);
+                    if !replaced && let Err(e) = remove_file(&chunk.path) {
+                        self.restore(chunks, chunk);
+                        return Err(e);
+                    }
+                }
+                Err(e) => {
+                    self.restore(chunks, chunk);
+                    return Err(e);
+                }
+            }
+        }
+        chunks.sort_by_key(|chunk| chunk.header.first);
+        self.chunks = chunks;
+
+        if !self.head.is_empty()
+            && Source::Head(&self.head).overlaps(from, to)
+            && edit(&mut self.head)
+        {
+            changed = true;
+            self.head.sort_by_key(Row::keyset);
+            self.write_head()?;
+        }
+        Ok(changed)
+    }
+
+    /// Put back the index of a rewrite that failed halfway
+    fn restore(&mut self, mut done: Vec<ChunkInfo>, failed: ChunkInfo) {
+        done.push(failed);
+        done.append(&mut self.chunks);
+        done.sort_by_key(|chunk| chunk.header.first);
+        self.chunks = done;
+    }
+
+    /// Readings taken before the cutoff
+    fn count_before(&self, cutoff: DateTime<Utc>) -> Result<u64, BsError> {
+        let mut count = 0;
+        for source in self
+            .sources()
+            .filter(|source| source.overlaps(None, Some(cutoff)))
+        {
+            count += if source.last().timestamp < cutoff {
+                match source {
+                    Source::Chunk(chunk) => chunk.header.rows,
+                    Source::Head(rows) => rows.len(),
+                }
+            } else {
+                let rows = source.rows(Projection::KEYS)?;
+                rows.iter().filter(|row| row.timestamp < cutoff).count()
+            } as u64;
+        }
+        Ok(count)
+    }
+
+    /// Remove readings taken before the cutoff. Chunks holding nothing
+    /// newer are deleted without being read.
+    fn expire(&mut self, cutoff: DateTime<Utc>) -> Result<u64, BsError> {
+        let mut removed = 0;
+        let (expired, kept) = std::mem::take(&mut self.chunks)
+            .into_iter()
+            .partition(|chunk| chunk.header.last.timestamp < cutoff);
+        self.chunks = kept;
+        for chunk in expired {
+            remove_file(&chunk.path)?;
+            removed += chunk.header.rows as u64;
+        }
+        self.rewrite(None, Some(cutoff), |rows| {
+            let before = rows.len();
+            rows.retain(|row| row.timestamp >= cutoff);
+            removed += (before - rows.len()) as u64;
+            rows.len() < before
+        })?;
+        Ok(removed)
+    }
+}
+
+/// Directory of a sensor, its id is hex encoded as sensor ids may hold anything
+fn series_directory_name(sensor_id: &str) -> String {
+    let hex: String = sensor_id
+        .bytes()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("sensor-{hex}")
+}
+
+fn parse_series_directory_name(name: &str) -> Option<String> {
+    let name = name.strip_prefix("sensor-")?;
+    let bytes = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(name.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+#[derive(Debug)]
+struct Store {
+    directory: PathBuf,
+    next_id: i64,
+    series: BTreeMap<String, SensorSeries>,
+}
+
+impl Store {
+    fn series_mut(&mut self, sensor_id: &str) -> Result<&mut SensorSeries, BsError> {
+        if !self.series.contains_key(sensor_id) {
+            let directory = self.directory.join(series_directory_name(sensor_id));
+            self.series
+                .insert(sensor_id.to_string(), SensorSeries::open(directory)?);
+        }
+        Ok(self
+            .series
+            .get_mut(sensor_id)
+            .expect("Series was just opened"))
+    }
+
+    fn matching<'a>(
+        &'a self,
+        sensor_id: Option<&'a str>,
+    ) -> impl Iterator<Item = (&'a String, &'a SensorSeries)> {
+        self.series
+            .iter()
+            .filter(move |(id, _)| sensor_id.is_none_or(|wanted| wanted == id.as_str()))
+    }
+
+    fn oldest(&self) -> Option<DateTime<Utc>> {
+        self.series.values().filter_map(SensorSeries::oldest).min()
+    }
+}
+
+fn aggregate(rollups: &mut BTreeMap<RollupKey, Aggregate>, sensor_id: &str, rows: &[Row]) {
+    for row in rows {
+        for (metric, value) in &row.values {
+            rollup::add_to_rollups(rollups, sensor_id, row.timestamp, metric, *value);
+        }
+    }
+}
+
+fn matches_filters(filters: &QueryFilter, sensor_id: &str, row: &Row) -> bool {
+    if filters.from.is_some_and(|from| row.timestamp < from)
+        || filters.to.is_some_and(|to| row.timestamp >= to)
+    {
+        return false;
+    }
+    if filters
+        .subscriptions
+        .as_ref()
+        .is_some_and(|subscriptions| !subscriptions.matches(sensor_id, row.values.keys()))
+    {
+        return false;
+    }
+    if let Some(metric) = &filters.metric {
+        let Some(value) = row.values.get(metric) else {
+            return false;
+        };
+        if filters.min.is_some_and(|min| *value < min)
+            || filters.max.is_some_and(|max| *value > max)
+        {
+            return false;
+        }
+    }
+    true
+}
+
+fn project(columns: &[String], sensor_id: &str, row: &Row) -> SensorReading {
+    let mut projected = SensorReading {
+        sensor_id: None,
+        topic: None,
+        timestamp: None,
+        metrics: BTreeMap::new(),
+    };
+    for col in columns {
+        match col.as_str() {
+            "sensor_id" => projected.sensor_id = Some(sensor_id.to_string()),
+            "topic" => projected.topic = Some(row.topic.clone()),
+            "timestamp" => projected.timestamp = Some(row.timestamp),
+            metric => {
+                if let Some(value) = row.values.get(metric) {
+                    projected.metrics.insert(metric.to_string(), *value);
+                }
+            }
+        }
+    }
+    projected
+}
+
+/// Readings are written one sensor at a time under a single lock, reads
+/// share it.
+#[derive(Debug, Clone)]
+pub struct ColumnarRepository {
+    pool: SqlitePool,
+    catalogue: SqliteRepository,
+    store: Arc<RwLock<Store>>,
+    cursor_key: CursorKey,
+    conflict_policy: ConflictPolicy,
+}
+
+impl ColumnarRepository {
+    /// Open the chunks found in `directory`, creating it when missing.
+    /// Readings still stored in the catalogue are moved into chunks.
+    pub async fn open(pool: SqlitePool, directory: impl Into<PathBuf>) -> Result<Self, BsError> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+
+        let mut series = BTreeMap::new();
+        for entry in fs::read_dir(&directory)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if let Some(sensor_id) = name.to_str().and_then(parse_series_directory_name)
+                && entry.file_type()?.is_dir()
+            {
+                series.insert(sensor_id, SensorSeries::open(entry.path())?);
+            }
+        }
+        let next_id = series
+            .values()
+            .filter_map(SensorSeries::max_id)
+            .max()
+            .unwrap_or(0)
+            + 1;
+
+        let repository = Self {
+            catalogue: SqliteRepository::new(pool.clone()),
+            pool,
+            store: Arc::new(RwLock::new(Store {
+                directory,
+                next_id,
+                series,
+            })),
+            cursor_key: CursorKey::random(),
+            conflict_policy: ConflictPolicy::default(),
+        };
+        repository.move_catalogue_readings().await?;
+
+        Ok(repository)
+    }
+
+    /// Sign cursors with a fixed key so they survive restarts
+    pub fn with_cursor_key(mut self, cursor_key: CursorKey) -> Self {
+        self.cursor_key = cursor_key;
+        self
+    }
+
+    /// Move readings stored before chunks were enabled, oldest ids first.
+    /// They keep their ids, readings below the next id were already moved
+    /// before a crash.
+    async fn move_catalogue_readings(&self) -> Result<(), BsError> {
+        let mut store = self.store.write().await;
+        loop {
+            let rows = sqlx::query(
+                "SELECT r.id, r.sensor_id, r.topic, r.timestamp, r.sequence, v.metric, v.value, \
+                 v.raw_value
+                    FROM sensor_readings r LEFT JOIN reading_values v ON v.reading_id = r.id
+                    WHERE r.id IN (SELECT id FROM sensor_readings ORDER BY id LIMIT ?)
+                    ORDER BY r.id",
+            )
+            .bind(MOVE_BATCH_SIZE)
+            .fetch_all(&self.pool)
+            .await?;
+            let Some(last_id) = rows.last().map(|row| row.get::<i64, _>("id")) else {
+                break;
+            };
+
+            let mut moved: BTreeMap<String, Vec<Row>> = BTreeMap::new();
+            for row in rows {
+                let id: i64 = row.get("id");
+                if id < store.next_id {
+                    continue;
+                }
+                let readings = moved.entry(row.get("sensor_id")).or_default();
+                if readings.last().is_none_or(|reading| reading.id != id) {
+                    readings.push(Row {
+                        id,
+                        timestamp: row.get("timestamp"),
+                        topic: row.get("topic"),
+                        sequence: row.get("sequence"),
+                        values: BTreeMap::new(),
+                        raw: BTreeMap::new(),
+                    });
+                }
+                if let Some(metric) = row.get::<Option<String>, _>("metric") {
+                    let reading = readings.last_mut().expect("Reading was just added");
+                    reading.values.insert(metric.clone(), row.get("value"));
+                    reading.raw.insert(metric, row.get("raw_value"));
+                }
+            }
+            let count: usize = moved.values().map(Vec::len).sum();
+            for (sensor_id, readings) in moved {
+                store.series_mut(&sensor_id)?.append(readings)?;
+            }
+            store.next_id = store.next_id.max(last_id + 1);
+
+            sqlx::query("DELETE FROM sensor_readings WHERE id <= ?")
+                .bind(last_id)
+                .execute(&self.pool)
+                .await?;
+            info!("Moved {count} readings into chunks");
+        }
+
+        // Ids carry on from here should the catalogue store readings again
+        let last_id = store.next_id - 1;
+        sqlx::query("UPDATE sqlite_sequence SET seq = MAX(seq, ?) WHERE name = 'sensor_readings'")
+            .bind(last_id)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            "INSERT INTO sqlite_sequence (name, seq) SELECT 'sensor_readings', ?
+                WHERE NOT EXISTS (SELECT 1 FROM sqlite_sequence WHERE name = 'sensor_readings')",
+        )
+        .bind(last_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns whether the reading was stored, readings the policy ignores are not
+    async fn insert_reading(
+        &self,
+        store: &mut Store,
+        topic: String,
+        reading: SensorReadingEvent,
+    ) -> Result<bool, BsError> {
+        let calibrations: Vec<Calibration> = self
+            .catalogue
+            .fetch_calibrations(Some(reading.sensor_id.clone()))
+            .await?
+            .into_iter()
+            .map(|stored| stored.calibration)
+            .collect();
+        let id = store.next_id;
+        let series = store.series_mut(&reading.sensor_id)?;
+
+        let (from, to) = (
+            reading.timestamp,
+            reading.timestamp + TimeDelta::nanoseconds(1),
+        );
+        let same =
+            |row: &Row| row.timestamp == reading.timestamp && row.sequence == reading.sequence;
+        let replaced = match self.conflict_policy {
+            ConflictPolicy::Ignore => {
+                let stored = series.scan(Some(from), Some(to), Projection::KEYS)?;
+                if stored.iter().any(same) {
+                    return Ok(false);
+                }
+                false
+            }
+            ConflictPolicy::Replace => series.rewrite(Some(from), Some(to), |rows| {
+                let before = rows.len();
+                rows.retain(|row| !same(row));
+                rows.len() < before
+            })?,
+            ConflictPolicy::KeepBoth => false,
+        };
+
+        let values = calibrate(&calibrations, reading.timestamp, &reading.metrics);
+        series.append(vec![Row {
+            id,
+            timestamp: reading.timestamp,
+            topic,
+            sequence: reading.sequence,
+            values: values.clone(),
+            raw: reading.metrics,
+        }])?;
+        store.next_id += 1;
+
+        // The reading is stored by now, a failure past this point leaves the
+        // rollups behind until they are rebuilt
+        self.catalogue
+            .fold_into_rollups(&reading.sensor_id, reading.timestamp, &values)
+            .await?;
+        // The rollups still count the values of the replaced reading
+        if replaced {
+            let from = Resolution::Day.bucket(reading.timestamp);
+            let to = from + TimeDelta::days(1);
+            let rows = store.series_mut(&reading.sensor_id)?.scan(
+                Some(from),
+                Some(to),
+                Projection::VALUES,
+            )?;
+            let mut rollups = BTreeMap::new();
+            aggregate(&mut rollups, &reading.sensor_id, &rows);
+            self.catalogue
+                .replace_rollups(from, to, Some(&reading.sensor_id), &rollups)
+                .await?;
+        }
+        Ok(true)
+    }
+
+    /// Count or remove expired readings
+    async fn expire_readings(
+        &self,
+        target: &RetentionTarget,
+        dry_run: bool,
+    ) -> Result<u64, BsError> {
+        let mut store = self.store.write().await;
+        let mut rows = 0;
+        for (sensor_id, series) in store.series.iter_mut() {
+            if !target.covers(sensor_id) {
+                continue;
+            }
+            rows += if dry_run {
+                series.count_before(target.cutoff)?
+            } else {
+                series.expire(target.cutoff)?
+            };
+        }
+        Ok(rows)
+    }
+}
+
+#[async_trait]
+impl Repository for ColumnarRepository {
+    fn with_conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict_policy = policy;
+        self
+    }
+
+    async fn insert_sensor_reading(
+        &self,
+        topic: String,
+        reading: SensorReadingEvent,
+    ) -> Result<(), BsError> {
+        let mut store = self.store.write().await;
+        self.insert_reading(&mut store, topic, reading).await?;
+
+        Ok(())
+    }
+
+    async fn insert_sensor_readings(
+        &self,
+        topic: String,
+        readings: Vec<SensorReadingEvent>,
+    ) -> Result<u64, BsError> {
+        let mut store = self.store.write().await;
+        let mut inserted = 0;
+        for reading in readings {
+            if self
+                .insert_reading(&mut store, topic.clone(), reading)
+                .await?
+            {
+                inserted += 1;
+            }
+        }
+
+        Ok(inserted)
+    }
+
+    async fn fetch_metrics(&self) -> Result<Vec<Metric>, BsError> {
+        self.catalogue.fetch_metrics().await
+    }
+
+    async fn upsert_metric(&self, metric: Metric) -> Result<(), BsError> {
+        self.catalogue.upsert_metric(metric).await
+    }
+
+    async fn fetch_sensors(&self) -> Result<Vec<Sensor>, BsError> {
+        self.catalogue.fetch_sensors().await
+    }
+
+    async fn fetch_sensor(&self, sensor_id: &str) -> Result<Option<Sensor>, BsError> {
+        self.catalogue.fetch_sensor(sensor_id).await
+    }
+
+    async fn upsert_sensor(
+        &self,
+        sensor_id: &str,
+        metadata: SensorMetadata,
+    ) -> Result<(), BsError> {
+        self.catalogue.upsert_sensor(sensor_id, metadata).await
+    }
+
+    async fn delete_sensor(&self, sensor_id: &str) -> Result<bool, BsError> {
+        self.catalogue.delete_sensor(sensor_id).await
+    }
+
+    async fn quarantine_sensor_reading(
+        &self,
+        topic: String,
+        reading: SensorReadingEvent,
+    ) -> Result<(), BsError> {
+        self.catalogue
+            .quarantine_sensor_reading(topic, reading)
+            .await
+    }
+
+    async fn approve_sensor(&self, sensor_id: &str) -> Result<Option<u64>, BsError> {
+        let updated = sqlx::query!(
+            "UPDATE sensors SET status = 'approved' WHERE sensor_id = ?",
+            sensor_id
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        if updated == 0 {
+            return Ok(None);
+        }
+
+        let quarantined = sqlx::query!(
+            r#"SELECT topic, timestamp AS "timestamp: DateTime<Utc>", sequence, metrics
+            FROM quarantined_readings WHERE sensor_id = ? ORDER BY timestamp, id"#,
+            sensor_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        // The quarantine is only cleared once every reading is stored. Approving
+        // again after a failure is safe as redelivered readings are ignored.
+        for row in &quarantined {
+            let reading = SensorReadingEvent {
+                sensor_id: sensor_id.to_string(),
+                timestamp: row.timestamp,
+                sequence: row.sequence,
+                metrics: serde_json::from_str(&row.metrics)?,
+            };
+            self.insert_sensor_reading(row.topic.clone(), reading)
+                .await?;
+        }
+        sqlx::query!(
+            "DELETE FROM quarantined_readings WHERE sensor_id = ?",
+            sensor_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(Some(quarantined.len() as u64))
+    }
+
+    async fn fetch_calibrations(
+        &self,
+        sensor_id: Option<String>,
+    ) -> Result<Vec<StoredCalibration>, BsError> {
+        self.catalogue.fetch_calibrations(sensor_id).await
+    }
+
+    async fn insert_calibration(&self, calibration: Calibration) -> Result<i64, BsError> {
+        self.catalogue.insert_calibration(calibration).await
+    }
+
+    async fn delete_calibration(&self, id: i64) -> Result<bool, BsError> {
+        self.catalogue.delete_calibration(id).await
+    }
+
+    async fn fetch_api_keys(&self) -> Result<Vec<ApiKey>, BsError> {
+        self.catalogue.fetch_api_keys().await
+    }
+
+    async fn insert_api_key(&self, key: &ApiKey, secret_hash: &str) -> Result<i64, BsError> {
+        self.catalogue.insert_api_key(key, secret_hash).await
+    }
+
+    async fn fetch_api_key(&self, secret_hash: &str) -> Result<Option<ApiKey>, BsError> {
+        self.catalogue.fetch_api_key(secret_hash).await
+    }
+
+    async fn revoke_api_key(&self, id: i64) -> Result<bool, BsError> {
+        self.catalogue.revoke_api_key(id).await
+    }
+
+    async fn recompute_calibrations(&self, sensor_id: Option<String>) -> Result<u64, BsError> {
+        let calibrations = self.catalogue.fetch_calibrations(sensor_id.clone()).await?;
+        let mut recomputed = 0;
+        // Held so no reading slips past the refreshed rollups
+        let mut store = self.store.write().await;
+        let mut rollups = BTreeMap::new();
+        for (id, series) in store.series.iter_mut() {
+            if sensor_id.as_ref().is_some_and(|wanted| wanted != id) {
+                continue;
+            }
+            let calibrations: Vec<Calibration> = calibrations
+                .iter()
+                .filter(|stored| &stored.calibration.sensor_id == id)
+                .map(|stored| stored.calibration.clone())
+                .collect();
+            series.rewrite(None, None, |rows| {
+                let mut changed = false;
+                for row in rows.iter_mut() {
+                    let values = calibrate(&calibrations, row.timestamp, &row.raw);
+                    recomputed += values.len() as u64;
+                    if values != row.values {
+                        row.values = values;
+                        changed = true;
+                    }
+                }
+                changed
+            })?;
+            let rows = series.scan(None, None, Projection::VALUES)?;
+            aggregate(&mut rollups, id, &rows);
+        }
+        if recomputed > 0 {
+            self.catalogue.upsert_rollups(&rollups).await?;
+        }
+
+        Ok(recomputed)
+    }
+
+    async fn fetch_latest_readings(&self) -> Result<Vec<SensorReading>, BsError> {
+        let projection = Projection {
+            topic: true,
+            metrics: None,
+            raw: false,
+        };
+        let store = self.store.read().await;
+        let mut readings = Vec::new();
+        for (sensor_id, series) in &store.series {
+            if let Some(row) = series.latest(projection)? {
+                readings.push(SensorReading {
+                    sensor_id: Some(sensor_id.clone()),
+                    topic: Some(row.topic),
+                    timestamp: Some(row.timestamp),
+                    metrics: row.values,
+                });
+            }
+        }
+        Ok(readings)
+    }
+
+    async fn fetch_sensor_readings_page(
+        &self,
+        query: MeasurementQuery,
+    ) -> Result<SensorReadingsPage, BsError> {
+        query.validate(&self.fetch_metrics().await?)?;
+        let position = query.position(&self.cursor_key)?.map(|c| c.keyset());
+        let order = query.scan_order();
+        let filters = &query.filters;
+        let beyond = |keyset: Keyset| {
+            position.is_none_or(|position| match order {
+                SortOrder::Asc => keyset > position,
+                SortOrder::Desc => keyset < position,
+            })
+        };
+
+        let mut metrics: Vec<String> = query.metric_columns().map(str::to_string).collect();
+        metrics.extend(filters.metric.clone());
+        if let Some(subscriptions) = &filters.subscriptions {
+            metrics.extend(subscriptions.metrics.iter().cloned());
+        }
+        let projection = Projection {
+            topic: query.columns.iter().any(|c| c == "topic"),
+            metrics: Some(&metrics),
+            raw: false,
+        };
+
+        let store = self.store.read().await;
+        let mut sources: Vec<(&str, Source<'_>)> = store
+            .matching(filters.sensor_id.as_deref())
+            .flat_map(|(sensor_id, series)| {
+                series
+                    .sources()
+                    .map(move |source| (sensor_id.as_str(), source))
+            })
+            .filter(|(_, source)| {
+                source.overlaps(filters.from, filters.to)
+                    && filters.metric.as_ref().is_none_or(|m| source.has_metric(m))
+                    && beyond(match order {
+                        SortOrder::Asc => source.last(),
+                        SortOrder::Desc => source.first(),
+                    })
+            })
+            .collect();
+        match order {
+            SortOrder::Asc => sources.sort_by_key(|(_, source)| source.first()),
+            SortOrder::Desc => sources.sort_by_key(|(_, source)| Reverse(source.last())),
+        }
+
+        // One extra row tells us whether there is a next page
+        let limit = query.pagination.page_size + 1;
+        let mut readings: Vec<(Keyset, SensorReading)> = Vec::new();
+        for (sensor_id, source) in sources {
+            // Sources are visited in order, once this one starts past the
+            // last row of the page so do all that follow
+            if let Some((boundary, _)) = readings.get(limit - 1) {
+                let past = match order {
+                    SortOrder::Asc => source.first() > *boundary,
+                    SortOrder::Desc => source.last() < *boundary,
+                };
+                if past {
+                    break;
+                }
+            }
+            for row in source.rows(projection)? {
+                if beyond(row.keyset()) && matches_filters(filters, sensor_id, &row) {
+                    readings.push((row.keyset(), project(&query.columns, sensor_id, &row)));
+                }
+            }
+            match order {
+                SortOrder::Asc => readings.sort_by_key(|(keyset, _)| *keyset),
+                SortOrder::Desc => readings.sort_by_key(|(keyset, _)| Reverse(*keyset)),
+            }
+            readings.truncate(limit);
+        }
+        drop(store);
+
+        let has_more = readings.len() > query.pagination.page_size;
+        readings.truncate(query.pagination.page_size);
+
+        Ok(query.into_page(&self.cursor_key, readings, has_more))
+    }
+
+    async fn fetch_stored_readings(
+        &self,
+        after: i64,
+        query: MeasurementQuery,
+    ) -> Result<Vec<(i64, SensorReading)>, BsError> {
+        query.validate(&self.fetch_metrics().await?)?;
+        let filters = &query.filters;
+        let mut metrics: Vec<String> = query.metric_columns().map(str::to_string).collect();
+        metrics.extend(filters.metric.clone());
+        if let Some(subscriptions) = &filters.subscriptions {
+            metrics.extend(subscriptions.metrics.iter().cloned());
+        }
+        let projection = Projection {
+            topic: query.columns.iter().any(|c| c == "topic"),
+            metrics: Some(&metrics),
+            raw: false,
+        };
+
+        // Ids are handed out in the order readings are stored, only sources
+        // holding readings stored after the position are decoded
+        let store = self.store.read().await;
+        let mut readings = Vec::new();
+        for (sensor_id, series) in store.matching(filters.sensor_id.as_deref()) {
+            let sources = series.sources().filter(|source| {
+                source.max_id() > after
+                    && source.overlaps(filters.from, filters.to)
+                    && filters.metric.as_ref().is_none_or(|m| source.has_metric(m))
+            });
+            for source in sources {
+                for row in source.rows(projection)? {
+                    if row.id > after && matches_filters(filters, sensor_id, &row) {
+                        readings.push((row.id, project(&query.columns, sensor_id, &row)));
+                    }
+                }
+            }
+        }
+        drop(store);
+
+        readings.sort_by_key(|(position, _)| *position);
+        readings.truncate(query.pagination.page_size);
+        Ok(readings)
+    }
+
+    async fn fetch_last_stored_position(&self) -> Result<i64, BsError> {
+        Ok(self.store.read().await.next_id - 1)
+    }
+
+    async fn fetch_series(&self, query: SeriesQuery) -> Result<Series, BsError> {
+        query.validate()?;
+        let resolution = query.effective_resolution();
+        if resolution != Resolution::Raw {
+            return self.catalogue.fetch_series(query).await;
+        }
+
+        let metrics = [query.metric.clone()];
+        let projection = Projection {
+            topic: false,
+            metrics: Some(&metrics),
+            raw: false,
+        };
+        let (from, to) = (Some(query.from), Some(query.to));
+        let store = self.store.read().await;
+        let mut points = Vec::new();
+        for (sensor_id, series) in store.matching(query.sensor_id.as_deref()) {
+            let sources = series
+                .sources()
+                .filter(|source| source.overlaps(from, to) && source.has_metric(&query.metric));
+            for source in sources {
+                for row in source.rows(projection)? {
+                    let Some(value) = row.values.get(&query.metric) else {
+                        continue;
+                    };
+                    if row.timestamp >= query.from && row.timestamp < query.to {
+                        points.push((
+                            row.keyset(),
+                            SeriesPoint {
+                                sensor_id: sensor_id.clone(),
+                                timestamp: row.timestamp,
+                                min: *value,
+                                max: *value,
+                                avg: *value,
+                                count: 1,
+                            },
+                        ));
+                    }
+                }
+            }
+        }
+        drop(store);
+        points.sort_by_key(|(keyset, _)| *keyset);
+
+        Ok(Series {
+            metric: query.metric,
+            resolution,
+            points: points.into_iter().map(|(_, point)| point).collect(),
+        })
+    }
+
+    async fn rebuild_rollups(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<u64, BsError> {
+        // Held so no reading slips past the rebuild
+        let store = self.store.write().await;
+        let Some(from) = from.or_else(|| store.oldest()) else {
+            return Ok(0);
+        };
+        let to = to.unwrap_or_else(Utc::now);
+        // Whole days so the hourly and daily buckets stay consistent
+        let from = Resolution::Day.bucket(from);
+        let to = Resolution::Day.bucket(to) + TimeDelta::days(1);
+
+        let mut rebuilt = 0;
+        for (sensor_id, series) in &store.series {
+            let Some(oldest) = series.oldest() else {
+                continue;
+            };
+            let rows = series.scan(Some(from), Some(to), Projection::VALUES)?;
+            let mut rollups = BTreeMap::new();
+            aggregate(&mut rollups, sensor_id, &rows);
+            // Buckets starting before the oldest reading of the sensor may
+            // count expired readings, they are only replaced by bigger aggregates
+            let (expired, covered): (BTreeMap<_, _>, BTreeMap<_, _>) = rollups
+                .into_iter()
+                .partition(|((_, _, bucket, _), _)| *bucket < oldest);
+            rebuilt += self
+                .catalogue
+                .replace_rollups(from.max(oldest), to, Some(sensor_id), &covered)
+                .await?;
+            rebuilt += self.catalogue.upsert_rollups(&expired).await?;
+        }
+        Ok(rebuilt)
+    }
+
+    async fn fetch_retention_policies(&self) -> Result<Vec<RetentionPolicy>, BsError> {
+        self.catalogue.fetch_retention_policies().await
+    }
+
+    async fn upsert_retention_policy(&self, policy: RetentionPolicy) -> Result<(), BsError> {
+        self.catalogue.upsert_retention_policy(policy).await
+    }
+
+    async fn delete_retention_policy(
+        &self,
+        sensor_id: Option<String>,
+        resolution: Resolution,
+    ) -> Result<bool, BsError> {
+        self.catalogue
+            .delete_retention_policy(sensor_id, resolution)
+            .await
+    }
+
+    async fn remove_duplicate_readings(&self, dry_run: bool) -> Result<u64, BsError> {
+        let mut removed = 0;
+        let mut store = self.store.write().await;
+        for (sensor_id, series) in store.series.iter_mut() {
+            // Scanned in (timestamp, id) order so the earliest stored copy is kept
+            let mut seen = HashSet::new();
+            let mut buckets = BTreeSet::new();
+            let duplicates: HashSet<i64> = series
+                .scan(None, None, Projection::KEYS)?
+                .into_iter()
+                .filter(|row| !seen.insert((row.timestamp, row.sequence)))
+                .inspect(|row| {
+                    buckets.extend(
+                        Resolution::ROLLED_UP
+                            .map(|resolution| (resolution, resolution.bucket(row.timestamp))),
+                    );
+                })
+                .map(|row| row.id)
+                .collect();
+            removed += duplicates.len() as u64;
+            if dry_run || duplicates.is_empty() {
+                continue;
+            }
+            series.rewrite(None, None, |rows| {
+                let before = rows.len();
+                rows.retain(|row| !duplicates.contains(&row.id));
+                rows.len() < before
+            })?;
+
+            // Only the buckets of the removed copies, others may hold
+            // rollups of expired readings
+            let mut rollups = BTreeMap::new();
+            for (_, day) in buckets.iter().filter(|(r, _)| *r == Resolution::Day) {
+                let rows = series.scan(
+                    Some(*day),
+                    Some(*day + TimeDelta::days(1)),
+                    Projection::VALUES,
+                )?;
+                aggregate(&mut rollups, sensor_id, &rows);
+            }
+            rollups
+                .retain(|(resolution, _, bucket, _), _| buckets.contains(&(*resolution, *bucket)));
+            self.catalogue
+                .replace_sensor_rollups(sensor_id, &buckets, &rollups)
+                .await?;
+        }
+
+        Ok(removed)
+    }
+
+    async fn enforce_retention(&self, dry_run: bool) -> Result<RetentionReport, BsError> {
+        let policies = self.fetch_retention_policies().await?;
+        let mut entries = Vec::new();
+        let mut rollups_removed = false;
+        for target in RetentionTarget::from_policies(&policies, Utc::now()) {
+            let rows = match target.resolution {
+                Resolution::Raw => self.expire_readings(&target, dry_run).await?,
+                _ if dry_run => self.catalogue.count_expired(&target).await?,
+                _ => {
+                    let rows = self.catalogue.delete_expired(&target).await?;
+                    rollups_removed |= rows > 0;
+                    rows
+                }
+            };
+            entries.push(RetentionReportEntry {
+                sensor_id: target.sensor_id,
+                resolution: target.resolution,
+                cutoff: target.cutoff,
+                rows,
+            });
+        }
+
+        if !dry_run && rollups_removed {
+            self.catalogue.incremental_vacuum().await?;
+        }
+
+        Ok(RetentionReport { dry_run, entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use sqlx::sqlite::SqliteConnectOptions;
+
+    use super::*;
+    use crate::db::Pagination;
+    use crate::db::conformance::{TestBackend, conformance_tests};
+    use crate::db::schema::SQLITE_MIGRATOR;
+
+    struct ScratchDirectory(PathBuf);
+
+    impl ScratchDirectory {
+        fn new() -> Self {
+            let name = format!("base-station-chunks-{}", rand::random::<u64>());
+            let path = std::env::temp_dir().join(name);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for ScratchDirectory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn catalogue(directory: &ScratchDirectory) -> SqlitePool {
+        let options = SqliteConnectOptions::new()
+            .filename(directory.0.join("catalogue.db"))
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        pool
+    }
+
+    struct ColumnarBackend {
+        repository: ColumnarRepository,
+        _directory: ScratchDirectory,
+    }
+
+    impl TestBackend for ColumnarBackend {
+        type Repository = ColumnarRepository;
+
+        async fn create() -> Option<Self> {
+            let directory = ScratchDirectory::new();
+            let pool = catalogue(&directory).await;
+            let repository = ColumnarRepository::open(pool, directory.0.join("chunks"))
+                .await
+                .unwrap();
+            Some(Self {
+                repository,
+                _directory: directory,
+            })
+        }
+
+        fn repository(&self) -> ColumnarRepository {
+            self.repository.clone()
+        }
+    }
+
+    conformance_tests!(ColumnarBackend);
+
+    /// A day of readings every minute, values changing slowly like they do
+    fn weather(minutes: i64) -> Vec<SensorReadingEvent> {
+        let start = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap();
+        (0..minutes)
+            .map(|i| SensorReadingEvent {
+                sensor_id: "outside".to_string(),
+                timestamp: start + TimeDelta::minutes(i) + TimeDelta::milliseconds(i % 7 * 3),
+                sequence: None,
+                metrics: [
+                    ("temperature".to_string(), 20.0 + (i / 20) as f64 * 0.5),
+                    ("humidity".to_string(), 40.0 + (i / 45) as f64),
+                    ("pressure".to_string(), 101_325.0 + (i / 30) as f64 * 10.0),
+                ]
+                .into(),
+            })
+            .collect()
+    }
+
+    fn chunk_files(directory: &Path) -> Vec<PathBuf> {
+        let series = directory.join(series_directory_name("outside"));
+        let mut files: Vec<PathBuf> = fs::read_dir(series)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "chunk")
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn everything() -> MeasurementQuery {
+        MeasurementQuery {
+            filters: QueryFilter::default(),
+            pagination: Pagination {
+                page_size: 5000,
+                ..Default::default()
+            },
+            columns: vec![
+                "timestamp".to_string(),
+                "topic".to_string(),
+                "temperature".to_string(),
+            ],
+        }
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let start = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap();
+        let rows: Vec<Row> = (0..50)
+            .map(|i| Row {
+                id: 100 + i,
+                timestamp: start + TimeDelta::seconds(i * 60),
+                topic: if i < 30 {
+                    "sensor/update"
+                } else {
+                    "sensor/other"
+                }
+                .to_string(),
+                sequence: (i % 3 == 0).then_some(i),
+                values: [("temperature".to_string(), 20.5 + i as f64)]
+                    .into_iter()
+                    .chain((i % 2 == 0).then(|| ("humidity".to_string(), 40.0)))
+                    .collect(),
+                raw: [("temperature".to_string(), 20.0 + i as f64)]
+                    .into_iter()
+                    .chain((i % 2 == 0).then(|| ("humidity".to_string(), 40.0)))
+                    .collect(),
+            })
+            .collect();
+        let bytes = encode_chunk(&rows);
+
+        assert_eq!(decode_chunk(&bytes, Projection::ALL).unwrap(), rows);
+        let keys = decode_chunk(&bytes, Projection::KEYS).unwrap();
+        assert!(
+            keys.iter()
+                .all(|row| row.values.is_empty() && row.topic.is_empty())
+        );
+        assert_eq!(keys[3].sequence, Some(3));
+        let header = read_header(&mut ChunkReader {
+            bytes: &bytes,
+            position: 0,
+        })
+        .unwrap();
+        assert_eq!(header.metrics, vec!["humidity", "temperature"]);
+        assert_eq!(header.max_id, 149);
+        assert!(decode_chunk(&bytes[..bytes.len() - 1], Projection::ALL).is_err());
+    }
+
+    #[tokio::test]
+    async fn chunks_are_a_tenth_of_sqlite() {
+        let directory = ScratchDirectory::new();
+        let chunks = directory.0.join("chunks");
+        let repo = ColumnarRepository::open(catalogue(&directory).await, &chunks)
+            .await
+            .unwrap();
+        let readings = weather(CHUNK_ROWS as i64 * 2);
+        repo.insert_sensor_readings("sensor/update".to_string(), readings)
+            .await
+            .unwrap();
+        let chunk_bytes: u64 = chunk_files(&chunks)
+            .iter()
+            .map(|path| fs::metadata(path).unwrap().len())
+            .sum();
+
+        let options = SqliteConnectOptions::new()
+            .filename(directory.0.join("plain.db"))
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        let page_bytes = || async {
+            let pages: i64 = sqlx::query_scalar("PRAGMA page_count")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            pages * 4096
+        };
+        let empty = page_bytes().await;
+        SqliteRepository::new(pool.clone())
+            .insert_sensor_readings("sensor/update".to_string(), weather(CHUNK_ROWS as i64 * 2))
+            .await
+            .unwrap();
+        let sqlite_bytes = (page_bytes().await - empty) as u64;
+
+        assert_eq!(chunk_files(&chunks).len(), 2);
+        assert!(
+            chunk_bytes * 10 <= sqlite_bytes,
+            "{chunk_bytes} bytes of chunks, {sqlite_bytes} bytes in SQLite"
+        );
+    }
+
+    #[tokio::test]
+    async fn readings_survive_a_restart() {
+        let directory = ScratchDirectory::new();
+        let chunks = directory.0.join("chunks");
+        let pool = catalogue(&directory).await;
+        let repo = ColumnarRepository::open(pool.clone(), &chunks)
+            .await
+            .unwrap();
+        let readings = weather(CHUNK_ROWS as i64 + 10);
+        repo.insert_sensor_readings("sensor/update".to_string(), readings)
+            .await
+            .unwrap();
+        let before = repo.fetch_sensor_readings_page(everything()).await.unwrap();
+        assert_eq!(before.rows.len(), CHUNK_ROWS + 10);
+        assert_eq!(chunk_files(&chunks).len(), 1);
+        drop(repo);
+
+        // A reading cut short by a crash
+        let log = chunks.join(series_directory_name("outside")).join(HEAD_LOG);
+        let mut file = OpenOptions::new().append(true).open(&log).unwrap();
+        file.write_all(br#"{"id":99999,"timest"#).unwrap();
+
+        let repo = ColumnarRepository::open(pool, &chunks).await.unwrap();
+        let after = repo.fetch_sensor_readings_page(everything()).await.unwrap();
+        assert_eq!(after.rows, before.rows);
+        assert_eq!(fs::read_to_string(&log).unwrap().lines().count(), 10);
+
+        // Ids carry on after the ones stored
+        repo.insert_sensor_readings("sensor/update".to_string(), weather(CHUNK_ROWS as i64 + 11))
+            .await
+            .unwrap();
+        let store = repo.store.read().await;
+        let head = &store.series["outside"].head;
+        assert_eq!(head.len(), 11);
+        assert_eq!(head[10].id, CHUNK_ROWS as i64 + 11);
+    }
+
+    #[tokio::test]
+    async fn retention_deletes_expired_chunks() {
+        let directory = ScratchDirectory::new();
+        let chunks = directory.0.join("chunks");
+        let repo = ColumnarRepository::open(catalogue(&directory).await, &chunks)
+            .await
+            .unwrap();
+        let now = Utc::now();
+        let readings: Vec<SensorReadingEvent> = (0..CHUNK_ROWS as i64 * 2)
+            .map(|i| SensorReadingEvent {
+                sensor_id: "outside".to_string(),
+                timestamp: now - TimeDelta::days(40) + TimeDelta::minutes(i * 10 + 1),
+                sequence: None,
+                metrics: [("temperature".to_string(), 1.0)].into(),
+            })
+            .collect();
+        repo.insert_sensor_readings("sensor/update".to_string(), readings)
+            .await
+            .unwrap();
+        repo.upsert_retention_policy(RetentionPolicy {
+            sensor_id: None,
+            resolution: Resolution::Raw,
+            keep_days: Some(30),
+        })
+        .await
+        .unwrap();
+        let sealed = chunk_files(&chunks);
+        assert_eq!(sealed.len(), 2);
+
+        let dry_run = repo.enforce_retention(true).await.unwrap();
+        let report = repo.enforce_retention(false).await.unwrap();
+        // Six readings an hour over the first ten days. The first chunk
+        // expired whole, the second one lost its oldest readings.
+        assert_eq!(dry_run.entries[0].rows, 1440);
+        assert_eq!(report.entries[0].rows, 1440);
+        let left = chunk_files(&chunks);
+        assert_eq!(left.len(), 1);
+        assert!(!sealed.contains(&left[0]));
+
+        let page = repo.fetch_sensor_readings_page(everything()).await.unwrap();
+        assert_eq!(page.rows.len(), CHUNK_ROWS * 2 - 1440);
+    }
+
+    #[tokio::test]
+    async fn catalogue_readings_move_into_chunks() {
+        let directory = ScratchDirectory::new();
+        let pool = catalogue(&directory).await;
+        SqliteRepository::new(pool.clone())
+            .insert_sensor_readings("sensor/update".to_string(), weather(10))
+            .await
+            .unwrap();
+        let expected = SqliteRepository::new(pool.clone())
+            .fetch_sensor_readings_page(everything())
+            .await
+            .unwrap();
+
+        let repo = ColumnarRepository::open(pool.clone(), directory.0.join("chunks"))
+            .await
+            .unwrap();
+        let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM reading_values")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(left, 0);
+        let page = repo.fetch_sensor_readings_page(everything()).await.unwrap();
+        assert_eq!(page.rows, expected.rows);
+
+        repo.insert_sensor_readings("sensor/update".to_string(), weather(11))
+            .await
+            .unwrap();
+        let store = repo.store.read().await;
+        assert_eq!(store.next_id, 12);
+    }
+}
@@ -0,0 +1,1352 @@
+//! Behaviour every `Repository` implementation has to share. Each backend
+//! instantiates the suite with `conformance_tests!` from its own test module.
+
+use chrono::{DateTime, TimeDelta, TimeZone, Utc};
+
+use super::{
+    AggregateFunction, AggregateQuery, AggregateRow, Calibration, ConflictPolicy, GapFill,
+    MeasurementQuery, Metric, NewApiKey, Pagination, QueryFilter, Repository, Resolution,
+    RetentionPolicy, Scope, SensorMetadata, SensorReadingsPage, SensorStatus, SeriesQuery,
+    SortOrder, Subscriptions,
+};
+use crate::SensorReadingEvent;
+use crate::error::BsError;
+
+/// Provides a fresh, migrated repository for every test
+pub(crate) trait TestBackend: Sized {
+    type Repository: Repository;
+
+    /// `None` skips the test when the backend is not available
+    async fn create() -> Option<Self>;
+    fn repository(&self) -> Self::Repository;
+    async fn teardown(self) {}
+}
+
+macro_rules! conformance_tests {
+    ($backend:ty) => {
+        $crate::db::conformance::conformance_tests!(
+            $backend;
+            catalogue_starts_with_the_default_metrics,
+            batch_inserts_skip_duplicates,
+            concurrent_duplicates_are_stored_once,
+            redelivered_readings_are_ignored,
+            replaced_readings_leave_the_rollups,
+            sequence_numbers_tell_readings_apart,
+            kept_duplicates_can_be_removed,
+            removing_duplicates_keeps_rollups_of_expired_readings,
+            filter_by_sensor_id,
+            filter_by_metric_presence,
+            filter_by_metric_min,
+            filter_by_metric_max,
+            filter_by_metric_range,
+            filter_by_time_span,
+            bounds_without_metric_are_rejected,
+            unknown_filter_metric_is_rejected,
+            only_requested_columns_are_returned,
+            invalid_columns_are_rejected,
+            pages_follow_next_cursor,
+            newest_first_paging,
+            identical_timestamps_are_not_skipped,
+            cursor_reused_with_other_filters_is_rejected,
+            stored_readings_come_in_the_order_they_were_stored,
+            subscriptions_select_sensors_or_metrics,
+            latest_reading_carries_every_metric,
+            latest_readings_come_one_per_sensor,
+            rollups_follow_inserts,
+            rebuilt_rollups_match_incremental_ones,
+            rebuilt_rollups_keep_partly_expired_buckets,
+            series_resolution_follows_span,
+            readings_aggregate_into_buckets,
+            retention_removes_expired_readings,
+            retention_policies_are_replaced_per_scope,
+            unknown_sensors_are_quarantined_until_approved,
+            registered_sensors_are_approved,
+            deleting_a_sensor_drops_its_quarantine,
+            calibrations_apply_during_ingestion,
+            overlapping_calibrations_are_rejected,
+            recompute_follows_calibration_changes,
+            recompute_keeps_rollups_of_expired_readings,
+            api_keys_authenticate_until_revoked,
+        );
+    };
+    ($backend:ty; $($test:ident),+ $(,)?) => {
+        $(
+            #[tokio::test]
+            async fn $test() {
+                use $crate::db::conformance::TestBackend;
+
+                let Some(backend) = <$backend>::create().await else {
+                    return;
+                };
+                $crate::db::conformance::$test(backend.repository()).await;
+                backend.teardown().await;
+            }
+        )+
+    };
+}
+
+pub(crate) use conformance_tests;
+
+fn reading(sensor_id: &str, second: u32, metrics: &[(&str, f64)]) -> SensorReadingEvent {
+    SensorReadingEvent {
+        sensor_id: sensor_id.to_string(),
+        timestamp: Utc.with_ymd_and_hms(2026, 1, 1, 12, 0, second).unwrap(),
+        sequence: None,
+        metrics: metrics
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value))
+            .collect(),
+    }
+}
+
+async fn insert_all<R: Repository>(
+    repo: &R,
+    readings: impl IntoIterator<Item = SensorReadingEvent>,
+) {
+    for r in readings {
+        repo.insert_sensor_reading("sensor/update".to_string(), r)
+            .await
+            .unwrap();
+    }
+}
+
+async fn seed<R: Repository>(repo: &R) {
+    insert_all(
+        repo,
+        [
+            reading("outside", 0, &[("temperature", 5.0), ("humidity", 80.0)]),
+            reading("inside", 1, &[("temperature", 21.0), ("humidity", 40.0)]),
+            reading("outside", 2, &[("temperature", 6.0), ("humidity", 78.0)]),
+            reading("office", 3, &[("temperature", 22.0), ("co2", 950.0)]),
+            reading("outside", 4, &[("temperature", 7.0), ("humidity", 75.0)]),
+        ],
+    )
+    .await;
+}
+
+fn query(filters: QueryFilter, columns: &[&str]) -> MeasurementQuery {
+    MeasurementQuery {
+        filters,
+        pagination: Pagination::default(),
+        columns: columns.iter().map(|c| c.to_string()).collect(),
+    }
+}
+
+fn paged(page_size: usize, order: SortOrder) -> MeasurementQuery {
+    let mut q = query(QueryFilter::default(), &["temperature"]);
+    q.pagination.page_size = page_size;
+    q.pagination.order = order;
+    q
+}
+
+fn temperatures(page: &SensorReadingsPage) -> Vec<f64> {
+    page.rows.iter().map(|r| r.metrics["temperature"]).collect()
+}
+
+pub(crate) async fn catalogue_starts_with_the_default_metrics<R: Repository>(repo: R) {
+    let mut metrics = repo.fetch_metrics().await.unwrap();
+    metrics.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut defaults = Metric::defaults();
+    defaults.sort_by(|a, b| a.name.cmp(&b.name));
+
+    assert_eq!(metrics, defaults);
+}
+
+pub(crate) async fn batch_inserts_skip_duplicates<R: Repository>(repo: R) {
+    seed(&repo).await;
+    let batch = vec![
+        reading("outside", 0, &[("temperature", 50.0)]),
+        reading("inside", 0, &[("temperature", 20.0)]),
+        reading("inside", 0, &[("temperature", 20.5)]),
+    ];
+
+    let inserted = repo
+        .insert_sensor_readings("import".to_string(), batch)
+        .await
+        .unwrap();
+    assert_eq!(inserted, 1);
+    let page = repo
+        .fetch_sensor_readings_page(query(QueryFilter::default(), &["temperature"]))
+        .await
+        .unwrap();
+    assert_eq!(temperatures(&page), vec![5.0, 20.0, 21.0, 6.0, 22.0, 7.0]);
+}
+
+pub(crate) async fn concurrent_duplicates_are_stored_once<R: Repository>(repo: R) {
+    let inserts = (0..8).map(|i| {
+        repo.insert_sensor_reading(
+            "sensor/update".to_string(),
+            reading("outside", 0, &[("temperature", f64::from(i))]),
+        )
+    });
+    for inserted in futures_util::future::join_all(inserts).await {
+        inserted.unwrap();
+    }
+
+    let page = repo
+        .fetch_sensor_readings_page(query(QueryFilter::default(), &["temperature"]))
+        .await
+        .unwrap();
+    assert_eq!(page.rows.len(), 1);
+    let hourly = repo
+        .fetch_series(series(
+            Some(Resolution::Hour),
+            "2026-01-01T12:00:00Z",
+            "2026-01-01T13:00:00Z",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(hourly.points[0].count, 1);
+}
+
+/// Minimum, maximum and count of the hour the seeded `outside` readings fall into
+async fn outside_hour<R: Repository>(repo: &R) -> (f64, f64, i64) {
+    let hourly = repo
+        .fetch_series(series(
+            Some(Resolution::Hour),
+            "2026-01-01T12:00:00Z",
+            "2026-01-01T13:00:00Z",
+        ))
+        .await
+        .unwrap();
+    let point = &hourly.points[0];
+    (point.min, point.max, point.count)
+}
+
+pub(crate) async fn redelivered_readings_are_ignored<R: Repository>(repo: R) {
+    seed(&repo).await;
+    insert_all(&repo, [reading("outside", 0, &[("temperature", 50.0)])]).await;
+
+    let page = repo
+        .fetch_sensor_readings_page(query(QueryFilter::default(), &["temperature"]))
+        .await
+        .unwrap();
+    assert_eq!(temperatures(&page), vec![5.0, 21.0, 6.0, 22.0, 7.0]);
+    assert_eq!(outside_hour(&repo).await, (5.0, 7.0, 3));
+}
+
+pub(crate) async fn replaced_readings_leave_the_rollups<R: Repository>(repo: R) {
+    let repo = repo.with_conflict_policy(ConflictPolicy::Replace);
+    seed(&repo).await;
+    insert_all(&repo, [reading("outside", 0, &[("temperature", 50.0)])]).await;
+
+    let page = repo
+        .fetch_sensor_readings_page(query(QueryFilter::default(), &["temperature", "humidity"]))
+        .await
+        .unwrap();
+    assert_eq!(temperatures(&page), vec![50.0, 21.0, 6.0, 22.0, 7.0]);
+    assert!(!page.rows[0].metrics.contains_key("humidity"));
+    assert_eq!(outside_hour(&repo).await, (6.0, 50.0, 3));
+}
+
+pub(crate) async fn sequence_numbers_tell_readings_apart<R: Repository>(repo: R) {
+    let with_sequence = |sequence, temperature| SensorReadingEvent {
+        sequence: Some(sequence),
+        ..reading("outside", 0, &[("temperature", temperature)])
+    };
+    insert_all(
+        &repo,
+        [
+            with_sequence(1, 5.0),
+            with_sequence(2, 6.0),
+            with_sequence(1, 7.0),
+            reading("outside", 0, &[("temperature", 8.0)]),
+        ],
+    )
+    .await;
+
+    let page = repo
+        .fetch_sensor_readings_page(query(QueryFilter::default(), &["temperature"]))
+        .await
+        .unwrap();
+    assert_eq!(temperatures(&page), vec![5.0, 6.0, 8.0]);
+}
+
+pub(crate) async fn kept_duplicates_can_be_removed<R: Repository>(repo: R) {
+    let repo = repo.with_conflict_policy(ConflictPolicy::KeepBoth);
+    seed(&repo).await;
+    insert_all(
+        &repo,
+        [
+            reading("outside", 0, &[("temperature", 50.0)]),
+            reading("outside", 2, &[("temperature", 60.0)]),
+        ],
+    )
+    .await;
+    assert_eq!(outside_hour(&repo).await, (5.0, 60.0, 5));
+
+    assert_eq!(repo.remove_duplicate_readings(true).await.unwrap(), 2);
+    let page = repo
+        .fetch_sensor_readings_page(query(QueryFilter::default(), &["temperature"]))
+        .await
+        .unwrap();
+    assert_eq!(page.rows.len(), 7);
+
+    assert_eq!(repo.remove_duplicate_readings(false).await.unwrap(), 2);
+    let page = repo
+        .fetch_sensor_readings_page(query(QueryFilter::default(), &["temperature"]))
+        .await
+        .unwrap();
+    assert_eq!(temperatures(&page), vec![5.0, 21.0, 6.0, 22.0, 7.0]);
+    assert_eq!(outside_hour(&repo).await, (5.0, 7.0, 3));
+    assert_eq!(repo.remove_duplicate_readings(false).await.unwrap(), 0);
+}
+
+pub(crate) async fn filter_by_sensor_id<R: Repository>(repo: R) {
+    seed(&repo).await;
+    let filters = QueryFilter {
+        sensor_id: Some("outside".to_string()),
+        ..Default::default()
+    };
+    let page = repo
+        .fetch_sensor_readings_page(query(filters, &["sensor_id", "temperature"]))
+        .await
+        .unwrap();
+
+    assert_eq!(temperatures(&page), vec![5.0, 6.0, 7.0]);
+    assert!(
+        page.rows
+            .iter()
+            .all(|r| r.sensor_id.as_deref() == Some("outside"))
+    );
+    assert_eq!(page.filters.sensor_id.as_deref(), Some("outside"));
+}
+
+pub(crate) async fn filter_by_metric_presence<R: Repository>(repo: R) {
+    seed(&repo).await;
+    let filters = QueryFilter {
+        metric: Some("co2".to_string()),
+        ..Default::default()
+    };
+    let page = repo
+        .fetch_sensor_readings_page(query(filters, &["sensor_id"]))
+        .await
+        .unwrap();
+
+    assert_eq!(page.rows.len(), 1);
+    assert_eq!(page.rows[0].sensor_id.as_deref(), Some("office"));
+}
+
+pub(crate) async fn filter_by_metric_min<R: Repository>(repo: R) {
+    seed(&repo).await;
+    let filters = QueryFilter {
+        metric: Some("temperature".to_string()),
+        min: Some(7.0),
+        ..Default::default()
+    };
+    let page = repo
+        .fetch_sensor_readings_page(query(filters, &["temperature"]))
+        .await
+        .unwrap();
+
+    assert_eq!(temperatures(&page), vec![21.0, 22.0, 7.0]);
+}
+
+pub(crate) async fn filter_by_metric_max<R: Repository>(repo: R) {
+    seed(&repo).await;
+    let filters = QueryFilter {
+        metric: Some("humidity".to_string()),
+        max: Some(78.0),
+        ..Default::default()
+    };
+    let page = repo
+        .fetch_sensor_readings_page(query(filters, &["humidity"]))
+        .await
+        .unwrap();
+
+    let humidity: Vec<f64> = page.rows.iter().map(|r| r.metrics["humidity"]).collect();
+    assert_eq!(humidity, vec![40.0, 78.0, 75.0]);
+}
+
+pub(crate) async fn filter_by_metric_range<R: Repository>(repo: R) {
+    seed(&repo).await;
+    let filters = QueryFilter {
+        sensor_id: Some("outside".to_string()),
+        metric: Some("temperature".to_string()),
+        min: Some(5.5),
+        max: Some(6.5),
+        ..Default::default()
+    };
+    let page = repo
+        .fetch_sensor_readings_page(query(filters, &["temperature"]))
+        .await
+        .unwrap();
+
+    assert_eq!(temperatures(&page), vec![6.0]);
+}
+
+pub(crate) async fn filter_by_time_span<R: Repository>(repo: R) {
+    seed(&repo).await;
+    let filters = QueryFilter {
+        from: Some(reading("outside", 1, &[]).timestamp),
+        to: Some(reading("outside", 4, &[]).timestamp),
+        ..Default::default()
+    };
+    let page = repo
+        .fetch_sensor_readings_page(query(filters, &["temperature"]))
+        .await
+        .unwrap();
+    assert_eq!(temperatures(&page), vec![21.0, 6.0, 22.0]);
+
+    let filters = QueryFilter {
+        from: Some(reading("outside", 4, &[]).timestamp),
+        to: Some(reading("outside", 1, &[]).timestamp),
+        ..Default::default()
+    };
+    let res = repo
+        .fetch_sensor_readings_page(query(filters, &["temperature"]))
+        .await;
+    assert!(matches!(res, Err(BsError::InvalidQuery(_))));
+}
+
+pub(crate) async fn bounds_without_metric_are_rejected<R: Repository>(repo: R) {
+    seed(&repo).await;
+    let filters = QueryFilter {
+        min: Some(1.0),
+        ..Default::default()
+    };
+    let res = repo
+        .fetch_sensor_readings_page(query(filters, &["temperature"]))
+        .await;
+
+    assert!(matches!(res, Err(BsError::InvalidQuery(_))));
+}
+
+pub(crate) async fn unknown_filter_metric_is_rejected<R: Repository>(repo: R) {
+    seed(&repo).await;
+    let filters = QueryFilter {
+        metric: Some("radiation".to_string()),
+        ..Default::default()
+    };
+    let res = repo
+        .fetch_sensor_readings_page(query(filters, &["temperature"]))
+        .await;
+
+    assert!(matches!(res, Err(BsError::InvalidQuery(_))));
+}
+
+pub(crate) async fn only_requested_columns_are_returned<R: Repository>(repo: R) {
+    seed(&repo).await;
+    let page = repo
+        .fetch_sensor_readings_page(query(QueryFilter::default(), &["timestamp", "co2"]))
+        .await
+        .unwrap();
+
+    assert_eq!(page.rows.len(), 5);
+    for row in &page.rows {
+        assert!(row.sensor_id.is_none());
+        assert!(row.topic.is_none());
+        assert!(row.timestamp.is_some());
+        assert!(row.metrics.keys().all(|m| m == "co2"));
+    }
+    assert_eq!(page.rows[3].metrics["co2"], 950.0);
+    assert!(page.rows[0].metrics.is_empty());
+
+    let page = repo
+        .fetch_sensor_readings_page(query(QueryFilter::default(), &["topic"]))
+        .await
+        .unwrap();
+    assert_eq!(page.rows[0].topic.as_deref(), Some("sensor/update"));
+    assert!(page.rows[0].timestamp.is_none());
+}
+
+pub(crate) async fn invalid_columns_are_rejected<R: Repository>(repo: R) {
+    seed(&repo).await;
+
+    for columns in [&[][..], &["id"], &["temperature; DROP TABLE metrics"]] {
+        let res = repo
+            .fetch_sensor_readings_page(query(QueryFilter::default(), columns))
+            .await;
+        assert!(matches!(res, Err(BsError::InvalidQuery(_))));
+    }
+}
+
+pub(crate) async fn pages_follow_next_cursor<R: Repository>(repo: R) {
+    seed(&repo).await;
+
+    let first = repo
+        .fetch_sensor_readings_page(paged(3, SortOrder::Asc))
+        .await
+        .unwrap();
+    assert_eq!(temperatures(&first), vec![5.0, 21.0, 6.0]);
+    assert!(first.previous.is_none());
+
+    let mut q = paged(3, SortOrder::Asc);
+    q.pagination.after = first.next;
+    let second = repo.fetch_sensor_readings_page(q).await.unwrap();
+    assert_eq!(temperatures(&second), vec![22.0, 7.0]);
+    assert!(second.next.is_none());
+
+    let mut q = paged(3, SortOrder::Asc);
+    q.pagination.before = second.previous;
+    let back = repo.fetch_sensor_readings_page(q).await.unwrap();
+    assert_eq!(temperatures(&back), vec![5.0, 21.0, 6.0]);
+    assert!(back.previous.is_none());
+    assert!(back.next.is_some());
+}
+
+pub(crate) async fn newest_first_paging<R: Repository>(repo: R) {
+    seed(&repo).await;
+
+    let first = repo
+        .fetch_sensor_readings_page(paged(2, SortOrder::Desc))
+        .await
+        .unwrap();
+    assert_eq!(temperatures(&first), vec![7.0, 22.0]);
+
+    let mut q = paged(2, SortOrder::Desc);
+    q.pagination.after = first.next;
+    let second = repo.fetch_sensor_readings_page(q).await.unwrap();
+    assert_eq!(temperatures(&second), vec![6.0, 21.0]);
+
+    let mut q = paged(2, SortOrder::Desc);
+    q.pagination.before = second.previous;
+    let back = repo.fetch_sensor_readings_page(q).await.unwrap();
+    assert_eq!(temperatures(&back), vec![7.0, 22.0]);
+}
+
+pub(crate) async fn identical_timestamps_are_not_skipped<R: Repository>(repo: R) {
+    insert_all(
+        &repo,
+        (0..5).map(|i| reading(&format!("sensor-{i}"), 0, &[("temperature", i as f64)])),
+    )
+    .await;
+
+    let mut seen = Vec::new();
+    let mut next = None;
+    loop {
+        let mut q = paged(2, SortOrder::Asc);
+        q.pagination.after = next;
+        let page = repo.fetch_sensor_readings_page(q).await.unwrap();
+        seen.extend(temperatures(&page));
+        next = page.next;
+        if next.is_none() {
+            break;
+        }
+    }
+    assert_eq!(seen, vec![0.0, 1.0, 2.0, 3.0, 4.0]);
+}
+
+pub(crate) async fn latest_reading_carries_every_metric<R: Repository>(repo: R) {
+    seed(&repo).await;
+
+    let latest = repo.fetch_latest_reading("outside").await.unwrap().unwrap();
+    assert_eq!(latest.sensor_id.as_deref(), Some("outside"));
+    assert_eq!(
+        latest.timestamp,
+        Some(Utc.with_ymd_and_hms(2026, 1, 1, 12, 0, 4).unwrap())
+    );
+    assert_eq!(
+        latest.metrics,
+        [
+            ("humidity".to_string(), 75.0),
+            ("temperature".to_string(), 7.0)
+        ]
+        .into()
+    );
+    assert_eq!(repo.fetch_latest_reading("attic").await.unwrap(), None);
+}
+
+pub(crate) async fn latest_readings_come_one_per_sensor<R: Repository>(repo: R) {
+    assert_eq!(repo.fetch_latest_readings().await.unwrap(), vec![]);
+    seed(&repo).await;
+    // Stored last but taken before the seeded reading of inside
+    insert_all(&repo, [reading("inside", 0, &[("temperature", 19.0)])]).await;
+
+    let latest = repo.fetch_latest_readings().await.unwrap();
+    let sensors: Vec<_> = latest
+        .iter()
+        .filter_map(|r| r.sensor_id.as_deref())
+        .collect();
+    assert_eq!(sensors, vec!["inside", "office", "outside"]);
+    for reading in &latest {
+        let sensor_id = reading.sensor_id.as_deref().unwrap();
+        let one = repo.fetch_latest_reading(sensor_id).await.unwrap().unwrap();
+        assert_eq!(
+            (&one.topic, one.timestamp),
+            (&reading.topic, reading.timestamp)
+        );
+        assert_eq!(one.metrics, reading.metrics, "{sensor_id}");
+    }
+    assert_eq!(latest[0].metrics["temperature"], 21.0);
+}
+
+pub(crate) async fn cursor_reused_with_other_filters_is_rejected<R: Repository>(repo: R) {
+    seed(&repo).await;
+    let first = repo
+        .fetch_sensor_readings_page(paged(2, SortOrder::Asc))
+        .await
+        .unwrap();
+
+    let filters = QueryFilter {
+        sensor_id: Some("outside".to_string()),
+        ..Default::default()
+    };
+    let mut q = query(filters, &["temperature"]);
+    q.pagination.page_size = 2;
+    q.pagination.after = first.next;
+    let res = repo.fetch_sensor_readings_page(q).await;
+
+    assert!(matches!(res, Err(BsError::InvalidQuery(_))));
+}
+
+pub(crate) async fn stored_readings_come_in_the_order_they_were_stored<R: Repository>(repo: R) {
+    let start = repo.fetch_last_stored_position().await.unwrap();
+    insert_all(
+        &repo,
+        [
+            reading_at("outside", "2026-02-01T12:00:00Z", 5.0),
+            // Stored late, taken a month before the reading stored first
+            reading_at("outside", "2026-01-15T12:00:00Z", 4.0),
+            reading_at("inside", "2026-02-01T12:01:00Z", 21.0),
+        ],
+    )
+    .await;
+
+    let mut q = query(QueryFilter::default(), &["sensor_id", "temperature"]);
+    let stored = repo.fetch_stored_readings(start, q.clone()).await.unwrap();
+    let temperatures: Vec<f64> = stored
+        .iter()
+        .map(|(_, reading)| reading.metrics["temperature"])
+        .collect();
+    assert_eq!(temperatures, vec![5.0, 4.0, 21.0]);
+    assert!(stored.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    let last = stored[2].0;
+    assert_eq!(repo.fetch_last_stored_position().await.unwrap(), last);
+
+    // Resuming after the first reading still finds the one stored late
+    q.filters.sensor_id = Some("outside".to_string());
+    let resumed = repo
+        .fetch_stored_readings(stored[0].0, q.clone())
+        .await
+        .unwrap();
+    assert_eq!(resumed, stored[1..2]);
+    q.filters.sensor_id = None;
+    q.pagination.page_size = 1;
+    let page = repo.fetch_stored_readings(start, q.clone()).await.unwrap();
+    assert_eq!(page, stored[..1]);
+    assert_eq!(repo.fetch_stored_readings(last, q).await.unwrap(), vec![]);
+}
+
+pub(crate) async fn subscriptions_select_sensors_or_metrics<R: Repository>(repo: R) {
+    seed(&repo).await;
+    let subscribed = |sensors: &[&str], metrics: &[&str]| {
+        let subscriptions = Subscriptions {
+            sensors: sensors.iter().map(|s| s.to_string()).collect(),
+            metrics: metrics.iter().map(|m| m.to_string()).collect(),
+        };
+        query(
+            QueryFilter {
+                subscriptions: Some(subscriptions),
+                ..Default::default()
+            },
+            &["temperature"],
+        )
+    };
+
+    let q = subscribed(&["inside"], &["co2"]);
+    let page = repo.fetch_sensor_readings_page(q.clone()).await.unwrap();
+    assert_eq!(temperatures(&page), vec![21.0, 22.0]);
+    let stored = repo.fetch_stored_readings(0, q).await.unwrap();
+    assert_eq!(stored.len(), 2);
+
+    let q = subscribed(&[], &[]);
+    let page = repo.fetch_sensor_readings_page(q.clone()).await.unwrap();
+    assert_eq!(temperatures(&page), Vec::<f64>::new());
+    assert_eq!(repo.fetch_stored_readings(0, q).await.unwrap(), vec![]);
+}
+
+fn reading_at(sensor_id: &str, timestamp: &str, temperature: f64) -> SensorReadingEvent {
+    SensorReadingEvent {
+        sensor_id: sensor_id.to_string(),
+        timestamp: timestamp.parse().unwrap(),
+        sequence: None,
+        metrics: [("temperature".to_string(), temperature)].into(),
+    }
+}
+
+async fn seed_hourly<R: Repository>(repo: &R) {
+    insert_all(
+        repo,
+        [
+            reading_at("outside", "2026-01-01T10:05:00Z", 1.0),
+            reading_at("outside", "2026-01-01T10:35:00.250Z", 3.0),
+            reading_at("outside", "2026-01-01T11:15:00Z", 8.0),
+            reading_at("outside", "2026-01-02T09:00:00Z", 4.0),
+            reading_at("inside", "2026-01-01T10:10:00Z", 20.0),
+        ],
+    )
+    .await;
+}
+
+fn series(resolution: Option<Resolution>, from: &str, to: &str) -> SeriesQuery {
+    SeriesQuery {
+        sensor_id: Some("outside".to_string()),
+        metric: "temperature".to_string(),
+        from: from.parse().unwrap(),
+        to: to.parse().unwrap(),
+        resolution,
+    }
+}
+
+pub(crate) async fn rollups_follow_inserts<R: Repository>(repo: R) {
+    seed_hourly(&repo).await;
+
+    let hourly = repo
+        .fetch_series(series(
+            Some(Resolution::Hour),
+            "2026-01-01T00:00:00Z",
+            "2026-01-03T00:00:00Z",
+        ))
+        .await
+        .unwrap();
+    let summary: Vec<_> = hourly
+        .points
+        .iter()
+        .map(|p| (p.min, p.max, p.avg, p.count))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![(1.0, 3.0, 2.0, 2), (8.0, 8.0, 8.0, 1), (4.0, 4.0, 4.0, 1)]
+    );
+
+    let daily = repo
+        .fetch_series(series(
+            Some(Resolution::Day),
+            "2026-01-01T12:00:00Z",
+            "2026-01-03T00:00:00Z",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(daily.points.len(), 2);
+    assert_eq!(daily.points[0].count, 3);
+    assert_eq!(daily.points[0].avg, 4.0);
+}
+
+pub(crate) async fn rebuilt_rollups_match_incremental_ones<R: Repository>(repo: R) {
+    seed_hourly(&repo).await;
+    let query = |resolution| {
+        let mut q = series(
+            Some(resolution),
+            "2026-01-01T00:00:00Z",
+            "2026-01-03T00:00:00Z",
+        );
+        q.sensor_id = None;
+        q
+    };
+    let hourly = repo.fetch_series(query(Resolution::Hour)).await.unwrap();
+    let daily = repo.fetch_series(query(Resolution::Day)).await.unwrap();
+
+    let rebuilt = repo.rebuild_rollups(None, None).await.unwrap();
+    assert_eq!(rebuilt, 7);
+
+    assert_eq!(
+        repo.fetch_series(query(Resolution::Hour))
+            .await
+            .unwrap()
+            .points,
+        hourly.points
+    );
+    assert_eq!(
+        repo.fetch_series(query(Resolution::Day))
+            .await
+            .unwrap()
+            .points,
+        daily.points
+    );
+}
+
+pub(crate) async fn rebuilt_rollups_keep_partly_expired_buckets<R: Repository>(repo: R) {
+    // One reading of the day expires, the other is kept
+    let cutoff = Utc::now() - TimeDelta::days(30);
+    let day = Resolution::Day.bucket(cutoff);
+    let kept = cutoff + (day + TimeDelta::days(1) - cutoff) / 2;
+    insert_all(
+        &repo,
+        [(day, 1.0), (kept, 3.0)].map(|(timestamp, temperature)| SensorReadingEvent {
+            sensor_id: "outside".to_string(),
+            timestamp,
+            sequence: None,
+            metrics: [("temperature".to_string(), temperature)].into(),
+        }),
+    )
+    .await;
+    repo.upsert_retention_policy(RetentionPolicy {
+        sensor_id: None,
+        resolution: Resolution::Raw,
+        keep_days: Some(30),
+    })
+    .await
+    .unwrap();
+    repo.enforce_retention(false).await.unwrap();
+    let page = repo
+        .fetch_sensor_readings_page(query(QueryFilter::default(), &["sensor_id"]))
+        .await
+        .unwrap();
+    assert_eq!(page.rows.len(), 1);
+
+    repo.rebuild_rollups(None, None).await.unwrap();
+    let daily = repo
+        .fetch_series(SeriesQuery {
+            sensor_id: Some("outside".to_string()),
+            metric: "temperature".to_string(),
+            from: day,
+            to: day + TimeDelta::days(1),
+            resolution: Some(Resolution::Day),
+        })
+        .await
+        .unwrap();
+    assert_eq!(daily.points.len(), 1);
+    assert_eq!((daily.points[0].count, daily.points[0].min), (2, 1.0));
+}
+
+pub(crate) async fn series_resolution_follows_span<R: Repository>(repo: R) {
+    seed_hourly(&repo).await;
+
+    let short = repo
+        .fetch_series(series(None, "2026-01-01T10:00:00Z", "2026-01-01T12:00:00Z"))
+        .await
+        .unwrap();
+    assert_eq!(short.resolution, Resolution::Raw);
+    assert_eq!(short.points.len(), 3);
+
+    let week = repo
+        .fetch_series(series(None, "2025-12-28T00:00:00Z", "2026-01-04T00:00:00Z"))
+        .await
+        .unwrap();
+    assert_eq!(week.resolution, Resolution::Hour);
+    assert_eq!(week.points.len(), 3);
+
+    let year = repo
+        .fetch_series(series(None, "2025-06-01T00:00:00Z", "2026-06-01T00:00:00Z"))
+        .await
+        .unwrap();
+    assert_eq!(year.resolution, Resolution::Day);
+    assert_eq!(year.points.len(), 2);
+}
+
+fn aggregate(
+    from: &str,
+    to: &str,
+    bucket: TimeDelta,
+    functions: &[AggregateFunction],
+) -> AggregateQuery {
+    AggregateQuery {
+        sensor_id: None,
+        metrics: vec!["temperature".to_string()],
+        functions: functions.to_vec(),
+        from: from.parse().unwrap(),
+        to: to.parse().unwrap(),
+        bucket,
+        group_by_sensor: false,
+        fill: None,
+    }
+}
+
+/// Temperature function values of every row
+fn aggregated(rows: &[AggregateRow], function: &str) -> Vec<Option<f64>> {
+    rows.iter()
+        .map(|row| row.metrics["temperature"][function])
+        .collect()
+}
+
+pub(crate) async fn readings_aggregate_into_buckets<R: Repository>(repo: R) {
+    use AggregateFunction::{Avg, Count, Last, Max, Min, P50};
+
+    seed_hourly(&repo).await;
+
+    let mut query = aggregate(
+        "2026-01-01T06:00:00Z",
+        "2026-01-02T12:00:00Z",
+        TimeDelta::hours(6),
+        &[Avg, Last, Count, P50],
+    );
+    query.sensor_id = Some("outside".to_string());
+    query.fill = Some(GapFill::Linear);
+    let rows = repo.aggregate_readings(query).await.unwrap().rows;
+    assert_eq!(rows.len(), 5);
+    assert_eq!(
+        rows[1].timestamp,
+        "2026-01-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap()
+    );
+    assert_eq!(aggregated(&rows, "avg")[0], Some(4.0));
+    assert_eq!(aggregated(&rows, "p50")[0], Some(3.0));
+    assert_eq!(
+        aggregated(&rows, "last"),
+        vec![Some(8.0), Some(7.0), Some(6.0), Some(5.0), Some(4.0)]
+    );
+    assert_eq!(
+        aggregated(&rows, "count"),
+        vec![Some(3.0), Some(0.0), Some(0.0), Some(0.0), Some(1.0)]
+    );
+
+    let mut query = aggregate(
+        "2026-01-01T10:00:00Z",
+        "2026-01-01T12:00:00Z",
+        TimeDelta::hours(1),
+        &[Min, Max],
+    );
+    query.group_by_sensor = true;
+    let rows = repo.aggregate_readings(query).await.unwrap().rows;
+    let sensors: Vec<_> = rows
+        .iter()
+        .map(|row| row.sensor_id.as_deref().unwrap())
+        .collect();
+    assert_eq!(sensors, vec!["inside", "outside", "outside"]);
+    assert_eq!(
+        aggregated(&rows, "min"),
+        vec![Some(20.0), Some(1.0), Some(8.0)]
+    );
+    assert_eq!(
+        aggregated(&rows, "max"),
+        vec![Some(20.0), Some(3.0), Some(8.0)]
+    );
+
+    let query = aggregate(
+        "2026-01-01T10:00:00Z",
+        "2026-01-01T11:00:00Z",
+        TimeDelta::hours(1),
+        &[Max, Count],
+    );
+    let rows = repo.aggregate_readings(query).await.unwrap().rows;
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].sensor_id, None);
+    assert_eq!(aggregated(&rows, "max"), vec![Some(20.0)]);
+    assert_eq!(aggregated(&rows, "count"), vec![Some(3.0)]);
+
+    let mut query = aggregate(
+        "2026-01-01T10:00:00Z",
+        "2026-01-01T11:00:00Z",
+        TimeDelta::hours(1),
+        &[Max],
+    );
+    query.metrics = vec!["wind_chill".to_string()];
+    assert!(matches!(
+        repo.aggregate_readings(query).await,
+        Err(BsError::InvalidQuery(_))
+    ));
+}
+
+pub(crate) async fn retention_removes_expired_readings<R: Repository>(repo: R) {
+    let now = Utc::now();
+    insert_all(
+        &repo,
+        [
+            ("outside", 40),
+            ("outside", 1),
+            ("balcony", 40),
+            ("attic", 40),
+        ]
+        .map(|(sensor_id, days_ago)| SensorReadingEvent {
+            sensor_id: sensor_id.to_string(),
+            timestamp: now - TimeDelta::days(days_ago),
+            sequence: None,
+            metrics: [("temperature".to_string(), 1.0)].into(),
+        }),
+    )
+    .await;
+    let policies = [
+        RetentionPolicy {
+            sensor_id: None,
+            resolution: Resolution::Raw,
+            keep_days: Some(30),
+        },
+        RetentionPolicy {
+            sensor_id: Some("attic".to_string()),
+            resolution: Resolution::Raw,
+            keep_days: None,
+        },
+        RetentionPolicy {
+            sensor_id: None,
+            resolution: Resolution::Hour,
+            keep_days: Some(10),
+        },
+    ];
+    for policy in policies {
+        repo.upsert_retention_policy(policy).await.unwrap();
+    }
+    let sensors = || async {
+        let mut q = query(QueryFilter::default(), &["sensor_id"]);
+        q.pagination.page_size = 10;
+        let page = repo.fetch_sensor_readings_page(q).await.unwrap();
+        let mut sensors: Vec<String> = page.rows.into_iter().filter_map(|r| r.sensor_id).collect();
+        sensors.sort();
+        sensors
+    };
+
+    let report = repo.enforce_retention(true).await.unwrap();
+    let rows: Vec<_> = report
+        .entries
+        .iter()
+        .map(|e| (e.resolution, e.rows))
+        .collect();
+    assert_eq!(rows, vec![(Resolution::Raw, 2), (Resolution::Hour, 3)]);
+    assert_eq!(sensors().await.len(), 4);
+
+    let report = repo.enforce_retention(false).await.unwrap();
+    assert!(!report.dry_run);
+    assert_eq!(sensors().await, vec!["attic", "outside"]);
+
+    let daily = repo
+        .fetch_series(SeriesQuery {
+            sensor_id: None,
+            metric: "temperature".to_string(),
+            from: now - TimeDelta::days(50),
+            to: now + TimeDelta::days(1),
+            resolution: Some(Resolution::Day),
+        })
+        .await
+        .unwrap();
+    assert_eq!(daily.points.len(), 4);
+}
+
+pub(crate) async fn retention_policies_are_replaced_per_scope<R: Repository>(repo: R) {
+    for keep_days in [Some(30), Some(60)] {
+        repo.upsert_retention_policy(RetentionPolicy {
+            sensor_id: None,
+            resolution: Resolution::Raw,
+            keep_days,
+        })
+        .await
+        .unwrap();
+    }
+    repo.upsert_retention_policy(RetentionPolicy {
+        sensor_id: Some("balcony".to_string()),
+        resolution: Resolution::Raw,
+        keep_days: Some(7),
+    })
+    .await
+    .unwrap();
+
+    let policies = repo.fetch_retention_policies().await.unwrap();
+    assert_eq!(policies.len(), 2);
+    assert_eq!(policies[0].keep_days, Some(60));
+
+    assert!(
+        repo.delete_retention_policy(Some("balcony".to_string()), Resolution::Raw)
+            .await
+            .unwrap()
+    );
+    assert_eq!(repo.fetch_retention_policies().await.unwrap().len(), 1);
+}
+
+async fn stored_sensors<R: Repository>(repo: &R) -> Vec<String> {
+    let page = repo
+        .fetch_sensor_readings_page(query(QueryFilter::default(), &["sensor_id"]))
+        .await
+        .unwrap();
+    page.rows.into_iter().filter_map(|r| r.sensor_id).collect()
+}
+
+pub(crate) async fn unknown_sensors_are_quarantined_until_approved<R: Repository>(repo: R) {
+    let status = repo
+        .ingest_sensor_reading(
+            "sensor/update".to_string(),
+            reading("garage", 0, &[("temperature", 9.0)]),
+        )
+        .await
+        .unwrap();
+    assert_eq!(status, SensorStatus::Unapproved);
+    assert!(stored_sensors(&repo).await.is_empty());
+
+    let sensor = repo.fetch_sensor("garage").await.unwrap().unwrap();
+    assert_eq!(sensor.status, SensorStatus::Unapproved);
+    assert_eq!(sensor.quarantined_readings, 1);
+    assert_eq!(sensor.first_seen, Some(reading("garage", 0, &[]).timestamp));
+
+    assert_eq!(repo.approve_sensor("garage").await.unwrap(), Some(1));
+    assert_eq!(stored_sensors(&repo).await, vec!["garage"]);
+    let sensor = repo.fetch_sensor("garage").await.unwrap().unwrap();
+    assert_eq!(sensor.status, SensorStatus::Approved);
+    assert_eq!(sensor.quarantined_readings, 0);
+
+    let status = repo
+        .ingest_sensor_reading(
+            "sensor/update".to_string(),
+            reading("garage", 1, &[("temperature", 9.5)]),
+        )
+        .await
+        .unwrap();
+    assert_eq!(status, SensorStatus::Approved);
+    assert_eq!(stored_sensors(&repo).await.len(), 2);
+}
+
+pub(crate) async fn registered_sensors_are_approved<R: Repository>(repo: R) {
+    let metadata = SensorMetadata {
+        name: Some("Balcony".to_string()),
+        location: Some("balcony".to_string()),
+        altitude_m: Some(212.5),
+        installed_on: "2026-03-01".parse().ok(),
+        notes: Some("Moved to the balcony".to_string()),
+    };
+    repo.upsert_sensor("balcony", metadata.clone())
+        .await
+        .unwrap();
+
+    let sensor = repo.fetch_sensor("balcony").await.unwrap().unwrap();
+    assert_eq!(sensor.status, SensorStatus::Approved);
+    assert_eq!(sensor.metadata, metadata);
+    assert!(sensor.first_seen.is_none());
+
+    let status = repo
+        .ingest_sensor_reading(
+            "sensor/update".to_string(),
+            reading("balcony", 0, &[("temperature", 4.0)]),
+        )
+        .await
+        .unwrap();
+    assert_eq!(status, SensorStatus::Approved);
+
+    let renamed = SensorMetadata {
+        name: Some("Balcony west".to_string()),
+        ..metadata
+    };
+    repo.upsert_sensor("balcony", renamed.clone())
+        .await
+        .unwrap();
+    let sensors = repo.fetch_sensors().await.unwrap();
+    assert_eq!(sensors.len(), 1);
+    assert_eq!(sensors[0].metadata, renamed);
+}
+
+pub(crate) async fn deleting_a_sensor_drops_its_quarantine<R: Repository>(repo: R) {
+    repo.ingest_sensor_reading(
+        "sensor/update".to_string(),
+        reading("rogue", 0, &[("temperature", 99.0)]),
+    )
+    .await
+    .unwrap();
+
+    assert!(repo.delete_sensor("rogue").await.unwrap());
+    assert!(!repo.delete_sensor("rogue").await.unwrap());
+    assert!(repo.fetch_sensor("rogue").await.unwrap().is_none());
+    assert_eq!(repo.approve_sensor("rogue").await.unwrap(), None);
+    assert!(stored_sensors(&repo).await.is_empty());
+}
+
+fn calibration(sensor_id: &str, offset: f64, from: Option<&str>, to: Option<&str>) -> Calibration {
+    Calibration {
+        sensor_id: sensor_id.to_string(),
+        metric: "temperature".to_string(),
+        gain: 1.0,
+        offset,
+        valid_from: from.map(|f| f.parse().unwrap()),
+        valid_to: to.map(|t| t.parse().unwrap()),
+    }
+}
+
+fn outside_series() -> SeriesQuery {
+    series(
+        Some(Resolution::Day),
+        "2026-01-01T00:00:00Z",
+        "2026-01-03T00:00:00Z",
+    )
+}
+
+pub(crate) async fn calibrations_apply_during_ingestion<R: Repository>(repo: R) {
+    repo.insert_calibration(calibration(
+        "outside",
+        -1.0,
+        Some("2026-01-01T11:00:00Z"),
+        None,
+    ))
+    .await
+    .unwrap();
+    seed_hourly(&repo).await;
+
+    let filters = QueryFilter {
+        sensor_id: Some("outside".to_string()),
+        ..Default::default()
+    };
+    let page = repo
+        .fetch_sensor_readings_page(query(filters, &["temperature"]))
+        .await
+        .unwrap();
+    assert_eq!(temperatures(&page), vec![1.0, 3.0, 7.0, 3.0]);
+
+    let daily = repo.fetch_series(outside_series()).await.unwrap();
+    let sums: Vec<_> = daily
+        .points
+        .iter()
+        .map(|p| p.avg * p.count as f64)
+        .collect();
+    assert_eq!(sums, vec![11.0, 3.0]);
+}
+
+pub(crate) async fn overlapping_calibrations_are_rejected<R: Repository>(repo: R) {
+    repo.insert_calibration(calibration(
+        "outside",
+        0.5,
+        None,
+        Some("2026-03-01T00:00:00Z"),
+    ))
+    .await
+    .unwrap();
+    repo.insert_calibration(calibration(
+        "outside",
+        0.2,
+        Some("2026-03-01T00:00:00Z"),
+        None,
+    ))
+    .await
+    .unwrap();
+    repo.insert_calibration(calibration("inside", 0.1, None, None))
+        .await
+        .unwrap();
+
+    let overlapping = calibration("outside", 0.3, Some("2026-02-01T00:00:00Z"), None);
+    let res = repo.insert_calibration(overlapping).await;
+    assert!(matches!(res, Err(BsError::InvalidQuery(_))));
+
+    let res = repo
+        .insert_calibration(calibration(
+            "outside",
+            0.0,
+            Some("2026-05-01T00:00:00Z"),
+            Some("2026-04-01T00:00:00Z"),
+        ))
+        .await;
+    assert!(matches!(res, Err(BsError::InvalidQuery(_))));
+
+    assert_eq!(repo.fetch_calibrations(None).await.unwrap().len(), 3);
+    assert_eq!(
+        repo.fetch_calibrations(Some("outside".to_string()))
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+}
+
+pub(crate) async fn recompute_follows_calibration_changes<R: Repository>(repo: R) {
+    let id = repo
+        .insert_calibration(calibration("outside", 10.0, None, None))
+        .await
+        .unwrap();
+    seed_hourly(&repo).await;
+    assert_eq!(
+        repo.fetch_series(outside_series()).await.unwrap().points[0].max,
+        18.0
+    );
+
+    assert!(repo.delete_calibration(id).await.unwrap());
+    assert!(!repo.delete_calibration(id).await.unwrap());
+    repo.insert_calibration(Calibration {
+        gain: 2.0,
+        ..calibration("outside", 0.0, Some("2026-01-02T00:00:00Z"), None)
+    })
+    .await
+    .unwrap();
+
+    let recomputed = repo
+        .recompute_calibrations(Some("outside".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(recomputed, 4);
+
+    let daily = repo.fetch_series(outside_series()).await.unwrap();
+    let maxima: Vec<_> = daily.points.iter().map(|p| p.max).collect();
+    assert_eq!(maxima, vec![8.0, 8.0]);
+
+    // Other sensors keep their values
+    let mut inside = outside_series();
+    inside.sensor_id = Some("inside".to_string());
+    assert_eq!(repo.fetch_series(inside).await.unwrap().points[0].max, 20.0);
+}
+
+pub(crate) async fn recompute_keeps_rollups_of_expired_readings<R: Repository>(repo: R) {
+    seed_hourly(&repo).await;
+    expire_inside_readings(&repo).await;
+    repo.insert_calibration(calibration("outside", 10.0, None, None))
+        .await
+        .unwrap();
+
+    let recomputed = repo
+        .recompute_calibrations(Some("outside".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(recomputed, 4);
+    assert_eq!(
+        repo.fetch_series(outside_series()).await.unwrap().points[0].max,
+        18.0
+    );
+
+    assert_inside_rollups_kept(&repo).await;
+}
+
+/// Purge the raw readings of inside from [`seed_hourly`], only its rollups
+/// are left
+async fn expire_inside_readings<R: Repository>(repo: &R) {
+    repo.upsert_retention_policy(RetentionPolicy {
+        sensor_id: Some("inside".to_string()),
+        resolution: Resolution::Raw,
+        keep_days: Some(30),
+    })
+    .await
+    .unwrap();
+    repo.enforce_retention(false).await.unwrap();
+}
+
+async fn assert_inside_rollups_kept<R: Repository>(repo: &R) {
+    for resolution in [Resolution::Hour, Resolution::Day] {
+        let mut inside = series(
+            Some(resolution),
+            "2026-01-01T00:00:00Z",
+            "2026-01-03T00:00:00Z",
+        );
+        inside.sensor_id = Some("inside".to_string());
+        let points = repo.fetch_series(inside).await.unwrap().points;
+        assert_eq!(points.len(), 1, "{resolution:?}");
+        assert_eq!(points[0].max, 20.0, "{resolution:?}");
+    }
+}
+
+pub(crate) async fn removing_duplicates_keeps_rollups_of_expired_readings<R: Repository>(repo: R) {
+    let repo = repo.with_conflict_policy(ConflictPolicy::KeepBoth);
+    seed_hourly(&repo).await;
+    expire_inside_readings(&repo).await;
+    insert_all(&repo, [reading_at("outside", "2026-01-01T10:05:00Z", 9.0)]).await;
+    assert_eq!(
+        repo.fetch_series(outside_series()).await.unwrap().points[0].max,
+        9.0
+    );
+
+    assert_eq!(repo.remove_duplicate_readings(false).await.unwrap(), 1);
+    let daily = repo.fetch_series(outside_series()).await.unwrap().points;
+    assert_eq!((daily[0].max, daily[0].count), (8.0, 3));
+    assert_inside_rollups_kept(&repo).await;
+}
+
+pub(crate) async fn api_keys_authenticate_until_revoked<R: Repository>(repo: R) {
+    let reader = repo
+        .issue_api_key(NewApiKey {
+            name: "dashboard".to_string(),
+            scopes: vec![Scope::ReadReadings],
+        })
+        .await
+        .unwrap();
+    let admin = repo
+        .issue_api_key(NewApiKey {
+            name: "laptop".to_string(),
+            scopes: vec![Scope::Admin, Scope::WriteConfig],
+        })
+        .await
+        .unwrap();
+    assert_ne!(reader.key.id, admin.key.id);
+
+    let key = repo.authenticate(&reader.secret).await.unwrap().unwrap();
+    assert_eq!(key, reader.key);
+    let key = repo.authenticate(&admin.secret).await.unwrap().unwrap();
+    assert_eq!(key.scopes, vec![Scope::WriteConfig, Scope::Admin]);
+    assert_eq!(repo.authenticate("bs_unknown").await.unwrap(), None);
+
+    assert!(repo.revoke_api_key(reader.key.id).await.unwrap());
+    assert!(!repo.revoke_api_key(admin.key.id + 1).await.unwrap());
+    assert_eq!(repo.authenticate(&reader.secret).await.unwrap(), None);
+
+    let keys = repo.fetch_api_keys().await.unwrap();
+    let names: Vec<_> = keys.iter().map(|k| k.name.as_str()).collect();
+    assert_eq!(names, vec!["dashboard", "laptop"]);
+    assert!(keys[0].revoked_at.is_some());
+    assert!(keys[1].revoked_at.is_none());
+}
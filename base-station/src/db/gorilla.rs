@@ -0,0 +1,294 @@
+//! Gorilla-style compression of the columns of a chunk, after "Gorilla: A
+//! Fast, Scalable, In-Memory Time Series Database" (Pelkonen et al., 2015).
+//!
+//! Integers such as timestamps are stored as the difference between
+//! consecutive deltas, which is zero for evenly spaced readings and costs a
+//! single bit. Floats are XORed with their predecessor and only the bits that
+//! changed are stored, a repeated value costs a single bit as well.
+
+use crate::error::BsError;
+
+/// Bits of a delta-of-delta after `1`, `11`, `111` and `1111`. A zero is a
+/// single `0` bit.
+const DELTA_BUCKETS: [u8; 4] = [7, 14, 32, 64];
+
+#[derive(Debug, Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits used of the last byte
+    used: u8,
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.used.is_multiple_of(8) {
+            self.bytes.push(0);
+            self.used = 0;
+        }
+        if bit {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= 0x80 >> self.used;
+        }
+        self.used += 1;
+    }
+
+    /// The lowest `count` bits of `value`, most significant first
+    fn write_bits(&mut self, value: u64, count: u8) {
+        for shift in (0..count).rev() {
+            self.write_bit(value >> shift & 1 == 1);
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<bool, BsError> {
+        let byte = self
+            .bytes
+            .get(self.position / 8)
+            .ok_or_else(|| BsError::Other("Compressed column ends early".to_string()))?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, count: u8) -> Result<u64, BsError> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = value << 1 | u64::from(self.read_bit()?);
+        }
+        Ok(value)
+    }
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+/// Compress integers that mostly grow by the same step, like timestamps
+pub(super) fn encode_integers(values: &[i64]) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    let Some((first, rest)) = values.split_first() else {
+        return writer.finish();
+    };
+    writer.write_bits(*first as u64, 64);
+
+    let (mut previous, mut previous_delta) = (*first, 0i64);
+    for value in rest {
+        let delta = value.wrapping_sub(previous);
+        let encoded = zigzag(delta.wrapping_sub(previous_delta));
+        if encoded == 0 {
+            writer.write_bit(false);
+        } else {
+            let bucket = DELTA_BUCKETS
+                .iter()
+                .position(|bits| *bits == 64 || encoded < 1 << bits)
+                .expect("The last bucket holds any value");
+            writer.write_bits(u64::MAX, bucket as u8 + 1);
+            if bucket < DELTA_BUCKETS.len() - 1 {
+                writer.write_bit(false);
+            }
+            writer.write_bits(encoded, DELTA_BUCKETS[bucket]);
+        }
+        previous = *value;
+        previous_delta = delta;
+    }
+    writer.finish()
+}
+
+pub(super) fn decode_integers(bytes: &[u8], count: usize) -> Result<Vec<i64>, BsError> {
+    let mut values = Vec::with_capacity(count);
+    if count == 0 {
+        return Ok(values);
+    }
+    let mut reader = BitReader::new(bytes);
+    let mut previous = reader.read_bits(64)? as i64;
+    let mut previous_delta = 0i64;
+    values.push(previous);
+
+    while values.len() < count {
+        let mut delta_of_delta = 0;
+        if reader.read_bit()? {
+            let mut bucket = 0;
+            while bucket < DELTA_BUCKETS.len() - 1 && reader.read_bit()? {
+                bucket += 1;
+            }
+            delta_of_delta = unzigzag(reader.read_bits(DELTA_BUCKETS[bucket])?);
+        }
+        let delta = previous_delta.wrapping_add(delta_of_delta);
+        previous = previous.wrapping_add(delta);
+        previous_delta = delta;
+        values.push(previous);
+    }
+    Ok(values)
+}
+
+/// Compress floats by the bits that differ from the previous value
+pub(super) fn encode_floats(values: &[f64]) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    let Some((first, rest)) = values.split_first() else {
+        return writer.finish();
+    };
+    let mut previous = first.to_bits();
+    writer.write_bits(previous, 64);
+
+    // Leading and trailing zeros of the last stored window of changed bits
+    let mut window: Option<(u32, u32)> = None;
+    for value in rest {
+        let bits = value.to_bits();
+        let xor = bits ^ previous;
+        previous = bits;
+        if xor == 0 {
+            writer.write_bit(false);
+            continue;
+        }
+        writer.write_bit(true);
+
+        // Five bits hold the leading zeros
+        let leading = xor.leading_zeros().min(31);
+        let trailing = xor.trailing_zeros();
+        match window {
+            Some((window_leading, window_trailing))
+                if leading >= window_leading && trailing >= window_trailing =>
+            {
+                writer.write_bit(false);
+                let meaningful = 64 - window_leading - window_trailing;
+                writer.write_bits(xor >> window_trailing, meaningful as u8);
+            }
+            _ => {
+                writer.write_bit(true);
+                let meaningful = 64 - leading - trailing;
+                writer.write_bits(leading.into(), 5);
+                writer.write_bits((meaningful - 1).into(), 6);
+                writer.write_bits(xor >> trailing, meaningful as u8);
+                window = Some((leading, trailing));
+            }
+        }
+    }
+    writer.finish()
+}
+
+pub(super) fn decode_floats(bytes: &[u8], count: usize) -> Result<Vec<f64>, BsError> {
+    let mut values = Vec::with_capacity(count);
+    if count == 0 {
+        return Ok(values);
+    }
+    let mut reader = BitReader::new(bytes);
+    let mut previous = reader.read_bits(64)?;
+    values.push(f64::from_bits(previous));
+
+    let mut window = (0, 0);
+    while values.len() < count {
+        if reader.read_bit()? {
+            if reader.read_bit()? {
+                let leading = reader.read_bits(5)? as u32;
+                let meaningful = reader.read_bits(6)? as u32 + 1;
+                window = (leading, 64 - leading - meaningful);
+            }
+            let (leading, trailing) = window;
+            let meaningful = 64 - leading - trailing;
+            previous ^= reader.read_bits(meaningful as u8)? << trailing;
+        }
+        values.push(f64::from_bits(previous));
+    }
+    Ok(values)
+}
+
+/// One bit per flag
+pub(super) fn encode_flags(flags: &[bool]) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    for flag in flags {
+        writer.write_bit(*flag);
+    }
+    writer.finish()
+}
+
+pub(super) fn decode_flags(bytes: &[u8], count: usize) -> Result<Vec<bool>, BsError> {
+    let mut reader = BitReader::new(bytes);
+    (0..count).map(|_| reader.read_bit()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integers_round_trip() {
+        let values = [
+            1_767_225_600_000_000_000,
+            1_767_225_660_000_000_000,
+            1_767_225_720_000_000_000,
+            1_767_225_780_250_000_000,
+            1_767_225_780_250_000_001,
+            1_767_225_700_000_000_000,
+            i64::MAX,
+            i64::MIN,
+            0,
+            -5,
+        ];
+        for len in 0..=values.len() {
+            let encoded = encode_integers(&values[..len]);
+            assert_eq!(decode_integers(&encoded, len).unwrap(), values[..len]);
+        }
+    }
+
+    #[test]
+    fn floats_round_trip() {
+        let values = [
+            21.5,
+            21.5,
+            21.4,
+            -3.25,
+            0.0,
+            -0.0,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            f64::INFINITY,
+            1e-300,
+            21.4,
+        ];
+        for len in 0..=values.len() {
+            let encoded = encode_floats(&values[..len]);
+            let decoded = decode_floats(&encoded, len).unwrap();
+            let bits = |values: &[f64]| values.iter().map(|v| v.to_bits()).collect::<Vec<_>>();
+            assert_eq!(bits(&decoded), bits(&values[..len]));
+        }
+    }
+
+    #[test]
+    fn regular_series_compress_well() {
+        // A day of readings every minute, a few milliseconds late now and then
+        let timestamps: Vec<i64> = (0..1440i64)
+            .map(|i| 1_767_225_600_000_000_000 + i * 60_000_000_000 + (i % 7) * 3_000_000)
+            .collect();
+        let encoded = encode_integers(&timestamps);
+        assert!(encoded.len() < 1440 * 2, "{} bytes", encoded.len());
+
+        let temperatures: Vec<f64> = (0..1440).map(|i| 20.0 + f64::from(i / 30) * 0.5).collect();
+        let encoded = encode_floats(&temperatures);
+        assert!(encoded.len() < 1440, "{} bytes", encoded.len());
+    }
+
+    #[test]
+    fn truncated_columns_are_rejected() {
+        let encoded = encode_integers(&[1, 2, 4, 8]);
+        assert!(decode_integers(&encoded[..encoded.len() - 2], 4).is_err());
+        assert!(decode_floats(&[0; 4], 1).is_err());
+    }
+}
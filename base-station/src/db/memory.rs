@@ -0,0 +1,951 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Bound;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+
+use super::calibration::{calibrate, check_overlaps};
+use super::rollup::{self, Aggregate, RollupKey};
+use super::{
+    ApiKey, Calibration, ConflictPolicy, CursorKey, Keyset, MeasurementQuery, Metric, QueryFilter,
+    Repository, Resolution, RetentionPolicy, RetentionReport, RetentionReportEntry,
+    RetentionTarget, Sensor, SensorMetadata, SensorReading, SensorReadingsPage, SensorStatus,
+    Series, SeriesPoint, SeriesQuery, SortOrder, StoredCalibration,
+};
+use crate::error::BsError;
+use crate::{SensorReadingEvent, is_valid_metric_name};
+
+/// Repository keeping everything in process memory. Nothing survives a
+/// restart, which suits tests and throwaway deployments.
+#[derive(Debug, Clone)]
+pub struct InMemoryRepository {
+    state: Arc<RwLock<State>>,
+    cursor_key: CursorKey,
+    conflict_policy: ConflictPolicy,
+}
+
+#[derive(Debug)]
+struct State {
+    next_id: i64,
+    metrics: BTreeMap<String, Metric>,
+    /// Kept in `(timestamp, id)` order, the order pages are served in
+    readings: BTreeMap<Keyset, StoredReading>,
+    /// Keyed so a range over one metric yields buckets in series order
+    rollups: BTreeMap<RollupKey, Aggregate>,
+    retention_policies: Vec<RetentionPolicy>,
+    sensors: BTreeMap<String, SensorEntry>,
+    /// Topic and reading of every held back reading, per sensor
+    quarantine: BTreeMap<String, Vec<(String, SensorReadingEvent)>>,
+    next_calibration_id: i64,
+    calibrations: BTreeMap<i64, Calibration>,
+    next_api_key_id: i64,
+    /// Keys together with the hash of their secret
+    api_keys: BTreeMap<i64, (ApiKey, String)>,
+}
+
+#[derive(Debug)]
+struct SensorEntry {
+    metadata: SensorMetadata,
+    status: SensorStatus,
+    first_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+struct StoredReading {
+    sensor_id: String,
+    topic: String,
+    sequence: Option<i64>,
+    /// Calibrated values
+    metrics: BTreeMap<String, f64>,
+    /// Values as reported by the sensor
+    raw_metrics: BTreeMap<String, f64>,
+}
+
+impl Default for InMemoryRepository {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryRepository {
+    pub fn new() -> Self {
+        let metrics = Metric::defaults()
+            .into_iter()
+            .map(|metric| (metric.name.clone(), metric))
+            .collect();
+        Self {
+            state: Arc::new(RwLock::new(State {
+                next_id: 1,
+                metrics,
+                readings: BTreeMap::new(),
+                rollups: BTreeMap::new(),
+                retention_policies: Vec::new(),
+                sensors: BTreeMap::new(),
+                quarantine: BTreeMap::new(),
+                next_calibration_id: 1,
+                calibrations: BTreeMap::new(),
+                next_api_key_id: 1,
+                api_keys: BTreeMap::new(),
+            })),
+            cursor_key: CursorKey::random(),
+            conflict_policy: ConflictPolicy::default(),
+        }
+    }
+
+    /// Sign cursors with a fixed key
+    pub fn with_cursor_key(mut self, cursor_key: CursorKey) -> Self {
+        self.cursor_key = cursor_key;
+        self
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, State> {
+        self.state
+            .read()
+            .expect("In-memory repository lock poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, State> {
+        self.state
+            .write()
+            .expect("In-memory repository lock poisoned")
+    }
+}
+
+impl State {
+    /// Returns whether the reading was stored, readings the policy ignores are not
+    fn insert_reading(
+        &mut self,
+        topic: String,
+        reading: SensorReadingEvent,
+        policy: ConflictPolicy,
+    ) -> bool {
+        let stored = self.same_readings(&reading);
+        match policy {
+            ConflictPolicy::Ignore if !stored.is_empty() => return false,
+            ConflictPolicy::Replace => {
+                for keyset in &stored {
+                    self.readings.remove(keyset);
+                }
+            }
+            _ => {}
+        }
+
+        let keyset = Keyset {
+            timestamp: reading.timestamp,
+            id: self.next_id,
+        };
+        self.next_id += 1;
+
+        let calibrated = calibrate(
+            &self.sensor_calibrations(&reading.sensor_id),
+            reading.timestamp,
+            &reading.metrics,
+        );
+        for (metric, value) in &calibrated {
+            // Metrics we have not seen before get registered without a unit
+            self.metrics
+                .entry(metric.clone())
+                .or_insert_with(|| Metric {
+                    name: metric.clone(),
+                    unit: None,
+                    description: None,
+                });
+            self.add_to_rollups(&reading.sensor_id, reading.timestamp, metric, *value);
+        }
+        self.readings.insert(
+            keyset,
+            StoredReading {
+                sensor_id: reading.sensor_id.clone(),
+                topic,
+                sequence: reading.sequence,
+                metrics: calibrated,
+                raw_metrics: reading.metrics,
+            },
+        );
+        // The rollups still count the values of the replaced reading
+        if policy == ConflictPolicy::Replace && !stored.is_empty() {
+            self.rebuild_sensor_rollups(&reading.sensor_id, reading.timestamp);
+        }
+        true
+    }
+
+    /// Stored readings with the sensor, timestamp and sequence number of `reading`
+    fn same_readings(&self, reading: &SensorReadingEvent) -> Vec<Keyset> {
+        self.readings
+            .range(
+                Keyset {
+                    timestamp: reading.timestamp,
+                    id: i64::MIN,
+                }..=Keyset {
+                    timestamp: reading.timestamp,
+                    id: i64::MAX,
+                },
+            )
+            .filter(|(_, stored)| {
+                stored.sensor_id == reading.sensor_id && stored.sequence == reading.sequence
+            })
+            .map(|(keyset, _)| *keyset)
+            .collect()
+    }
+
+    /// Recompute the rollup buckets of a sensor the timestamp falls into
+    fn rebuild_sensor_rollups(&mut self, sensor_id: &str, timestamp: DateTime<Utc>) {
+        for resolution in Resolution::ROLLED_UP {
+            let bucket = resolution.bucket(timestamp);
+            let bucket_end = bucket
+                + resolution
+                    .bucket_width()
+                    .expect("Rolled up resolutions have a width");
+            self.rollups
+                .retain(|(r, _, b, s), _| !(*r == resolution && *b == bucket && s == sensor_id));
+            let samples: Vec<_> = self
+                .readings
+                .range(
+                    Keyset {
+                        timestamp: bucket,
+                        id: i64::MIN,
+                    }..Keyset {
+                        timestamp: bucket_end,
+                        id: i64::MIN,
+                    },
+                )
+                .filter(|(_, reading)| reading.sensor_id == sensor_id)
+                .flat_map(|(_, reading)| {
+                    reading
+                        .metrics
+                        .iter()
+                        .map(|(metric, value)| (metric.clone(), *value))
+                })
+                .collect();
+            for (metric, value) in samples {
+                self.add_to_rollup(resolution, sensor_id, bucket, &metric, value);
+            }
+        }
+    }
+
+    /// Recompute the rollup buckets holding readings of `sensor_id`, or of
+    /// every sensor. Buckets whose rollup counted more values than the
+    /// readings left in them are kept, part of their readings expired.
+    fn refresh_rollups(&mut self, sensor_id: Option<&str>) {
+        let mut refreshed = BTreeMap::new();
+        for (keyset, reading) in &self.readings {
+            if sensor_id.is_some_and(|wanted| reading.sensor_id != wanted) {
+                continue;
+            }
+            for (metric, value) in &reading.metrics {
+                rollup::add_to_rollups(
+                    &mut refreshed,
+                    &reading.sensor_id,
+                    keyset.timestamp,
+                    metric,
+                    *value,
+                );
+            }
+        }
+        for (key, aggregate) in refreshed {
+            match self.rollups.get(&key) {
+                Some(stored) if stored.count > aggregate.count => {}
+                _ => {
+                    self.rollups.insert(key, aggregate);
+                }
+            }
+        }
+    }
+
+    fn sensor_calibrations(&self, sensor_id: &str) -> Vec<Calibration> {
+        self.calibrations
+            .values()
+            .filter(|calibration| calibration.sensor_id == sensor_id)
+            .cloned()
+            .collect()
+    }
+
+    fn sensor(&self, sensor_id: &str) -> Option<Sensor> {
+        let entry = self.sensors.get(sensor_id)?;
+        Some(Sensor {
+            sensor_id: sensor_id.to_string(),
+            metadata: entry.metadata.clone(),
+            status: entry.status,
+            first_seen: entry.first_seen,
+            quarantined_readings: self.quarantine.get(sensor_id).map_or(0, Vec::len) as u64,
+        })
+    }
+
+    fn add_to_rollups(
+        &mut self,
+        sensor_id: &str,
+        timestamp: DateTime<Utc>,
+        metric: &str,
+        value: f64,
+    ) {
+        rollup::add_to_rollups(&mut self.rollups, sensor_id, timestamp, metric, value);
+    }
+
+    fn add_to_rollup(
+        &mut self,
+        resolution: Resolution,
+        sensor_id: &str,
+        bucket: DateTime<Utc>,
+        metric: &str,
+        value: f64,
+    ) {
+        let key = (
+            resolution,
+            metric.to_string(),
+            bucket,
+            sensor_id.to_string(),
+        );
+        self.rollups
+            .entry(key)
+            .and_modify(|aggregate| aggregate.add(value))
+            .or_insert_with(|| Aggregate::new(value));
+    }
+
+    fn expired_readings(&self, target: &RetentionTarget) -> Vec<Keyset> {
+        self.readings
+            .range(
+                ..Keyset {
+                    timestamp: target.cutoff,
+                    id: i64::MIN,
+                },
+            )
+            .filter(|(_, reading)| target.covers(&reading.sensor_id))
+            .map(|(keyset, _)| *keyset)
+            .collect()
+    }
+
+    fn expired_rollups(&self, target: &RetentionTarget) -> Vec<RollupKey> {
+        let cutoff = target.resolution.bucket(target.cutoff);
+        self.rollups
+            .keys()
+            .filter(|(resolution, _, bucket, sensor_id)| {
+                *resolution == target.resolution && *bucket < cutoff && target.covers(sensor_id)
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+fn matches_filters(filters: &QueryFilter, keyset: &Keyset, reading: &StoredReading) -> bool {
+    if filters.from.is_some_and(|from| keyset.timestamp < from)
+        || filters.to.is_some_and(|to| keyset.timestamp >= to)
+    {
+        return false;
+    }
+    if filters
+        .sensor_id
+        .as_ref()
+        .is_some_and(|sensor_id| &reading.sensor_id != sensor_id)
+    {
+        return false;
+    }
+    if filters.subscriptions.as_ref().is_some_and(|subscriptions| {
+        !subscriptions.matches(&reading.sensor_id, reading.metrics.keys())
+    }) {
+        return false;
+    }
+    if let Some(metric) = &filters.metric {
+        let Some(value) = reading.metrics.get(metric) else {
+            return false;
+        };
+        if filters.min.is_some_and(|min| *value < min)
+            || filters.max.is_some_and(|max| *value > max)
+        {
+            return false;
+        }
+    }
+    true
+}
+
+fn project(columns: &[String], keyset: &Keyset, reading: &StoredReading) -> SensorReading {
+    let mut projected = SensorReading {
+        sensor_id: None,
+        topic: None,
+        timestamp: None,
+        metrics: BTreeMap::new(),
+    };
+    for col in columns {
+        match col.as_str() {
+            "sensor_id" => projected.sensor_id = Some(reading.sensor_id.clone()),
+            "topic" => projected.topic = Some(reading.topic.clone()),
+            "timestamp" => projected.timestamp = Some(keyset.timestamp),
+            metric => {
+                if let Some(value) = reading.metrics.get(metric) {
+                    projected.metrics.insert(metric.to_string(), *value);
+                }
+            }
+        }
+    }
+    projected
+}
+
+#[async_trait]
+impl Repository for InMemoryRepository {
+    fn with_conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict_policy = policy;
+        self
+    }
+
+    async fn insert_sensor_reading(
+        &self,
+        topic: String,
+        reading: SensorReadingEvent,
+    ) -> Result<(), BsError> {
+        self.write()
+            .insert_reading(topic, reading, self.conflict_policy);
+
+        Ok(())
+    }
+
+    async fn insert_sensor_readings(
+        &self,
+        topic: String,
+        readings: Vec<SensorReadingEvent>,
+    ) -> Result<u64, BsError> {
+        let mut state = self.write();
+        let mut inserted = 0;
+        for reading in readings {
+            if state.insert_reading(topic.clone(), reading, self.conflict_policy) {
+                inserted += 1;
+            }
+        }
+
+        Ok(inserted)
+    }
+
+    async fn fetch_metrics(&self) -> Result<Vec<Metric>, BsError> {
+        Ok(self.read().metrics.values().cloned().collect())
+    }
+
+    async fn upsert_metric(&self, metric: Metric) -> Result<(), BsError> {
+        if !is_valid_metric_name(&metric.name) {
+            return Err(BsError::Other(format!(
+                "Invalid metric name: {}",
+                metric.name
+            )));
+        }
+        self.write().metrics.insert(metric.name.clone(), metric);
+
+        Ok(())
+    }
+
+    async fn fetch_sensors(&self) -> Result<Vec<Sensor>, BsError> {
+        let state = self.read();
+        Ok(state
+            .sensors
+            .keys()
+            .filter_map(|sensor_id| state.sensor(sensor_id))
+            .collect())
+    }
+
+    async fn fetch_sensor(&self, sensor_id: &str) -> Result<Option<Sensor>, BsError> {
+        Ok(self.read().sensor(sensor_id))
+    }
+
+    async fn upsert_sensor(
+        &self,
+        sensor_id: &str,
+        metadata: SensorMetadata,
+    ) -> Result<(), BsError> {
+        self.write()
+            .sensors
+            .entry(sensor_id.to_string())
+            .and_modify(|entry| entry.metadata = metadata.clone())
+            .or_insert_with(|| SensorEntry {
+                metadata,
+                status: SensorStatus::Approved,
+                first_seen: None,
+            });
+
+        Ok(())
+    }
+
+    async fn delete_sensor(&self, sensor_id: &str) -> Result<bool, BsError> {
+        let mut state = self.write();
+        state.quarantine.remove(sensor_id);
+
+        Ok(state.sensors.remove(sensor_id).is_some())
+    }
+
+    async fn quarantine_sensor_reading(
+        &self,
+        topic: String,
+        reading: SensorReadingEvent,
+    ) -> Result<(), BsError> {
+        let mut state = self.write();
+        state
+            .sensors
+            .entry(reading.sensor_id.clone())
+            .or_insert_with(|| SensorEntry {
+                metadata: SensorMetadata::default(),
+                status: SensorStatus::Unapproved,
+                first_seen: Some(reading.timestamp),
+            });
+        state
+            .quarantine
+            .entry(reading.sensor_id.clone())
+            .or_default()
+            .push((topic, reading));
+
+        Ok(())
+    }
+
+    async fn approve_sensor(&self, sensor_id: &str) -> Result<Option<u64>, BsError> {
+        let mut state = self.write();
+        let Some(entry) = state.sensors.get_mut(sensor_id) else {
+            return Ok(None);
+        };
+        entry.status = SensorStatus::Approved;
+
+        let quarantined = state.quarantine.remove(sensor_id).unwrap_or_default();
+        let released = quarantined.len() as u64;
+        for (topic, reading) in quarantined {
+            state.insert_reading(topic, reading, self.conflict_policy);
+        }
+
+        Ok(Some(released))
+    }
+
+    async fn fetch_calibrations(
+        &self,
+        sensor_id: Option<String>,
+    ) -> Result<Vec<StoredCalibration>, BsError> {
+        let mut calibrations: Vec<_> = self
+            .read()
+            .calibrations
+            .iter()
+            .filter(|(_, calibration)| {
+                sensor_id
+                    .as_ref()
+                    .is_none_or(|wanted| &calibration.sensor_id == wanted)
+            })
+            .map(|(id, calibration)| StoredCalibration {
+                id: *id,
+                calibration: calibration.clone(),
+            })
+            .collect();
+        calibrations.sort_by(|a, b| {
+            let key = |c: &Calibration| (c.sensor_id.clone(), c.metric.clone(), c.valid_from);
+            key(&a.calibration).cmp(&key(&b.calibration))
+        });
+
+        Ok(calibrations)
+    }
+
+    async fn insert_calibration(&self, calibration: Calibration) -> Result<i64, BsError> {
+        calibration.validate()?;
+        let mut state = self.write();
+        let stored: Vec<_> = state
+            .calibrations
+            .iter()
+            .map(|(id, calibration)| StoredCalibration {
+                id: *id,
+                calibration: calibration.clone(),
+            })
+            .collect();
+        check_overlaps(&calibration, &stored)?;
+        let id = state.next_calibration_id;
+        state.next_calibration_id += 1;
+        state.calibrations.insert(id, calibration);
+
+        Ok(id)
+    }
+
+    async fn delete_calibration(&self, id: i64) -> Result<bool, BsError> {
+        Ok(self.write().calibrations.remove(&id).is_some())
+    }
+
+    async fn fetch_api_keys(&self) -> Result<Vec<ApiKey>, BsError> {
+        Ok(self
+            .read()
+            .api_keys
+            .values()
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    async fn insert_api_key(&self, key: &ApiKey, secret_hash: &str) -> Result<i64, BsError> {
+        let mut state = self.write();
+        let id = state.next_api_key_id;
+        state.next_api_key_id += 1;
+        let key = ApiKey { id, ..key.clone() };
+        state.api_keys.insert(id, (key, secret_hash.to_string()));
+
+        Ok(id)
+    }
+
+    async fn fetch_api_key(&self, secret_hash: &str) -> Result<Option<ApiKey>, BsError> {
+        let state = self.read();
+        let key = state
+            .api_keys
+            .values()
+            .find(|(_, hash)| hash == secret_hash);
+        Ok(key.map(|(key, _)| key.clone()))
+    }
+
+    async fn revoke_api_key(&self, id: i64) -> Result<bool, BsError> {
+        match self.write().api_keys.get_mut(&id) {
+            Some((key, _)) => {
+                key.revoked_at.get_or_insert_with(Utc::now);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn recompute_calibrations(&self, sensor_id: Option<String>) -> Result<u64, BsError> {
+        let mut guard = self.write();
+        let state = &mut *guard;
+        let mut recomputed = 0;
+        for (keyset, reading) in state.readings.iter_mut() {
+            if sensor_id
+                .as_ref()
+                .is_some_and(|wanted| &reading.sensor_id != wanted)
+            {
+                continue;
+            }
+            let calibrations: Vec<_> = state
+                .calibrations
+                .values()
+                .filter(|calibration| calibration.sensor_id == reading.sensor_id)
+                .cloned()
+                .collect();
+            reading.metrics = calibrate(&calibrations, keyset.timestamp, &reading.raw_metrics);
+            recomputed += reading.metrics.len() as u64;
+        }
+        if recomputed > 0 {
+            state.refresh_rollups(sensor_id.as_deref());
+        }
+
+        Ok(recomputed)
+    }
+
+    async fn fetch_sensor_readings_page(
+        &self,
+        query: MeasurementQuery,
+    ) -> Result<SensorReadingsPage, BsError> {
+        query.validate(&self.fetch_metrics().await?)?;
+        let position = query.position(&self.cursor_key)?.map(|c| c.keyset());
+        let order = query.scan_order();
+
+        let state = self.read();
+        let range = match (position, order) {
+            (Some(keyset), SortOrder::Asc) => (Bound::Excluded(keyset), Bound::Unbounded),
+            (Some(keyset), SortOrder::Desc) => (Bound::Unbounded, Bound::Excluded(keyset)),
+            (None, _) => (Bound::Unbounded, Bound::Unbounded),
+        };
+        let scan: Box<dyn Iterator<Item = (&Keyset, &StoredReading)>> = match order {
+            SortOrder::Asc => Box::new(state.readings.range(range)),
+            SortOrder::Desc => Box::new(state.readings.range(range).rev()),
+        };
+        // One extra row tells us whether there is a next page
+        let mut readings: Vec<_> = scan
+            .filter(|(keyset, reading)| matches_filters(&query.filters, keyset, reading))
+            .take(query.pagination.page_size + 1)
+            .map(|(keyset, reading)| (*keyset, project(&query.columns, keyset, reading)))
+            .collect();
+        drop(state);
+
+        let has_more = readings.len() > query.pagination.page_size;
+        readings.truncate(query.pagination.page_size);
+
+        Ok(query.into_page(&self.cursor_key, readings, has_more))
+    }
+
+    async fn fetch_stored_readings(
+        &self,
+        after: i64,
+        query: MeasurementQuery,
+    ) -> Result<Vec<(i64, SensorReading)>, BsError> {
+        query.validate(&self.fetch_metrics().await?)?;
+        let state = self.read();
+        // Ids are handed out in the order readings are stored
+        let mut stored: Vec<_> = state
+            .readings
+            .iter()
+            .filter(|(keyset, reading)| {
+                keyset.id > after && matches_filters(&query.filters, keyset, reading)
+            })
+            .collect();
+        stored.sort_by_key(|(keyset, _)| keyset.id);
+        Ok(stored
+            .into_iter()
+            .take(query.pagination.page_size)
+            .map(|(keyset, reading)| (keyset.id, project(&query.columns, keyset, reading)))
+            .collect())
+    }
+
+    async fn fetch_last_stored_position(&self) -> Result<i64, BsError> {
+        Ok(self.read().next_id - 1)
+    }
+
+    async fn fetch_latest_readings(&self) -> Result<Vec<SensorReading>, BsError> {
+        let state = self.read();
+        let mut latest = BTreeMap::new();
+        for (keyset, reading) in state.readings.iter().rev() {
+            latest
+                .entry(reading.sensor_id.as_str())
+                .or_insert_with(|| SensorReading {
+                    sensor_id: Some(reading.sensor_id.clone()),
+                    topic: Some(reading.topic.clone()),
+                    timestamp: Some(keyset.timestamp),
+                    metrics: reading.metrics.clone(),
+                });
+        }
+        Ok(latest.into_values().collect())
+    }
+
+    async fn fetch_series(&self, query: SeriesQuery) -> Result<Series, BsError> {
+        query.validate()?;
+        let resolution = query.effective_resolution();
+        let sensor_matches = |sensor_id: &str| {
+            query
+                .sensor_id
+                .as_deref()
+                .is_none_or(|wanted| wanted == sensor_id)
+        };
+
+        let state = self.read();
+        let points = match resolution {
+            Resolution::Raw => state
+                .readings
+                .range(
+                    Keyset {
+                        timestamp: query.from,
+                        id: i64::MIN,
+                    }..Keyset {
+                        timestamp: query.to,
+                        id: i64::MIN,
+                    },
+                )
+                .filter(|(_, reading)| sensor_matches(&reading.sensor_id))
+                .filter_map(|(keyset, reading)| {
+                    let value = *reading.metrics.get(&query.metric)?;
+                    Some(SeriesPoint {
+                        sensor_id: reading.sensor_id.clone(),
+                        timestamp: keyset.timestamp,
+                        min: value,
+                        max: value,
+                        avg: value,
+                        count: 1,
+                    })
+                })
+                .collect(),
+            rolled_up => {
+                let from = (
+                    rolled_up,
+                    query.metric.clone(),
+                    rolled_up.bucket(query.from),
+                    String::new(),
+                );
+                let to = (rolled_up, query.metric.clone(), query.to, String::new());
+                state
+                    .rollups
+                    .range(from..to)
+                    .filter(|((_, _, _, sensor_id), _)| sensor_matches(sensor_id))
+                    .map(|((_, _, bucket, sensor_id), aggregate)| SeriesPoint {
+                        sensor_id: sensor_id.clone(),
+                        timestamp: *bucket,
+                        min: aggregate.min,
+                        max: aggregate.max,
+                        avg: aggregate.sum / aggregate.count as f64,
+                        count: aggregate.count,
+                    })
+                    .collect()
+            }
+        };
+
+        Ok(Series {
+            metric: query.metric,
+            resolution,
+            points,
+        })
+    }
+
+    async fn rebuild_rollups(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<u64, BsError> {
+        let mut state = self.write();
+        let from = match from.or_else(|| state.readings.keys().next().map(|k| k.timestamp)) {
+            Some(from) => from,
+            None => return Ok(0),
+        };
+        let to = to.unwrap_or_else(Utc::now);
+        // Whole days so the hourly and daily buckets stay consistent
+        let from = Resolution::Day.bucket(from);
+        let to = Resolution::Day.bucket(to) + TimeDelta::days(1);
+
+        // Buckets starting before the oldest reading of their sensor may
+        // count expired readings, they are only replaced by bigger aggregates
+        let mut oldest = HashMap::new();
+        for (keyset, reading) in &state.readings {
+            oldest
+                .entry(reading.sensor_id.clone())
+                .or_insert(keyset.timestamp);
+        }
+        state.rollups.retain(|(_, _, bucket, sensor_id), _| {
+            *bucket < from
+                || *bucket >= to
+                || oldest.get(sensor_id).is_none_or(|oldest| bucket < oldest)
+        });
+
+        let mut rebuilt = BTreeMap::new();
+        for (keyset, reading) in state.readings.range(
+            Keyset {
+                timestamp: from,
+                id: i64::MIN,
+            }..Keyset {
+                timestamp: to,
+                id: i64::MIN,
+            },
+        ) {
+            for (metric, value) in &reading.metrics {
+                rollup::add_to_rollups(
+                    &mut rebuilt,
+                    &reading.sensor_id,
+                    keyset.timestamp,
+                    metric,
+                    *value,
+                );
+            }
+        }
+        let mut stored = 0;
+        for (key, aggregate) in rebuilt {
+            match state.rollups.get(&key) {
+                Some(kept) if kept.count > aggregate.count => {}
+                _ => {
+                    state.rollups.insert(key, aggregate);
+                    stored += 1;
+                }
+            }
+        }
+
+        Ok(stored)
+    }
+
+    async fn remove_duplicate_readings(&self, dry_run: bool) -> Result<u64, BsError> {
+        let mut state = self.write();
+        let mut seen = std::collections::HashSet::new();
+        // Iterated in (timestamp, id) order so the earliest stored copy is kept
+        let duplicates: Vec<_> = state
+            .readings
+            .iter()
+            .filter(|(keyset, reading)| {
+                !seen.insert((
+                    reading.sensor_id.clone(),
+                    keyset.timestamp,
+                    reading.sequence,
+                ))
+            })
+            .map(|(keyset, _)| *keyset)
+            .collect();
+        if dry_run || duplicates.is_empty() {
+            return Ok(duplicates.len() as u64);
+        }
+        let mut buckets = BTreeSet::new();
+        for keyset in &duplicates {
+            if let Some(reading) = state.readings.remove(keyset) {
+                buckets.insert((reading.sensor_id, Resolution::Hour.bucket(keyset.timestamp)));
+            }
+        }
+        for (sensor_id, bucket) in buckets {
+            state.rebuild_sensor_rollups(&sensor_id, bucket);
+        }
+
+        Ok(duplicates.len() as u64)
+    }
+
+    async fn fetch_retention_policies(&self) -> Result<Vec<RetentionPolicy>, BsError> {
+        let mut policies = self.read().retention_policies.clone();
+        policies.sort_by(|a, b| {
+            (a.resolution.as_str(), &a.sensor_id).cmp(&(b.resolution.as_str(), &b.sensor_id))
+        });
+        Ok(policies)
+    }
+
+    async fn upsert_retention_policy(&self, policy: RetentionPolicy) -> Result<(), BsError> {
+        policy.validate()?;
+        let mut state = self.write();
+        state
+            .retention_policies
+            .retain(|p| p.sensor_id != policy.sensor_id || p.resolution != policy.resolution);
+        state.retention_policies.push(policy);
+
+        Ok(())
+    }
+
+    async fn delete_retention_policy(
+        &self,
+        sensor_id: Option<String>,
+        resolution: Resolution,
+    ) -> Result<bool, BsError> {
+        let mut state = self.write();
+        let before = state.retention_policies.len();
+        state
+            .retention_policies
+            .retain(|p| p.sensor_id != sensor_id || p.resolution != resolution);
+
+        Ok(state.retention_policies.len() < before)
+    }
+
+    async fn enforce_retention(&self, dry_run: bool) -> Result<RetentionReport, BsError> {
+        let policies = self.fetch_retention_policies().await?;
+        let mut state = self.write();
+        let mut entries = Vec::new();
+        for target in RetentionTarget::from_policies(&policies, Utc::now()) {
+            let rows = match target.resolution {
+                Resolution::Raw => {
+                    let expired = state.expired_readings(&target);
+                    if !dry_run {
+                        for keyset in &expired {
+                            state.readings.remove(keyset);
+                        }
+                    }
+                    expired.len()
+                }
+                _ => {
+                    let expired = state.expired_rollups(&target);
+                    if !dry_run {
+                        for key in &expired {
+                            state.rollups.remove(key);
+                        }
+                    }
+                    expired.len()
+                }
+            };
+            entries.push(RetentionReportEntry {
+                sensor_id: target.sensor_id,
+                resolution: target.resolution,
+                cutoff: target.cutoff,
+                rows: rows as u64,
+            });
+        }
+
+        Ok(RetentionReport { dry_run, entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::conformance::{TestBackend, conformance_tests};
+
+    struct InMemoryBackend(InMemoryRepository);
+
+    impl TestBackend for InMemoryBackend {
+        type Repository = InMemoryRepository;
+
+        async fn create() -> Option<Self> {
+            Some(Self(InMemoryRepository::new()))
+        }
+
+        fn repository(&self) -> InMemoryRepository {
+            self.0.clone()
+        }
+    }
+
+    conformance_tests!(InMemoryBackend);
+}
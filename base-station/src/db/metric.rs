@@ -0,0 +1,38 @@
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+/// Catalogue entry describing a kind of measurement a sensor can report
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Object, sqlx::FromRow)]
+pub struct Metric {
+    pub name: String,
+    pub unit: Option<String>,
+    pub description: Option<String>,
+}
+
+/// Catalogue a fresh repository starts with. The migrations seed the same
+/// rows, the conformance suite checks every backend agrees with it.
+const DEFAULT_METRICS: &[(&str, &str, &str)] = &[
+    ("temperature", "°C", "Air temperature"),
+    ("pressure", "Pa", "Barometric pressure"),
+    ("humidity", "%", "Relative humidity"),
+    ("co2", "ppm", "Carbon dioxide concentration"),
+    ("voc", "index", "Volatile organic compounds index"),
+    ("pm2_5", "µg/m³", "Fine particulate matter concentration"),
+    ("lux", "lx", "Illuminance"),
+    ("wind_speed", "m/s", "Wind speed"),
+];
+
+impl Metric {
+    /// The catalogue a fresh repository starts with, for backends without
+    /// migrations to seed it
+    pub(crate) fn defaults() -> Vec<Metric> {
+        DEFAULT_METRICS
+            .iter()
+            .map(|(name, unit, description)| Metric {
+                name: name.to_string(),
+                unit: Some(unit.to_string()),
+                description: Some(description.to_string()),
+            })
+            .collect()
+    }
+}
@@ -1,134 +1,212 @@
 use async_trait::async_trait;
-use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use chrono::{DateTime, Utc};
 
 use crate::SensorReadingEvent;
 use crate::error::BsError;
 
+mod aggregate;
+mod api_key;
+mod calibration;
+mod columnar;
+#[cfg(test)]
+mod conformance;
+mod gorilla;
+mod memory;
+mod metric;
 mod pagination;
-
-pub use pagination::MeasurementQuery;
+mod partitioned;
+mod postgres;
+mod reading;
+mod retention;
+mod rollup;
+pub mod schema;
+mod sensor;
+mod sqlite;
+
+pub use aggregate::{
+    AggregateFunction, AggregateQuery, AggregateRow, Aggregation, GapFill, MAX_AGGREGATE_SPAN,
+    MAX_BUCKETS, parse_bucket_width,
+};
+pub use api_key::{ApiKey, IssuedApiKey, NewApiKey, Scope};
+pub use calibration::{Calibration, StoredCalibration};
+pub use columnar::ColumnarRepository;
+pub use memory::InMemoryRepository;
+pub use metric::Metric;
+pub use pagination::{
+    Cursor, CursorKey, Direction, Keyset, MeasurementQuery, Pagination, QueryFilter, SortOrder,
+    Subscriptions,
+};
+pub use partitioned::{PartitionPeriod, PartitionedSqliteRepository};
+pub use postgres::PostgresRepository;
+pub use reading::{ConflictPolicy, SensorReading, SensorReadingsPage};
+pub use retention::{
+    RetentionPolicy, RetentionReport, RetentionReportEntry, RetentionTarget, spawn_retention_task,
+};
+pub use rollup::{Resolution, Series, SeriesPoint, SeriesQuery};
+pub use sensor::{Sensor, SensorMetadata, SensorStatus};
+pub use sqlite::SqliteRepository;
 
 #[async_trait]
 pub trait Repository: Send + Sync {
+    /// Resolve readings repeating a stored one with `policy` instead of
+    /// ignoring them
+    fn with_conflict_policy(self, policy: ConflictPolicy) -> Self
+    where
+        Self: Sized;
+    /// Store a reading straight away, whatever the state of its sensor
     async fn insert_sensor_reading(
         &self,
         topic: String,
         sensor_reading: SensorReadingEvent,
     ) -> Result<(), BsError>;
-    async fn fetch_sensor_readings_page(&self, query: MeasurementQuery) -> Result<(), BsError>;
-}
-
-#[derive(Debug, Clone)]
-pub struct SqliteRepository {
-    pool: SqlitePool,
-}
-
-impl SqliteRepository {
-    pub fn new(pool: SqlitePool) -> Self {
-        Self { pool }
-    }
-}
-
-#[async_trait]
-impl Repository for SqliteRepository {
-    async fn insert_sensor_reading(
+    /// Store readings in one transaction, resolving those repeating a stored
+    /// reading with the conflict policy. Returns how many were stored.
+    async fn insert_sensor_readings(
         &self,
         topic: String,
-        reading: SensorReadingEvent,
-    ) -> Result<(), BsError> {
-        let pool = self.pool.clone();
-        sqlx::query!(
-            "INSERT INTO sensor_readings (sensor_id, topic, timestamp, temperature, pressure, \
-             humidity) 
-                VALUES (?,?,?,?,?,?)",
-            reading.sensor_id,
-            topic,
-            reading.timestamp,
-            reading.temperature,
-            reading.pressure,
-            reading.humidity
-        )
-        .execute(&pool)
-        .await?;
-
-        Ok(())
-    }
-
-    async fn fetch_sensor_readings_page(&self, query: MeasurementQuery) -> Result<(), BsError> {
-        if query.columns.is_empty() || !query.are_columns_sane() {
-            return Err(BsError::Other("Invalid columns".to_string()));
-        }
-        let mut qb = QueryBuilder::<Sqlite>::new("SELECT ");
-
-        for (i, col) in query.columns.iter().enumerate() {
-            if i > 0 {
-                qb.push(", ");
+        sensor_readings: Vec<SensorReadingEvent>,
+    ) -> Result<u64, BsError>;
+    /// Store a reading received from the broker. Readings of sensors that
+    /// have not been approved are quarantined instead.
+    async fn ingest_sensor_reading(
+        &self,
+        topic: String,
+        sensor_reading: SensorReadingEvent,
+    ) -> Result<SensorStatus, BsError> {
+        match self.fetch_sensor(&sensor_reading.sensor_id).await? {
+            Some(sensor) if sensor.status == SensorStatus::Approved => {
+                self.insert_sensor_reading(topic, sensor_reading).await?;
+                Ok(SensorStatus::Approved)
             }
-            qb.push(col);
-        }
-
-        qb.push(" FROM sensor_readings");
-
-        // WHERE clause
-        let mut has_where = false;
-        let mut push_and = |qb: &mut QueryBuilder<Sqlite>| {
-            if has_where {
-                qb.push(" AND ");
-            } else {
-                qb.push(" WHERE ");
-                has_where = true;
+            _ => {
+                self.quarantine_sensor_reading(topic, sensor_reading)
+                    .await?;
+                Ok(SensorStatus::Unapproved)
             }
-        };
-
-        let f = &query.filters;
-
-        if let Some(sensor_id) = &f.sensor_id {
-            push_and(&mut qb);
-            qb.push("sensor_id = ").push_bind(sensor_id);
-        }
-
-        if let Some(min_temp) = f.min_temperature {
-            push_and(&mut qb);
-            qb.push("temperature >= ").push_bind(min_temp);
-        }
-
-        if let Some(max_temp) = f.max_temperature {
-            push_and(&mut qb);
-            qb.push("temperature <= ").push_bind(max_temp);
         }
-
-        if let Some(min_hum) = f.min_humidity {
-            push_and(&mut qb);
-            qb.push("humidity >= ").push_bind(min_hum);
-        }
-
-        if let Some(max_hum) = f.max_humidity {
-            push_and(&mut qb);
-            qb.push("humidity <= ").push_bind(max_hum);
-        }
-
-        if let Some(min_press) = f.min_pressure {
-            push_and(&mut qb);
-            qb.push("pressure >= ").push_bind(min_press);
-        }
-
-        if let Some(max_press) = f.max_pressure {
-            push_and(&mut qb);
-            qb.push("pressure <= ").push_bind(max_press);
-        }
-
-        if let Some(after) = query.pagination.after {
-            push_and(&mut qb);
-            qb.push("timestamp > ").push_bind(after);
-        }
-
-        qb.push(" ORDER BY timestamp ASC");
-        qb.push(" LIMIT ")
-            .push_bind(query.pagination.page_size as i64);
-
-        let sql_query = qb.build();
-        let rows = sql_query.fetch_all(&self.pool).await?;
-
-        unimplemented!("Need to format the result")
     }
+    async fn fetch_metrics(&self) -> Result<Vec<Metric>, BsError>;
+    async fn upsert_metric(&self, metric: Metric) -> Result<(), BsError>;
+    async fn fetch_sensors(&self) -> Result<Vec<Sensor>, BsError>;
+    async fn fetch_sensor(&self, sensor_id: &str) -> Result<Option<Sensor>, BsError>;
+    /// Update the metadata of a sensor. Sensors registered this way are
+    /// approved right away.
+    async fn upsert_sensor(&self, sensor_id: &str, metadata: SensorMetadata)
+    -> Result<(), BsError>;
+    /// Forget a sensor together with its quarantined readings. Stored
+    /// readings are kept.
+    async fn delete_sensor(&self, sensor_id: &str) -> Result<bool, BsError>;
+    /// Hold a reading back, registering its sensor as unapproved when unknown
+    async fn quarantine_sensor_reading(
+        &self,
+        topic: String,
+        sensor_reading: SensorReadingEvent,
+    ) -> Result<(), BsError>;
+    /// Accept a sensor and store its quarantined readings. Returns how many
+    /// readings were released, `None` for unknown sensors.
+    async fn approve_sensor(&self, sensor_id: &str) -> Result<Option<u64>, BsError>;
+    async fn fetch_calibrations(
+        &self,
+        sensor_id: Option<String>,
+    ) -> Result<Vec<StoredCalibration>, BsError>;
+    /// Store a calibration, rejecting ones overlapping another calibration
+    /// of the same sensor and metric. Stored readings are corrected once
+    /// recomputed.
+    async fn insert_calibration(&self, calibration: Calibration) -> Result<i64, BsError>;
+    async fn delete_calibration(&self, id: i64) -> Result<bool, BsError>;
+    /// Correct the stored raw values with the current calibrations and
+    /// rebuild the affected rollups. Returns how many values were corrected.
+    async fn recompute_calibrations(&self, sensor_id: Option<String>) -> Result<u64, BsError>;
+    /// Every issued API key, revoked ones included
+    async fn fetch_api_keys(&self) -> Result<Vec<ApiKey>, BsError>;
+    /// Store a key by the hash of its secret. Returns the id it was given.
+    async fn insert_api_key(&self, key: &ApiKey, secret_hash: &str) -> Result<i64, BsError>;
+    /// The key whose secret hashes to `secret_hash`, revoked or not
+    async fn fetch_api_key(&self, secret_hash: &str) -> Result<Option<ApiKey>, BsError>;
+    /// Refuse a key from now on. Returns false for unknown keys.
+    async fn revoke_api_key(&self, id: i64) -> Result<bool, BsError>;
+    /// Issue a key with a fresh secret, only the hash of the secret is stored
+    async fn issue_api_key(&self, key: NewApiKey) -> Result<IssuedApiKey, BsError> {
+        let mut issued = IssuedApiKey::generate(key)?;
+        let secret_hash = api_key::hash_secret(&issued.secret);
+        issued.key.id = self.insert_api_key(&issued.key, &secret_hash).await?;
+        Ok(issued)
+    }
+    /// The key a secret belongs to, none when unknown or revoked
+    async fn authenticate(&self, secret: &str) -> Result<Option<ApiKey>, BsError> {
+        let key = self.fetch_api_key(&api_key::hash_secret(secret)).await?;
+        Ok(key.filter(|key| key.revoked_at.is_none()))
+    }
+    async fn fetch_sensor_readings_page(
+        &self,
+        query: MeasurementQuery,
+    ) -> Result<SensorReadingsPage, BsError>;
+    /// Readings matching the filters of `query` stored after position
+    /// `after`, at most a page of them in the order they were stored and each
+    /// with its position. A reading stored late comes after the readings
+    /// stored before it, whatever its timestamp. Cursors and the sort order of
+    /// the query are not used.
+    async fn fetch_stored_readings(
+        &self,
+        after: i64,
+        query: MeasurementQuery,
+    ) -> Result<Vec<(i64, SensorReading)>, BsError>;
+    /// Position of the last stored reading, 0 before the first
+    async fn fetch_last_stored_position(&self) -> Result<i64, BsError>;
+    /// Newest stored reading of a sensor with every metric it carries
+    async fn fetch_latest_reading(
+        &self,
+        sensor_id: &str,
+    ) -> Result<Option<SensorReading>, BsError> {
+        let catalogue = self.fetch_metrics().await?;
+        let query = MeasurementQuery {
+            filters: QueryFilter {
+                sensor_id: Some(sensor_id.to_string()),
+                ..Default::default()
+            },
+            pagination: Pagination {
+                page_size: 1,
+                order: SortOrder::Desc,
+                ..Default::default()
+            },
+            columns: MeasurementQuery::BASE_COLUMNS
+                .iter()
+                .map(|c| c.to_string())
+                .chain(catalogue.into_iter().map(|m| m.name))
+                .collect(),
+        };
+        Ok(self.fetch_sensor_readings_page(query).await?.rows.pop())
+    }
+    /// Newest stored reading of every sensor with every metric it carries,
+    /// fetched at once and ordered by sensor
+    async fn fetch_latest_readings(&self) -> Result<Vec<SensorReading>, BsError>;
+    /// Aggregate the readings of a time span into buckets. Defaults to
+    /// scanning the raw readings a page at a time.
+    async fn aggregate_readings(&self, query: AggregateQuery) -> Result<Aggregation, BsError> {
+        aggregate::aggregate_pages(self, query).await
+    }
+    /// Serve a metric series, from the rollups when the span is long enough
+    async fn fetch_series(&self, query: SeriesQuery) -> Result<Series, BsError>;
+    /// Recompute the rollups from the raw readings. Defaults to the whole span
+    /// covered by raw readings so aggregates of pruned data are kept. Buckets
+    /// starting before the oldest reading of their sensor keep their rollup
+    /// when it counted more values, part of their readings expired.
+    async fn rebuild_rollups(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<u64, BsError>;
+    async fn fetch_retention_policies(&self) -> Result<Vec<RetentionPolicy>, BsError>;
+    /// Replaces the policy with the same sensor and resolution
+    async fn upsert_retention_policy(&self, policy: RetentionPolicy) -> Result<(), BsError>;
+    async fn delete_retention_policy(
+        &self,
+        sensor_id: Option<String>,
+        resolution: Resolution,
+    ) -> Result<bool, BsError>;
+    /// Remove readings repeating the sensor, timestamp and sequence number of
+    /// an earlier one and rebuild the affected rollups. A dry run only counts them.
+    async fn remove_duplicate_readings(&self, dry_run: bool) -> Result<u64, BsError>;
+    /// Remove data past its retention. A dry run only counts what would go.
+    async fn enforce_retention(&self, dry_run: bool) -> Result<RetentionReport, BsError>;
 }
@@ -1,41 +1,454 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use chrono::{DateTime, Utc};
-use poem_openapi::Object;
-use serde::Deserialize;
+use hmac::{Hmac, Mac};
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{Metric, SensorReading, SensorReadingsPage};
+use crate::error::BsError;
 
-#[derive(Debug, Deserialize, Object)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Object)]
 pub struct QueryFilter {
     pub sensor_id: Option<String>,
-    pub min_temperature: Option<f32>,
-    pub max_temperature: Option<f32>,
-    pub min_humidity: Option<f32>,
-    pub max_humidity: Option<f32>,
-    pub min_pressure: Option<f32>,
-    pub max_pressure: Option<f32>,
+    /// Metric the `min` and `max` bounds apply to
+    pub metric: Option<String>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Readings taken at or after this instant
+    pub from: Option<DateTime<Utc>>,
+    /// Readings taken before this instant
+    pub to: Option<DateTime<Utc>>,
+    /// Readings of a subscribed sensor or carrying a subscribed metric, what
+    /// live connections follow
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[oai(skip)]
+    pub subscriptions: Option<Subscriptions>,
+}
+
+/// Sensors and metrics a live connection subscribed to
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subscriptions {
+    pub sensors: BTreeSet<String>,
+    pub metrics: BTreeSet<String>,
+}
+
+impl Subscriptions {
+    /// Whether a reading of `sensor_id` carrying `metrics` is subscribed
+    pub fn matches<'a>(
+        &self,
+        sensor_id: &str,
+        mut metrics: impl Iterator<Item = &'a String>,
+    ) -> bool {
+        self.sensors.contains(sensor_id) || metrics.any(|metric| self.metrics.contains(metric))
+    }
+
+    pub fn len(&self) -> usize {
+        self.sensors.len() + self.metrics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
-#[derive(Debug, Deserialize, Object)]
+impl QueryFilter {
+    pub fn is_sane(&self, catalogue: &[Metric]) -> bool {
+        let bounds_sane = match &self.metric {
+            Some(metric) => catalogue.iter().any(|m| &m.name == metric),
+            None => self.min.is_none() && self.max.is_none(),
+        };
+        let span_sane = match (self.from, self.to) {
+            (Some(from), Some(to)) => from < to,
+            _ => true,
+        };
+        bounds_sane && span_sane
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+pub enum SortOrder {
+    /// Oldest reading first
+    #[default]
+    Asc,
+    /// Newest reading first
+    Desc,
+}
+
+impl SortOrder {
+    pub fn reversed(self) -> Self {
+        match self {
+            SortOrder::Asc => SortOrder::Desc,
+            SortOrder::Desc => SortOrder::Asc,
+        }
+    }
+
+    pub fn as_sql(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Object)]
 pub struct Pagination {
-    pub after: Option<DateTime<Utc>>,
+    /// Cursor returned as `next` by the previous page
+    pub after: Option<String>,
+    /// Cursor returned as `previous` by the following page
+    pub before: Option<String>,
     #[serde(default = "default_page_size")]
+    #[oai(default = "default_page_size")]
     pub page_size: usize,
+    #[serde(default)]
+    #[oai(default)]
+    pub order: SortOrder,
+}
+
+impl Pagination {
+    /// Largest page a query may ask for
+    pub const MAX_PAGE_SIZE: usize = 5000;
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self {
+            after: None,
+            before: None,
+            page_size: default_page_size(),
+            order: SortOrder::default(),
+        }
+    }
 }
 
-fn default_page_size() -> usize{
+fn default_page_size() -> usize {
     10
 }
 
-#[derive(Debug, Deserialize, Object)]
+#[derive(Debug, Clone, Deserialize, Object)]
 pub struct MeasurementQuery {
     pub filters: QueryFilter,
     pub pagination: Pagination,
     pub columns: Vec<String>,
 }
 
-impl MeasurementQuery{
-    
-    const ALLOWED_COLUMNS: &[&str] = &["sensor_id", "topic", "timestamp", "temperature", "humidity", "pressure"];
+impl MeasurementQuery {
+    pub const BASE_COLUMNS: &[&str] = &["sensor_id", "topic", "timestamp"];
+
+    /// Columns are either one of the reading columns or a metric from the catalogue
+    pub fn are_columns_sane(&self, catalogue: &[Metric]) -> bool {
+        self.columns.iter().all(|c| {
+            Self::BASE_COLUMNS.contains(&c.as_str()) || catalogue.iter().any(|m| &m.name == c)
+        })
+    }
+
+    /// Checks the columns and filters against the metric catalogue and the
+    /// page size against its cap
+    pub fn validate(&self, catalogue: &[Metric]) -> Result<(), BsError> {
+        if self.columns.is_empty() || !self.are_columns_sane(catalogue) {
+            return Err(BsError::InvalidQuery("Invalid columns".to_string()));
+        }
+        if !self.filters.is_sane(catalogue) {
+            return Err(BsError::InvalidQuery("Invalid filters".to_string()));
+        }
+        if !(1..=Pagination::MAX_PAGE_SIZE).contains(&self.pagination.page_size) {
+            return Err(BsError::InvalidQuery(format!(
+                "Invalid page size, at most {} readings fit on a page",
+                Pagination::MAX_PAGE_SIZE
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn metric_columns(&self) -> impl Iterator<Item = &str> {
+        self.columns
+            .iter()
+            .map(String::as_str)
+            .filter(|c| !Self::BASE_COLUMNS.contains(c))
+    }
+
+    /// Decode and verify the requested cursor
+    pub fn position(&self, key: &CursorKey) -> Result<Option<Cursor>, BsError> {
+        let (encoded, direction) = match (&self.pagination.after, &self.pagination.before) {
+            (Some(_), Some(_)) => {
+                return Err(BsError::InvalidQuery(
+                    "Only one of after and before can be used".to_string(),
+                ));
+            }
+            (Some(after), None) => (after, Direction::Forward),
+            (None, Some(before)) => (before, Direction::Backward),
+            (None, None) => return Ok(None),
+        };
+        let cursor = Cursor::decode(encoded, key)?;
+        if cursor.direction != direction {
+            return Err(BsError::InvalidQuery(
+                "Cursor used in the wrong direction".to_string(),
+            ));
+        }
+        if cursor.filter_hash != self.filter_hash() {
+            return Err(BsError::InvalidQuery(
+                "Cursor was issued for different filters".to_string(),
+            ));
+        }
+        Ok(Some(cursor))
+    }
+
+    /// The order rows have to be scanned in. Paging backwards walks the
+    /// index the other way and flips the rows afterwards.
+    pub fn scan_order(&self) -> SortOrder {
+        if self.pagination.before.is_some() {
+            self.pagination.order.reversed()
+        } else {
+            self.pagination.order
+        }
+    }
+
+    /// Builds the page out of at most `page_size` rows given in scan order
+    pub fn into_page(
+        self,
+        key: &CursorKey,
+        mut rows: Vec<(Keyset, SensorReading)>,
+        has_more: bool,
+    ) -> SensorReadingsPage {
+        let backward = self.pagination.before.is_some();
+        let came_from_cursor = self.pagination.before.is_some() || self.pagination.after.is_some();
+        if backward {
+            rows.reverse();
+        }
+        let filter_hash = self.filter_hash();
+        let cursor = |keyset: &Keyset, direction| {
+            Cursor {
+                timestamp: keyset.timestamp,
+                id: keyset.id,
+                direction,
+                filter_hash,
+            }
+            .encode(key)
+        };
+
+        let (has_next, has_previous) = if backward {
+            (came_from_cursor, has_more)
+        } else {
+            (has_more, came_from_cursor)
+        };
+        let next = rows
+            .last()
+            .filter(|_| has_next)
+            .map(|(keyset, _)| cursor(keyset, Direction::Forward));
+        let previous = rows
+            .first()
+            .filter(|_| has_previous)
+            .map(|(keyset, _)| cursor(keyset, Direction::Backward));
+
+        SensorReadingsPage {
+            rows: rows.into_iter().map(|(_, reading)| reading).collect(),
+            filters: self.filters,
+            next,
+            previous,
+        }
+    }
+
+    fn filter_hash(&self) -> [u8; 8] {
+        let canonical = serde_json::to_vec(&(&self.filters, self.pagination.order))
+            .expect("Filters are always serializable");
+        let digest = Sha256::digest(canonical);
+        let mut hash = [0u8; 8];
+        hash.copy_from_slice(&digest[..8]);
+        hash
+    }
+}
+
+/// Position of a reading in the `(timestamp, id)` ordering
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Keyset {
+    pub timestamp: DateTime<Utc>,
+    pub id: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub timestamp: DateTime<Utc>,
+    pub id: i64,
+    pub direction: Direction,
+    filter_hash: [u8; 8],
+}
+
+impl Cursor {
+    const VERSION: u8 = 1;
+    const PAYLOAD_LEN: usize = 1 + 8 + 8 + 1 + 8;
+    const MAC_LEN: usize = 16;
+
+    pub fn keyset(&self) -> Keyset {
+        Keyset {
+            timestamp: self.timestamp,
+            id: self.id,
+        }
+    }
+
+    fn encode(&self, key: &CursorKey) -> String {
+        let nanos = self
+            .timestamp
+            .timestamp_nanos_opt()
+            .expect("Reading timestamps fit in i64 nanoseconds");
+        let mut buf = Vec::with_capacity(Self::PAYLOAD_LEN + Self::MAC_LEN);
+        buf.push(Self::VERSION);
+        buf.extend_from_slice(&nanos.to_be_bytes());
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.push(match self.direction {
+            Direction::Forward => 0,
+            Direction::Backward => 1,
+        });
+        buf.extend_from_slice(&self.filter_hash);
+        let mac = key.sign(&buf);
+        buf.extend_from_slice(&mac[..Self::MAC_LEN]);
+        URL_SAFE_NO_PAD.encode(buf)
+    }
+
+    fn decode(encoded: &str, key: &CursorKey) -> Result<Self, BsError> {
+        let invalid = || BsError::InvalidQuery("Invalid cursor".to_string());
+        let buf = URL_SAFE_NO_PAD.decode(encoded).map_err(|_| invalid())?;
+        if buf.len() != Self::PAYLOAD_LEN + Self::MAC_LEN || buf[0] != Self::VERSION {
+            return Err(invalid());
+        }
+        let (payload, mac) = buf.split_at(Self::PAYLOAD_LEN);
+        if !key.verify(payload, mac) {
+            return Err(invalid());
+        }
+
+        let nanos = i64::from_be_bytes(payload[1..9].try_into().map_err(|_| invalid())?);
+        let id = i64::from_be_bytes(payload[9..17].try_into().map_err(|_| invalid())?);
+        let direction = match payload[17] {
+            0 => Direction::Forward,
+            1 => Direction::Backward,
+            _ => return Err(invalid()),
+        };
+        let mut filter_hash = [0u8; 8];
+        filter_hash.copy_from_slice(&payload[18..26]);
+
+        Ok(Self {
+            timestamp: DateTime::from_timestamp_nanos(nanos),
+            id,
+            direction,
+            filter_hash,
+        })
+    }
+}
+
+/// Secret used to sign pagination cursors so clients cannot forge positions
+#[derive(Clone)]
+pub struct CursorKey(Arc<[u8]>);
+
+impl CursorKey {
+    pub fn new(secret: impl AsRef<[u8]>) -> Self {
+        Self(Arc::from(secret.as_ref()))
+    }
+
+    /// Cursors signed with a random key stop working after a restart
+    pub fn random() -> Self {
+        Self::new(rand::random::<[u8; 32]>())
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC accepts keys of any length")
+    }
+
+    fn sign(&self, payload: &[u8]) -> Vec<u8> {
+        let mut mac = self.mac();
+        mac.update(payload);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn verify(&self, payload: &[u8], tag: &[u8]) -> bool {
+        let mut mac = self.mac();
+        mac.update(payload);
+        mac.verify_truncated_left(tag).is_ok()
+    }
+}
+
+impl std::fmt::Debug for CursorKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CursorKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query() -> MeasurementQuery {
+        MeasurementQuery {
+            filters: QueryFilter {
+                sensor_id: Some("outside".to_string()),
+                ..Default::default()
+            },
+            pagination: Pagination::default(),
+            columns: vec!["temperature".to_string()],
+        }
+    }
+
+    fn cursor(query: &MeasurementQuery, direction: Direction) -> Cursor {
+        Cursor {
+            timestamp: DateTime::from_timestamp_nanos(1_767_268_800_123_456_789),
+            id: 42,
+            direction,
+            filter_hash: query.filter_hash(),
+        }
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let key = CursorKey::random();
+        let mut q = query();
+        let expected = cursor(&q, Direction::Forward);
+        q.pagination.after = Some(expected.encode(&key));
+
+        assert_eq!(q.position(&key).unwrap(), Some(expected));
+    }
+
+    #[test]
+    fn tampered_cursor_is_rejected() {
+        let key = CursorKey::random();
+        let mut q = query();
+        let mut encoded = URL_SAFE_NO_PAD
+            .decode(cursor(&q, Direction::Forward).encode(&key))
+            .unwrap();
+        // Bump the id
+        encoded[16] ^= 1;
+        q.pagination.after = Some(URL_SAFE_NO_PAD.encode(encoded));
+
+        assert!(q.position(&key).is_err());
+        q.pagination.after = Some(cursor(&q, Direction::Forward).encode(&CursorKey::random()));
+        assert!(q.position(&key).is_err());
+    }
+
+    #[test]
+    fn cursor_is_bound_to_filters_and_direction() {
+        let key = CursorKey::random();
+        let mut q = query();
+        let encoded = cursor(&q, Direction::Forward).encode(&key);
+
+        q.pagination.before = Some(encoded.clone());
+        assert!(q.position(&key).is_err());
+
+        q.pagination.before = None;
+        q.pagination.after = Some(encoded);
+        q.filters.sensor_id = Some("inside".to_string());
+        assert!(q.position(&key).is_err());
 
-    pub fn are_columns_sane(&self) -> bool {
-        self.columns.iter().all(|c| Self::ALLOWED_COLUMNS.contains(&c.as_str()))
+        q.filters.sensor_id = Some("outside".to_string());
+        q.pagination.order = SortOrder::Desc;
+        assert!(q.position(&key).is_err());
     }
 }
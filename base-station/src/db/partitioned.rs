@@ -0,0 +1,1077 @@
+//! SQLite storage keeping the readings of every month, day or year in a
+//! database file of its own. Everything else lives in the catalogue, the
+//! database the repository was opened with.
+//!
+//! A partition is opened with the catalogue attached. Partition tables come
+//! first when SQLite resolves unqualified names, the catalogue provides the
+//! rest, so the queries of `SqliteRepository` work unchanged against either.
+//! Partitions are opened on demand and queries spanning several periods
+//! visit them one after another. Expired periods are dropped by deleting
+//! their files. Partitions number their readings on from the ids of the
+//! catalogue, so ids grow in the order readings are stored whichever
+//! partition they land in.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, TimeDelta, Utc};
+use sqlx::SqlitePool;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use tracing::info;
+
+use super::{
+    ApiKey, Calibration, ConflictPolicy, CursorKey, MeasurementQuery, Metric, Repository,
+    Resolution, RetentionPolicy, RetentionReport, RetentionReportEntry, RetentionTarget, Sensor,
+    SensorMetadata, SensorReading, SensorReadingsPage, Series, SeriesQuery, SortOrder,
+    SqliteRepository, StoredCalibration,
+};
+use crate::SensorReadingEvent;
+use crate::error::BsError;
+
+/// Tables of a partition. Metrics live in the catalogue, foreign keys can't
+/// reach them from here.
+const PARTITION_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS sensor_readings (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        sensor_id TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        timestamp DATETIME NOT NULL,
+        sequence INTEGER,
+        copy INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS idx_sensor_time ON sensor_readings (sensor_id, timestamp DESC);
+    CREATE UNIQUE INDEX IF NOT EXISTS idx_reading_key ON sensor_readings (
+        timestamp, sensor_id, sequence IS NULL, IFNULL(sequence, 0), copy
+    );
+    CREATE TABLE IF NOT EXISTS reading_values (
+        reading_id INTEGER NOT NULL REFERENCES sensor_readings (id) ON DELETE CASCADE,
+        metric TEXT NOT NULL,
+        value REAL NOT NULL,
+        raw_value REAL NOT NULL,
+        PRIMARY KEY (reading_id, metric)
+    ) WITHOUT ROWID;
+    CREATE INDEX IF NOT EXISTS idx_metric_value ON reading_values (metric, value);
+";
+
+/// Connections of partitions nobody reads any more are closed after this long
+const PARTITION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Span of time stored in one partition
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PartitionPeriod {
+    Day,
+    #[default]
+    Month,
+    Year,
+}
+
+impl PartitionPeriod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PartitionPeriod::Day => "day",
+            PartitionPeriod::Month => "month",
+            PartitionPeriod::Year => "year",
+        }
+    }
+
+    /// Number of the partition a timestamp falls into. Consecutive periods
+    /// get consecutive numbers.
+    fn index(self, timestamp: DateTime<Utc>) -> i64 {
+        match self {
+            PartitionPeriod::Day => timestamp.date_naive().num_days_from_ce().into(),
+            PartitionPeriod::Month => {
+                i64::from(timestamp.year()) * 12 + i64::from(timestamp.month0())
+            }
+            PartitionPeriod::Year => timestamp.year().into(),
+        }
+    }
+
+    fn start(self, index: i64) -> DateTime<Utc> {
+        let date = match self {
+            PartitionPeriod::Day => NaiveDate::from_num_days_from_ce_opt(index as i32),
+            PartitionPeriod::Month => NaiveDate::from_ymd_opt(
+                index.div_euclid(12) as i32,
+                index.rem_euclid(12) as u32 + 1,
+                1,
+            ),
+            PartitionPeriod::Year => NaiveDate::from_ymd_opt(index as i32, 1, 1),
+        };
+        date.expect("Partition numbers come from valid dates")
+            .and_time(NaiveTime::MIN)
+            .and_utc()
+    }
+
+    fn file_name(self, index: i64) -> String {
+        let format = match self {
+            PartitionPeriod::Day => "readings-%Y-%m-%d.db",
+            PartitionPeriod::Month => "readings-%Y-%m.db",
+            PartitionPeriod::Year => "readings-%Y.db",
+        };
+        self.start(index).format(format).to_string()
+    }
+
+    /// Partition number of a file written with this period
+    fn parse_file_name(self, name: &str) -> Option<i64> {
+        let stem = name.strip_prefix("readings-")?.strip_suffix(".db")?;
+        let date = match self {
+            PartitionPeriod::Day => NaiveDate::parse_from_str(stem, "%Y-%m-%d").ok()?,
+            PartitionPeriod::Month => {
+                NaiveDate::parse_from_str(&format!("{stem}-01"), "%Y-%m-%d").ok()?
+            }
+            PartitionPeriod::Year => NaiveDate::from_ymd_opt(stem.parse().ok()?, 1, 1)?,
+        };
+        let index = self.index(date.and_time(NaiveTime::MIN).and_utc());
+        // Only the canonical name, "readings-2026-1.db" would parse as well
+        (self.file_name(index) == name).then_some(index)
+    }
+}
+
+impl std::str::FromStr for PartitionPeriod {
+    type Err = BsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(PartitionPeriod::Day),
+            "month" => Ok(PartitionPeriod::Month),
+            "year" => Ok(PartitionPeriod::Year),
+            other => Err(BsError::Other(format!("Unknown partition period: {other}"))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Partition {
+    pool: SqlitePool,
+    repository: SqliteRepository,
+}
+
+/// Readings of one period are stored in one transaction, a batch spanning
+/// several periods is stored one partition at a time.
+#[derive(Debug, Clone)]
+pub struct PartitionedSqliteRepository {
+    pool: SqlitePool,
+    catalogue: SqliteRepository,
+    catalogue_file: String,
+    directory: PathBuf,
+    period: PartitionPeriod,
+    partitions: Arc<Mutex<BTreeMap<i64, Partition>>>,
+    cursor_key: CursorKey,
+    conflict_policy: ConflictPolicy,
+}
+
+impl PartitionedSqliteRepository {
+    /// Open the partitions found in `directory`, creating it when missing.
+    /// Readings still stored in the catalogue are moved into partitions.
+    pub async fn open(
+        pool: SqlitePool,
+        directory: impl Into<PathBuf>,
+        period: PartitionPeriod,
+    ) -> Result<Self, BsError> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        let catalogue_file: String =
+            sqlx::query_scalar("SELECT file FROM pragma_database_list WHERE name = 'main'")
+                .fetch_one(&pool)
+                .await?;
+        if catalogue_file.is_empty() {
+            return Err(BsError::Other(
+                "Partitioned storage needs the catalogue in a file".to_string(),
+            ));
+        }
+
+        let repository = Self {
+            catalogue: SqliteRepository::new(pool.clone()),
+            pool,
+            catalogue_file,
+            directory,
+            period,
+            partitions: Arc::new(Mutex::new(BTreeMap::new())),
+            cursor_key: CursorKey::random(),
+            conflict_policy: ConflictPolicy::default(),
+        };
+        for entry in fs::read_dir(&repository.directory)? {
+            let name = entry?.file_name();
+            if let Some(index) = name.to_str().and_then(|name| period.parse_file_name(name)) {
+                let partition = repository.connect(index, false).await?;
+                repository.lock().insert(index, partition);
+            }
+        }
+        repository.move_catalogue_readings().await?;
+        repository.align_reading_ids().await?;
+
+        Ok(repository)
+    }
+
+    /// Sign cursors with a fixed key so they survive restarts
+    pub fn with_cursor_key(mut self, cursor_key: CursorKey) -> Self {
+        self.cursor_key = cursor_key;
+        self
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<i64, Partition>> {
+        self.partitions.lock().expect("Partition map lock poisoned")
+    }
+
+    fn path(&self, index: i64) -> PathBuf {
+        self.directory.join(self.period.file_name(index))
+    }
+
+    /// Connect to a partition, creating its file and tables for a new one.
+    /// Connections are only made once the partition is used.
+    async fn connect(&self, index: i64, create: bool) -> Result<Partition, BsError> {
+        let options = SqliteConnectOptions::new()
+            .filename(self.path(index))
+            .create_if_missing(create)
+            .journal_mode(SqliteJournalMode::Wal);
+        let catalogue_file = self.catalogue_file.clone();
+        let pool = SqlitePoolOptions::new()
+            .min_connections(0)
+            .idle_timeout(PARTITION_IDLE_TIMEOUT)
+            .after_connect(move |conn, _| {
+                let catalogue_file = catalogue_file.clone();
+                Box::pin(async move {
+                    sqlx::query("ATTACH DATABASE ? AS catalogue")
+                        .bind(catalogue_file)
+                        .execute(conn)
+                        .await?;
+                    Ok(())
+                })
+            })
+            .connect_lazy_with(options);
+
+        if create {
+            sqlx::raw_sql(PARTITION_SCHEMA).execute(&pool).await?;
+            // Ids start at the partition number so they stay unique when
+            // readings move between the catalogue and partitions
+            sqlx::query(
+                "INSERT INTO main.sqlite_sequence (name, seq) SELECT 'sensor_readings', ?
+                    WHERE NOT EXISTS (SELECT 1 FROM main.sqlite_sequence WHERE name = \
+                 'sensor_readings')",
+            )
+            .bind(index << 32)
+            .execute(&pool)
+            .await?;
+        }
+
+        let repository = SqliteRepository::new(pool.clone())
+            .with_conflict_policy(self.conflict_policy)
+            .with_catalogue_ids();
+        Ok(Partition { pool, repository })
+    }
+
+    /// The partition of `index`, created when missing
+    async fn partition(&self, index: i64) -> Result<Partition, BsError> {
+        if let Some(partition) = self.lock().get(&index) {
+            return Ok(partition.clone());
+        }
+        let created = self.connect(index, true).await?;
+        // Whoever opened the partition first wins, creating it twice is harmless
+        Ok(self.lock().entry(index).or_insert(created).clone())
+    }
+
+    /// Partitions overlapping the span, oldest first
+    fn partitions_between(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Vec<(i64, Partition)> {
+        self.lock()
+            .iter()
+            .filter(|(index, _)| {
+                from.is_none_or(|from| self.period.start(**index + 1) > from)
+                    && to.is_none_or(|to| self.period.start(**index) < to)
+            })
+            .map(|(index, partition)| (*index, partition.clone()))
+            .collect()
+    }
+
+    /// Close a partition and delete its files
+    async fn drop_partition(&self, index: i64) -> Result<(), BsError> {
+        let removed = self.lock().remove(&index);
+        if let Some(partition) = removed {
+            partition.pool.close().await;
+        }
+        let path = self.path(index);
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            match fs::remove_file(file) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        info!("Dropped partition {}", path.display());
+        Ok(())
+    }
+
+    /// Move readings stored before partitioning was enabled, one period at
+    /// a time
+    async fn move_catalogue_readings(&self) -> Result<(), BsError> {
+        loop {
+            let oldest: Option<DateTime<Utc>> =
+                sqlx::query_scalar("SELECT MIN(timestamp) FROM sensor_readings")
+                    .fetch_one(&self.pool)
+                    .await?;
+            let Some(oldest) = oldest else {
+                return Ok(());
+            };
+            let index = self.period.index(oldest);
+            let (from, to) = (self.period.start(index), self.period.start(index + 1));
+
+            let partition = self.partition(index).await?;
+            let mut tx = partition.pool.begin().await?;
+            let moved = sqlx::query(
+                "INSERT INTO main.sensor_readings (id, sensor_id, topic, timestamp, sequence, copy)
+                    SELECT id, sensor_id, topic, timestamp, sequence, copy
+                    FROM catalogue.sensor_readings
+                    WHERE timestamp >= ? AND timestamp < ?",
+            )
+            .bind(from)
+            .bind(to)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+            sqlx::query(
+                "INSERT INTO main.reading_values (reading_id, metric, value, raw_value)
+                    SELECT v.reading_id, v.metric, v.value, v.raw_value
+                    FROM catalogue.reading_values v JOIN catalogue.sensor_readings r ON r.id = \
+                 v.reading_id
+                    WHERE r.timestamp >= ? AND r.timestamp < ?",
+            )
+            .bind(from)
+            .bind(to)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(
+                "DELETE FROM catalogue.sensor_readings WHERE timestamp >= ? AND timestamp < ?",
+            )
+            .bind(from)
+            .bind(to)
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            info!(
+                "Moved {moved} readings into partition {}",
+                self.path(index).display()
+            );
+        }
+    }
+
+    /// Carry the reading ids of the catalogue on past the ids every partition
+    /// handed out, partitions take their ids from there. Ids handed out by a
+    /// partition whose commit reached the catalogue only partly are caught up
+    /// with as well.
+    async fn align_reading_ids(&self) -> Result<(), BsError> {
+        let mut last = self.fetch_last_stored_position().await?;
+        for (_, partition) in self.partitions_between(None, None) {
+            let handed_out: i64 = sqlx::query_scalar(
+                "SELECT IFNULL(MAX(seq), 0) FROM main.sqlite_sequence WHERE name = \
+                 'sensor_readings'",
+            )
+            .fetch_one(&partition.pool)
+            .await?;
+            last = last.max(handed_out);
+        }
+        sqlx::query("UPDATE sqlite_sequence SET seq = ? WHERE name = 'sensor_readings'")
+            .bind(last)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            "INSERT INTO sqlite_sequence (name, seq) SELECT 'sensor_readings', ?
+                WHERE NOT EXISTS (SELECT 1 FROM sqlite_sequence WHERE name = 'sensor_readings')",
+        )
+        .bind(last)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Count or remove expired readings. Partitions expired for every sensor
+    /// are dropped whole.
+    async fn expire_readings(
+        &self,
+        target: &RetentionTarget,
+        dry_run: bool,
+    ) -> Result<u64, BsError> {
+        let mut rows = 0;
+        for (index, partition) in self.partitions_between(None, Some(target.cutoff)) {
+            let whole = self.period.start(index + 1) <= target.cutoff
+                && target.sensor_id.is_none()
+                && target.excluded.is_empty();
+            rows += if dry_run {
+                partition.repository.count_expired(target).await?
+            } else if whole {
+                let expired = partition.repository.count_expired(target).await?;
+                self.drop_partition(index).await?;
+                expired
+            } else {
+                partition.repository.delete_expired(target).await?
+            };
+        }
+        Ok(rows)
+    }
+
+    /// Drop past partitions retention has left without readings
+    async fn drop_empty_partitions(&self) -> Result<(), BsError> {
+        let current = self.period.start(self.period.index(Utc::now()));
+        for (index, partition) in self.partitions_between(None, Some(current)) {
+            let empty: bool =
+                sqlx::query_scalar("SELECT NOT EXISTS (SELECT 1 FROM main.sensor_readings)")
+                    .fetch_one(&partition.pool)
+                    .await?;
+            if empty {
+                self.drop_partition(index).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Repository for PartitionedSqliteRepository {
+    fn with_conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict_policy = policy;
+        for partition in self.lock().values_mut() {
+            partition.repository = partition.repository.clone().with_conflict_policy(policy);
+        }
+        self
+    }
+
+    async fn insert_sensor_reading(
+        &self,
+        topic: String,
+        reading: SensorReadingEvent,
+    ) -> Result<(), BsError> {
+        let partition = self.partition(self.period.index(reading.timestamp)).await?;
+        partition
+            .repository
+            .insert_sensor_reading(topic, reading)
+            .await
+    }
+
+    async fn insert_sensor_readings(
+        &self,
+        topic: String,
+        readings: Vec<SensorReadingEvent>,
+    ) -> Result<u64, BsError> {
+        let mut by_partition: BTreeMap<i64, Vec<SensorReadingEvent>> = BTreeMap::new();
+        for reading in readings {
+            by_partition
+                .entry(self.period.index(reading.timestamp))
+                .or_default()
+                .push(reading);
+        }
+
+        let mut inserted = 0;
+        for (index, readings) in by_partition {
+            let partition = self.partition(index).await?;
+            inserted += partition
+                .repository
+                .insert_sensor_readings(topic.clone(), readings)
+                .await?;
+        }
+        Ok(inserted)
+    }
+
+    async fn fetch_metrics(&self) -> Result<Vec<Metric>, BsError> {
+        self.catalogue.fetch_metrics().await
+    }
+
+    async fn upsert_metric(&self, metric: Metric) -> Result<(), BsError> {
+        self.catalogue.upsert_metric(metric).await
+    }
+
+    async fn fetch_sensors(&self) -> Result<Vec<Sensor>, BsError> {
+        self.catalogue.fetch_sensors().await
+    }
+
+    async fn fetch_sensor(&self, sensor_id: &str) -> Result<Option<Sensor>, BsError> {
+        self.catalogue.fetch_sensor(sensor_id).await
+    }
+
+    async fn upsert_sensor(
+        &self,
+        sensor_id: &str,
+        metadata: SensorMetadata,
+    ) -> Result<(), BsError> {
+        self.catalogue.upsert_sensor(sensor_id, metadata).await
+    }
+
+    async fn delete_sensor(&self, sensor_id: &str) -> Result<bool, BsError> {
+        self.catalogue.delete_sensor(sensor_id).await
+    }
+
+    async fn quarantine_sensor_reading(
+        &self,
+        topic: String,
+        reading: SensorReadingEvent,
+    ) -> Result<(), BsError> {
+        self.catalogue
+            .quarantine_sensor_reading(topic, reading)
+            .await
+    }
+
+    async fn approve_sensor(&self, sensor_id: &str) -> Result<Option<u64>, BsError> {
+        let updated = sqlx::query!(
+            "UPDATE sensors SET status = 'approved' WHERE sensor_id = ?",
+            sensor_id
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        if updated == 0 {
+            return Ok(None);
+        }
+
+        let quarantined = sqlx::query!(
+            r#"SELECT topic, timestamp AS "timestamp: DateTime<Utc>", sequence, metrics
+            FROM quarantined_readings WHERE sensor_id = ? ORDER BY timestamp, id"#,
+            sensor_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        // The quarantine is only cleared once every reading is stored. Approving
+        // again after a failure is safe as redelivered readings are ignored.
+        for row in &quarantined {
+            let reading = SensorReadingEvent {
+                sensor_id: sensor_id.to_string(),
+                timestamp: row.timestamp,
+                sequence: row.sequence,
+                metrics: serde_json::from_str(&row.metrics)?,
+            };
+            self.insert_sensor_reading(row.topic.clone(), reading)
+                .await?;
+        }
+        sqlx::query!(
+            "DELETE FROM quarantined_readings WHERE sensor_id = ?",
+            sensor_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(Some(quarantined.len() as u64))
+    }
+
+    async fn fetch_calibrations(
+        &self,
+        sensor_id: Option<String>,
+    ) -> Result<Vec<StoredCalibration>, BsError> {
+        self.catalogue.fetch_calibrations(sensor_id).await
+    }
+
+    async fn insert_calibration(&self, calibration: Calibration) -> Result<i64, BsError> {
+        self.catalogue.insert_calibration(calibration).await
+    }
+
+    async fn delete_calibration(&self, id: i64) -> Result<bool, BsError> {
+        self.catalogue.delete_calibration(id).await
+    }
+
+    async fn fetch_api_keys(&self) -> Result<Vec<ApiKey>, BsError> {
+        self.catalogue.fetch_api_keys().await
+    }
+
+    async fn insert_api_key(&self, key: &ApiKey, secret_hash: &str) -> Result<i64, BsError> {
+        self.catalogue.insert_api_key(key, secret_hash).await
+    }
+
+    async fn fetch_api_key(&self, secret_hash: &str) -> Result<Option<ApiKey>, BsError> {
+        self.catalogue.fetch_api_key(secret_hash).await
+    }
+
+    async fn revoke_api_key(&self, id: i64) -> Result<bool, BsError> {
+        self.catalogue.revoke_api_key(id).await
+    }
+
+    async fn recompute_calibrations(&self, sensor_id: Option<String>) -> Result<u64, BsError> {
+        let mut recomputed = 0;
+        for (_, partition) in self.partitions_between(None, None) {
+            recomputed += partition
+                .repository
+                .recompute_calibrations(sensor_id.clone())
+                .await?;
+        }
+        Ok(recomputed)
+    }
+
+    async fn fetch_sensor_readings_page(
+        &self,
+        query: MeasurementQuery,
+    ) -> Result<SensorReadingsPage, BsError> {
+        query.validate(&self.fetch_metrics().await?)?;
+        let position = query.position(&self.cursor_key)?.map(|c| c.keyset());
+        let order = query.scan_order();
+
+        // Partitions behind the cursor can be skipped as well
+        let (mut from, mut to) = (query.filters.from, query.filters.to);
+        if let Some(keyset) = position {
+            match order {
+                SortOrder::Asc => {
+                    from = Some(from.map_or(keyset.timestamp, |f| f.max(keyset.timestamp)))
+                }
+                SortOrder::Desc => {
+                    let past = keyset.timestamp + TimeDelta::nanoseconds(1);
+                    to = Some(to.map_or(past, |t| t.min(past)));
+                }
+            }
+        }
+        let mut partitions = self.partitions_between(from, to);
+        if order == SortOrder::Desc {
+            partitions.reverse();
+        }
+
+        // One extra row tells us whether there is a next page
+        let limit = query.pagination.page_size + 1;
+        let mut readings = Vec::with_capacity(limit);
+        for (_, partition) in partitions {
+            if readings.len() == limit {
+                break;
+            }
+            let rows = partition
+                .repository
+                .fetch_page_rows(&query, position, order, limit - readings.len())
+                .await?;
+            readings.extend(rows);
+        }
+
+        let has_more = readings.len() > query.pagination.page_size;
+        readings.truncate(query.pagination.page_size);
+
+        Ok(query.into_page(&self.cursor_key, readings, has_more))
+    }
+
+    async fn fetch_stored_readings(
+        &self,
+        after: i64,
+        query: MeasurementQuery,
+    ) -> Result<Vec<(i64, SensorReading)>, BsError> {
+        query.validate(&self.fetch_metrics().await?)?;
+        // Ids are shared by all partitions, a reading stored late sits in an
+        // older partition with a higher id
+        let mut readings = Vec::new();
+        for (_, partition) in self.partitions_between(query.filters.from, query.filters.to) {
+            let rows = partition
+                .repository
+                .fetch_stored_readings(after, query.clone())
+                .await?;
+            readings.extend(rows);
+        }
+        readings.sort_by_key(|(position, _)| *position);
+        readings.truncate(query.pagination.page_size);
+        Ok(readings)
+    }
+
+    async fn fetch_last_stored_position(&self) -> Result<i64, BsError> {
+        let last = sqlx::query_scalar(
+            "SELECT IFNULL(MAX(seq), 0) FROM sqlite_sequence WHERE name = 'sensor_readings'",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(last)
+    }
+
+    async fn fetch_latest_readings(&self) -> Result<Vec<SensorReading>, BsError> {
+        // Newest partition first, a sensor's reading there beats older ones
+        let mut latest = BTreeMap::new();
+        for (_, partition) in self.partitions_between(None, None).into_iter().rev() {
+            for reading in partition.repository.fetch_latest_readings().await? {
+                if let Some(sensor_id) = reading.sensor_id.clone() {
+                    latest.entry(sensor_id).or_insert(reading);
+                }
+            }
+        }
+        Ok(latest.into_values().collect())
+    }
+
+    async fn fetch_series(&self, query: SeriesQuery) -> Result<Series, BsError> {
+        query.validate()?;
+        let resolution = query.effective_resolution();
+        if resolution != Resolution::Raw {
+            return self.catalogue.fetch_series(query).await;
+        }
+
+        let mut points = Vec::new();
+        for (index, partition) in self.partitions_between(Some(query.from), Some(query.to)) {
+            let span = SeriesQuery {
+                from: query.from.max(self.period.start(index)),
+                to: query.to.min(self.period.start(index + 1)),
+                resolution: Some(Resolution::Raw),
+                ..query.clone()
+            };
+            points.extend(partition.repository.fetch_series(span).await?.points);
+        }
+
+        Ok(Series {
+            metric: query.metric,
+            resolution,
+            points,
+        })
+    }
+
+    async fn rebuild_rollups(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<u64, BsError> {
+        let to = to.unwrap_or_else(Utc::now);
+        let mut rebuilt = 0;
+        for (index, partition) in self.partitions_between(from, Some(to)) {
+            // Kept within the partition so the rollups of its neighbours are
+            // left alone. Partitions always end on a day boundary.
+            let start = self.period.start(index);
+            let last = self.period.start(index + 1) - TimeDelta::nanoseconds(1);
+            rebuilt += partition
+                .repository
+                .rebuild_rollups(from.map(|from| from.max(start)), Some(to.min(last)))
+                .await?;
+        }
+        Ok(rebuilt)
+    }
+
+    async fn fetch_retention_policies(&self) -> Result<Vec<RetentionPolicy>, BsError> {
+        self.catalogue.fetch_retention_policies().await
+    }
+
+    async fn upsert_retention_policy(&self, policy: RetentionPolicy) -> Result<(), BsError> {
+        self.catalogue.upsert_retention_policy(policy).await
+    }
+
+    async fn delete_retention_policy(
+        &self,
+        sensor_id: Option<String>,
+        resolution: Resolution,
+    ) -> Result<bool, BsError> {
+        self.catalogue
+            .delete_retention_policy(sensor_id, resolution)
+            .await
+    }
+
+    async fn remove_duplicate_readings(&self, dry_run: bool) -> Result<u64, BsError> {
+        // Duplicates share a timestamp and so a partition
+        let mut removed = 0;
+        for (_, partition) in self.partitions_between(None, None) {
+            removed += partition
+                .repository
+                .remove_duplicate_readings(dry_run)
+                .await?;
+        }
+        Ok(removed)
+    }
+
+    async fn enforce_retention(&self, dry_run: bool) -> Result<RetentionReport, BsError> {
+        let policies = self.fetch_retention_policies().await?;
+        let mut entries = Vec::new();
+        let mut rollups_removed = false;
+        for target in RetentionTarget::from_policies(&policies, Utc::now()) {
+            let rows = match target.resolution {
+                Resolution::Raw => self.expire_readings(&target, dry_run).await?,
+                _ if dry_run => self.catalogue.count_expired(&target).await?,
+                _ => {
+                    let rows = self.catalogue.delete_expired(&target).await?;
+                    rollups_removed |= rows > 0;
+                    rows
+                }
+            };
+            entries.push(RetentionReportEntry {
+                sensor_id: target.sensor_id,
+                resolution: target.resolution,
+                cutoff: target.cutoff,
+                rows,
+            });
+        }
+
+        if !dry_run {
+            self.drop_empty_partitions().await?;
+            if rollups_removed {
+                self.catalogue.incremental_vacuum().await?;
+            }
+        }
+
+        Ok(RetentionReport { dry_run, entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::db::conformance::{TestBackend, conformance_tests};
+    use crate::db::schema::SQLITE_MIGRATOR;
+    use crate::db::{Pagination, QueryFilter};
+
+    struct ScratchDirectory(PathBuf);
+
+    impl ScratchDirectory {
+        fn new() -> Self {
+            let name = format!("base-station-partitions-{}", rand::random::<u64>());
+            let path = std::env::temp_dir().join(name);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for ScratchDirectory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn catalogue(directory: &ScratchDirectory) -> SqlitePool {
+        let options = SqliteConnectOptions::new()
+            .filename(directory.0.join("catalogue.db"))
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        pool
+    }
+
+    struct PartitionedBackend {
+        repository: PartitionedSqliteRepository,
+        _directory: ScratchDirectory,
+    }
+
+    impl TestBackend for PartitionedBackend {
+        type Repository = PartitionedSqliteRepository;
+
+        async fn create() -> Option<Self> {
+            let directory = ScratchDirectory::new();
+            let pool = catalogue(&directory).await;
+            let repository = PartitionedSqliteRepository::open(
+                pool,
+                directory.0.join("partitions"),
+                PartitionPeriod::Month,
+            )
+            .await
+            .unwrap();
+            Some(Self {
+                repository,
+                _directory: directory,
+            })
+        }
+
+        fn repository(&self) -> PartitionedSqliteRepository {
+            self.repository.clone()
+        }
+    }
+
+    conformance_tests!(PartitionedBackend);
+
+    fn reading_at(timestamp: DateTime<Utc>, temperature: f64) -> SensorReadingEvent {
+        SensorReadingEvent {
+            sensor_id: "outside".to_string(),
+            timestamp,
+            sequence: None,
+            metrics: [("temperature".to_string(), temperature)].into(),
+        }
+    }
+
+    fn partition_files(directory: &PathBuf) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".db"))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn partition_files_are_named_after_their_period() {
+        let ts = Utc.with_ymd_and_hms(2026, 3, 1, 17, 42, 7).unwrap();
+        for (period, name) in [
+            (PartitionPeriod::Day, "readings-2026-03-01.db"),
+            (PartitionPeriod::Month, "readings-2026-03.db"),
+            (PartitionPeriod::Year, "readings-2026.db"),
+        ] {
+            let index = period.index(ts);
+            assert_eq!(period.file_name(index), name);
+            assert_eq!(period.parse_file_name(name), Some(index));
+            assert!(period.start(index) <= ts && ts < period.start(index + 1));
+        }
+        assert_eq!(
+            PartitionPeriod::Month.start(PartitionPeriod::Month.index(ts) + 10),
+            Utc.with_ymd_and_hms(2027, 1, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            PartitionPeriod::Month.parse_file_name("readings-2026-3.db"),
+            None
+        );
+        assert_eq!(
+            PartitionPeriod::Month.parse_file_name("readings-2026-03-01.db"),
+            None
+        );
+        assert_eq!(
+            PartitionPeriod::Month.parse_file_name("readings-2026-03.db-wal"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn pages_cross_partitions() {
+        let directory = ScratchDirectory::new();
+        let pool = catalogue(&directory).await;
+        let partitions = directory.0.join("partitions");
+        let repo = PartitionedSqliteRepository::open(pool, &partitions, PartitionPeriod::Month)
+            .await
+            .unwrap();
+        let timestamps = [
+            Utc.with_ymd_and_hms(2026, 1, 31, 23, 59, 59).unwrap(),
+            Utc.with_ymd_and_hms(2026, 2, 1, 0, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(2026, 4, 15, 12, 0, 0).unwrap(),
+        ];
+        let readings = timestamps.iter().map(|ts| reading_at(*ts, 1.0)).collect();
+        let stored = repo
+            .insert_sensor_readings("sensor/update".to_string(), readings)
+            .await
+            .unwrap();
+        assert_eq!(stored, 3);
+        assert_eq!(
+            partition_files(&partitions),
+            vec![
+                "readings-2026-01.db",
+                "readings-2026-02.db",
+                "readings-2026-04.db"
+            ]
+        );
+
+        for order in [SortOrder::Asc, SortOrder::Desc] {
+            let mut seen = Vec::new();
+            let mut after = None;
+            loop {
+                let query = MeasurementQuery {
+                    filters: QueryFilter::default(),
+                    pagination: Pagination {
+                        page_size: 2,
+                        order,
+                        after,
+                        ..Default::default()
+                    },
+                    columns: vec!["timestamp".to_string()],
+                };
+                let page = repo.fetch_sensor_readings_page(query).await.unwrap();
+                seen.extend(page.rows.iter().map(|r| r.timestamp.unwrap()));
+                match page.next {
+                    Some(next) => after = Some(next),
+                    None => break,
+                }
+            }
+            let mut expected = timestamps.to_vec();
+            if order == SortOrder::Desc {
+                expected.reverse();
+            }
+            assert_eq!(seen, expected);
+        }
+
+        let series = repo
+            .fetch_series(SeriesQuery {
+                sensor_id: None,
+                metric: "temperature".to_string(),
+                from: timestamps[0],
+                to: timestamps[1] + TimeDelta::seconds(1),
+                resolution: Some(Resolution::Raw),
+            })
+            .await
+            .unwrap();
+        let points: Vec<_> = series.points.iter().map(|p| p.timestamp).collect();
+        assert_eq!(points, timestamps[..2]);
+    }
+
+    #[tokio::test]
+    async fn retention_deletes_expired_partitions() {
+        let directory = ScratchDirectory::new();
+        let pool = catalogue(&directory).await;
+        let partitions = directory.0.join("partitions");
+        let repo = PartitionedSqliteRepository::open(pool, &partitions, PartitionPeriod::Day)
+            .await
+            .unwrap();
+        let now = Utc::now();
+        for days_ago in [40, 39, 0] {
+            repo.insert_sensor_reading(
+                "sensor/update".to_string(),
+                reading_at(now - TimeDelta::days(days_ago), 1.0),
+            )
+            .await
+            .unwrap();
+        }
+        repo.upsert_retention_policy(RetentionPolicy {
+            sensor_id: None,
+            resolution: Resolution::Raw,
+            keep_days: Some(30),
+        })
+        .await
+        .unwrap();
+        assert_eq!(partition_files(&partitions).len(), 3);
+
+        let report = repo.enforce_retention(true).await.unwrap();
+        assert_eq!(report.entries[0].rows, 2);
+        assert_eq!(partition_files(&partitions).len(), 3);
+
+        let report = repo.enforce_retention(false).await.unwrap();
+        assert_eq!(report.entries[0].rows, 2);
+        assert_eq!(
+            partition_files(&partitions),
+            vec![PartitionPeriod::Day.file_name(PartitionPeriod::Day.index(now))]
+        );
+        // Rollups outlive the raw readings
+        let series = repo
+            .fetch_series(SeriesQuery {
+                sensor_id: None,
+                metric: "temperature".to_string(),
+                from: now - TimeDelta::days(60),
+                to: now + TimeDelta::days(1),
+                resolution: Some(Resolution::Day),
+            })
+            .await
+            .unwrap();
+        assert_eq!(series.points.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn catalogue_readings_move_into_partitions() {
+        let directory = ScratchDirectory::new();
+        let pool = catalogue(&directory).await;
+        let unpartitioned = SqliteRepository::new(pool.clone());
+        let january = Utc.with_ymd_and_hms(2026, 1, 10, 8, 0, 0).unwrap();
+        let march = Utc.with_ymd_and_hms(2026, 3, 10, 8, 0, 0).unwrap();
+        for ts in [january, march] {
+            unpartitioned
+                .insert_sensor_reading("sensor/update".to_string(), reading_at(ts, 1.0))
+                .await
+                .unwrap();
+        }
+
+        let partitions = directory.0.join("partitions");
+        let repo =
+            PartitionedSqliteRepository::open(pool.clone(), &partitions, PartitionPeriod::Month)
+                .await
+                .unwrap();
+        assert_eq!(
+            partition_files(&partitions),
+            vec!["readings-2026-01.db", "readings-2026-03.db"]
+        );
+        let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM reading_values")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(left, 0);
+
+        let query = MeasurementQuery {
+            filters: QueryFilter::default(),
+            pagination: Pagination::default(),
+            columns: vec!["timestamp".to_string(), "temperature".to_string()],
+        };
+        let page = repo.fetch_sensor_readings_page(query).await.unwrap();
+        let rows: Vec<_> = page
+            .rows
+            .iter()
+            .map(|r| (r.timestamp.unwrap(), r.metrics["temperature"]))
+            .collect();
+        assert_eq!(rows, vec![(january, 1.0), (march, 1.0)]);
+    }
+}
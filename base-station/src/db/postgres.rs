@@ -0,0 +1,1256 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+use sqlx::postgres::PgRow;
+use sqlx::{PgConnection, PgPool, Postgres, QueryBuilder, Row};
+
+use super::api_key::{ApiKeyRow, join_scopes};
+use super::calibration::{CalibrationRow, calibrate, check_overlaps};
+use super::reading::{LatestValueRow, latest_readings};
+use super::sensor::SensorRow;
+use super::{
+    ApiKey, Calibration, ConflictPolicy, CursorKey, Keyset, MeasurementQuery, Metric, Repository,
+    Resolution, RetentionPolicy, RetentionReport, RetentionReportEntry, RetentionTarget, Sensor,
+    SensorMetadata, SensorReading, SensorReadingsPage, Series, SeriesPoint, SeriesQuery, SortOrder,
+    StoredCalibration,
+};
+use crate::error::BsError;
+use crate::{SensorReadingEvent, is_valid_metric_name};
+
+/// Advisory lock held by transactions storing readings
+const READING_INSERT_LOCK: i64 = 0x6273_7265_6164;
+
+/// Repository for a shared PostgreSQL database, optionally with TimescaleDB.
+///
+/// Queries are checked at runtime rather than with the `query!` macros so the
+/// crate still builds against the SQLite schema alone.
+#[derive(Debug, Clone)]
+pub struct PostgresRepository {
+    pool: PgPool,
+    cursor_key: CursorKey,
+    conflict_policy: ConflictPolicy,
+}
+
+impl PostgresRepository {
+    /// Rows removed per statement so retention never holds locks for long
+    const RETENTION_BATCH_SIZE: i64 = 500;
+
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            cursor_key: CursorKey::random(),
+            conflict_policy: ConflictPolicy::default(),
+        }
+    }
+
+    /// Sign cursors with a fixed key so they survive restarts
+    pub fn with_cursor_key(mut self, cursor_key: CursorKey) -> Self {
+        self.cursor_key = cursor_key;
+        self
+    }
+
+    async fn count_expired(&self, target: &RetentionTarget) -> Result<u64, BsError> {
+        let mut qb = QueryBuilder::<Postgres>::new("SELECT COUNT(*) ");
+        push_expired_rows(&mut qb, target);
+        let count: i64 = qb.build_query_scalar().fetch_one(&self.pool).await?;
+        Ok(count as u64)
+    }
+
+    async fn delete_expired(&self, target: &RetentionTarget) -> Result<u64, BsError> {
+        let mut removed = 0;
+        loop {
+            let mut qb = QueryBuilder::<Postgres>::new("");
+            let deleted = match target.resolution {
+                Resolution::Raw => {
+                    // Values have no foreign key to cascade from
+                    qb.push("WITH removed AS (DELETE FROM sensor_readings WHERE id IN (SELECT id ");
+                    push_expired_rows(&mut qb, target);
+                    qb.push(" LIMIT ")
+                        .push_bind(Self::RETENTION_BATCH_SIZE)
+                        .push(
+                            ") RETURNING id), removed_values AS (DELETE FROM reading_values WHERE \
+                             reading_id IN (SELECT id FROM removed)) SELECT COUNT(*) FROM removed",
+                        );
+                    let deleted: i64 = qb.build_query_scalar().fetch_one(&self.pool).await?;
+                    deleted as u64
+                }
+                _ => {
+                    qb.push(
+                        "DELETE FROM reading_rollups WHERE (resolution, sensor_id, metric, bucket) \
+                         IN (SELECT resolution, sensor_id, metric, bucket ",
+                    );
+                    push_expired_rows(&mut qb, target);
+                    qb.push(" LIMIT ")
+                        .push_bind(Self::RETENTION_BATCH_SIZE)
+                        .push(")");
+                    qb.build().execute(&self.pool).await?.rows_affected()
+                }
+            };
+            removed += deleted;
+            if deleted < Self::RETENTION_BATCH_SIZE as u64 {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        Ok(removed)
+    }
+
+    /// Recompute the rollup buckets holding readings of `sensor_id`, or of
+    /// every sensor. Buckets of other sensors are left alone, as are buckets
+    /// whose rollup counted more values than the readings left in them: part
+    /// of their readings expired and the rollup is all that remains of them.
+    async fn refresh_rollups(&self, sensor_id: Option<&str>) -> Result<u64, BsError> {
+        let mut tx = self.pool.begin().await?;
+        let mut refreshed = 0;
+        for resolution in Resolution::ROLLED_UP {
+            refreshed += sqlx::query(
+                "INSERT INTO reading_rollups (resolution, sensor_id, metric, bucket, min_value, \
+                 max_value, sum_value, sample_count)
+                    SELECT $1, r.sensor_id, v.metric, date_trunc($2, r.timestamp, 'UTC'), \
+                 MIN(v.value), MAX(v.value), SUM(v.value), COUNT(*)
+                    FROM sensor_readings r JOIN reading_values v ON v.reading_id = r.id
+                    WHERE $3::TEXT IS NULL OR r.sensor_id = $3
+                    GROUP BY 2, 3, 4
+                    ON CONFLICT (resolution, sensor_id, metric, bucket) DO UPDATE SET
+                        min_value = excluded.min_value,
+                        max_value = excluded.max_value,
+                        sum_value = excluded.sum_value,
+                        sample_count = excluded.sample_count
+                    WHERE excluded.sample_count >= reading_rollups.sample_count",
+            )
+            .bind(resolution.as_str())
+            .bind(postgres_bucket_field(resolution))
+            .bind(sensor_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(refreshed)
+    }
+}
+
+#[async_trait]
+impl Repository for PostgresRepository {
+    fn with_conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict_policy = policy;
+        self
+    }
+
+    async fn insert_sensor_reading(
+        &self,
+        topic: String,
+        reading: SensorReadingEvent,
+    ) -> Result<(), BsError> {
+        let mut tx = self.pool.begin().await?;
+        insert_reading(&mut tx, &topic, &reading, self.conflict_policy).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn insert_sensor_readings(
+        &self,
+        topic: String,
+        readings: Vec<SensorReadingEvent>,
+    ) -> Result<u64, BsError> {
+        let mut tx = self.pool.begin().await?;
+        let mut inserted = 0;
+        for reading in &readings {
+            if insert_reading(&mut tx, &topic, reading, self.conflict_policy).await? {
+                inserted += 1;
+            }
+        }
+        tx.commit().await?;
+
+        Ok(inserted)
+    }
+
+    async fn fetch_metrics(&self) -> Result<Vec<Metric>, BsError> {
+        let metrics = sqlx::query_as("SELECT name, unit, description FROM metrics ORDER BY name")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(metrics)
+    }
+
+    async fn upsert_metric(&self, metric: Metric) -> Result<(), BsError> {
+        if !is_valid_metric_name(&metric.name) {
+            return Err(BsError::Other(format!(
+                "Invalid metric name: {}",
+                metric.name
+            )));
+        }
+        sqlx::query(
+            "INSERT INTO metrics (name, unit, description) VALUES ($1, $2, $3)
+                ON CONFLICT (name) DO UPDATE SET unit = excluded.unit, description = \
+             excluded.description",
+        )
+        .bind(&metric.name)
+        .bind(&metric.unit)
+        .bind(&metric.description)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn fetch_sensors(&self) -> Result<Vec<Sensor>, BsError> {
+        let rows: Vec<SensorRow> = sqlx::query_as(&format!("{SENSOR_SELECT} ORDER BY s.sensor_id"))
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.into_iter().map(Sensor::try_from).collect()
+    }
+
+    async fn fetch_sensor(&self, sensor_id: &str) -> Result<Option<Sensor>, BsError> {
+        let row: Option<SensorRow> =
+            sqlx::query_as(&format!("{SENSOR_SELECT} WHERE s.sensor_id = $1"))
+                .bind(sensor_id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        row.map(Sensor::try_from).transpose()
+    }
+
+    async fn upsert_sensor(
+        &self,
+        sensor_id: &str,
+        metadata: SensorMetadata,
+    ) -> Result<(), BsError> {
+        sqlx::query(
+            "INSERT INTO sensors (sensor_id, name, location, altitude_m, installed_on, notes, \
+             status)
+                VALUES ($1, $2, $3, $4, $5, $6, 'approved')
+                ON CONFLICT (sensor_id) DO UPDATE SET name = excluded.name, location = \
+             excluded.location, altitude_m = excluded.altitude_m, installed_on = \
+             excluded.installed_on, notes = excluded.notes",
+        )
+        .bind(sensor_id)
+        .bind(&metadata.name)
+        .bind(&metadata.location)
+        .bind(metadata.altitude_m)
+        .bind(metadata.installed_on)
+        .bind(&metadata.notes)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_sensor(&self, sensor_id: &str) -> Result<bool, BsError> {
+        let deleted = sqlx::query("DELETE FROM sensors WHERE sensor_id = $1")
+            .bind(sensor_id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+
+        Ok(deleted > 0)
+    }
+
+    async fn quarantine_sensor_reading(
+        &self,
+        topic: String,
+        reading: SensorReadingEvent,
+    ) -> Result<(), BsError> {
+        let metrics = serde_json::to_string(&reading.metrics)?;
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO sensors (sensor_id, first_seen) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(&reading.sensor_id)
+        .bind(reading.timestamp)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "INSERT INTO quarantined_readings (sensor_id, topic, timestamp, sequence, metrics) \
+             VALUES ($1, $2, $3, $4, $5::jsonb)",
+        )
+        .bind(&reading.sensor_id)
+        .bind(&topic)
+        .bind(reading.timestamp)
+        .bind(reading.sequence)
+        .bind(metrics)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn approve_sensor(&self, sensor_id: &str) -> Result<Option<u64>, BsError> {
+        let mut tx = self.pool.begin().await?;
+        let updated = sqlx::query("UPDATE sensors SET status = 'approved' WHERE sensor_id = $1")
+            .bind(sensor_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if updated == 0 {
+            return Ok(None);
+        }
+
+        let quarantined: Vec<(String, DateTime<Utc>, Option<i64>, String)> = sqlx::query_as(
+            "DELETE FROM quarantined_readings WHERE sensor_id = $1 RETURNING topic, timestamp, \
+             sequence, metrics::text",
+        )
+        .bind(sensor_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        let mut released = 0;
+        for (topic, timestamp, sequence, metrics) in quarantined {
+            let reading = SensorReadingEvent {
+                sensor_id: sensor_id.to_string(),
+                timestamp,
+                sequence,
+                metrics: serde_json::from_str(&metrics)?,
+            };
+            insert_reading(&mut tx, &topic, &reading, self.conflict_policy).await?;
+            released += 1;
+        }
+        tx.commit().await?;
+
+        Ok(Some(released))
+    }
+
+    async fn fetch_calibrations(
+        &self,
+        sensor_id: Option<String>,
+    ) -> Result<Vec<StoredCalibration>, BsError> {
+        let rows: Vec<CalibrationRow> = sqlx::query_as(&format!(
+            "{CALIBRATION_SELECT} WHERE $1::TEXT IS NULL OR sensor_id = $1 ORDER BY sensor_id, \
+             metric, valid_from NULLS FIRST"
+        ))
+        .bind(sensor_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(StoredCalibration::from).collect())
+    }
+
+    async fn insert_calibration(&self, calibration: Calibration) -> Result<i64, BsError> {
+        calibration.validate()?;
+        let mut tx = self.pool.begin().await?;
+        // Serialise writers so two overlapping calibrations can't both pass the check
+        sqlx::query("LOCK TABLE calibrations IN SHARE ROW EXCLUSIVE MODE")
+            .execute(&mut *tx)
+            .await?;
+        let stored = sensor_calibrations(&mut tx, &calibration.sensor_id).await?;
+        check_overlaps(&calibration, &stored)?;
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO calibrations (sensor_id, metric, gain, offset_value, valid_from, \
+             valid_to) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+        )
+        .bind(&calibration.sensor_id)
+        .bind(&calibration.metric)
+        .bind(calibration.gain)
+        .bind(calibration.offset)
+        .bind(calibration.valid_from)
+        .bind(calibration.valid_to)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(id)
+    }
+
+    async fn delete_calibration(&self, id: i64) -> Result<bool, BsError> {
+        let deleted = sqlx::query("DELETE FROM calibrations WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+
+        Ok(deleted > 0)
+    }
+
+    async fn fetch_api_keys(&self) -> Result<Vec<ApiKey>, BsError> {
+        let rows: Vec<ApiKeyRow> = sqlx::query_as(&format!("{API_KEY_SELECT} ORDER BY id"))
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.into_iter().map(ApiKey::try_from).collect()
+    }
+
+    async fn insert_api_key(&self, key: &ApiKey, secret_hash: &str) -> Result<i64, BsError> {
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO api_keys (name, prefix, secret_hash, scopes, created_at) \
+             VALUES ($1, $2, $3, $4, $5) RETURNING id",
+        )
+        .bind(&key.name)
+        .bind(&key.prefix)
+        .bind(secret_hash)
+        .bind(join_scopes(&key.scopes))
+        .bind(key.created_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(id)
+    }
+
+    async fn fetch_api_key(&self, secret_hash: &str) -> Result<Option<ApiKey>, BsError> {
+        let row: Option<ApiKeyRow> =
+            sqlx::query_as(&format!("{API_KEY_SELECT} WHERE secret_hash = $1"))
+                .bind(secret_hash)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        row.map(ApiKey::try_from).transpose()
+    }
+
+    async fn revoke_api_key(&self, id: i64) -> Result<bool, BsError> {
+        let revoked = sqlx::query(
+            "UPDATE api_keys SET revoked_at = COALESCE(revoked_at, NOW()) WHERE id = $1",
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+
+        Ok(revoked > 0)
+    }
+
+    async fn recompute_calibrations(&self, sensor_id: Option<String>) -> Result<u64, BsError> {
+        let recomputed = sqlx::query(
+            "UPDATE reading_values v SET value = COALESCE(
+                (SELECT v.raw_value * c.gain + c.offset_value
+                    FROM sensor_readings r JOIN calibrations c ON c.sensor_id = r.sensor_id
+                    WHERE r.id = v.reading_id AND c.metric = v.metric
+                        AND (c.valid_from IS NULL OR c.valid_from <= r.timestamp)
+                        AND (c.valid_to IS NULL OR r.timestamp < c.valid_to)),
+                v.raw_value)
+            WHERE v.reading_id IN (SELECT id FROM sensor_readings WHERE $1::TEXT IS NULL OR \
+             sensor_id = $1)",
+        )
+        .bind(&sensor_id)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        if recomputed > 0 {
+            self.refresh_rollups(sensor_id.as_deref()).await?;
+        }
+
+        Ok(recomputed)
+    }
+
+    async fn fetch_sensor_readings_page(
+        &self,
+        query: MeasurementQuery,
+    ) -> Result<SensorReadingsPage, BsError> {
+        query.validate(&self.fetch_metrics().await?)?;
+        let position = query.position(&self.cursor_key)?.map(|c| c.keyset());
+        let order = query.scan_order();
+
+        let mut qb = QueryBuilder::<Postgres>::new("");
+        push_page_query(&mut qb, &query, position, order);
+
+        let mut rows = qb.build().fetch_all(&self.pool).await?;
+
+        let has_more = rows.len() > query.pagination.page_size;
+        rows.truncate(query.pagination.page_size);
+        let readings = reading_rows(rows, &query)?;
+
+        Ok(query.into_page(&self.cursor_key, readings, has_more))
+    }
+
+    async fn fetch_stored_readings(
+        &self,
+        after: i64,
+        query: MeasurementQuery,
+    ) -> Result<Vec<(i64, SensorReading)>, BsError> {
+        query.validate(&self.fetch_metrics().await?)?;
+        let mut qb = QueryBuilder::<Postgres>::new("");
+        push_stored_query(&mut qb, &query, after);
+        let rows = qb.build().fetch_all(&self.pool).await?;
+        Ok(reading_rows(rows, &query)?
+            .into_iter()
+            .map(|(keyset, reading)| (keyset.id, reading))
+            .collect())
+    }
+
+    async fn fetch_last_stored_position(&self) -> Result<i64, BsError> {
+        let last: Option<i64> = sqlx::query_scalar("SELECT MAX(id) FROM sensor_readings")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(last.unwrap_or(0))
+    }
+
+    async fn fetch_latest_readings(&self) -> Result<Vec<SensorReading>, BsError> {
+        // Sensors are found skipping through `idx_sensor_time`, readings
+        // without a registered sensor count as well
+        let rows: Vec<LatestValueRow> = sqlx::query_as(
+            "WITH RECURSIVE seen (sensor_id) AS (
+                (SELECT sensor_id FROM sensor_readings ORDER BY sensor_id LIMIT 1)
+                UNION ALL
+                SELECT (SELECT sensor_id FROM sensor_readings WHERE sensor_id > seen.sensor_id
+                    ORDER BY sensor_id LIMIT 1)
+                FROM seen WHERE seen.sensor_id IS NOT NULL
+            )
+            SELECT r.sensor_id, r.topic, r.timestamp, v.metric, v.value
+            FROM seen
+            CROSS JOIN LATERAL (SELECT id, sensor_id, topic, timestamp FROM sensor_readings l
+                WHERE l.sensor_id = seen.sensor_id ORDER BY l.timestamp DESC, l.id DESC LIMIT 1) r
+            LEFT JOIN reading_values v ON v.reading_id = r.id
+            ORDER BY r.sensor_id, v.metric",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(latest_readings(rows))
+    }
+
+    async fn fetch_series(&self, query: SeriesQuery) -> Result<Series, BsError> {
+        query.validate()?;
+        let resolution = query.effective_resolution();
+
+        let mut qb = QueryBuilder::<Postgres>::new("");
+        match resolution {
+            Resolution::Raw => {
+                qb.push(
+                    "SELECT r.sensor_id, r.timestamp, v.value AS min, v.value AS max, v.value AS \
+                     avg, 1::BIGINT AS count FROM sensor_readings r JOIN reading_values v ON \
+                     v.reading_id = r.id WHERE v.metric = ",
+                )
+                .push_bind(&query.metric)
+                .push(" AND r.timestamp >= ")
+                .push_bind(query.from)
+                .push(" AND r.timestamp < ")
+                .push_bind(query.to);
+                if let Some(sensor_id) = &query.sensor_id {
+                    qb.push(" AND r.sensor_id = ").push_bind(sensor_id);
+                }
+                qb.push(" ORDER BY r.timestamp, r.id");
+            }
+            rolled_up => {
+                qb.push(
+                    "SELECT sensor_id, bucket AS timestamp, min_value AS min, max_value AS max, \
+                     sum_value / sample_count AS avg, sample_count AS count FROM reading_rollups \
+                     WHERE resolution = ",
+                )
+                .push_bind(rolled_up.as_str())
+                .push(" AND metric = ")
+                .push_bind(&query.metric)
+                .push(" AND bucket >= ")
+                .push_bind(rolled_up.bucket(query.from))
+                .push(" AND bucket < ")
+                .push_bind(query.to);
+                if let Some(sensor_id) = &query.sensor_id {
+                    qb.push(" AND sensor_id = ").push_bind(sensor_id);
+                }
+                qb.push(" ORDER BY bucket, sensor_id");
+            }
+        }
+
+        let points = qb
+            .build_query_as::<SeriesPoint>()
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(Series {
+            metric: query.metric,
+            resolution,
+            points,
+        })
+    }
+
+    async fn rebuild_rollups(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<u64, BsError> {
+        let from = match from {
+            Some(from) => from,
+            None => {
+                let oldest: Option<DateTime<Utc>> =
+                    sqlx::query_scalar("SELECT MIN(timestamp) FROM sensor_readings")
+                        .fetch_one(&self.pool)
+                        .await?;
+                match oldest {
+                    Some(oldest) => oldest,
+                    None => return Ok(0),
+                }
+            }
+        };
+        let to = to.unwrap_or_else(Utc::now);
+        // Whole days so the hourly and daily buckets stay consistent
+        let from = Resolution::Day.bucket(from);
+        let to = Resolution::Day.bucket(to) + TimeDelta::days(1);
+
+        let mut tx = self.pool.begin().await?;
+        // Buckets starting before the oldest reading of their sensor may
+        // count expired readings, the insert below only grows them
+        sqlx::query(
+            "DELETE FROM reading_rollups WHERE bucket >= $1 AND bucket < $2
+                AND bucket >= (SELECT MIN(r.timestamp) FROM sensor_readings r
+                    WHERE r.sensor_id = reading_rollups.sensor_id)",
+        )
+        .bind(from)
+        .bind(to)
+        .execute(&mut *tx)
+        .await?;
+
+        let mut rebuilt = 0;
+        for resolution in Resolution::ROLLED_UP {
+            rebuilt += sqlx::query(
+                "INSERT INTO reading_rollups (resolution, sensor_id, metric, bucket, min_value, \
+                 max_value, sum_value, sample_count)
+                    SELECT $1, r.sensor_id, v.metric, date_trunc($2, r.timestamp, 'UTC'), \
+                 MIN(v.value), MAX(v.value), SUM(v.value), COUNT(*)
+                    FROM sensor_readings r JOIN reading_values v ON v.reading_id = r.id
+                    WHERE r.timestamp >= $3 AND r.timestamp < $4
+                    GROUP BY 2, 3, 4
+                    ON CONFLICT (resolution, sensor_id, metric, bucket) DO UPDATE SET
+                        min_value = excluded.min_value,
+                        max_value = excluded.max_value,
+                        sum_value = excluded.sum_value,
+                        sample_count = excluded.sample_count
+                    WHERE excluded.sample_count >= reading_rollups.sample_count",
+            )
+            .bind(resolution.as_str())
+            .bind(postgres_bucket_field(resolution))
+            .bind(from)
+            .bind(to)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        tx.commit().await?;
+
+        Ok(rebuilt)
+    }
+
+    async fn remove_duplicate_readings(&self, dry_run: bool) -> Result<u64, BsError> {
+        // The earliest stored copy is kept
+        let count: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) {DUPLICATE_READINGS}"))
+            .fetch_one(&self.pool)
+            .await?;
+        if dry_run || count == 0 {
+            return Ok(count as u64);
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let removed: Vec<(String, DateTime<Utc>)> = sqlx::query_as(&format!(
+            "WITH removed AS (DELETE FROM sensor_readings WHERE id IN (SELECT r.id \
+             {DUPLICATE_READINGS}) RETURNING id, sensor_id, timestamp), removed_values AS (DELETE \
+             FROM reading_values WHERE reading_id IN (SELECT id FROM removed)) SELECT sensor_id, \
+             timestamp FROM removed"
+        ))
+        .fetch_all(&mut *tx)
+        .await?;
+        let buckets: BTreeSet<_> = removed
+            .iter()
+            .map(|(sensor_id, timestamp)| (sensor_id.as_str(), Resolution::Hour.bucket(*timestamp)))
+            .collect();
+        for (sensor_id, bucket) in buckets {
+            rebuild_sensor_rollups(&mut tx, sensor_id, bucket).await?;
+        }
+        tx.commit().await?;
+
+        Ok(removed.len() as u64)
+    }
+
+    async fn fetch_retention_policies(&self) -> Result<Vec<RetentionPolicy>, BsError> {
+        let rows = sqlx::query(
+            "SELECT sensor_id, resolution, keep_days FROM retention_policies ORDER BY resolution, \
+             sensor_id NULLS FIRST",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(RetentionPolicy {
+                    sensor_id: row.try_get("sensor_id")?,
+                    resolution: row.try_get::<String, _>("resolution")?.parse()?,
+                    keep_days: row
+                        .try_get::<Option<i32>, _>("keep_days")?
+                        .map(u32::try_from)
+                        .transpose()
+                        .map_err(|e| BsError::Other(format!("Invalid keep_days: {e}")))?,
+                })
+            })
+            .collect()
+    }
+
+    async fn upsert_retention_policy(&self, policy: RetentionPolicy) -> Result<(), BsError> {
+        policy.validate()?;
+        let keep_days = policy
+            .keep_days
+            .map(i32::try_from)
+            .transpose()
+            .map_err(|e| BsError::InvalidQuery(format!("Invalid keep_days: {e}")))?;
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "DELETE FROM retention_policies WHERE COALESCE(sensor_id, '') = COALESCE($1, '') AND \
+             resolution = $2",
+        )
+        .bind(&policy.sensor_id)
+        .bind(policy.resolution.as_str())
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "INSERT INTO retention_policies (sensor_id, resolution, keep_days) VALUES ($1, $2, $3)",
+        )
+        .bind(&policy.sensor_id)
+        .bind(policy.resolution.as_str())
+        .bind(keep_days)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn delete_retention_policy(
+        &self,
+        sensor_id: Option<String>,
+        resolution: Resolution,
+    ) -> Result<bool, BsError> {
+        let deleted = sqlx::query(
+            "DELETE FROM retention_policies WHERE COALESCE(sensor_id, '') = COALESCE($1, '') AND \
+             resolution = $2",
+        )
+        .bind(sensor_id)
+        .bind(resolution.as_str())
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+
+        Ok(deleted > 0)
+    }
+
+    async fn enforce_retention(&self, dry_run: bool) -> Result<RetentionReport, BsError> {
+        let policies = self.fetch_retention_policies().await?;
+        let mut entries = Vec::new();
+        for target in RetentionTarget::from_policies(&policies, Utc::now()) {
+            let rows = if dry_run {
+                self.count_expired(&target).await?
+            } else {
+                self.delete_expired(&target).await?
+            };
+            entries.push(RetentionReportEntry {
+                sensor_id: target.sensor_id,
+                resolution: target.resolution,
+                cutoff: target.cutoff,
+                rows,
+            });
+        }
+
+        // Freed space is reclaimed by autovacuum
+        Ok(RetentionReport { dry_run, entries })
+    }
+}
+
+/// Store a reading with its values and fold it into the rollups. Returns
+/// whether it was stored, readings the policy ignores are not.
+async fn insert_reading(
+    conn: &mut PgConnection,
+    topic: &str,
+    reading: &SensorReadingEvent,
+    policy: ConflictPolicy,
+) -> Result<bool, BsError> {
+    // Inserting transactions take turns so ids become visible in the order
+    // they are handed out, streams following the ids would miss a reading
+    // committed after one with a higher id
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(READING_INSERT_LOCK)
+        .execute(&mut *conn)
+        .await?;
+    // The unique key of readings settles conflicts, so concurrent inserts
+    // of the same reading cannot both get in
+    let (copy, on_conflict) = match policy {
+        ConflictPolicy::Ignore => ("0".to_string(), "DO NOTHING"),
+        ConflictPolicy::Replace => (
+            "0".to_string(),
+            "(timestamp, sensor_id, sequence, copy) DO UPDATE SET topic = excluded.topic",
+        ),
+        // A copy number taken meanwhile is retried with the next one
+        ConflictPolicy::KeepBoth => (
+            format!(
+                "(SELECT COALESCE(MAX(copy) + 1, 0) FROM sensor_readings WHERE {SAME_READING})"
+            ),
+            "DO NOTHING",
+        ),
+    };
+    let reading_id: i64 = loop {
+        let reading_id: Option<i64> = sqlx::query_scalar(&format!(
+            "INSERT INTO sensor_readings (sensor_id, topic, timestamp, sequence, copy) VALUES ($1, \
+             $4, $2, $3, {copy}) ON CONFLICT {on_conflict} RETURNING id"
+        ))
+        .bind(&reading.sensor_id)
+        .bind(reading.timestamp)
+        .bind(reading.sequence)
+        .bind(topic)
+        .fetch_optional(&mut *conn)
+        .await?;
+        match reading_id {
+            Some(reading_id) => break reading_id,
+            None if policy == ConflictPolicy::KeepBoth => continue,
+            None => return Ok(false),
+        }
+    };
+    // Replaced in place, the values of the stored reading go. They have no
+    // foreign key to cascade from.
+    let replaced = policy == ConflictPolicy::Replace
+        && sqlx::query("DELETE FROM reading_values WHERE reading_id = $1")
+            .bind(reading_id)
+            .execute(&mut *conn)
+            .await?
+            .rows_affected()
+            > 0;
+
+    let calibrations: Vec<Calibration> = sensor_calibrations(&mut *conn, &reading.sensor_id)
+        .await?
+        .into_iter()
+        .map(|stored| stored.calibration)
+        .collect();
+    let calibrated = calibrate(&calibrations, reading.timestamp, &reading.metrics);
+
+    for (metric, value) in &calibrated {
+        // Metrics we have not seen before get registered without a unit
+        sqlx::query("INSERT INTO metrics (name) VALUES ($1) ON CONFLICT DO NOTHING")
+            .bind(metric)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query(
+            "INSERT INTO reading_values (reading_id, metric, value, raw_value) VALUES ($1, $2, \
+             $3, $4)",
+        )
+        .bind(reading_id)
+        .bind(metric)
+        .bind(value)
+        .bind(reading.metrics[metric])
+        .execute(&mut *conn)
+        .await?;
+
+        for resolution in Resolution::ROLLED_UP {
+            sqlx::query(
+                "INSERT INTO reading_rollups (resolution, sensor_id, metric, bucket, \
+                 min_value, max_value, sum_value, sample_count)
+                    VALUES ($1, $2, $3, $4, $5, $5, $5, 1)
+                    ON CONFLICT (resolution, sensor_id, metric, bucket) DO UPDATE SET
+                        min_value = LEAST(reading_rollups.min_value, excluded.min_value),
+                        max_value = GREATEST(reading_rollups.max_value, excluded.max_value),
+                        sum_value = reading_rollups.sum_value + excluded.sum_value,
+                        sample_count = reading_rollups.sample_count + 1",
+            )
+            .bind(resolution.as_str())
+            .bind(&reading.sensor_id)
+            .bind(metric)
+            .bind(resolution.bucket(reading.timestamp))
+            .bind(value)
+            .execute(&mut *conn)
+            .await?;
+        }
+    }
+    // The rollups still count the values of the replaced reading
+    if replaced {
+        rebuild_sensor_rollups(conn, &reading.sensor_id, reading.timestamp).await?;
+    }
+    Ok(true)
+}
+
+/// Recompute the rollup buckets of a sensor the timestamp falls into
+async fn rebuild_sensor_rollups(
+    conn: &mut PgConnection,
+    sensor_id: &str,
+    timestamp: DateTime<Utc>,
+) -> Result<(), BsError> {
+    for resolution in Resolution::ROLLED_UP {
+        let bucket = resolution.bucket(timestamp);
+        let bucket_end = bucket
+            + resolution
+                .bucket_width()
+                .expect("Rolled up resolutions have a width");
+        sqlx::query(
+            "DELETE FROM reading_rollups WHERE resolution = $1 AND sensor_id = $2 AND bucket = $3",
+        )
+        .bind(resolution.as_str())
+        .bind(sensor_id)
+        .bind(bucket)
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query(
+            "INSERT INTO reading_rollups (resolution, sensor_id, metric, bucket, min_value, \
+             max_value, sum_value, sample_count)
+                SELECT $1, r.sensor_id, v.metric, $3, MIN(v.value), MAX(v.value), SUM(v.value), \
+             COUNT(*)
+                FROM sensor_readings r JOIN reading_values v ON v.reading_id = r.id
+                WHERE r.sensor_id = $2 AND r.timestamp >= $3 AND r.timestamp < $4
+                GROUP BY r.sensor_id, v.metric",
+        )
+        .bind(resolution.as_str())
+        .bind(sensor_id)
+        .bind(bucket)
+        .bind(bucket_end)
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn sensor_calibrations(
+    conn: &mut PgConnection,
+    sensor_id: &str,
+) -> Result<Vec<StoredCalibration>, BsError> {
+    let rows: Vec<CalibrationRow> =
+        sqlx::query_as(&format!("{CALIBRATION_SELECT} WHERE sensor_id = $1"))
+            .bind(sensor_id)
+            .fetch_all(&mut *conn)
+            .await?;
+
+    Ok(rows.into_iter().map(StoredCalibration::from).collect())
+}
+
+/// Readings of sensor `$1` taken at `$2` with sequence number `$3`
+const SAME_READING: &str =
+    "sensor_id = $1 AND timestamp = $2 AND sequence IS NOT DISTINCT FROM $3::BIGINT";
+
+/// Readings repeating the sensor, timestamp and sequence number of one stored before them
+const DUPLICATE_READINGS: &str = "FROM sensor_readings r WHERE EXISTS (SELECT 1 FROM \
+                                  sensor_readings o WHERE o.sensor_id = r.sensor_id AND \
+                                  o.timestamp = r.timestamp AND o.sequence IS NOT DISTINCT FROM \
+                                  r.sequence AND o.id < r.id)";
+
+const CALIBRATION_SELECT: &str =
+    "SELECT id, sensor_id, metric, gain, offset_value, valid_from, valid_to FROM calibrations";
+
+const API_KEY_SELECT: &str =
+    "SELECT id, name, prefix, scopes, created_at, revoked_at FROM api_keys";
+
+const SENSOR_SELECT: &str = "SELECT s.sensor_id, s.name, s.location, s.altitude_m, \
+                             s.installed_on, s.notes, s.status, s.first_seen, (SELECT COUNT(*) \
+                             FROM quarantined_readings q WHERE q.sensor_id = s.sensor_id) AS \
+                             quarantined_readings FROM sensors s";
+
+/// `FROM ... WHERE ...` selecting the rows a retention target expires
+fn push_expired_rows(qb: &mut QueryBuilder<'_, Postgres>, target: &RetentionTarget) {
+    match target.resolution {
+        Resolution::Raw => {
+            qb.push("FROM sensor_readings WHERE timestamp < ")
+                .push_bind(target.cutoff);
+        }
+        rolled_up => {
+            qb.push("FROM reading_rollups WHERE resolution = ")
+                .push_bind(rolled_up.as_str())
+                .push(" AND bucket < ")
+                .push_bind(rolled_up.bucket(target.cutoff));
+        }
+    }
+    if let Some(sensor_id) = &target.sensor_id {
+        qb.push(" AND sensor_id = ").push_bind(sensor_id.clone());
+    }
+    if !target.excluded.is_empty() {
+        qb.push(" AND sensor_id <> ALL(")
+            .push_bind(target.excluded.clone())
+            .push(")");
+    }
+}
+
+/// `date_trunc` field matching the bucket of a resolution
+fn postgres_bucket_field(resolution: Resolution) -> &'static str {
+    match resolution {
+        Resolution::Raw => unreachable!("Raw readings are not rolled up"),
+        Resolution::Hour => "hour",
+        Resolution::Day => "day",
+    }
+}
+
+/// Readings selected by [`push_readings_query`], with their keyset
+fn reading_rows(
+    rows: Vec<PgRow>,
+    query: &MeasurementQuery,
+) -> Result<Vec<(Keyset, SensorReading)>, BsError> {
+    let mut readings = Vec::with_capacity(rows.len());
+    for row in rows {
+        let keyset = Keyset {
+            timestamp: row.try_get(0)?,
+            id: row.try_get(1)?,
+        };
+
+        let mut reading = SensorReading {
+            sensor_id: None,
+            topic: None,
+            timestamp: None,
+            metrics: BTreeMap::new(),
+        };
+        for (i, col) in query.columns.iter().enumerate() {
+            let idx = i + 2;
+            match col.as_str() {
+                "sensor_id" => reading.sensor_id = Some(row.try_get(idx)?),
+                "topic" => reading.topic = Some(row.try_get(idx)?),
+                "timestamp" => reading.timestamp = Some(row.try_get(idx)?),
+                metric => {
+                    if let Some(value) = row.try_get::<Option<f64>, _>(idx)? {
+                        reading.metrics.insert(metric.to_string(), value);
+                    }
+                }
+            }
+        }
+        readings.push((keyset, reading));
+    }
+    Ok(readings)
+}
+
+/// Select the keyset and the requested columns of the readings matching the
+/// filters of `query`, leaving the statement open for more conditions
+fn push_readings_query<'a>(qb: &mut QueryBuilder<'a, Postgres>, query: &'a MeasurementQuery) {
+    // The keyset is always selected so the page cursors can be built
+    // even when the columns were not requested
+    qb.push("SELECT r.timestamp, r.id");
+
+    for col in &query.columns {
+        qb.push(", ");
+        if MeasurementQuery::BASE_COLUMNS.contains(&col.as_str()) {
+            qb.push("r.").push(col);
+        } else {
+            qb.push("(SELECT value FROM reading_values WHERE reading_id = r.id AND metric = ")
+                .push_bind(col.clone())
+                .push(")");
+        }
+    }
+
+    qb.push(" FROM sensor_readings r WHERE TRUE");
+
+    let f = &query.filters;
+
+    if let Some(sensor_id) = &f.sensor_id {
+        qb.push(" AND r.sensor_id = ").push_bind(sensor_id);
+    }
+
+    if let Some(metric) = &f.metric {
+        qb.push(
+            " AND EXISTS (SELECT 1 FROM reading_values v WHERE v.reading_id = r.id AND v.metric = ",
+        )
+        .push_bind(metric);
+        if let Some(min) = f.min {
+            qb.push(" AND v.value >= ").push_bind(min);
+        }
+        if let Some(max) = f.max {
+            qb.push(" AND v.value <= ").push_bind(max);
+        }
+        qb.push(")");
+    }
+
+    if let Some(from) = f.from {
+        qb.push(" AND r.timestamp >= ").push_bind(from);
+    }
+
+    if let Some(to) = f.to {
+        qb.push(" AND r.timestamp < ").push_bind(to);
+    }
+
+    if let Some(subscriptions) = &f.subscriptions {
+        qb.push(" AND (FALSE");
+        if !subscriptions.sensors.is_empty() {
+            qb.push(" OR r.sensor_id IN (");
+            let mut sensors = qb.separated(", ");
+            for sensor_id in &subscriptions.sensors {
+                sensors.push_bind(sensor_id);
+            }
+            qb.push(")");
+        }
+        if !subscriptions.metrics.is_empty() {
+            qb.push(
+                " OR EXISTS (SELECT 1 FROM reading_values v WHERE v.reading_id = r.id AND v.metric \
+                 IN (",
+            );
+            let mut metrics = qb.separated(", ");
+            for metric in &subscriptions.metrics {
+                metrics.push_bind(metric);
+            }
+            qb.push("))");
+        }
+        qb.push(")");
+    }
+}
+
+fn push_page_query<'a>(
+    qb: &mut QueryBuilder<'a, Postgres>,
+    query: &'a MeasurementQuery,
+    position: Option<Keyset>,
+    order: SortOrder,
+) {
+    push_readings_query(qb, query);
+
+    if let Some(keyset) = position {
+        let comparison = match order {
+            SortOrder::Asc => " > ",
+            SortOrder::Desc => " < ",
+        };
+        qb.push(" AND (r.timestamp, r.id)")
+            .push(comparison)
+            .push("(")
+            .push_bind(keyset.timestamp)
+            .push(", ")
+            .push_bind(keyset.id)
+            .push(")");
+    }
+
+    qb.push(" ORDER BY r.timestamp ")
+        .push(order.as_sql())
+        .push(", r.id ")
+        .push(order.as_sql());
+    // One extra row tells us whether there is a next page
+    qb.push(" LIMIT ")
+        .push_bind(query.pagination.page_size as i64 + 1);
+}
+
+/// Readings stored after `after`. Ids grow in the order readings are stored
+/// as inserts take turns, see `insert_reading`.
+fn push_stored_query<'a>(
+    qb: &mut QueryBuilder<'a, Postgres>,
+    query: &'a MeasurementQuery,
+    after: i64,
+) {
+    push_readings_query(qb, query);
+    qb.push(" AND r.id > ").push_bind(after);
+    qb.push(" ORDER BY r.id LIMIT ")
+        .push_bind(query.pagination.page_size as i64);
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::Executor;
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+    use crate::db::conformance::{TestBackend, conformance_tests};
+    use crate::db::schema::{self, POSTGRES_MIGRATOR};
+
+    /// Each test runs in a schema of its own on the server `POSTGRES_TEST_URL`
+    /// points at. The tests are skipped when it is not set.
+    struct PostgresBackend {
+        admin: PgPool,
+        schema: String,
+        pool: PgPool,
+    }
+
+    impl TestBackend for PostgresBackend {
+        type Repository = PostgresRepository;
+
+        async fn create() -> Option<Self> {
+            let Ok(url) = std::env::var("POSTGRES_TEST_URL") else {
+                eprintln!("POSTGRES_TEST_URL is not set, skipping");
+                return None;
+            };
+            let admin = PgPool::connect(&url).await.unwrap();
+            let schema = format!("test_{:016x}", rand::random::<u64>());
+            admin
+                .execute(format!("CREATE SCHEMA {schema}").as_str())
+                .await
+                .unwrap();
+
+            let search_path = format!("SET search_path TO {schema}");
+            let pool = PgPoolOptions::new()
+                .after_connect(move |conn, _| {
+                    let search_path = search_path.clone();
+                    Box::pin(async move {
+                        conn.execute(search_path.as_str()).await?;
+                        Ok(())
+                    })
+                })
+                .connect(&url)
+                .await
+                .unwrap();
+            POSTGRES_MIGRATOR.run(&pool).await.unwrap();
+
+            Some(Self {
+                admin,
+                schema,
+                pool,
+            })
+        }
+
+        fn repository(&self) -> PostgresRepository {
+            PostgresRepository::new(self.pool.clone())
+        }
+
+        async fn teardown(self) {
+            self.pool.close().await;
+            self.admin
+                .execute(format!("DROP SCHEMA {} CASCADE", self.schema).as_str())
+                .await
+                .unwrap();
+        }
+    }
+
+    conformance_tests!(PostgresBackend);
+
+    #[tokio::test]
+    async fn expired_readings_take_their_values() {
+        let Some(backend) = PostgresBackend::create().await else {
+            return;
+        };
+        let repo = backend.repository();
+        let reading = SensorReadingEvent {
+            sensor_id: "outside".to_string(),
+            timestamp: Utc::now() - TimeDelta::days(40),
+            sequence: None,
+            metrics: [
+                ("temperature".to_string(), 1.0),
+                ("humidity".to_string(), 80.0),
+            ]
+            .into(),
+        };
+        repo.insert_sensor_reading("sensor/update".to_string(), reading)
+            .await
+            .unwrap();
+        repo.upsert_retention_policy(RetentionPolicy {
+            sensor_id: None,
+            resolution: Resolution::Raw,
+            keep_days: Some(30),
+        })
+        .await
+        .unwrap();
+
+        let report = repo.enforce_retention(false).await.unwrap();
+        assert_eq!(report.entries[0].rows, 1);
+        let values: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM reading_values")
+            .fetch_one(&backend.pool)
+            .await
+            .unwrap();
+        assert_eq!(values, 0);
+
+        backend.teardown().await;
+    }
+
+    #[tokio::test]
+    async fn migrations_are_reversible() {
+        let Some(backend) = PostgresBackend::create().await else {
+            return;
+        };
+        let mut conn = backend.pool.acquire().await.unwrap();
+
+        let reverted = schema::migrate_down(&POSTGRES_MIGRATOR, &mut *conn, 0)
+            .await
+            .unwrap();
+        assert_eq!(reverted.len(), POSTGRES_MIGRATOR.iter().count() / 2);
+        let tables: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM information_schema.tables \
+             WHERE table_schema = current_schema() AND table_name <> '_sqlx_migrations'",
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .unwrap();
+        assert_eq!(tables, 0);
+
+        schema::migrate_up(&POSTGRES_MIGRATOR, &mut *conn, None)
+            .await
+            .unwrap();
+        drop(conn);
+        let repo = backend.repository();
+        repo.insert_sensor_reading(
+            "sensor/update".to_string(),
+            SensorReadingEvent {
+                sensor_id: "outside".to_string(),
+                timestamp: Utc::now(),
+                sequence: None,
+                metrics: [("temperature".to_string(), 1.0)].into(),
+            },
+        )
+        .await
+        .unwrap();
+
+        backend.teardown().await;
+    }
+}
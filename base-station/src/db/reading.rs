@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use poem_openapi::Object;
+use serde::Serialize;
+
+use super::QueryFilter;
+use crate::error::BsError;
+
+/// What to do with a reading taken by a sensor at the timestamp, and with the
+/// sequence number, of a stored one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the stored reading, for redelivered messages
+    #[default]
+    Ignore,
+    /// Store the new reading in place of the stored one
+    Replace,
+    /// Store both
+    KeepBoth,
+}
+
+impl ConflictPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConflictPolicy::Ignore => "ignore",
+            ConflictPolicy::Replace => "replace",
+            ConflictPolicy::KeepBoth => "keep-both",
+        }
+    }
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = BsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(ConflictPolicy::Ignore),
+            "replace" => Ok(ConflictPolicy::Replace),
+            "keep-both" => Ok(ConflictPolicy::KeepBoth),
+            other => Err(BsError::Other(format!("Unknown conflict policy: {other}"))),
+        }
+    }
+}
+
+/// A single reading restricted to the columns requested in the query
+#[derive(Debug, Clone, PartialEq, Serialize, Object)]
+pub struct SensorReading {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[oai(skip_serializing_if_is_none)]
+    pub sensor_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[oai(skip_serializing_if_is_none)]
+    pub topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[oai(skip_serializing_if_is_none)]
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Requested metric values the reading actually carries
+    pub metrics: BTreeMap<String, f64>,
+}
+
+/// Value of the newest reading of a sensor as stored by the SQL backends. A
+/// reading without values comes as a row without a metric.
+#[derive(Debug, sqlx::FromRow)]
+pub(crate) struct LatestValueRow {
+    pub sensor_id: String,
+    pub topic: String,
+    pub timestamp: DateTime<Utc>,
+    pub metric: Option<String>,
+    pub value: Option<f64>,
+}
+
+/// Newest readings from the rows of their values, ordered by sensor
+pub(crate) fn latest_readings(rows: Vec<LatestValueRow>) -> Vec<SensorReading> {
+    let mut readings: Vec<SensorReading> = Vec::new();
+    for row in rows {
+        let reading = match readings.last_mut() {
+            Some(reading) if reading.sensor_id.as_ref() == Some(&row.sensor_id) => reading,
+            _ => {
+                readings.push(SensorReading {
+                    sensor_id: Some(row.sensor_id),
+                    topic: Some(row.topic),
+                    timestamp: Some(row.timestamp),
+                    metrics: BTreeMap::new(),
+                });
+                readings.last_mut().expect("Just pushed")
+            }
+        };
+        if let (Some(metric), Some(value)) = (row.metric, row.value) {
+            reading.metrics.insert(metric, value);
+        }
+    }
+    readings
+}
+
+#[derive(Debug, Serialize, Object)]
+pub struct SensorReadingsPage {
+    pub rows: Vec<SensorReading>,
+    pub filters: QueryFilter,
+    /// Pass as `pagination.after` to fetch the following page
+    pub next: Option<String>,
+    /// Pass as `pagination.before` to fetch the preceding page
+    pub previous: Option<String>,
+}
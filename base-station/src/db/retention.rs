@@ -0,0 +1,179 @@
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+use super::{Repository, Resolution};
+use crate::error::BsError;
+
+/// How long data of one resolution is kept, either for a single sensor or
+/// for every sensor without a policy of its own
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Object)]
+pub struct RetentionPolicy {
+    /// Applies to all sensors when missing
+    pub sensor_id: Option<String>,
+    pub resolution: Resolution,
+    /// Kept forever when missing
+    pub keep_days: Option<u32>,
+}
+
+impl RetentionPolicy {
+    pub fn validate(&self) -> Result<(), BsError> {
+        if self.keep_days == Some(0) {
+            return Err(BsError::InvalidQuery(
+                "keep_days must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Data older than `cutoff` to be removed for one sensor, or for all sensors
+/// except the `excluded` ones that have their own policy
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionTarget {
+    pub resolution: Resolution,
+    pub sensor_id: Option<String>,
+    pub excluded: Vec<String>,
+    pub cutoff: DateTime<Utc>,
+}
+
+impl RetentionTarget {
+    pub fn from_policies(policies: &[RetentionPolicy], now: DateTime<Utc>) -> Vec<Self> {
+        let mut targets = Vec::new();
+        for resolution in [Resolution::Raw, Resolution::Hour, Resolution::Day] {
+            let policies = policies.iter().filter(|p| p.resolution == resolution);
+            let mut excluded = Vec::new();
+            let mut global = None;
+            for policy in policies {
+                match &policy.sensor_id {
+                    Some(sensor_id) => {
+                        excluded.push(sensor_id.clone());
+                        if let Some(days) = policy.keep_days {
+                            targets.push(RetentionTarget {
+                                resolution,
+                                sensor_id: Some(sensor_id.clone()),
+                                excluded: Vec::new(),
+                                cutoff: now - TimeDelta::days(days.into()),
+                            });
+                        }
+                    }
+                    None => global = policy.keep_days,
+                }
+            }
+            if let Some(days) = global {
+                targets.push(RetentionTarget {
+                    resolution,
+                    sensor_id: None,
+                    excluded,
+                    cutoff: now - TimeDelta::days(days.into()),
+                });
+            }
+        }
+        targets
+    }
+
+    /// Whether data of the sensor falls under this target
+    pub fn covers(&self, sensor_id: &str) -> bool {
+        match &self.sensor_id {
+            Some(target_sensor) => target_sensor == sensor_id,
+            None => !self.excluded.iter().any(|excluded| excluded == sensor_id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Object)]
+pub struct RetentionReportEntry {
+    /// Missing for the entry covering all sensors without their own policy
+    pub sensor_id: Option<String>,
+    pub resolution: Resolution,
+    pub cutoff: DateTime<Utc>,
+    /// Readings or rollup buckets removed, or that would be removed on a dry run
+    pub rows: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Object)]
+pub struct RetentionReport {
+    pub dry_run: bool,
+    pub entries: Vec<RetentionReportEntry>,
+}
+
+/// Periodically enforce the retention policies in the background
+pub fn spawn_retention_task<R>(repository: R, every: Duration) -> JoinHandle<()>
+where
+    R: Repository + 'static,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            match repository.enforce_retention(false).await {
+                Ok(report) => {
+                    let removed: u64 = report.entries.iter().map(|e| e.rows).sum();
+                    info!("Retention removed {removed} rows");
+                }
+                Err(e) => error!("Retention enforcement failed: {e}"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn policy(
+        sensor_id: Option<&str>,
+        resolution: Resolution,
+        keep_days: Option<u32>,
+    ) -> RetentionPolicy {
+        RetentionPolicy {
+            sensor_id: sensor_id.map(str::to_string),
+            resolution,
+            keep_days,
+        }
+    }
+
+    #[test]
+    fn sensor_policies_override_the_global_one() {
+        let now = Utc.with_ymd_and_hms(2026, 10, 1, 0, 0, 0).unwrap();
+        let policies = [
+            policy(None, Resolution::Raw, Some(30)),
+            policy(Some("balcony"), Resolution::Raw, Some(7)),
+            policy(Some("attic"), Resolution::Raw, None),
+            policy(None, Resolution::Hour, Some(730)),
+            policy(None, Resolution::Day, None),
+        ];
+
+        let targets = RetentionTarget::from_policies(&policies, now);
+
+        assert_eq!(
+            targets,
+            vec![
+                RetentionTarget {
+                    resolution: Resolution::Raw,
+                    sensor_id: Some("balcony".to_string()),
+                    excluded: vec![],
+                    cutoff: now - TimeDelta::days(7),
+                },
+                RetentionTarget {
+                    resolution: Resolution::Raw,
+                    sensor_id: None,
+                    excluded: vec!["balcony".to_string(), "attic".to_string()],
+                    cutoff: now - TimeDelta::days(30),
+                },
+                RetentionTarget {
+                    resolution: Resolution::Hour,
+                    sensor_id: None,
+                    excluded: vec![],
+                    cutoff: now - TimeDelta::days(730),
+                },
+            ]
+        );
+    }
+}
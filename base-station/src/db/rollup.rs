@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+use crate::error::BsError;
+
+/// Granularity a series is served at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+pub enum Resolution {
+    /// Every stored reading
+    Raw,
+    /// Hourly rollups
+    Hour,
+    /// Daily rollups, buckets start at midnight UTC
+    Day,
+}
+
+impl Resolution {
+    /// Longest span still served from raw readings when no resolution is requested
+    pub const RAW_SPAN_LIMIT: TimeDelta = TimeDelta::hours(6);
+    /// Longest span still served from hourly rollups when no resolution is requested
+    pub const HOURLY_SPAN_LIMIT: TimeDelta = TimeDelta::days(90);
+
+    /// Resolutions that are materialized in the rollup table
+    pub const ROLLED_UP: [Resolution; 2] = [Resolution::Hour, Resolution::Day];
+
+    pub fn for_span(span: TimeDelta) -> Self {
+        if span <= Self::RAW_SPAN_LIMIT {
+            Resolution::Raw
+        } else if span <= Self::HOURLY_SPAN_LIMIT {
+            Resolution::Hour
+        } else {
+            Resolution::Day
+        }
+    }
+
+    pub fn bucket_width(self) -> Option<TimeDelta> {
+        match self {
+            Resolution::Raw => None,
+            Resolution::Hour => Some(TimeDelta::hours(1)),
+            Resolution::Day => Some(TimeDelta::days(1)),
+        }
+    }
+
+    /// Start of the bucket the timestamp falls into
+    pub fn bucket(self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        match self.bucket_width() {
+            Some(width) => timestamp
+                .duration_trunc(width)
+                .expect("Reading timestamps are within the representable range"),
+            None => timestamp,
+        }
+    }
+
+    /// Name stored in the rollup table
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Resolution::Raw => "raw",
+            Resolution::Hour => "hour",
+            Resolution::Day => "day",
+        }
+    }
+}
+
+impl std::str::FromStr for Resolution {
+    type Err = BsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Resolution::Raw),
+            "hour" => Ok(Resolution::Hour),
+            "day" => Ok(Resolution::Day),
+            other => Err(BsError::Other(format!("Unknown resolution: {other}"))),
+        }
+    }
+}
+
+/// Resolution, metric, bucket start and sensor of a rollup bucket
+pub(super) type RollupKey = (Resolution, String, DateTime<Utc>, String);
+
+/// Running min, max, sum and count of the values in a rollup bucket
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Aggregate {
+    pub(super) min: f64,
+    pub(super) max: f64,
+    pub(super) sum: f64,
+    pub(super) count: i64,
+}
+
+impl Aggregate {
+    pub(super) fn new(value: f64) -> Self {
+        Self {
+            min: value,
+            max: value,
+            sum: value,
+            count: 1,
+        }
+    }
+
+    pub(super) fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Fold a value into the rollup buckets the timestamp falls into
+pub(super) fn add_to_rollups(
+    rollups: &mut BTreeMap<RollupKey, Aggregate>,
+    sensor_id: &str,
+    timestamp: DateTime<Utc>,
+    metric: &str,
+    value: f64,
+) {
+    for resolution in Resolution::ROLLED_UP {
+        let key = (
+            resolution,
+            metric.to_string(),
+            resolution.bucket(timestamp),
+            sensor_id.to_string(),
+        );
+        rollups
+            .entry(key)
+            .and_modify(|aggregate| aggregate.add(value))
+            .or_insert_with(|| Aggregate::new(value));
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Object)]
+pub struct SeriesQuery {
+    pub sensor_id: Option<String>,
+    pub metric: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    /// Picked from the length of the time span when missing
+    pub resolution: Option<Resolution>,
+}
+
+impl SeriesQuery {
+    /// Longest span a series may be served from raw readings for, the raw
+    /// points are fetched at once
+    pub const MAX_RAW_SPAN: TimeDelta = Resolution::RAW_SPAN_LIMIT;
+
+    pub fn validate(&self) -> Result<(), BsError> {
+        if self.from >= self.to {
+            return Err(BsError::InvalidQuery("Empty time span".to_string()));
+        }
+        if self.effective_resolution() == Resolution::Raw
+            && self.to - self.from > Self::MAX_RAW_SPAN
+        {
+            return Err(BsError::InvalidQuery(format!(
+                "Raw series span at most {} hours, use hourly or daily rollups",
+                Self::MAX_RAW_SPAN.num_hours()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn effective_resolution(&self) -> Resolution {
+        self.resolution
+            .unwrap_or_else(|| Resolution::for_span(self.to - self.from))
+    }
+}
+
+/// Aggregate of a metric over one bucket. Raw readings are buckets of one.
+#[derive(Debug, Clone, PartialEq, Serialize, Object, sqlx::FromRow)]
+pub struct SeriesPoint {
+    pub sensor_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    pub count: i64,
+}
+
+#[derive(Debug, Serialize, Object)]
+pub struct Series {
+    pub metric: String,
+    pub resolution: Resolution,
+    pub points: Vec<SeriesPoint>,
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn resolution_follows_span() {
+        assert_eq!(Resolution::for_span(TimeDelta::hours(1)), Resolution::Raw);
+        assert_eq!(Resolution::for_span(TimeDelta::days(7)), Resolution::Hour);
+        assert_eq!(Resolution::for_span(TimeDelta::days(365)), Resolution::Day);
+    }
+
+    #[test]
+    fn buckets_are_truncated() {
+        let ts = Utc.with_ymd_and_hms(2026, 3, 1, 17, 42, 7).unwrap();
+
+        assert_eq!(
+            Resolution::Hour.bucket(ts),
+            Utc.with_ymd_and_hms(2026, 3, 1, 17, 0, 0).unwrap()
+        );
+        assert_eq!(
+            Resolution::Day.bucket(ts),
+            Utc.with_ymd_and_hms(2026, 3, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(Resolution::Raw.bucket(ts), ts);
+    }
+
+    #[test]
+    fn raw_series_span_is_capped() {
+        let from = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap();
+        let mut query = SeriesQuery {
+            sensor_id: None,
+            metric: "temperature".to_string(),
+            from,
+            to: from + SeriesQuery::MAX_RAW_SPAN,
+            resolution: Some(Resolution::Raw),
+        };
+        assert!(query.validate().is_ok());
+
+        query.to += TimeDelta::seconds(1);
+        assert!(query.validate().is_err());
+        query.resolution = None;
+        assert!(query.validate().is_ok());
+    }
+}
@@ -0,0 +1,379 @@
+//! Schema versions of the SQL backends and the migrations between them.
+//!
+//! Pending migrations are applied on start, going back to an older schema
+//! is left to the `migrate` subcommand.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use sqlx::migrate::{Migrate, MigrateError, Migration, Migrator};
+
+use crate::error::BsError;
+
+pub static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+pub static POSTGRES_MIGRATOR: Migrator = sqlx::migrate!("./migrations-postgres");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    /// Applied, but the migration has been edited since
+    Modified,
+    /// Applied by a build that knows migrations this one doesn't
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MigrationStatus {
+    pub version: i64,
+    /// Missing for migrations this build doesn't know
+    pub description: Option<String>,
+    pub state: MigrationState,
+}
+
+/// Every migration this build knows and every one applied to the database
+pub async fn status<C>(migrator: &Migrator, conn: &mut C) -> Result<Vec<MigrationStatus>, BsError>
+where
+    C: Migrate + Send + ?Sized,
+{
+    conn.ensure_migrations_table().await?;
+    let mut applied: BTreeMap<_, _> = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|m| (m.version, m.checksum))
+        .collect();
+
+    let mut statuses: Vec<_> = up_migrations(migrator)
+        .map(|migration| {
+            let state = match applied.remove(&migration.version) {
+                Some(checksum) if checksum == migration.checksum => MigrationState::Applied,
+                Some(_) => MigrationState::Modified,
+                None => MigrationState::Pending,
+            };
+            MigrationStatus {
+                version: migration.version,
+                description: Some(migration.description.to_string()),
+                state,
+            }
+        })
+        .collect();
+    statuses.extend(applied.into_keys().map(|version| MigrationStatus {
+        version,
+        description: None,
+        state: MigrationState::Unknown,
+    }));
+    statuses.sort_by_key(|s| s.version);
+
+    Ok(statuses)
+}
+
+/// Migrations `up` would apply to reach `target`, the newest version when missing
+pub async fn plan_up<'m, C>(
+    migrator: &'m Migrator,
+    conn: &mut C,
+    target: Option<i64>,
+) -> Result<Vec<&'m Migration>, BsError>
+where
+    C: Migrate + Send + ?Sized,
+{
+    let statuses = checked_status(migrator, conn).await?;
+    if let Some(target) = target {
+        ensure_version_known(migrator, target)?;
+    }
+    Ok(up_migrations(migrator)
+        .filter(|m| target.is_none_or(|target| m.version <= target))
+        .filter(|m| {
+            statuses
+                .iter()
+                .any(|s| s.version == m.version && s.state == MigrationState::Pending)
+        })
+        .collect())
+}
+
+/// Migrations `down` would revert, newest first, to get back to `target`.
+/// Version 0 reverts every migration.
+pub async fn plan_down<'m, C>(
+    migrator: &'m Migrator,
+    conn: &mut C,
+    target: i64,
+) -> Result<Vec<&'m Migration>, BsError>
+where
+    C: Migrate + Send + ?Sized,
+{
+    let statuses = checked_status(migrator, conn).await?;
+    if target != 0 {
+        ensure_version_known(migrator, target)?;
+    }
+    let mut plan: Vec<_> = migrator
+        .iter()
+        .filter(|m| m.migration_type.is_down_migration() && m.version > target)
+        .filter(|m| {
+            statuses
+                .iter()
+                .any(|s| s.version == m.version && s.state == MigrationState::Applied)
+        })
+        .collect();
+    plan.sort_by_key(|m| std::cmp::Reverse(m.version));
+    if let Some(irreversible) = up_migrations(migrator).find(|m| {
+        m.version > target
+            && !m.migration_type.is_reversible()
+            && statuses
+                .iter()
+                .any(|s| s.version == m.version && s.state == MigrationState::Applied)
+    }) {
+        return Err(BsError::Other(format!(
+            "Migration {} cannot be reverted",
+            irreversible.version
+        )));
+    }
+    Ok(plan)
+}
+
+/// Apply the pending migrations up to `target`. Returns the versions applied.
+pub async fn migrate_up<C>(
+    migrator: &Migrator,
+    conn: &mut C,
+    target: Option<i64>,
+) -> Result<Vec<i64>, BsError>
+where
+    C: Migrate + Send + ?Sized,
+{
+    conn.lock().await?;
+    let result = async {
+        let mut applied = Vec::new();
+        for migration in plan_up(migrator, conn, target).await? {
+            conn.apply(migration).await?;
+            applied.push(migration.version);
+        }
+        Ok(applied)
+    }
+    .await;
+    conn.unlock().await?;
+    result
+}
+
+/// Revert the migrations newer than `target`. Returns the versions reverted.
+pub async fn migrate_down<C>(
+    migrator: &Migrator,
+    conn: &mut C,
+    target: i64,
+) -> Result<Vec<i64>, BsError>
+where
+    C: Migrate + Send + ?Sized,
+{
+    conn.lock().await?;
+    let result = async {
+        let mut reverted = Vec::new();
+        for migration in plan_down(migrator, conn, target).await? {
+            conn.revert(migration).await?;
+            reverted.push(migration.version);
+        }
+        Ok(reverted)
+    }
+    .await;
+    conn.unlock().await?;
+    result
+}
+
+fn up_migrations(migrator: &Migrator) -> impl Iterator<Item = &Migration> {
+    migrator
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+}
+
+/// Status of a database this build can safely migrate
+async fn checked_status<C>(
+    migrator: &Migrator,
+    conn: &mut C,
+) -> Result<Vec<MigrationStatus>, BsError>
+where
+    C: Migrate + Send + ?Sized,
+{
+    conn.ensure_migrations_table().await?;
+    if let Some(version) = conn.dirty_version().await? {
+        return Err(MigrateError::Dirty(version).into());
+    }
+    let statuses = status(migrator, conn).await?;
+    if let Some(unknown) = statuses
+        .iter()
+        .rev()
+        .find(|s| s.state == MigrationState::Unknown)
+    {
+        return Err(BsError::SchemaTooNew {
+            database: unknown.version,
+            known: up_migrations(migrator)
+                .map(|m| m.version)
+                .max()
+                .unwrap_or(0),
+        });
+    }
+    if let Some(modified) = statuses
+        .iter()
+        .find(|s| s.state == MigrationState::Modified)
+    {
+        return Err(MigrateError::VersionMismatch(modified.version).into());
+    }
+    Ok(statuses)
+}
+
+fn ensure_version_known(migrator: &Migrator, version: i64) -> Result<(), BsError> {
+    if up_migrations(migrator).any(|m| m.version == version) {
+        Ok(())
+    } else {
+        Err(BsError::Other(format!("Unknown schema version {version}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::SqlitePool;
+
+    use super::*;
+    use crate::SensorReadingEvent;
+    use crate::db::{Repository, SqliteRepository};
+
+    async fn tables(pool: &SqlitePool) -> Vec<String> {
+        sqlx::query_scalar(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' \
+             ORDER BY name",
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn migrations_are_reversible(pool: SqlitePool) {
+        let mut conn = pool.acquire().await.unwrap();
+        let applied = migrate_up(&SQLITE_MIGRATOR, &mut *conn, None)
+            .await
+            .unwrap();
+        assert_eq!(applied.len(), up_migrations(&SQLITE_MIGRATOR).count());
+        let migrated_tables = tables(&pool).await;
+
+        let reading: SensorReadingEvent =
+            serde_json::from_str(r#"{"sensor_id":"attic","t":21.5,"p":101325,"h":40}"#).unwrap();
+        SqliteRepository::new(pool.clone())
+            .insert_sensor_reading("sensor/update".to_string(), reading)
+            .await
+            .unwrap();
+
+        let reverted = migrate_down(&SQLITE_MIGRATOR, &mut *conn, 0).await.unwrap();
+        assert_eq!(reverted.first(), applied.last());
+        assert_eq!(tables(&pool).await, vec!["_sqlx_migrations"]);
+        assert!(
+            status(&SQLITE_MIGRATOR, &mut *conn)
+                .await
+                .unwrap()
+                .iter()
+                .all(|s| s.state == MigrationState::Pending)
+        );
+
+        migrate_up(&SQLITE_MIGRATOR, &mut *conn, None)
+            .await
+            .unwrap();
+        assert_eq!(tables(&pool).await, migrated_tables);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn reverting_calibrations_restores_raw_values(pool: SqlitePool) {
+        let mut conn = pool.acquire().await.unwrap();
+        migrate_up(&SQLITE_MIGRATOR, &mut *conn, None)
+            .await
+            .unwrap();
+        let reading: SensorReadingEvent =
+            serde_json::from_str(r#"{"sensor_id":"attic","t":21.5,"p":101325,"h":40}"#).unwrap();
+        SqliteRepository::new(pool.clone())
+            .insert_sensor_reading("sensor/update".to_string(), reading)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE reading_values SET value = value + 10")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+
+        migrate_down(&SQLITE_MIGRATOR, &mut *conn, 20261019130000)
+            .await
+            .unwrap();
+        let temperature: f64 =
+            sqlx::query_scalar("SELECT value FROM reading_values WHERE metric = 'temperature'")
+                .fetch_one(&mut *conn)
+                .await
+                .unwrap();
+        assert_eq!(temperature, 21.5);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn reverts_losing_readings_are_refused(pool: SqlitePool) {
+        let mut conn = pool.acquire().await.unwrap();
+        migrate_up(&SQLITE_MIGRATOR, &mut *conn, None)
+            .await
+            .unwrap();
+        let reading: SensorReadingEvent =
+            serde_json::from_str(r#"{"sensor_id":"cellar","co2":415}"#).unwrap();
+        SqliteRepository::new(pool.clone())
+            .insert_sensor_reading("sensor/update".to_string(), reading)
+            .await
+            .unwrap();
+
+        let res = migrate_down(&SQLITE_MIGRATOR, &mut *conn, 0).await;
+        assert!(res.unwrap_err().to_string().contains("would be lost"));
+        assert!(tables(&pool).await.contains(&"reading_values".to_string()));
+
+        sqlx::query("DELETE FROM sensor_readings")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        migrate_down(&SQLITE_MIGRATOR, &mut *conn, 0).await.unwrap();
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn migrations_stop_at_the_target(pool: SqlitePool) {
+        let mut conn = pool.acquire().await.unwrap();
+        let versions: Vec<_> = up_migrations(&SQLITE_MIGRATOR).map(|m| m.version).collect();
+
+        let applied = migrate_up(&SQLITE_MIGRATOR, &mut *conn, Some(versions[1]))
+            .await
+            .unwrap();
+        assert_eq!(applied, versions[..2]);
+        let pending = plan_up(&SQLITE_MIGRATOR, &mut *conn, None).await.unwrap();
+        assert_eq!(pending.len(), versions.len() - 2);
+
+        let plan = plan_down(&SQLITE_MIGRATOR, &mut *conn, versions[0])
+            .await
+            .unwrap();
+        assert_eq!(
+            plan.iter().map(|m| m.version).collect::<Vec<_>>(),
+            vec![versions[1]]
+        );
+        assert!(plan_down(&SQLITE_MIGRATOR, &mut *conn, 42).await.is_err());
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn newer_schemas_are_refused(pool: SqlitePool) {
+        let mut conn = pool.acquire().await.unwrap();
+        migrate_up(&SQLITE_MIGRATOR, &mut *conn, None)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, \
+             execution_time) VALUES (99991231000000, 'from the future', TRUE, X'00', 0)",
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+
+        let res = migrate_up(&SQLITE_MIGRATOR, &mut *conn, None).await;
+        assert!(matches!(
+            res,
+            Err(BsError::SchemaTooNew {
+                database: 99991231000000,
+                ..
+            })
+        ));
+        let statuses = status(&SQLITE_MIGRATOR, &mut *conn).await.unwrap();
+        assert_eq!(statuses.last().unwrap().state, MigrationState::Unknown);
+    }
+}
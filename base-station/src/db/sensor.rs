@@ -0,0 +1,97 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+use crate::error::BsError;
+
+/// Whether readings of a sensor are stored or held back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+pub enum SensorStatus {
+    /// Seen on the broker but not accepted yet, its readings are quarantined
+    Unapproved,
+    /// Readings are stored as they arrive
+    Approved,
+}
+
+impl SensorStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SensorStatus::Unapproved => "unapproved",
+            SensorStatus::Approved => "approved",
+        }
+    }
+}
+
+impl std::str::FromStr for SensorStatus {
+    type Err = BsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unapproved" => Ok(SensorStatus::Unapproved),
+            "approved" => Ok(SensorStatus::Approved),
+            other => Err(BsError::Other(format!("Unknown sensor status: {other}"))),
+        }
+    }
+}
+
+/// Details about a sensor an admin can edit
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Object)]
+pub struct SensorMetadata {
+    /// Human friendly name
+    pub name: Option<String>,
+    /// Room or place the sensor is installed in
+    pub location: Option<String>,
+    /// Metres above sea level
+    pub altitude_m: Option<f64>,
+    pub installed_on: Option<NaiveDate>,
+    pub notes: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Object)]
+pub struct Sensor {
+    pub sensor_id: String,
+    #[serde(flatten)]
+    #[oai(flatten)]
+    pub metadata: SensorMetadata,
+    pub status: SensorStatus,
+    /// First reading received, missing for sensors registered before they published
+    pub first_seen: Option<DateTime<Utc>>,
+    /// Readings held back until the sensor is approved
+    pub quarantined_readings: u64,
+}
+
+/// Sensor as stored by the SQL backends
+#[derive(Debug, sqlx::FromRow)]
+pub(crate) struct SensorRow {
+    pub sensor_id: String,
+    pub name: Option<String>,
+    pub location: Option<String>,
+    pub altitude_m: Option<f64>,
+    pub installed_on: Option<NaiveDate>,
+    pub notes: Option<String>,
+    pub status: String,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub quarantined_readings: i64,
+}
+
+impl TryFrom<SensorRow> for Sensor {
+    type Error = BsError;
+
+    fn try_from(row: SensorRow) -> Result<Self, Self::Error> {
+        Ok(Sensor {
+            sensor_id: row.sensor_id,
+            metadata: SensorMetadata {
+                name: row.name,
+                location: row.location,
+                altitude_m: row.altitude_m,
+                installed_on: row.installed_on,
+                notes: row.notes,
+            },
+            status: row.status.parse()?,
+            first_seen: row.first_seen,
+            quarantined_readings: row.quarantined_readings as u64,
+        })
+    }
+}
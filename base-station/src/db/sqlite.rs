@@ -0,0 +1,1719 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use futures_util::TryStreamExt;
+use sqlx::{QueryBuilder, Row, Sqlite, SqliteConnection, SqlitePool, Transaction};
+
+use super::aggregate::Aggregator;
+use super::api_key::{ApiKeyRow, join_scopes};
+use super::calibration::{CalibrationRow, calibrate, check_overlaps};
+use super::reading::{LatestValueRow, latest_readings};
+use super::rollup::{Aggregate, RollupKey};
+use super::sensor::SensorRow;
+use super::{
+    AggregateQuery, Aggregation, ApiKey, Calibration, ConflictPolicy, CursorKey, Keyset,
+    MeasurementQuery, Metric, Repository, Resolution, RetentionPolicy, RetentionReport,
+    RetentionReportEntry, RetentionTarget, Sensor, SensorMetadata, SensorReading,
+    SensorReadingsPage, Series, SeriesPoint, SeriesQuery, SortOrder, StoredCalibration,
+};
+use crate::error::BsError;
+use crate::{SensorReadingEvent, is_valid_metric_name};
+
+/// `PRAGMA auto_vacuum` value of `INCREMENTAL`
+const SQLITE_INCREMENTAL_VACUUM: i64 = 2;
+
+#[derive(Debug, Clone)]
+pub struct SqliteRepository {
+    pool: SqlitePool,
+    cursor_key: CursorKey,
+    conflict_policy: ConflictPolicy,
+    /// Reading ids continue those of the attached catalogue
+    catalogue_ids: bool,
+}
+
+impl SqliteRepository {
+    /// Rows removed per statement so retention never holds the write lock for long
+    const RETENTION_BATCH_SIZE: i64 = 500;
+
+    pub fn new(pool: SqlitePool) -> Self {
+        Self {
+            pool,
+            cursor_key: CursorKey::random(),
+            conflict_policy: ConflictPolicy::default(),
+            catalogue_ids: false,
+        }
+    }
+
+    /// Sign cursors with a fixed key so they survive restarts
+    pub fn with_cursor_key(mut self, cursor_key: CursorKey) -> Self {
+        self.cursor_key = cursor_key;
+        self
+    }
+
+    /// Number readings on from the last id handed out by the catalogue
+    /// attached as `catalogue`, and count it up as readings are stored. Ids
+    /// then grow in the order readings are stored across every database
+    /// sharing the catalogue.
+    pub(super) fn with_catalogue_ids(mut self) -> Self {
+        self.catalogue_ids = true;
+        self
+    }
+
+    /// Begin a transaction storing readings
+    async fn begin_insert(&self) -> Result<Transaction<'static, Sqlite>, BsError> {
+        let mut tx = self.pool.begin().await?;
+        if self.catalogue_ids {
+            // Writing the catalogue first takes its lock, nobody else hands
+            // out ids until we commit
+            sqlx::query(
+                "UPDATE catalogue.sqlite_sequence SET seq = seq WHERE name = 'sensor_readings'",
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(
+                "UPDATE main.sqlite_sequence SET seq = MAX(seq, IFNULL((SELECT seq FROM \
+                 catalogue.sqlite_sequence WHERE name = 'sensor_readings'), 0))
+                    WHERE name = 'sensor_readings'",
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        Ok(tx)
+    }
+
+    async fn commit_insert(&self, mut tx: Transaction<'static, Sqlite>) -> Result<(), BsError> {
+        if self.catalogue_ids {
+            sqlx::query(
+                "UPDATE catalogue.sqlite_sequence SET seq = (SELECT seq FROM main.sqlite_sequence \
+                 WHERE name = 'sensor_readings')
+                    WHERE name = 'sensor_readings'",
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Switch the database to incremental auto-vacuum so retention can hand
+    /// freed pages back to the file system. The first switch needs one full
+    /// VACUUM which can take a while on a large database.
+    pub async fn ensure_incremental_vacuum(&self) -> Result<(), BsError> {
+        let mode: i64 = sqlx::query_scalar("PRAGMA auto_vacuum")
+            .fetch_one(&self.pool)
+            .await?;
+        if mode != SQLITE_INCREMENTAL_VACUUM {
+            tracing::info!("Enabling incremental auto-vacuum, running a full VACUUM");
+            let mut conn = self.pool.acquire().await?;
+            sqlx::query("PRAGMA auto_vacuum = INCREMENTAL")
+                .execute(&mut *conn)
+                .await?;
+            sqlx::query("VACUUM").execute(&mut *conn).await?;
+        }
+        Ok(())
+    }
+
+    /// Up to `limit` readings matching the query past `position`, in scan order
+    pub(super) async fn fetch_page_rows(
+        &self,
+        query: &MeasurementQuery,
+        position: Option<Keyset>,
+        order: SortOrder,
+        limit: usize,
+    ) -> Result<Vec<(Keyset, SensorReading)>, BsError> {
+        let mut qb = QueryBuilder::<Sqlite>::new("");
+        push_page_query(&mut qb, query, position, order, limit);
+        self.fetch_rows(qb, query).await
+    }
+
+    /// Readings selected by [`push_readings_query`], with their keyset
+    async fn fetch_rows(
+        &self,
+        mut qb: QueryBuilder<'_, Sqlite>,
+        query: &MeasurementQuery,
+    ) -> Result<Vec<(Keyset, SensorReading)>, BsError> {
+        let rows = qb.build().fetch_all(&self.pool).await?;
+
+        let mut readings = Vec::with_capacity(rows.len());
+        for row in rows {
+            let keyset = Keyset {
+                timestamp: row.try_get(0)?,
+                id: row.try_get(1)?,
+            };
+
+            let mut reading = SensorReading {
+                sensor_id: None,
+                topic: None,
+                timestamp: None,
+                metrics: BTreeMap::new(),
+            };
+            for (i, col) in query.columns.iter().enumerate() {
+                let idx = i + 2;
+                match col.as_str() {
+                    "sensor_id" => reading.sensor_id = Some(row.try_get(idx)?),
+                    "topic" => reading.topic = Some(row.try_get(idx)?),
+                    "timestamp" => reading.timestamp = Some(row.try_get(idx)?),
+                    metric => {
+                        if let Some(value) = row.try_get::<Option<f64>, _>(idx)? {
+                            reading.metrics.insert(metric.to_string(), value);
+                        }
+                    }
+                }
+            }
+            readings.push((keyset, reading));
+        }
+        Ok(readings)
+    }
+
+    /// Hand pages freed by retention back to the file system. A no-op unless
+    /// incremental auto-vacuum has been enabled.
+    pub(super) async fn incremental_vacuum(&self) -> Result<(), BsError> {
+        sqlx::query("PRAGMA incremental_vacuum")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Register the metrics of a reading stored elsewhere and fold its
+    /// values into the rollups
+    pub(super) async fn fold_into_rollups(
+        &self,
+        sensor_id: &str,
+        timestamp: DateTime<Utc>,
+        values: &BTreeMap<String, f64>,
+    ) -> Result<(), BsError> {
+        let mut tx = self.pool.begin().await?;
+        for (metric, value) in values {
+            register_metric(&mut tx, metric).await?;
+            add_to_rollups(&mut tx, sensor_id, timestamp, metric, *value).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Overwrite rollup buckets with aggregates computed elsewhere. Buckets
+    /// whose stored rollup counted more values are kept, see
+    /// [`SqliteRepository::refresh_rollups`]. Returns how many were stored.
+    pub(super) async fn upsert_rollups(
+        &self,
+        rollups: &BTreeMap<RollupKey, Aggregate>,
+    ) -> Result<u64, BsError> {
+        let mut tx = self.pool.begin().await?;
+        let mut stored = 0;
+        for ((resolution, metric, bucket, sensor_id), aggregate) in rollups {
+            let resolution_name = resolution.as_str();
+            stored += sqlx::query!(
+                "INSERT INTO reading_rollups (resolution, sensor_id, metric, bucket, min_value, \
+                 max_value, sum_value, sample_count) VALUES (?,?,?,?,?,?,?,?)
+                    ON CONFLICT (resolution, sensor_id, metric, bucket) DO UPDATE SET
+                        min_value = excluded.min_value,
+                        max_value = excluded.max_value,
+                        sum_value = excluded.sum_value,
+                        sample_count = excluded.sample_count
+                    WHERE excluded.sample_count >= reading_rollups.sample_count",
+                resolution_name,
+                sensor_id,
+                metric,
+                bucket,
+                aggregate.min,
+                aggregate.max,
+                aggregate.sum,
+                aggregate.count
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(stored)
+    }
+
+    /// Recompute the rollup buckets holding readings of `sensor_id`, or of
+    /// every sensor. Buckets of other sensors are left alone, as are buckets
+    /// whose rollup counted more values than the readings left in them: part
+    /// of their readings expired and the rollup is all that remains of them.
+    async fn refresh_rollups(&self, sensor_id: Option<&str>) -> Result<u64, BsError> {
+        let mut tx = self.pool.begin().await?;
+        let mut refreshed = 0;
+        for resolution in Resolution::ROLLED_UP {
+            let resolution_name = resolution.as_str();
+            let bucket_format = sqlite_bucket_format(resolution);
+            refreshed += sqlx::query!(
+                "INSERT INTO reading_rollups (resolution, sensor_id, metric, bucket, min_value, \
+                 max_value, sum_value, sample_count)
+                    SELECT ?, r.sensor_id, v.metric, strftime(?, r.timestamp), MIN(v.value), \
+                 MAX(v.value), SUM(v.value), COUNT(*)
+                    FROM sensor_readings r JOIN reading_values v ON v.reading_id = r.id
+                    WHERE IFNULL(?, r.sensor_id) = r.sensor_id
+                    GROUP BY 2, 3, 4
+                    ON CONFLICT (resolution, sensor_id, metric, bucket) DO UPDATE SET
+                        min_value = excluded.min_value,
+                        max_value = excluded.max_value,
+                        sum_value = excluded.sum_value,
+                        sample_count = excluded.sample_count
+                    WHERE excluded.sample_count >= reading_rollups.sample_count",
+                resolution_name,
+                bucket_format,
+                sensor_id
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(refreshed)
+    }
+
+    /// Replace the rollup buckets of `from..to`, of one sensor or of all,
+    /// with aggregates computed elsewhere. Returns how many were stored.
+    pub(super) async fn replace_rollups(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        sensor_id: Option<&str>,
+        rollups: &BTreeMap<RollupKey, Aggregate>,
+    ) -> Result<u64, BsError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            "DELETE FROM reading_rollups WHERE bucket >= ? AND bucket < ? AND IFNULL(?, sensor_id) \
+             = sensor_id",
+            from,
+            to,
+            sensor_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        for ((resolution, metric, bucket, sensor_id), aggregate) in rollups {
+            let resolution_name = resolution.as_str();
+            sqlx::query!(
+                "INSERT INTO reading_rollups (resolution, sensor_id, metric, bucket, min_value, \
+                 max_value, sum_value, sample_count) VALUES (?,?,?,?,?,?,?,?)",
+                resolution_name,
+                sensor_id,
+                metric,
+                bucket,
+                aggregate.min,
+                aggregate.max,
+                aggregate.sum,
+                aggregate.count
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(rollups.len() as u64)
+    }
+
+    /// Replace the given rollup buckets of a sensor with aggregates computed
+    /// elsewhere, other buckets are left alone
+    pub(super) async fn replace_sensor_rollups(
+        &self,
+        sensor_id: &str,
+        buckets: &BTreeSet<(Resolution, DateTime<Utc>)>,
+        rollups: &BTreeMap<RollupKey, Aggregate>,
+    ) -> Result<(), BsError> {
+        let mut tx = self.pool.begin().await?;
+        for (resolution, bucket) in buckets {
+            let resolution_name = resolution.as_str();
+            sqlx::query!(
+                "DELETE FROM reading_rollups WHERE resolution = ? AND sensor_id = ? AND bucket = ?",
+                resolution_name,
+                sensor_id,
+                bucket
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        for ((resolution, metric, bucket, sensor_id), aggregate) in rollups {
+            let resolution_name = resolution.as_str();
+            sqlx::query!(
+                "INSERT INTO reading_rollups (resolution, sensor_id, metric, bucket, min_value, \
+                 max_value, sum_value, sample_count) VALUES (?,?,?,?,?,?,?,?)",
+                resolution_name,
+                sensor_id,
+                metric,
+                bucket,
+                aggregate.min,
+                aggregate.max,
+                aggregate.sum,
+                aggregate.count
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub(super) async fn count_expired(&self, target: &RetentionTarget) -> Result<u64, BsError> {
+        let mut qb = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) ");
+        push_expired_rows(&mut qb, target);
+        let count: i64 = qb.build_query_scalar().fetch_one(&self.pool).await?;
+        Ok(count as u64)
+    }
+
+    pub(super) async fn delete_expired(&self, target: &RetentionTarget) -> Result<u64, BsError> {
+        let mut removed = 0;
+        loop {
+            let mut qb = QueryBuilder::<Sqlite>::new("");
+            match target.resolution {
+                Resolution::Raw => {
+                    qb.push("DELETE FROM sensor_readings WHERE id IN (SELECT id ");
+                }
+                _ => {
+                    qb.push(
+                        "DELETE FROM reading_rollups WHERE (resolution, sensor_id, metric, bucket) \
+                         IN (SELECT resolution, sensor_id, metric, bucket ",
+                    );
+                }
+            }
+            push_expired_rows(&mut qb, target);
+            qb.push(" LIMIT ")
+                .push_bind(Self::RETENTION_BATCH_SIZE)
+                .push(")");
+
+            let deleted = qb.build().execute(&self.pool).await?.rows_affected();
+            removed += deleted;
+            if deleted < Self::RETENTION_BATCH_SIZE as u64 {
+                break;
+            }
+            // Let ingestion grab the write lock between batches
+            tokio::task::yield_now().await;
+        }
+        Ok(removed)
+    }
+}
+
+#[async_trait]
+impl Repository for SqliteRepository {
+    fn with_conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict_policy = policy;
+        self
+    }
+
+    async fn insert_sensor_reading(
+        &self,
+        topic: String,
+        reading: SensorReadingEvent,
+    ) -> Result<(), BsError> {
+        let mut tx = self.begin_insert().await?;
+        insert_reading(&mut tx, &topic, &reading, self.conflict_policy).await?;
+        self.commit_insert(tx).await?;
+
+        Ok(())
+    }
+
+    async fn insert_sensor_readings(
+        &self,
+        topic: String,
+        readings: Vec<SensorReadingEvent>,
+    ) -> Result<u64, BsError> {
+        let mut tx = self.begin_insert().await?;
+        let mut inserted = 0;
+        for reading in &readings {
+            if insert_reading(&mut tx, &topic, reading, self.conflict_policy).await? {
+                inserted += 1;
+            }
+        }
+        self.commit_insert(tx).await?;
+
+        Ok(inserted)
+    }
+
+    async fn fetch_metrics(&self) -> Result<Vec<Metric>, BsError> {
+        let metrics = sqlx::query_as!(
+            Metric,
+            "SELECT name, unit, description FROM metrics ORDER BY name"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(metrics)
+    }
+
+    async fn upsert_metric(&self, metric: Metric) -> Result<(), BsError> {
+        if !is_valid_metric_name(&metric.name) {
+            return Err(BsError::Other(format!(
+                "Invalid metric name: {}",
+                metric.name
+            )));
+        }
+        sqlx::query!(
+            "INSERT INTO metrics (name, unit, description) VALUES (?,?,?)
+                ON CONFLICT (name) DO UPDATE SET unit = excluded.unit, description = \
+             excluded.description",
+            metric.name,
+            metric.unit,
+            metric.description
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn fetch_sensors(&self) -> Result<Vec<Sensor>, BsError> {
+        let rows = sqlx::query_as!(
+            SensorRow,
+            r#"SELECT s.sensor_id, s.name, s.location, s.altitude_m,
+                s.installed_on AS "installed_on: NaiveDate", s.notes, s.status,
+                s.first_seen AS "first_seen: DateTime<Utc>",
+                (SELECT COUNT(*) FROM quarantined_readings q WHERE q.sensor_id = s.sensor_id)
+                    AS "quarantined_readings!: i64"
+            FROM sensors s ORDER BY s.sensor_id"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter().map(Sensor::try_from).collect()
+    }
+
+    async fn fetch_sensor(&self, sensor_id: &str) -> Result<Option<Sensor>, BsError> {
+        let row = sqlx::query_as!(
+            SensorRow,
+            r#"SELECT s.sensor_id, s.name, s.location, s.altitude_m,
+                s.installed_on AS "installed_on: NaiveDate", s.notes, s.status,
+                s.first_seen AS "first_seen: DateTime<Utc>",
+                (SELECT COUNT(*) FROM quarantined_readings q WHERE q.sensor_id = s.sensor_id)
+                    AS "quarantined_readings!: i64"
+            FROM sensors s WHERE s.sensor_id = ?"#,
+            sensor_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.map(Sensor::try_from).transpose()
+    }
+
+    async fn upsert_sensor(
+        &self,
+        sensor_id: &str,
+        metadata: SensorMetadata,
+    ) -> Result<(), BsError> {
+        sqlx::query!(
+            "INSERT INTO sensors (sensor_id, name, location, altitude_m, installed_on, notes, \
+             status)
+                VALUES (?,?,?,?,?,?,'approved')
+                ON CONFLICT (sensor_id) DO UPDATE SET name = excluded.name, location = \
+             excluded.location, altitude_m = excluded.altitude_m, installed_on = \
+             excluded.installed_on, notes = excluded.notes",
+            sensor_id,
+            metadata.name,
+            metadata.location,
+            metadata.altitude_m,
+            metadata.installed_on,
+            metadata.notes
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_sensor(&self, sensor_id: &str) -> Result<bool, BsError> {
+        let deleted = sqlx::query!("DELETE FROM sensors WHERE sensor_id = ?", sensor_id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+
+        Ok(deleted > 0)
+    }
+
+    async fn quarantine_sensor_reading(
+        &self,
+        topic: String,
+        reading: SensorReadingEvent,
+    ) -> Result<(), BsError> {
+        let metrics = serde_json::to_string(&reading.metrics)?;
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            "INSERT OR IGNORE INTO sensors (sensor_id, first_seen) VALUES (?,?)",
+            reading.sensor_id,
+            reading.timestamp
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO quarantined_readings (sensor_id, topic, timestamp, sequence, metrics) \
+             VALUES (?,?,?,?,?)",
+            reading.sensor_id,
+            topic,
+            reading.timestamp,
+            reading.sequence,
+            metrics
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn approve_sensor(&self, sensor_id: &str) -> Result<Option<u64>, BsError> {
+        let mut tx = self.pool.begin().await?;
+        let updated = sqlx::query!(
+            "UPDATE sensors SET status = 'approved' WHERE sensor_id = ?",
+            sensor_id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if updated == 0 {
+            return Ok(None);
+        }
+
+        let quarantined = sqlx::query!(
+            r#"SELECT topic, timestamp AS "timestamp: DateTime<Utc>", sequence, metrics
+            FROM quarantined_readings WHERE sensor_id = ? ORDER BY timestamp, id"#,
+            sensor_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for row in &quarantined {
+            let reading = SensorReadingEvent {
+                sensor_id: sensor_id.to_string(),
+                timestamp: row.timestamp,
+                sequence: row.sequence,
+                metrics: serde_json::from_str(&row.metrics)?,
+            };
+            insert_reading(&mut tx, &row.topic, &reading, self.conflict_policy).await?;
+        }
+        sqlx::query!(
+            "DELETE FROM quarantined_readings WHERE sensor_id = ?",
+            sensor_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(Some(quarantined.len() as u64))
+    }
+
+    async fn fetch_calibrations(
+        &self,
+        sensor_id: Option<String>,
+    ) -> Result<Vec<StoredCalibration>, BsError> {
+        let rows = sqlx::query_as!(
+            CalibrationRow,
+            r#"SELECT id AS "id!", sensor_id, metric, gain, offset_value,
+                valid_from AS "valid_from: DateTime<Utc>", valid_to AS "valid_to: DateTime<Utc>"
+            FROM calibrations WHERE ? IS NULL OR sensor_id = ?
+            ORDER BY sensor_id, metric, valid_from"#,
+            sensor_id,
+            sensor_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(StoredCalibration::from).collect())
+    }
+
+    async fn insert_calibration(&self, calibration: Calibration) -> Result<i64, BsError> {
+        calibration.validate()?;
+        // Checked and inserted in one transaction so two overlapping calibrations can't race
+        let mut tx = self.pool.begin().await?;
+        let stored = sensor_calibrations(&mut tx, &calibration.sensor_id).await?;
+        check_overlaps(&calibration, &stored)?;
+        let id = sqlx::query!(
+            "INSERT INTO calibrations (sensor_id, metric, gain, offset_value, valid_from, \
+             valid_to) VALUES (?,?,?,?,?,?)",
+            calibration.sensor_id,
+            calibration.metric,
+            calibration.gain,
+            calibration.offset,
+            calibration.valid_from,
+            calibration.valid_to
+        )
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+        tx.commit().await?;
+
+        Ok(id)
+    }
+
+    async fn delete_calibration(&self, id: i64) -> Result<bool, BsError> {
+        let deleted = sqlx::query!("DELETE FROM calibrations WHERE id = ?", id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+
+        Ok(deleted > 0)
+    }
+
+    async fn fetch_api_keys(&self) -> Result<Vec<ApiKey>, BsError> {
+        let rows = sqlx::query_as!(
+            ApiKeyRow,
+            r#"SELECT id AS "id!", name, prefix, scopes, created_at AS "created_at: DateTime<Utc>",
+                revoked_at AS "revoked_at: DateTime<Utc>"
+            FROM api_keys ORDER BY id"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter().map(ApiKey::try_from).collect()
+    }
+
+    async fn insert_api_key(&self, key: &ApiKey, secret_hash: &str) -> Result<i64, BsError> {
+        let scopes = join_scopes(&key.scopes);
+        let id = sqlx::query!(
+            "INSERT INTO api_keys (name, prefix, secret_hash, scopes, created_at) \
+             VALUES (?,?,?,?,?)",
+            key.name,
+            key.prefix,
+            secret_hash,
+            scopes,
+            key.created_at
+        )
+        .execute(&self.pool)
+        .await?
+        .last_insert_rowid();
+
+        Ok(id)
+    }
+
+    async fn fetch_api_key(&self, secret_hash: &str) -> Result<Option<ApiKey>, BsError> {
+        let row = sqlx::query_as!(
+            ApiKeyRow,
+            r#"SELECT id AS "id!", name, prefix, scopes, created_at AS "created_at: DateTime<Utc>",
+                revoked_at AS "revoked_at: DateTime<Utc>"
+            FROM api_keys WHERE secret_hash = ?"#,
+            secret_hash
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.map(ApiKey::try_from).transpose()
+    }
+
+    async fn revoke_api_key(&self, id: i64) -> Result<bool, BsError> {
+        let now = Utc::now();
+        let revoked = sqlx::query!(
+            "UPDATE api_keys SET revoked_at = COALESCE(revoked_at, ?) WHERE id = ?",
+            now,
+            id
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+
+        Ok(revoked > 0)
+    }
+
+    async fn recompute_calibrations(&self, sensor_id: Option<String>) -> Result<u64, BsError> {
+        let recomputed = sqlx::query!(
+            "UPDATE reading_values SET value = COALESCE(
+                (SELECT reading_values.raw_value * c.gain + c.offset_value
+                    FROM sensor_readings r JOIN calibrations c ON c.sensor_id = r.sensor_id
+                    WHERE r.id = reading_values.reading_id AND c.metric = reading_values.metric
+                        AND (c.valid_from IS NULL OR c.valid_from <= r.timestamp)
+                        AND (c.valid_to IS NULL OR r.timestamp < c.valid_to)),
+                raw_value)
+            WHERE reading_id IN (SELECT id FROM sensor_readings WHERE ? IS NULL OR sensor_id = ?)",
+            sensor_id,
+            sensor_id
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        if recomputed > 0 {
+            self.refresh_rollups(sensor_id.as_deref()).await?;
+        }
+
+        Ok(recomputed)
+    }
+
+    async fn fetch_sensor_readings_page(
+        &self,
+        query: MeasurementQuery,
+    ) -> Result<SensorReadingsPage, BsError> {
+        query.validate(&self.fetch_metrics().await?)?;
+        let position = query.position(&self.cursor_key)?.map(|c| c.keyset());
+        let order = query.scan_order();
+
+        // One extra row tells us whether there is a next page
+        let mut readings = self
+            .fetch_page_rows(&query, position, order, query.pagination.page_size + 1)
+            .await?;
+
+        let has_more = readings.len() > query.pagination.page_size;
+        readings.truncate(query.pagination.page_size);
+
+        Ok(query.into_page(&self.cursor_key, readings, has_more))
+    }
+
+    async fn fetch_stored_readings(
+        &self,
+        after: i64,
+        query: MeasurementQuery,
+    ) -> Result<Vec<(i64, SensorReading)>, BsError> {
+        query.validate(&self.fetch_metrics().await?)?;
+        let mut qb = QueryBuilder::<Sqlite>::new("");
+        push_stored_query(&mut qb, &query, after);
+        let readings = self.fetch_rows(qb, &query).await?;
+        Ok(readings
+            .into_iter()
+            .map(|(keyset, reading)| (keyset.id, reading))
+            .collect())
+    }
+
+    async fn fetch_last_stored_position(&self) -> Result<i64, BsError> {
+        let last: Option<i64> = sqlx::query_scalar("SELECT MAX(id) FROM sensor_readings")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(last.unwrap_or(0))
+    }
+
+    async fn fetch_latest_readings(&self) -> Result<Vec<SensorReading>, BsError> {
+        // Sensors are found skipping through `idx_sensor_time`, readings
+        // without a registered sensor count as well
+        let rows = sqlx::query_as!(
+            LatestValueRow,
+            r#"WITH RECURSIVE seen (sensor_id) AS (
+                SELECT MIN(sensor_id) FROM sensor_readings
+                UNION ALL
+                SELECT (SELECT MIN(sensor_id) FROM sensor_readings WHERE sensor_id > seen.sensor_id)
+                FROM seen WHERE seen.sensor_id IS NOT NULL
+            )
+            SELECT r.sensor_id AS "sensor_id!", r.topic AS "topic!",
+                r.timestamp AS "timestamp!: DateTime<Utc>", v.metric AS "metric?",
+                v.value AS "value?: f64"
+            FROM seen
+            JOIN sensor_readings r ON r.id = (SELECT l.id FROM sensor_readings l
+                WHERE l.sensor_id = seen.sensor_id ORDER BY l.timestamp DESC, l.id DESC LIMIT 1)
+            LEFT JOIN reading_values v ON v.reading_id = r.id
+            ORDER BY r.sensor_id, v.metric"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(latest_readings(rows))
+    }
+
+    /// A single scan of `idx_sensor_time` streamed into the aggregator
+    async fn aggregate_readings(&self, query: AggregateQuery) -> Result<Aggregation, BsError> {
+        query.validate(&self.fetch_metrics().await?)?;
+        let mut qb = QueryBuilder::<Sqlite>::new("");
+        push_aggregate_query(&mut qb, &query);
+        let mut aggregator = Aggregator::new(query.clone());
+        let mut rows = qb.build().fetch(&self.pool);
+        while let Some(row) = rows.try_next().await? {
+            aggregator.push(
+                row.try_get(0)?,
+                row.try_get(1)?,
+                row.try_get(2)?,
+                row.try_get(3)?,
+            );
+        }
+        Ok(aggregator.finish())
+    }
+
+    async fn fetch_series(&self, query: SeriesQuery) -> Result<Series, BsError> {
+        query.validate()?;
+        let resolution = query.effective_resolution();
+
+        let mut qb = QueryBuilder::<Sqlite>::new("");
+        match resolution {
+            Resolution::Raw => {
+                qb.push(
+                    "SELECT r.sensor_id, r.timestamp, v.value AS min, v.value AS max, v.value AS \
+                     avg, 1 AS count FROM sensor_readings r JOIN reading_values v ON v.reading_id \
+                     = r.id WHERE v.metric = ",
+                )
+                .push_bind(&query.metric)
+                .push(" AND r.timestamp >= ")
+                .push_bind(query.from)
+                .push(" AND r.timestamp < ")
+                .push_bind(query.to);
+                if let Some(sensor_id) = &query.sensor_id {
+                    qb.push(" AND r.sensor_id = ").push_bind(sensor_id);
+                }
+                qb.push(" ORDER BY r.timestamp, r.id");
+            }
+            rolled_up => {
+                qb.push(
+                    "SELECT sensor_id, bucket AS timestamp, min_value AS min, max_value AS max, \
+                     sum_value / sample_count AS avg, sample_count AS count FROM reading_rollups \
+                     WHERE resolution = ",
+                )
+                .push_bind(rolled_up.as_str())
+                .push(" AND metric = ")
+                .push_bind(&query.metric)
+                .push(" AND bucket >= ")
+                .push_bind(rolled_up.bucket(query.from))
+                .push(" AND bucket < ")
+                .push_bind(query.to);
+                if let Some(sensor_id) = &query.sensor_id {
+                    qb.push(" AND sensor_id = ").push_bind(sensor_id);
+                }
+                qb.push(" ORDER BY bucket, sensor_id");
+            }
+        }
+
+        let points = qb
+            .build_query_as::<SeriesPoint>()
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(Series {
+            metric: query.metric,
+            resolution,
+            points,
+        })
+    }
+
+    async fn rebuild_rollups(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<u64, BsError> {
+        let from = match from {
+            Some(from) => from,
+            None => {
+                let oldest = sqlx::query_scalar!(
+                    r#"SELECT MIN(timestamp) AS "oldest: DateTime<Utc>" FROM sensor_readings"#
+                )
+                .fetch_one(&self.pool)
+                .await?;
+                match oldest {
+                    Some(oldest) => oldest,
+                    None => return Ok(0),
+                }
+            }
+        };
+        let to = to.unwrap_or_else(Utc::now);
+        // Whole days so the hourly and daily buckets stay consistent
+        let from = Resolution::Day.bucket(from);
+        let to = Resolution::Day.bucket(to) + TimeDelta::days(1);
+
+        let mut tx = self.pool.begin().await?;
+        // Buckets starting before the oldest reading of their sensor may
+        // count expired readings, the insert below only grows them
+        sqlx::query!(
+            "DELETE FROM reading_rollups WHERE bucket >= ? AND bucket < ?
+                AND bucket >= (SELECT MIN(r.timestamp) FROM sensor_readings r
+                    WHERE r.sensor_id = reading_rollups.sensor_id)",
+            from,
+            to
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let mut rebuilt = 0;
+        for resolution in Resolution::ROLLED_UP {
+            let resolution_name = resolution.as_str();
+            let bucket_format = sqlite_bucket_format(resolution);
+            rebuilt += sqlx::query!(
+                "INSERT INTO reading_rollups (resolution, sensor_id, metric, bucket, min_value, \
+                 max_value, sum_value, sample_count)
+                    SELECT ?, r.sensor_id, v.metric, strftime(?, r.timestamp), MIN(v.value), \
+                 MAX(v.value), SUM(v.value), COUNT(*)
+                    FROM sensor_readings r JOIN reading_values v ON v.reading_id = r.id
+                    WHERE r.timestamp >= ? AND r.timestamp < ?
+                    GROUP BY 2, 3, 4
+                    ON CONFLICT (resolution, sensor_id, metric, bucket) DO UPDATE SET
+                        min_value = excluded.min_value,
+                        max_value = excluded.max_value,
+                        sum_value = excluded.sum_value,
+                        sample_count = excluded.sample_count
+                    WHERE excluded.sample_count >= reading_rollups.sample_count",
+                resolution_name,
+                bucket_format,
+                from,
+                to
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        tx.commit().await?;
+
+        Ok(rebuilt)
+    }
+
+    async fn remove_duplicate_readings(&self, dry_run: bool) -> Result<u64, BsError> {
+        // The earliest stored copy is kept
+        let duplicates = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!: i64" FROM sensor_readings r
+            WHERE EXISTS (SELECT 1 FROM sensor_readings o
+                WHERE o.sensor_id = r.sensor_id AND o.timestamp = r.timestamp
+                AND o.sequence IS r.sequence AND o.id < r.id)"#
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if dry_run || duplicates == 0 {
+            return Ok(duplicates as u64);
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let removed = sqlx::query!(
+            r#"DELETE FROM sensor_readings WHERE id IN (SELECT r.id FROM sensor_readings r
+                WHERE EXISTS (SELECT 1 FROM sensor_readings o
+                    WHERE o.sensor_id = r.sensor_id AND o.timestamp = r.timestamp
+                    AND o.sequence IS r.sequence AND o.id < r.id))
+            RETURNING sensor_id, timestamp AS "timestamp: DateTime<Utc>""#
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        let buckets: BTreeSet<_> = removed
+            .iter()
+            .map(|row| {
+                (
+                    row.sensor_id.as_str(),
+                    Resolution::Hour.bucket(row.timestamp),
+                )
+            })
+            .collect();
+        for (sensor_id, bucket) in buckets {
+            rebuild_sensor_rollups(&mut tx, sensor_id, bucket).await?;
+        }
+        tx.commit().await?;
+
+        Ok(removed.len() as u64)
+    }
+
+    async fn fetch_retention_policies(&self) -> Result<Vec<RetentionPolicy>, BsError> {
+        let rows = sqlx::query!(
+            "SELECT sensor_id, resolution, keep_days FROM retention_policies ORDER BY resolution, \
+             sensor_id"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(RetentionPolicy {
+                    sensor_id: row.sensor_id,
+                    resolution: row.resolution.parse()?,
+                    keep_days: row
+                        .keep_days
+                        .map(u32::try_from)
+                        .transpose()
+                        .map_err(|e| BsError::Other(format!("Invalid keep_days: {e}")))?,
+                })
+            })
+            .collect()
+    }
+
+    async fn upsert_retention_policy(&self, policy: RetentionPolicy) -> Result<(), BsError> {
+        policy.validate()?;
+        let resolution = policy.resolution.as_str();
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            "DELETE FROM retention_policies WHERE IFNULL(sensor_id, '') = IFNULL(?, '') AND \
+             resolution = ?",
+            policy.sensor_id,
+            resolution
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO retention_policies (sensor_id, resolution, keep_days) VALUES (?,?,?)",
+            policy.sensor_id,
+            resolution,
+            policy.keep_days
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn delete_retention_policy(
+        &self,
+        sensor_id: Option<String>,
+        resolution: Resolution,
+    ) -> Result<bool, BsError> {
+        let resolution = resolution.as_str();
+        let deleted = sqlx::query!(
+            "DELETE FROM retention_policies WHERE IFNULL(sensor_id, '') = IFNULL(?, '') AND \
+             resolution = ?",
+            sensor_id,
+            resolution
+        )
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+
+        Ok(deleted > 0)
+    }
+
+    async fn enforce_retention(&self, dry_run: bool) -> Result<RetentionReport, BsError> {
+        let policies = self.fetch_retention_policies().await?;
+        let mut entries = Vec::new();
+        for target in RetentionTarget::from_policies(&policies, Utc::now()) {
+            let rows = if dry_run {
+                self.count_expired(&target).await?
+            } else {
+                self.delete_expired(&target).await?
+            };
+            entries.push(RetentionReportEntry {
+                sensor_id: target.sensor_id,
+                resolution: target.resolution,
+                cutoff: target.cutoff,
+                rows,
+            });
+        }
+
+        if !dry_run && entries.iter().any(|e| e.rows > 0) {
+            self.incremental_vacuum().await?;
+        }
+
+        Ok(RetentionReport { dry_run, entries })
+    }
+}
+
+/// Store a reading with its values and fold it into the rollups. Returns
+/// whether it was stored, readings the policy ignores are not.
+async fn insert_reading(
+    conn: &mut SqliteConnection,
+    topic: &str,
+    reading: &SensorReadingEvent,
+    policy: ConflictPolicy,
+) -> Result<bool, BsError> {
+    // The unique key of readings settles conflicts, so concurrent inserts
+    // of the same reading cannot both get in
+    let reading_id = match policy {
+        ConflictPolicy::Ignore => {
+            sqlx::query_scalar!(
+            "INSERT INTO sensor_readings (sensor_id, topic, timestamp, sequence) VALUES (?,?,?,?)
+                ON CONFLICT DO NOTHING RETURNING id",
+            reading.sensor_id,
+            topic,
+            reading.timestamp,
+            reading.sequence,
+        )
+            .fetch_optional(&mut *conn)
+            .await?
+        }
+        ConflictPolicy::Replace => Some(
+            sqlx::query_scalar!(
+                "INSERT INTO sensor_readings (sensor_id, topic, timestamp, sequence) VALUES \
+                 (?,?,?,?)
+                    ON CONFLICT (timestamp, sensor_id, sequence IS NULL, IFNULL(sequence, 0), \
+                 copy)
+                    DO UPDATE SET topic = excluded.topic RETURNING id",
+                reading.sensor_id,
+                topic,
+                reading.timestamp,
+                reading.sequence,
+            )
+            .fetch_one(&mut *conn)
+            .await?,
+        ),
+        ConflictPolicy::KeepBoth => Some(
+            sqlx::query_scalar!(
+                "INSERT INTO sensor_readings (sensor_id, topic, timestamp, sequence, copy)
+                    SELECT ?1, ?2, ?3, ?4, IFNULL(MAX(copy) + 1, 0) FROM sensor_readings
+                    WHERE sensor_id = ?1 AND timestamp = ?3 AND sequence IS ?4
+                    RETURNING id",
+                reading.sensor_id,
+                topic,
+                reading.timestamp,
+                reading.sequence,
+            )
+            .fetch_one(&mut *conn)
+            .await?,
+        ),
+    };
+    let Some(reading_id) = reading_id else {
+        return Ok(false);
+    };
+    // Replaced in place, the values of the stored reading go
+    let replaced = policy == ConflictPolicy::Replace
+        && sqlx::query!(
+            "DELETE FROM reading_values WHERE reading_id = ?",
+            reading_id
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected()
+            > 0;
+
+    let calibrations: Vec<Calibration> = sensor_calibrations(&mut *conn, &reading.sensor_id)
+        .await?
+        .into_iter()
+        .map(|stored| stored.calibration)
+        .collect();
+    let calibrated = calibrate(&calibrations, reading.timestamp, &reading.metrics);
+
+    for (metric, value) in &calibrated {
+        let raw_value = reading.metrics[metric];
+        register_metric(&mut *conn, metric).await?;
+        sqlx::query!(
+            "INSERT INTO reading_values (reading_id, metric, value, raw_value) VALUES (?,?,?,?)",
+            reading_id,
+            metric,
+            value,
+            raw_value
+        )
+        .execute(&mut *conn)
+        .await?;
+        add_to_rollups(
+            &mut *conn,
+            &reading.sensor_id,
+            reading.timestamp,
+            metric,
+            *value,
+        )
+        .await?;
+    }
+    // The rollups still count the values of the replaced reading
+    if replaced {
+        rebuild_sensor_rollups(conn, &reading.sensor_id, reading.timestamp).await?;
+    }
+    Ok(true)
+}
+
+/// Metrics we have not seen before get registered without a unit
+async fn register_metric(conn: &mut SqliteConnection, metric: &str) -> Result<(), BsError> {
+    sqlx::query!("INSERT OR IGNORE INTO metrics (name) VALUES (?)", metric)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Fold a value into the rollup buckets the timestamp falls into
+async fn add_to_rollups(
+    conn: &mut SqliteConnection,
+    sensor_id: &str,
+    timestamp: DateTime<Utc>,
+    metric: &str,
+    value: f64,
+) -> Result<(), BsError> {
+    for resolution in Resolution::ROLLED_UP {
+        let resolution_name = resolution.as_str();
+        let bucket = resolution.bucket(timestamp);
+        sqlx::query!(
+            "INSERT INTO reading_rollups (resolution, sensor_id, metric, bucket, \
+             min_value, max_value, sum_value, sample_count)
+                VALUES (?,?,?,?,?,?,?,1)
+                ON CONFLICT (resolution, sensor_id, metric, bucket) DO UPDATE SET
+                    min_value = MIN(min_value, excluded.min_value),
+                    max_value = MAX(max_value, excluded.max_value),
+                    sum_value = sum_value + excluded.sum_value,
+                    sample_count = sample_count + 1",
+            resolution_name,
+            sensor_id,
+            metric,
+            bucket,
+            value,
+            value,
+            value
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Recompute the rollup buckets of a sensor the timestamp falls into
+async fn rebuild_sensor_rollups(
+    conn: &mut SqliteConnection,
+    sensor_id: &str,
+    timestamp: DateTime<Utc>,
+) -> Result<(), BsError> {
+    for resolution in Resolution::ROLLED_UP {
+        let resolution_name = resolution.as_str();
+        let bucket = resolution.bucket(timestamp);
+        let bucket_end = bucket
+            + resolution
+                .bucket_width()
+                .expect("Rolled up resolutions have a width");
+        sqlx::query!(
+            "DELETE FROM reading_rollups WHERE resolution = ? AND sensor_id = ? AND bucket = ?",
+            resolution_name,
+            sensor_id,
+            bucket
+        )
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO reading_rollups (resolution, sensor_id, metric, bucket, min_value, \
+             max_value, sum_value, sample_count)
+                SELECT ?, r.sensor_id, v.metric, ?, MIN(v.value), MAX(v.value), SUM(v.value), COUNT(*)
+                FROM sensor_readings r JOIN reading_values v ON v.reading_id = r.id
+                WHERE r.sensor_id = ? AND r.timestamp >= ? AND r.timestamp < ?
+                GROUP BY r.sensor_id, v.metric",
+            resolution_name,
+            bucket,
+            sensor_id,
+            bucket,
+            bucket_end
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn sensor_calibrations(
+    conn: &mut SqliteConnection,
+    sensor_id: &str,
+) -> Result<Vec<StoredCalibration>, BsError> {
+    let rows = sqlx::query_as!(
+        CalibrationRow,
+        r#"SELECT id AS "id!", sensor_id, metric, gain, offset_value,
+            valid_from AS "valid_from: DateTime<Utc>", valid_to AS "valid_to: DateTime<Utc>"
+        FROM calibrations WHERE sensor_id = ?"#,
+        sensor_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(rows.into_iter().map(StoredCalibration::from).collect())
+}
+
+/// `FROM ... WHERE ...` selecting the rows a retention target expires
+fn push_expired_rows(qb: &mut QueryBuilder<'_, Sqlite>, target: &RetentionTarget) {
+    match target.resolution {
+        Resolution::Raw => {
+            qb.push("FROM sensor_readings WHERE timestamp < ")
+                .push_bind(target.cutoff);
+        }
+        rolled_up => {
+            qb.push("FROM reading_rollups WHERE resolution = ")
+                .push_bind(rolled_up.as_str())
+                .push(" AND bucket < ")
+                .push_bind(rolled_up.bucket(target.cutoff));
+        }
+    }
+    if let Some(sensor_id) = &target.sensor_id {
+        qb.push(" AND sensor_id = ").push_bind(sensor_id.clone());
+    }
+    if !target.excluded.is_empty() {
+        qb.push(" AND sensor_id NOT IN (");
+        let mut separated = qb.separated(", ");
+        for sensor_id in &target.excluded {
+            separated.push_bind(sensor_id.clone());
+        }
+        qb.push(")");
+    }
+}
+
+/// `strftime` format producing the same text sqlx stores for a bucket start
+fn sqlite_bucket_format(resolution: Resolution) -> &'static str {
+    match resolution {
+        Resolution::Raw => unreachable!("Raw readings are not rolled up"),
+        Resolution::Hour => "%Y-%m-%dT%H:00:00+00:00",
+        Resolution::Day => "%Y-%m-%dT00:00:00+00:00",
+    }
+}
+
+/// Values of the requested metrics in the order the aggregator takes them,
+/// by time within every group
+fn push_aggregate_query<'a>(qb: &mut QueryBuilder<'a, Sqlite>, query: &'a AggregateQuery) {
+    qb.push(
+        // CROSS JOIN keeps SQLite from driving the scan by idx_metric_value
+        "SELECT r.sensor_id, r.timestamp, v.metric, v.value FROM sensor_readings r CROSS JOIN \
+         reading_values v ON v.reading_id = r.id WHERE ",
+    );
+    match &query.sensor_id {
+        Some(sensor_id) => {
+            qb.push("r.sensor_id = ").push_bind(sensor_id);
+        }
+        // Lets every sensor's time span be looked up in the index instead of scanning the table
+        None => {
+            qb.push("r.sensor_id IN (SELECT DISTINCT sensor_id FROM sensor_readings)");
+        }
+    }
+    qb.push(" AND r.timestamp >= ")
+        .push_bind(query.from)
+        .push(" AND r.timestamp < ")
+        .push_bind(query.to)
+        .push(" AND v.metric IN (");
+    let mut separated = qb.separated(", ");
+    for metric in &query.metrics {
+        separated.push_bind(metric);
+    }
+    qb.push(")");
+    if query.group_by_sensor {
+        qb.push(" ORDER BY r.sensor_id, r.timestamp");
+    } else {
+        qb.push(" ORDER BY r.timestamp");
+    }
+}
+
+/// Select the keyset and the requested columns of the readings matching the
+/// filters of `query`, leaving the statement open for more conditions
+fn push_readings_query<'a>(qb: &mut QueryBuilder<'a, Sqlite>, query: &'a MeasurementQuery) {
+    // The keyset is always selected so the page cursors can be built
+    // even when the columns were not requested
+    qb.push("SELECT r.timestamp, r.id");
+
+    for col in &query.columns {
+        qb.push(", ");
+        if MeasurementQuery::BASE_COLUMNS.contains(&col.as_str()) {
+            qb.push("r.").push(col);
+        } else {
+            qb.push("(SELECT value FROM reading_values WHERE reading_id = r.id AND metric = ")
+                .push_bind(col.clone())
+                .push(")");
+        }
+    }
+
+    qb.push(" FROM sensor_readings r WHERE TRUE");
+
+    let f = &query.filters;
+
+    if let Some(sensor_id) = &f.sensor_id {
+        qb.push(" AND r.sensor_id = ").push_bind(sensor_id);
+    }
+
+    if let Some(metric) = &f.metric {
+        qb.push(
+            " AND EXISTS (SELECT 1 FROM reading_values v WHERE v.reading_id = r.id AND v.metric = ",
+        )
+        .push_bind(metric);
+        if let Some(min) = f.min {
+            qb.push(" AND v.value >= ").push_bind(min);
+        }
+        if let Some(max) = f.max {
+            qb.push(" AND v.value <= ").push_bind(max);
+        }
+        qb.push(")");
+    }
+
+    if let Some(from) = f.from {
+        qb.push(" AND r.timestamp >= ").push_bind(from);
+    }
+
+    if let Some(to) = f.to {
+        qb.push(" AND r.timestamp < ").push_bind(to);
+    }
+
+    if let Some(subscriptions) = &f.subscriptions {
+        qb.push(" AND (FALSE");
+        if !subscriptions.sensors.is_empty() {
+            qb.push(" OR r.sensor_id IN (");
+            let mut sensors = qb.separated(", ");
+            for sensor_id in &subscriptions.sensors {
+                sensors.push_bind(sensor_id);
+            }
+            qb.push(")");
+        }
+        if !subscriptions.metrics.is_empty() {
+            qb.push(
+                " OR EXISTS (SELECT 1 FROM reading_values v WHERE v.reading_id = r.id AND v.metric \
+                 IN (",
+            );
+            let mut metrics = qb.separated(", ");
+            for metric in &subscriptions.metrics {
+                metrics.push_bind(metric);
+            }
+            qb.push("))");
+        }
+        qb.push(")");
+    }
+}
+
+fn push_page_query<'a>(
+    qb: &mut QueryBuilder<'a, Sqlite>,
+    query: &'a MeasurementQuery,
+    position: Option<Keyset>,
+    order: SortOrder,
+    limit: usize,
+) {
+    push_readings_query(qb, query);
+
+    if let Some(keyset) = position {
+        let comparison = match order {
+            SortOrder::Asc => " > ",
+            SortOrder::Desc => " < ",
+        };
+        qb.push(" AND (r.timestamp, r.id)")
+            .push(comparison)
+            .push("(")
+            .push_bind(keyset.timestamp)
+            .push(", ")
+            .push_bind(keyset.id)
+            .push(")");
+    }
+
+    qb.push(" ORDER BY r.timestamp ")
+        .push(order.as_sql())
+        .push(", r.id ")
+        .push(order.as_sql());
+    qb.push(" LIMIT ").push_bind(limit as i64);
+}
+
+/// Readings stored after `after`, ids grow in the order readings are stored
+fn push_stored_query<'a>(
+    qb: &mut QueryBuilder<'a, Sqlite>,
+    query: &'a MeasurementQuery,
+    after: i64,
+) {
+    push_readings_query(qb, query);
+    qb.push(" AND r.id > ").push_bind(after);
+    qb.push(" ORDER BY r.id LIMIT ")
+        .push_bind(query.pagination.page_size as i64);
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+    use crate::db::conformance::{TestBackend, conformance_tests};
+    use crate::db::schema::SQLITE_MIGRATOR;
+    use crate::db::{AggregateFunction, Pagination, QueryFilter};
+
+    struct SqliteBackend(SqlitePool);
+
+    impl TestBackend for SqliteBackend {
+        type Repository = SqliteRepository;
+
+        async fn create() -> Option<Self> {
+            // A single connection that never expires keeps the in-memory database alive
+            let pool = SqlitePoolOptions::new()
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+                .connect("sqlite::memory:")
+                .await
+                .unwrap();
+            SQLITE_MIGRATOR.run(&pool).await.unwrap();
+            Some(Self(pool))
+        }
+
+        fn repository(&self) -> SqliteRepository {
+            SqliteRepository::new(self.0.clone())
+        }
+    }
+
+    conformance_tests!(SqliteBackend);
+
+    fn reading_at(sensor_id: &str, timestamp: &str, temperature: f64) -> SensorReadingEvent {
+        SensorReadingEvent {
+            sensor_id: sensor_id.to_string(),
+            timestamp: timestamp.parse().unwrap(),
+            sequence: None,
+            metrics: [("temperature".to_string(), temperature)].into(),
+        }
+    }
+
+    async fn hourly_repository(pool: SqlitePool) -> SqliteRepository {
+        let repo = SqliteRepository::new(pool);
+        let readings = [
+            reading_at("outside", "2026-01-01T10:05:00Z", 1.0),
+            reading_at("outside", "2026-01-01T10:35:00.250Z", 3.0),
+            reading_at("outside", "2026-01-01T11:15:00Z", 8.0),
+            reading_at("outside", "2026-01-02T09:00:00Z", 4.0),
+            reading_at("inside", "2026-01-01T10:10:00Z", 20.0),
+        ];
+        for r in readings {
+            repo.insert_sensor_reading("sensor/update".to_string(), r)
+                .await
+                .unwrap();
+        }
+        repo
+    }
+
+    fn series(resolution: Option<Resolution>, from: &str, to: &str) -> SeriesQuery {
+        SeriesQuery {
+            sensor_id: Some("outside".to_string()),
+            metric: "temperature".to_string(),
+            from: from.parse().unwrap(),
+            to: to.parse().unwrap(),
+            resolution,
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations/")]
+    async fn sensor_scan_uses_time_index(pool: SqlitePool) {
+        let filters = QueryFilter {
+            sensor_id: Some("outside".to_string()),
+            ..Default::default()
+        };
+        let q = MeasurementQuery {
+            filters,
+            pagination: Pagination {
+                order: SortOrder::Desc,
+                ..Default::default()
+            },
+            columns: vec!["temperature".to_string()],
+        };
+        let position = Keyset {
+            timestamp: Utc::now(),
+            id: 1,
+        };
+
+        let mut qb = QueryBuilder::<Sqlite>::new("EXPLAIN QUERY PLAN ");
+        push_page_query(&mut qb, &q, Some(position), q.scan_order(), 11);
+        let plan: Vec<String> = qb
+            .build()
+            .fetch_all(&pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get("detail"))
+            .collect();
+
+        assert!(plan.iter().any(|step| step.contains("idx_sensor_time")));
+        assert!(
+            !plan
+                .iter()
+                .any(|step| step.contains("TEMP B-TREE FOR ORDER BY"))
+        );
+    }
+
+    #[sqlx::test(migrations = "./migrations/")]
+    async fn aggregation_scans_time_index(pool: SqlitePool) {
+        for (sensor_id, group_by_sensor) in [(Some("outside"), false), (None, true), (None, false)]
+        {
+            let query = AggregateQuery {
+                sensor_id: sensor_id.map(String::from),
+                metrics: vec!["temperature".to_string(), "humidity".to_string()],
+                functions: vec![AggregateFunction::P95],
+                from: "2026-01-01T00:00:00Z".parse().unwrap(),
+                to: "2026-02-01T00:00:00Z".parse().unwrap(),
+                bucket: TimeDelta::hours(1),
+                group_by_sensor,
+                fill: None,
+            };
+            let mut qb = QueryBuilder::<Sqlite>::new("EXPLAIN QUERY PLAN ");
+            push_aggregate_query(&mut qb, &query);
+            let plan: Vec<String> = qb
+                .build()
+                .fetch_all(&pool)
+                .await
+                .unwrap()
+                .iter()
+                .map(|row| row.get("detail"))
+                .collect();
+            assert!(
+                plan.iter()
+                    .any(|step| step.starts_with("SEARCH r USING COVERING INDEX idx_sensor_time")),
+                "{plan:?}"
+            );
+            // Readings of a single sensor come out of the index in order
+            if sensor_id.is_some() {
+                assert!(
+                    !plan.iter().any(|step| step.contains("TEMP B-TREE")),
+                    "{plan:?}"
+                );
+            }
+        }
+    }
+
+    #[sqlx::test(migrations = "./migrations/")]
+    async fn rebuild_matches_incremental_rollups(pool: SqlitePool) {
+        let repo = hourly_repository(pool.clone()).await;
+        let query = || {
+            let mut q = series(
+                Some(Resolution::Hour),
+                "2026-01-01T00:00:00Z",
+                "2026-01-03T00:00:00Z",
+            );
+            q.sensor_id = None;
+            q
+        };
+        let incremental = repo.fetch_series(query()).await.unwrap();
+
+        // Rollups that lost values are repaired, bigger ones could count
+        // expired readings
+        sqlx::query("UPDATE reading_rollups SET sample_count = 0, max_value = 100")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let rebuilt = repo.rebuild_rollups(None, None).await.unwrap();
+        assert_eq!(rebuilt, 7);
+
+        let from_raw = repo.fetch_series(query()).await.unwrap();
+        assert_eq!(incremental.points, from_raw.points);
+    }
+
+    #[sqlx::test(migrations = "./migrations/")]
+    async fn retention_removes_expired_data(pool: SqlitePool) {
+        let repo = SqliteRepository::new(pool.clone());
+        let now = Utc::now();
+        for (sensor_id, days_ago) in [
+            ("outside", 40),
+            ("outside", 1),
+            ("balcony", 40),
+            ("attic", 40),
+        ] {
+            let r = SensorReadingEvent {
+                sensor_id: sensor_id.to_string(),
+                timestamp: now - TimeDelta::days(days_ago),
+                sequence: None,
+                metrics: [("temperature".to_string(), 1.0)].into(),
+            };
+            repo.insert_sensor_reading("sensor/update".to_string(), r)
+                .await
+                .unwrap();
+        }
+        let policies = [
+            RetentionPolicy {
+                sensor_id: None,
+                resolution: Resolution::Raw,
+                keep_days: Some(30),
+            },
+            RetentionPolicy {
+                sensor_id: Some("attic".to_string()),
+                resolution: Resolution::Raw,
+                keep_days: None,
+            },
+            RetentionPolicy {
+                sensor_id: None,
+                resolution: Resolution::Hour,
+                keep_days: Some(10),
+            },
+        ];
+        for policy in policies {
+            repo.upsert_retention_policy(policy).await.unwrap();
+        }
+
+        let report = repo.enforce_retention(true).await.unwrap();
+        let rows: Vec<_> = report
+            .entries
+            .iter()
+            .map(|e| (e.resolution, e.rows))
+            .collect();
+        assert_eq!(rows, vec![(Resolution::Raw, 2), (Resolution::Hour, 3)]);
+        let remaining: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM sensor_readings")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(remaining, 4);
+
+        let report = repo.enforce_retention(false).await.unwrap();
+        assert!(!report.dry_run);
+        let sensors: Vec<String> =
+            sqlx::query_scalar("SELECT sensor_id FROM sensor_readings ORDER BY sensor_id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(sensors, vec!["attic", "outside"]);
+        let values: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM reading_values")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(values, 2);
+        let daily: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM reading_rollups WHERE resolution = 'day'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(daily, 4);
+    }
+
+    #[sqlx::test(migrations = "./migrations/")]
+    async fn incremental_vacuum_is_enabled(pool: SqlitePool) {
+        let repo = SqliteRepository::new(pool.clone());
+        repo.ensure_incremental_vacuum().await.unwrap();
+
+        // Open a read transaction so the connection sees the rewritten header
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query("SELECT COUNT(*) FROM sqlite_master")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        let mode: i64 = sqlx::query_scalar("PRAGMA auto_vacuum")
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap();
+        assert_eq!(mode, SQLITE_INCREMENTAL_VACUUM);
+    }
+}
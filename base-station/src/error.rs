@@ -16,8 +16,21 @@ pub enum BsError {
     Database(#[from] sqlx::Error),
     #[error("Database migration error: {0}")]
     Migrations(#[from] sqlx::migrate::MigrateError),
+    #[error(
+        "Database schema version {database} is newer than {known}, the newest this build \
+         knows. Run a newer base-station or migrate down with it."
+    )]
+    SchemaTooNew { database: i64, known: i64 },
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] arrow_schema::ArrowError),
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
     #[error("Error: {0}")]
-    Other(String)
+    Other(String),
 }
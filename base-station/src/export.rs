@@ -0,0 +1,593 @@
+//! Bulk export of stored readings to Parquet or CSV files for analysis in
+//! pandas, DuckDB and friends.
+//!
+//! Readings are streamed page by page out of the `Repository` so exports of
+//! any size run in constant memory. Files are laid out hive style, e.g.
+//! `day=2026-01-01/readings-20260102T000000Z.parquet`, and incremental
+//! exports leave a watermark next to them so the next run only writes
+//! readings taken since.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, TimestampMicrosecondArray};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, SecondsFormat, Utc};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+use crate::db::{MeasurementQuery, Pagination, QueryFilter, Repository, SensorReading};
+use crate::error::BsError;
+
+/// Readings fetched from the repository per round trip
+const EXPORT_PAGE_SIZE: usize = 5000;
+/// Left in the export directory by incremental exports
+const WATERMARK_FILE: &str = "_watermark.json";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// Columnar and snappy compressed
+    #[default]
+    Parquet,
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Parquet => "parquet",
+            ExportFormat::Csv => "csv",
+        }
+    }
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = BsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "parquet" => Ok(ExportFormat::Parquet),
+            "csv" => Ok(ExportFormat::Csv),
+            other => Err(BsError::InvalidQuery(format!(
+                "Unknown export format: {other}"
+            ))),
+        }
+    }
+}
+
+/// How readings are split into directories by the time they were taken
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+pub enum Partitioning {
+    /// Everything in the export directory itself
+    #[default]
+    None,
+    Day,
+    Month,
+}
+
+impl Partitioning {
+    /// Directory below the export directory a reading is written to
+    fn directory(self, timestamp: DateTime<Utc>) -> Option<String> {
+        match self {
+            Partitioning::None => None,
+            Partitioning::Day => Some(timestamp.format("day=%Y-%m-%d").to_string()),
+            Partitioning::Month => Some(timestamp.format("month=%Y-%m").to_string()),
+        }
+    }
+}
+
+impl std::str::FromStr for Partitioning {
+    type Err = BsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Partitioning::None),
+            "day" => Ok(Partitioning::Day),
+            "month" => Ok(Partitioning::Month),
+            other => Err(BsError::InvalidQuery(format!(
+                "Unknown partitioning: {other}"
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Object)]
+pub struct ExportRequest {
+    #[serde(default)]
+    #[oai(default)]
+    pub filters: QueryFilter,
+    /// Metrics to export, every metric of the catalogue when empty
+    #[serde(default)]
+    #[oai(default)]
+    pub metrics: Vec<String>,
+    #[serde(default)]
+    #[oai(default)]
+    pub format: ExportFormat,
+    #[serde(default)]
+    #[oai(default)]
+    pub partitioning: Partitioning,
+    /// Only export readings taken since the watermark of the previous
+    /// incremental export to the same directory
+    #[serde(default)]
+    #[oai(default)]
+    pub incremental: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Object)]
+pub struct ExportedFile {
+    /// Relative to the export directory
+    pub path: String,
+    pub rows: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Object)]
+pub struct ExportReport {
+    pub files: Vec<ExportedFile>,
+    pub rows: u64,
+    /// Readings taken before this instant have been exported, the next
+    /// incremental export starts here
+    pub watermark: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Watermark {
+    exported_until: DateTime<Utc>,
+}
+
+/// Write the readings selected by `request` to files below `directory`
+pub async fn export<R: Repository>(
+    repository: &R,
+    request: ExportRequest,
+    directory: &Path,
+) -> Result<ExportReport, BsError> {
+    let catalogue = repository.fetch_metrics().await?;
+    let metrics = if request.metrics.is_empty() {
+        catalogue.iter().map(|m| m.name.clone()).collect()
+    } else {
+        if let Some(unknown) = request
+            .metrics
+            .iter()
+            .find(|metric| !catalogue.iter().any(|m| &m.name == *metric))
+        {
+            return Err(BsError::InvalidQuery(format!("Unknown metric: {unknown}")));
+        }
+        request.metrics
+    };
+
+    let mut filters = request.filters;
+    // Readings arriving while we export are left for the next run
+    let watermark = filters.to.unwrap_or_else(Utc::now);
+    filters.to = Some(watermark);
+    if request.incremental
+        && let Some(previous) = read_watermark(directory)?
+    {
+        filters.from = Some(filters.from.map_or(previous, |from| from.max(previous)));
+    }
+    let mut report = ExportReport {
+        files: Vec::new(),
+        rows: 0,
+        watermark,
+    };
+    if filters.from.is_some_and(|from| from >= watermark) {
+        return Ok(report);
+    }
+
+    std::fs::create_dir_all(directory)?;
+    let file_name = format!(
+        "readings-{}.{}",
+        watermark.format("%Y%m%dT%H%M%SZ"),
+        request.format.extension()
+    );
+    let mut query = MeasurementQuery {
+        filters,
+        pagination: Pagination {
+            page_size: EXPORT_PAGE_SIZE,
+            ..Default::default()
+        },
+        columns: query_columns(&metrics),
+    };
+    let mut current: Option<(Option<String>, PartitionWriter)> = None;
+    loop {
+        let page = repository.fetch_sensor_readings_page(query).await?;
+        // Pages come oldest first so every partition is written in one go
+        for chunk in page.rows.chunk_by(|a, b| {
+            request.partitioning.directory(timestamp(a))
+                == request.partitioning.directory(timestamp(b))
+        }) {
+            let partition = request.partitioning.directory(timestamp(&chunk[0]));
+            if current.as_ref().is_none_or(|(open, _)| *open != partition) {
+                if let Some((_, writer)) = current.take() {
+                    report.files.push(writer.finish()?);
+                }
+                let relative = match &partition {
+                    Some(partition) => PathBuf::from(partition).join(&file_name),
+                    None => PathBuf::from(&file_name),
+                };
+                let writer =
+                    PartitionWriter::create(directory, relative, request.format, &metrics)?;
+                current = Some((partition, writer));
+            }
+            if let Some((_, writer)) = current.as_mut() {
+                writer.write(chunk)?;
+            }
+        }
+
+        match page.next {
+            Some(next) => {
+                query = MeasurementQuery {
+                    filters: page.filters,
+                    pagination: Pagination {
+                        after: Some(next),
+                        page_size: EXPORT_PAGE_SIZE,
+                        ..Default::default()
+                    },
+                    columns: query_columns(&metrics),
+                };
+            }
+            None => break,
+        }
+    }
+    if let Some((_, writer)) = current.take() {
+        report.files.push(writer.finish()?);
+    }
+    report.rows = report.files.iter().map(|f| f.rows).sum();
+
+    if request.incremental {
+        write_watermark(directory, watermark)?;
+    }
+    Ok(report)
+}
+
+fn query_columns(metrics: &[String]) -> Vec<String> {
+    MeasurementQuery::BASE_COLUMNS
+        .iter()
+        .map(|c| c.to_string())
+        .chain(metrics.iter().cloned())
+        .collect()
+}
+
+fn timestamp(reading: &SensorReading) -> DateTime<Utc> {
+    reading
+        .timestamp
+        .expect("Exports always select the timestamp column")
+}
+
+fn read_watermark(directory: &Path) -> Result<Option<DateTime<Utc>>, BsError> {
+    match std::fs::read(directory.join(WATERMARK_FILE)) {
+        Ok(contents) => {
+            let watermark: Watermark = serde_json::from_slice(&contents)?;
+            Ok(Some(watermark.exported_until))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaced atomically so a crash never leaves a torn watermark behind
+fn write_watermark(directory: &Path, exported_until: DateTime<Utc>) -> Result<(), BsError> {
+    let temporary = directory.join(format!("{WATERMARK_FILE}.tmp"));
+    std::fs::write(
+        &temporary,
+        serde_json::to_vec(&Watermark { exported_until })?,
+    )?;
+    std::fs::rename(temporary, directory.join(WATERMARK_FILE))?;
+    Ok(())
+}
+
+/// One output file
+struct PartitionWriter {
+    relative: PathBuf,
+    rows: u64,
+    columns: Vec<String>,
+    sink: Sink,
+}
+
+enum Sink {
+    Csv(csv::Writer<BufWriter<File>>),
+    Parquet {
+        schema: SchemaRef,
+        writer: ArrowWriter<File>,
+    },
+}
+
+impl PartitionWriter {
+    fn create(
+        directory: &Path,
+        relative: PathBuf,
+        format: ExportFormat,
+        metrics: &[String],
+    ) -> Result<Self, BsError> {
+        let path = directory.join(&relative);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = File::create(&path)?;
+        let columns = query_columns(metrics);
+        let sink = match format {
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(BufWriter::new(file));
+                writer.write_record(&columns)?;
+                Sink::Csv(writer)
+            }
+            ExportFormat::Parquet => {
+                let schema = arrow_schema(&columns);
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                let writer = ArrowWriter::try_new(file, schema.clone(), Some(properties))?;
+                Sink::Parquet { schema, writer }
+            }
+        };
+        Ok(Self {
+            relative,
+            rows: 0,
+            columns,
+            sink,
+        })
+    }
+
+    fn write(&mut self, readings: &[SensorReading]) -> Result<(), BsError> {
+        match &mut self.sink {
+            Sink::Csv(writer) => {
+                for reading in readings {
+                    writer.write_record(csv_record(&self.columns, reading))?;
+                }
+            }
+            Sink::Parquet { schema, writer } => {
+                writer.write(&record_batch(schema.clone(), readings)?)?;
+            }
+        }
+        self.rows += readings.len() as u64;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<ExportedFile, BsError> {
+        match self.sink {
+            Sink::Csv(mut writer) => writer.flush()?,
+            Sink::Parquet { writer, .. } => {
+                writer.close()?;
+            }
+        }
+        Ok(ExportedFile {
+            path: self.relative.to_string_lossy().into_owned(),
+            rows: self.rows,
+        })
+    }
+}
+
+/// CSV fields of a reading in the order of `columns`, missing values are empty
+pub(crate) fn csv_record(columns: &[String], reading: &SensorReading) -> Vec<String> {
+    columns
+        .iter()
+        .map(|column| match column.as_str() {
+            "sensor_id" => reading.sensor_id.clone().unwrap_or_default(),
+            "topic" => reading.topic.clone().unwrap_or_default(),
+            "timestamp" => reading
+                .timestamp
+                .map(|t| t.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+                .unwrap_or_default(),
+            metric => reading
+                .metrics
+                .get(metric)
+                .map(f64::to_string)
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Arrow schema of the given reading columns and metrics
+pub(crate) fn arrow_schema(columns: &[String]) -> SchemaRef {
+    let fields: Vec<_> = columns
+        .iter()
+        .map(|column| match column.as_str() {
+            "sensor_id" | "topic" => Field::new(column, DataType::Utf8, false),
+            "timestamp" => Field::new(
+                column,
+                DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+                false,
+            ),
+            // A reading only carries the metrics its sensor measures
+            metric => Field::new(metric, DataType::Float64, true),
+        })
+        .collect();
+    Arc::new(Schema::new(fields))
+}
+
+/// Readings as a batch of the columns of `schema`
+pub(crate) fn record_batch(
+    schema: SchemaRef,
+    readings: &[SensorReading],
+) -> Result<RecordBatch, BsError> {
+    let columns: Vec<ArrayRef> = schema
+        .fields()
+        .iter()
+        .map(|field| -> ArrayRef {
+            match field.name().as_str() {
+                "sensor_id" => Arc::new(StringArray::from_iter(
+                    readings.iter().map(|r| r.sensor_id.as_deref()),
+                )),
+                "topic" => Arc::new(StringArray::from_iter(
+                    readings.iter().map(|r| r.topic.as_deref()),
+                )),
+                "timestamp" => Arc::new(
+                    TimestampMicrosecondArray::from_iter(
+                        readings
+                            .iter()
+                            .map(|r| r.timestamp.map(|t| t.timestamp_micros())),
+                    )
+                    .with_timezone("UTC"),
+                ),
+                metric => Arc::new(Float64Array::from_iter(
+                    readings.iter().map(|r| r.metrics.get(metric).copied()),
+                )),
+            }
+        })
+        .collect();
+    Ok(RecordBatch::try_new(schema, columns)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use super::*;
+    use crate::SensorReadingEvent;
+    use crate::db::InMemoryRepository;
+
+    /// Removed again when the test finishes
+    struct ScratchDirectory(PathBuf);
+
+    impl ScratchDirectory {
+        fn new() -> Self {
+            let name = format!("base-station-export-{}", rand::random::<u64>());
+            Self(std::env::temp_dir().join(name))
+        }
+    }
+
+    impl Drop for ScratchDirectory {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn insert(repo: &InMemoryRepository, timestamp: &str, temperature: f64) {
+        let reading = SensorReadingEvent {
+            sensor_id: "outside".to_string(),
+            timestamp: timestamp.parse().unwrap(),
+            sequence: None,
+            metrics: [("temperature".to_string(), temperature)].into(),
+        };
+        repo.insert_sensor_reading("sensor/update".to_string(), reading)
+            .await
+            .unwrap();
+    }
+
+    fn request(format: ExportFormat, partitioning: Partitioning, to: &str) -> ExportRequest {
+        ExportRequest {
+            filters: QueryFilter {
+                to: Some(to.parse().unwrap()),
+                ..Default::default()
+            },
+            metrics: vec!["temperature".to_string(), "humidity".to_string()],
+            format,
+            partitioning,
+            incremental: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn parquet_files_are_partitioned_by_day() {
+        let repo = InMemoryRepository::new();
+        insert(&repo, "2026-01-01T10:00:00Z", 1.0).await;
+        insert(&repo, "2026-01-01T23:59:59Z", 2.0).await;
+        insert(&repo, "2026-01-02T00:00:00Z", 3.0).await;
+        let scratch = ScratchDirectory::new();
+
+        let request = request(
+            ExportFormat::Parquet,
+            Partitioning::Day,
+            "2026-01-03T00:00:00Z",
+        );
+        let schema = arrow_schema(&query_columns(&request.metrics));
+        let report = export(&repo, request, &scratch.0).await.unwrap();
+
+        assert_eq!(report.rows, 3);
+        assert_eq!(
+            report.files,
+            vec![
+                ExportedFile {
+                    path: "day=2026-01-01/readings-20260103T000000Z.parquet".to_string(),
+                    rows: 2,
+                },
+                ExportedFile {
+                    path: "day=2026-01-02/readings-20260103T000000Z.parquet".to_string(),
+                    rows: 1,
+                },
+            ]
+        );
+
+        let file = File::open(scratch.0.join(&report.files[0].path)).unwrap();
+        let batches: Vec<_> = ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let batch = &batches[0];
+        assert_eq!(batch.schema(), schema);
+        let temperatures = batch
+            .column_by_name("temperature")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<arrow_array::Float64Array>()
+            .unwrap();
+        assert_eq!(temperatures.values().to_vec(), vec![1.0, 2.0]);
+        assert_eq!(batch.column_by_name("humidity").unwrap().null_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn incremental_exports_continue_from_the_watermark() {
+        let repo = InMemoryRepository::new();
+        insert(&repo, "2026-01-01T10:00:00Z", 1.0).await;
+        insert(&repo, "2026-01-01T11:00:00Z", 2.0).await;
+        let scratch = ScratchDirectory::new();
+
+        let first = export(
+            &repo,
+            request(
+                ExportFormat::Csv,
+                Partitioning::None,
+                "2026-01-01T11:00:00Z",
+            ),
+            &scratch.0,
+        )
+        .await
+        .unwrap();
+        assert_eq!(first.rows, 1);
+
+        insert(&repo, "2026-01-01T12:00:00Z", 3.0).await;
+        let second = export(
+            &repo,
+            request(
+                ExportFormat::Csv,
+                Partitioning::None,
+                "2026-01-02T00:00:00Z",
+            ),
+            &scratch.0,
+        )
+        .await
+        .unwrap();
+        assert_eq!(second.rows, 2);
+        assert_eq!(read_watermark(&scratch.0).unwrap(), Some(second.watermark));
+
+        let csv = std::fs::read_to_string(scratch.0.join(&second.files[0].path)).unwrap();
+        assert_eq!(
+            csv,
+            "sensor_id,topic,timestamp,temperature,humidity\n\
+             outside,sensor/update,2026-01-01T11:00:00Z,2,\n\
+             outside,sensor/update,2026-01-01T12:00:00Z,3,\n"
+        );
+
+        let caught_up = export(
+            &repo,
+            request(
+                ExportFormat::Csv,
+                Partitioning::None,
+                "2026-01-02T00:00:00Z",
+            ),
+            &scratch.0,
+        )
+        .await
+        .unwrap();
+        assert!(caught_up.files.is_empty());
+    }
+}
@@ -0,0 +1,586 @@
+//! Bulk import of historical readings from CSV or JSON Lines files, e.g.
+//! the logs of a previous logger or the exports of another base station.
+//!
+//! Rows are validated one by one and stored through the `Repository` in
+//! large batches. Rows that fail validation are reported with their line and
+//! skipped, readings the database already has are resolved with the
+//! repository's conflict policy.
+
+use std::collections::BTreeMap;
+use std::io::BufRead;
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::db::{Metric, Repository};
+use crate::error::BsError;
+use crate::{SensorReadingEvent, canonical_metric_name, is_valid_metric_name};
+
+/// Readings stored per transaction
+const IMPORT_BATCH_SIZE: usize = 5000;
+/// Further row errors are only counted
+const MAX_REPORTED_ERRORS: usize = 100;
+/// Layouts of timestamps without an offset tried when no format is given
+const NAIVE_TIMESTAMP_FORMATS: &[&str] = &["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Enum)]
+#[serde(rename_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+pub enum ImportFormat {
+    /// Comma separated with a header row
+    #[default]
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+}
+
+impl std::str::FromStr for ImportFormat {
+    type Err = BsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ImportFormat::Csv),
+            "ndjson" | "jsonl" => Ok(ImportFormat::Ndjson),
+            other => Err(BsError::InvalidQuery(format!(
+                "Unknown import format: {other}"
+            ))),
+        }
+    }
+}
+
+/// How the columns of a file map onto readings
+#[derive(Debug, Clone, Deserialize, Object)]
+pub struct ImportOptions {
+    #[serde(default)]
+    #[oai(default)]
+    pub format: ImportFormat,
+    #[serde(default = "default_sensor_id_column")]
+    #[oai(default = "default_sensor_id_column")]
+    pub sensor_id_column: String,
+    /// Sensor of rows without a sensor id column
+    pub sensor_id: Option<String>,
+    #[serde(default = "default_timestamp_column")]
+    #[oai(default = "default_timestamp_column")]
+    pub timestamp_column: String,
+    /// `strftime` layout of the timestamps. RFC 3339, `YYYY-MM-DD HH:MM:SS`
+    /// and unix seconds are recognised without one.
+    pub timestamp_format: Option<String>,
+    /// IANA time zone of timestamps without an offset, UTC when missing
+    pub timezone: Option<String>,
+    /// Column to metric name. Every other column is a metric of the same
+    /// name when empty.
+    #[serde(default)]
+    #[oai(default)]
+    pub metrics: BTreeMap<String, String>,
+    /// Metric to the unit the file uses, values are converted to the unit of
+    /// the catalogue
+    #[serde(default)]
+    #[oai(default)]
+    pub units: BTreeMap<String, String>,
+    /// Recorded as the topic of the imported readings
+    #[serde(default = "default_topic")]
+    #[oai(default = "default_topic")]
+    pub topic: String,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            format: ImportFormat::default(),
+            sensor_id_column: default_sensor_id_column(),
+            sensor_id: None,
+            timestamp_column: default_timestamp_column(),
+            timestamp_format: None,
+            timezone: None,
+            metrics: BTreeMap::new(),
+            units: BTreeMap::new(),
+            topic: default_topic(),
+        }
+    }
+}
+
+fn default_sensor_id_column() -> String {
+    "sensor_id".to_string()
+}
+
+fn default_timestamp_column() -> String {
+    "timestamp".to_string()
+}
+
+fn default_topic() -> String {
+    "import".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Object)]
+pub struct RowError {
+    /// Line of the file, the CSV header being line 1
+    pub line: u64,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Object)]
+pub struct ImportReport {
+    /// Rows read, not counting the CSV header and blank lines
+    pub rows: u64,
+    pub imported: u64,
+    /// Readings the database already had and the conflict policy ignored
+    pub duplicates: u64,
+    /// Rows skipped because they failed validation
+    pub failed: u64,
+    /// The first failures
+    pub errors: Vec<RowError>,
+}
+
+impl ImportReport {
+    fn reject(&mut self, line: u64, message: String) {
+        self.failed += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(RowError { line, message });
+        }
+    }
+}
+
+/// `value * gain + offset` taking a value from a unit to the catalogue unit
+#[derive(Debug, Clone, Copy)]
+struct Conversion {
+    gain: f64,
+    offset: f64,
+}
+
+impl Conversion {
+    /// From unit, catalogue unit, gain and offset
+    const KNOWN: &[(&str, &str, f64, f64)] = &[
+        ("°F", "°C", 5.0 / 9.0, -32.0 * 5.0 / 9.0),
+        ("K", "°C", 1.0, -273.15),
+        ("hPa", "Pa", 100.0, 0.0),
+        ("mbar", "Pa", 100.0, 0.0),
+        ("kPa", "Pa", 1000.0, 0.0),
+        ("inHg", "Pa", 3386.389, 0.0),
+        ("km/h", "m/s", 1.0 / 3.6, 0.0),
+        ("mph", "m/s", 0.44704, 0.0),
+        ("kn", "m/s", 1852.0 / 3600.0, 0.0),
+    ];
+
+    fn between(from: &str, to: &str) -> Option<Self> {
+        if from == to {
+            return Some(Self {
+                gain: 1.0,
+                offset: 0.0,
+            });
+        }
+        Self::KNOWN
+            .iter()
+            .find(|(known_from, known_to, _, _)| *known_from == from && *known_to == to)
+            .map(|(_, _, gain, offset)| Self {
+                gain: *gain,
+                offset: *offset,
+            })
+    }
+
+    fn apply(self, value: f64) -> f64 {
+        value * self.gain + self.offset
+    }
+}
+
+/// Options checked against the catalogue, ready to turn rows into readings
+struct RowParser<'a> {
+    options: &'a ImportOptions,
+    timezone: Tz,
+    conversions: BTreeMap<String, Conversion>,
+}
+
+impl<'a> RowParser<'a> {
+    fn new(options: &'a ImportOptions, catalogue: &[Metric]) -> Result<Self, BsError> {
+        let timezone = match &options.timezone {
+            Some(name) => name
+                .parse()
+                .map_err(|_| BsError::InvalidQuery(format!("Unknown time zone: {name}")))?,
+            None => Tz::UTC,
+        };
+        if let Some(metric) = options.metrics.values().find(|m| !is_valid_metric_name(m)) {
+            return Err(BsError::InvalidQuery(format!(
+                "Invalid metric name: {metric}"
+            )));
+        }
+
+        let mut conversions = BTreeMap::new();
+        for (metric, unit) in &options.units {
+            let target = catalogue
+                .iter()
+                .find(|m| &m.name == metric)
+                .and_then(|m| m.unit.as_deref())
+                .ok_or_else(|| {
+                    BsError::InvalidQuery(format!("Metric {metric} has no unit to convert to"))
+                })?;
+            let conversion = Conversion::between(unit, target).ok_or_else(|| {
+                BsError::InvalidQuery(format!("Cannot convert {metric} from {unit} to {target}"))
+            })?;
+            conversions.insert(metric.clone(), conversion);
+        }
+
+        Ok(Self {
+            options,
+            timezone,
+            conversions,
+        })
+    }
+
+    fn parse(&self, row: &BTreeMap<String, Value>) -> Result<SensorReadingEvent, String> {
+        let sensor_id = match row.get(&self.options.sensor_id_column).and_then(text) {
+            Some(sensor_id) => sensor_id,
+            None => self
+                .options
+                .sensor_id
+                .clone()
+                .ok_or_else(|| format!("Missing {}", self.options.sensor_id_column))?,
+        };
+        let timestamp = row
+            .get(&self.options.timestamp_column)
+            .filter(|value| !is_blank(value))
+            .ok_or_else(|| format!("Missing {}", self.options.timestamp_column))?;
+        let timestamp = self.timestamp(timestamp)?;
+
+        let mut metrics = BTreeMap::new();
+        for (column, value) in row {
+            if *column == self.options.sensor_id_column || *column == self.options.timestamp_column
+            {
+                continue;
+            }
+            let metric = if self.options.metrics.is_empty() {
+                canonical_metric_name(column)
+            } else {
+                match self.options.metrics.get(column) {
+                    Some(metric) => metric.clone(),
+                    None => continue,
+                }
+            };
+            if is_blank(value) {
+                continue;
+            }
+            if !is_valid_metric_name(&metric) {
+                return Err(format!("Invalid metric name: {column}"));
+            }
+            let value = number(value)
+                .filter(|v| v.is_finite())
+                .ok_or_else(|| format!("Invalid value of {column}: {value}"))?;
+            let value = self
+                .conversions
+                .get(&metric)
+                .map_or(value, |conversion| conversion.apply(value));
+            metrics.insert(metric, value);
+        }
+        if metrics.is_empty() {
+            return Err("Row contains no metrics".to_string());
+        }
+
+        Ok(SensorReadingEvent {
+            sensor_id,
+            timestamp,
+            sequence: None,
+            metrics,
+        })
+    }
+
+    fn timestamp(&self, value: &Value) -> Result<DateTime<Utc>, String> {
+        let invalid = || format!("Invalid timestamp: {value}");
+        if let Some(seconds) = value.as_f64() {
+            return unix_timestamp(seconds).ok_or_else(invalid);
+        }
+        let text = value.as_str().ok_or_else(invalid)?.trim();
+
+        if let Some(format) = &self.options.timestamp_format {
+            if let Ok(timestamp) = DateTime::parse_from_str(text, format) {
+                return Ok(timestamp.to_utc());
+            }
+            let naive = NaiveDateTime::parse_from_str(text, format).map_err(|_| invalid())?;
+            return self.localise(naive);
+        }
+        if let Ok(timestamp) = DateTime::parse_from_rfc3339(text) {
+            return Ok(timestamp.to_utc());
+        }
+        if let Some(naive) = NAIVE_TIMESTAMP_FORMATS
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        {
+            return self.localise(naive);
+        }
+        text.parse()
+            .ok()
+            .and_then(unix_timestamp)
+            .ok_or_else(invalid)
+    }
+
+    fn localise(&self, naive: NaiveDateTime) -> Result<DateTime<Utc>, String> {
+        // Clocks going back repeat an hour, guessing would silently shift readings
+        self.timezone
+            .from_local_datetime(&naive)
+            .single()
+            .map(|local| local.to_utc())
+            .ok_or_else(|| {
+                format!(
+                    "Ambiguous or skipped local time in {}: {naive}",
+                    self.timezone
+                )
+            })
+    }
+}
+
+fn unix_timestamp(seconds: f64) -> Option<DateTime<Utc>> {
+    if !seconds.is_finite() {
+        return None;
+    }
+    DateTime::from_timestamp_micros((seconds * 1_000_000.0).round() as i64)
+}
+
+fn is_blank(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::String(s) => s.trim().is_empty(),
+        _ => false,
+    }
+}
+
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) if !s.trim().is_empty() => Some(s.trim().to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Line number and columns of every row, or why it could not be read
+type Rows<'a> =
+    Box<dyn Iterator<Item = (u64, Result<BTreeMap<String, Value>, String>)> + Send + 'a>;
+
+fn csv_rows<'a>(input: impl BufRead + Send + 'a) -> Result<Rows<'a>, BsError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(input);
+    let headers = reader.headers()?.clone();
+    Ok(Box::new(reader.into_records().map(move |record| {
+        let line = record
+            .as_ref()
+            .ok()
+            .and_then(|r| r.position())
+            .map_or(0, |p| p.line());
+        match record {
+            Ok(record) => (
+                line,
+                Ok(headers
+                    .iter()
+                    .zip(record.iter())
+                    .map(|(header, field)| (header.to_string(), Value::String(field.to_string())))
+                    .collect()),
+            ),
+            Err(e) => (
+                e.position().map_or(line, |p| p.line()),
+                Err(format!("Unreadable row: {e}")),
+            ),
+        }
+    })))
+}
+
+fn ndjson_rows<'a>(input: impl BufRead + Send + 'a) -> Rows<'a> {
+    Box::new(
+        input
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index as u64 + 1, line))
+            .filter(|(_, line)| line.as_ref().map_or(true, |l| !l.trim().is_empty()))
+            .map(|(line_number, line)| {
+                let row = line
+                    .map_err(|e| format!("Unreadable line: {e}"))
+                    .and_then(|line| {
+                        serde_json::from_str(&line).map_err(|e| format!("Invalid JSON object: {e}"))
+                    });
+                (line_number, row)
+            }),
+    )
+}
+
+/// Read readings from `input` and store the valid ones
+pub async fn import<R: Repository>(
+    repository: &R,
+    input: impl BufRead + Send,
+    options: &ImportOptions,
+) -> Result<ImportReport, BsError> {
+    let catalogue = repository.fetch_metrics().await?;
+    let parser = RowParser::new(options, &catalogue)?;
+    let rows = match options.format {
+        ImportFormat::Csv => csv_rows(input)?,
+        ImportFormat::Ndjson => ndjson_rows(input),
+    };
+
+    let mut report = ImportReport::default();
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    for (line, row) in rows {
+        report.rows += 1;
+        match row.and_then(|row| parser.parse(&row)) {
+            Ok(reading) => batch.push(reading),
+            Err(message) => report.reject(line, message),
+        }
+        if batch.len() == IMPORT_BATCH_SIZE {
+            store(repository, options, &mut batch, &mut report).await?;
+        }
+    }
+    store(repository, options, &mut batch, &mut report).await?;
+
+    Ok(report)
+}
+
+async fn store<R: Repository>(
+    repository: &R,
+    options: &ImportOptions,
+    batch: &mut Vec<SensorReadingEvent>,
+    report: &mut ImportReport,
+) -> Result<(), BsError> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let readings = std::mem::replace(batch, Vec::with_capacity(IMPORT_BATCH_SIZE));
+    let count = readings.len() as u64;
+    let imported = repository
+        .insert_sensor_readings(options.topic.clone(), readings)
+        .await?;
+    report.imported += imported;
+    report.duplicates += count - imported;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{InMemoryRepository, MeasurementQuery, Pagination, QueryFilter};
+
+    async fn stored(repo: &InMemoryRepository, metric: &str) -> Vec<(String, DateTime<Utc>, f64)> {
+        let query = MeasurementQuery {
+            filters: QueryFilter {
+                metric: Some(metric.to_string()),
+                ..Default::default()
+            },
+            pagination: Pagination {
+                page_size: 100,
+                ..Default::default()
+            },
+            columns: vec![
+                "sensor_id".to_string(),
+                "timestamp".to_string(),
+                metric.to_string(),
+            ],
+        };
+        let page = repo.fetch_sensor_readings_page(query).await.unwrap();
+        page.rows
+            .into_iter()
+            .map(|r| {
+                (
+                    r.sensor_id.unwrap(),
+                    r.timestamp.unwrap(),
+                    r.metrics[metric],
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn csv_rows_are_validated_and_deduplicated() {
+        let repo = InMemoryRepository::new();
+        let csv = "sensor_id,timestamp,t,humidity\n\
+                   attic,2026-01-01T10:00:00Z,21.5,40\n\
+                   attic,2026-01-01T10:00:00Z,21.5,40\n\
+                   attic,yesterday,21.0,41\n\
+                   attic,2026-01-01T11:00:00Z,warm,41\n\
+                   ,2026-01-01T12:00:00Z,22.0,\n";
+
+        let report = import(&repo, csv.as_bytes(), &ImportOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(report.rows, 5);
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.duplicates, 1);
+        assert_eq!(report.failed, 3);
+        let lines: Vec<_> = report.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![4, 5, 6]);
+
+        let again = import(&repo, csv.as_bytes(), &ImportOptions::default())
+            .await
+            .unwrap();
+        assert_eq!((again.imported, again.duplicates), (0, 2));
+        assert_eq!(
+            stored(&repo, "temperature").await,
+            vec![(
+                "attic".to_string(),
+                "2026-01-01T10:00:00Z".parse().unwrap(),
+                21.5
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn ndjson_columns_units_and_time_zone_are_mapped() {
+        let repo = InMemoryRepository::new();
+        let ndjson = r#"{"time": "2026-07-01 12:00:00", "temp_f": 212, "baro": 1013.25}
+
+{"time": 1767225600, "temp_f": "32", "ignored": "x"}
+"#;
+        let options = ImportOptions {
+            format: ImportFormat::Ndjson,
+            sensor_id: Some("old-logger".to_string()),
+            timestamp_column: "time".to_string(),
+            timezone: Some("Europe/Warsaw".to_string()),
+            metrics: [
+                ("temp_f".to_string(), "temperature".to_string()),
+                ("baro".to_string(), "pressure".to_string()),
+            ]
+            .into(),
+            units: [
+                ("temperature".to_string(), "°F".to_string()),
+                ("pressure".to_string(), "hPa".to_string()),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        let report = import(&repo, ndjson.as_bytes(), &options).await.unwrap();
+        assert_eq!((report.rows, report.imported, report.failed), (2, 2, 0));
+
+        assert_eq!(
+            stored(&repo, "temperature").await,
+            vec![
+                (
+                    "old-logger".to_string(),
+                    "2026-01-01T00:00:00Z".parse().unwrap(),
+                    0.0
+                ),
+                (
+                    "old-logger".to_string(),
+                    "2026-07-01T10:00:00Z".parse().unwrap(),
+                    100.0
+                ),
+            ]
+        );
+        assert_eq!(stored(&repo, "pressure").await[0].2, 101325.0);
+    }
+
+    #[tokio::test]
+    async fn unknown_conversions_reject_the_import() {
+        let options = ImportOptions {
+            units: [("temperature".to_string(), "furlongs".to_string())].into(),
+            ..Default::default()
+        };
+        let res = import(&InMemoryRepository::new(), "".as_bytes(), &options).await;
+
+        assert!(matches!(res, Err(BsError::InvalidQuery(_))));
+    }
+}
@@ -1,35 +1,56 @@
-use chrono::Utc;
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
 
 pub mod api;
 pub mod db;
 pub mod error;
+pub mod export;
+pub mod import;
+pub mod live;
 pub mod mqtt;
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, Deserialize)]
 pub struct SensorReadingEvent {
     #[serde(default = "default_sensor")]
     sensor_id: String,
-    #[serde(rename = "t", with = "from_string_or_float")]
-    temperature: f64,
-    #[serde(rename = "p", with = "from_string_or_float")]
-    pressure: f64,
-    #[serde(rename = "h", with = "from_string_or_float")]
-    humidity: f64,
     #[serde(default = "default_timestamp")]
     timestamp: chrono::DateTime<chrono::Utc>,
+    /// Counter of sensors that send one, tells apart readings taken within
+    /// the same timestamp
+    #[serde(default, alias = "seq")]
+    sequence: Option<i64>,
+    /// Every other numeric field of the payload is treated as a metric
+    /// reading, other fields are skipped
+    #[serde(flatten, with = "metric_values")]
+    metrics: BTreeMap<String, f64>,
 }
 
 impl std::fmt::Display for SensorReadingEvent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}[{}] - temperature: {}, pressure: {}, humidity: {}",
-            self.sensor_id,
-            self.timestamp.to_rfc3339(),
-            self.temperature,
-            self.pressure,
-            self.humidity
-        )
+        write!(f, "{}[{}] -", self.sensor_id, self.timestamp.to_rfc3339())?;
+        for (i, (metric, value)) in self.metrics.iter().enumerate() {
+            let separator = if i > 0 { "," } else { "" };
+            write!(f, "{separator} {metric}: {value}")?;
+        }
+        Ok(())
+    }
+}
+
+impl SensorReadingEvent {
+    /// Parse an MQTT payload. Readings without a timestamp were taken when
+    /// they were received.
+    pub fn from_payload(
+        payload: &[u8],
+        received_at: DateTime<Utc>,
+    ) -> Result<Self, serde_json::Error> {
+        let mut reading: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_slice(payload)?;
+        reading
+            .entry("timestamp")
+            .or_insert_with(|| received_at.to_rfc3339().into());
+        serde_json::from_value(reading.into())
     }
 }
 
@@ -41,32 +62,157 @@ fn default_timestamp() -> chrono::DateTime<Utc> {
     Utc::now()
 }
 
-mod from_string_or_float {
+/// Maps the short keys used by the sensor firmware onto the catalogue metric names
+fn canonical_metric_name(key: &str) -> String {
+    match key {
+        "t" => "temperature".to_string(),
+        "p" => "pressure".to_string(),
+        "h" => "humidity".to_string(),
+        other => other.to_lowercase(),
+    }
+}
+
+/// Metric names end up in the SQL and in the API responses so keep them boring
+pub fn is_valid_metric_name(name: &str) -> bool {
+    !db::MeasurementQuery::BASE_COLUMNS.contains(&name)
+        && !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+mod metric_values {
+    use std::collections::BTreeMap;
+
     use serde::{self, Deserialize, Deserializer};
 
-    pub fn deserialize<'de, D>(deserializer: D) -> Result<f64, D::Error>
+    use super::from_string_or_float::FloatOrString;
+    use super::{canonical_metric_name, is_valid_metric_name};
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<BTreeMap<String, f64>, D::Error>
     where
         D: Deserializer<'de>,
     {
-        #[derive(Debug, Deserialize)]
-        #[serde(untagged)]
-        enum FloatOrString {
-            String(String),
-            Float(f64),
-            Number(i64),
+        let raw: BTreeMap<String, serde_json::Value> = BTreeMap::deserialize(deserializer)?;
+        let mut metrics = BTreeMap::new();
+        for (key, raw_value) in raw {
+            // Firmware versions, flags and the like are not readings
+            let value = Option::<FloatOrString>::deserialize(&raw_value)
+                .map_err(|e| e.to_string())
+                .and_then(FloatOrString::into_f64);
+            let Ok(value) = value else {
+                tracing::warn!("Skipping non-numeric field {key}: {raw_value}");
+                continue;
+            };
+            let name = canonical_metric_name(&key);
+            if !is_valid_metric_name(&name) {
+                tracing::warn!("Skipping field with an invalid metric name: {key}");
+                continue;
+            }
+            metrics.insert(name, value);
         }
-        let maybe_value: Option<FloatOrString> = Option::deserialize(deserializer)?;
-        match maybe_value {
-            Some(FloatOrString::String(as_string)) => {
-                let as_float: f64 = as_string.parse().map_err(|e| {
-                    tracing::error!("Failed to parse string to float: {e}");
-                    serde::de::Error::custom(e)
-                })?;
-                Ok(as_float)
+        if metrics.is_empty() {
+            return Err(serde::de::Error::custom("Reading contains no metrics"));
+        }
+        Ok(metrics)
+    }
+}
+
+mod from_string_or_float {
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(untagged)]
+    pub enum FloatOrString {
+        String(String),
+        Float(f64),
+        Number(i64),
+    }
+
+    impl FloatOrString {
+        pub fn into_f64(maybe_value: Option<Self>) -> Result<f64, String> {
+            match maybe_value {
+                Some(FloatOrString::String(as_string)) => {
+                    let as_float: f64 = as_string.parse().map_err(|e| format!("{e}"))?;
+                    if !as_float.is_finite() {
+                        return Err(format!("{as_string} is not a finite number"));
+                    }
+                    Ok(as_float)
+                }
+                Some(FloatOrString::Float(f)) => Ok(f),
+                Some(FloatOrString::Number(f)) => Ok(f as f64),
+                None => Err("Field is missing".to_string()),
             }
-            Some(FloatOrString::Float(f)) => Ok(f),
-            Some(FloatOrString::Number(f)) => Ok(f as f64),
-            None => Err(serde::de::Error::custom("Field is missing")),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_keys_map_to_catalogue_metrics() {
+        let reading: SensorReadingEvent =
+            serde_json::from_str(r#"{"t":"21.5","p":101325,"h":40.5,"co2":"415"}"#).unwrap();
+
+        assert_eq!(reading.sensor_id, "outside-sensor");
+        assert_eq!(reading.metrics["temperature"], 21.5);
+        assert_eq!(reading.metrics["pressure"], 101325.0);
+        assert_eq!(reading.metrics["humidity"], 40.5);
+        assert_eq!(reading.metrics["co2"], 415.0);
+    }
+
+    #[test]
+    fn payloads_without_a_timestamp_take_the_receive_time() {
+        let received_at = "2026-10-19T08:00:00Z".parse().unwrap();
+        let reading = SensorReadingEvent::from_payload(br#"{"t":"21.5"}"#, received_at).unwrap();
+        assert_eq!(reading.timestamp, received_at);
+
+        let reading = SensorReadingEvent::from_payload(
+            br#"{"t":"21.5","timestamp":"2026-10-19T07:59:30Z"}"#,
+            received_at,
+        )
+        .unwrap();
+        assert_eq!(reading.timestamp.to_rfc3339(), "2026-10-19T07:59:30+00:00");
+    }
+
+    #[test]
+    fn sequence_numbers_are_not_metrics() {
+        let reading: SensorReadingEvent = serde_json::from_str(r#"{"seq":41,"t":"21.5"}"#).unwrap();
+
+        assert_eq!(reading.sequence, Some(41));
+        assert_eq!(reading.metrics.len(), 1);
+    }
+
+    #[test]
+    fn non_numeric_fields_are_skipped() {
+        let reading: SensorReadingEvent = serde_json::from_str(
+            r#"{"t":"21.5","firmware":"1.4.2","charging":true,"error":null,"h":40}"#,
+        )
+        .unwrap();
+
+        assert_eq!(reading.metrics.len(), 2);
+        assert_eq!(reading.metrics["humidity"], 40.0);
+        let res = serde_json::from_str::<SensorReadingEvent>(r#"{"firmware":"1.4.2"}"#);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn invalid_metric_names_and_non_finite_values_are_skipped() {
+        let reading: SensorReadingEvent = serde_json::from_str(
+            r#"{"t":"21.5","Temp-C":21.5,"sensor_id ":1,"h":"nan","p":"-inf","co2":"inf"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(reading.metrics.len(), 1);
+        assert_eq!(reading.metrics["temperature"], 21.5);
+    }
+
+    #[test]
+    fn reading_without_metrics_is_rejected() {
+        let res = serde_json::from_str::<SensorReadingEvent>(r#"{"sensor_id":"balcony"}"#);
+        assert!(res.is_err());
+    }
+}
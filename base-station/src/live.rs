@@ -0,0 +1,32 @@
+//! Tells live API streams that readings were stored, so they only query the
+//! database when there is something new.
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// Counts the readings stored since the base station started. Notifying never
+/// waits for the streams, a stream that falls behind only sees the latest
+/// count and catches up from the database.
+#[derive(Debug, Clone)]
+pub struct ReadingNotifier(Arc<watch::Sender<u64>>);
+
+impl ReadingNotifier {
+    pub fn new() -> Self {
+        Self(Arc::new(watch::Sender::new(0)))
+    }
+
+    pub fn notify(&self) {
+        self.0.send_modify(|stored| *stored += 1);
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.0.subscribe()
+    }
+}
+
+impl Default for ReadingNotifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
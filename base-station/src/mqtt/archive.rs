@@ -0,0 +1,456 @@
+//! Archive of the raw messages received from the broker, so they can be
+//! ingested again once a parsing bug is fixed.
+//!
+//! Messages are appended as JSON lines to a segment per hour of receipt.
+//! Segments are compressed with gzip once the hour is over and removed when
+//! they are older than the retention.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, DurationRound, NaiveDateTime, TimeDelta, Utc};
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use mqttrs::{Publish, QoS};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::read_loop::ingest_publish;
+use crate::db::{Repository, SensorStatus};
+use crate::error::BsError;
+
+const SEGMENT_PREFIX: &str = "messages-";
+const SEGMENT_FORMAT: &str = "%Y%m%dT%H";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedMessage {
+    pub topic: String,
+    /// Base64 in the archive, payloads are not necessarily UTF-8
+    #[serde(with = "base64_payload")]
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+    pub received_at: DateTime<Utc>,
+}
+
+impl ArchivedMessage {
+    pub(crate) fn from_publish(publish: &Publish, received_at: DateTime<Utc>) -> Self {
+        let qos = match publish.qospid.qos() {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce => 1,
+            QoS::ExactlyOnce => 2,
+        };
+        Self {
+            topic: publish.topic_name.to_string(),
+            payload: publish.payload.to_vec(),
+            qos,
+            retain: publish.retain,
+            received_at,
+        }
+    }
+}
+
+pub struct RawArchive {
+    directory: PathBuf,
+    keep_days: Option<u32>,
+    segment: Mutex<Option<Segment>>,
+}
+
+/// The segment messages are currently appended to
+struct Segment {
+    hour: DateTime<Utc>,
+    path: PathBuf,
+    file: File,
+}
+
+impl Segment {
+    fn open(directory: &Path, hour: DateTime<Utc>) -> Result<Self, BsError> {
+        let name = format!("{SEGMENT_PREFIX}{}.ndjson", hour.format(SEGMENT_FORMAT));
+        let path = directory.join(name);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self { hour, path, file })
+    }
+}
+
+impl RawArchive {
+    /// Archive into `directory`. Segments older than `keep_days` are removed,
+    /// they are kept forever when it is missing.
+    pub fn open(directory: impl Into<PathBuf>, keep_days: Option<u32>) -> Result<Self, BsError> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+
+        // Segments left open by a previous run
+        let current = hour_of(Utc::now())?;
+        for (hour, path) in segments(&directory)? {
+            if hour < current && !is_compressed(&path) {
+                compress(&path)?;
+            }
+        }
+
+        Ok(Self {
+            directory,
+            keep_days,
+            segment: Mutex::new(None),
+        })
+    }
+
+    pub async fn record(&self, message: &ArchivedMessage) -> Result<(), BsError> {
+        let hour = hour_of(message.received_at)?;
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+
+        let closed = {
+            let mut segment = self.segment.lock().expect("Archive lock poisoned");
+            let mut closed = None;
+            let mut current = match segment.take() {
+                Some(current) if current.hour == hour => current,
+                previous => {
+                    closed = previous;
+                    Segment::open(&self.directory, hour)?
+                }
+            };
+            current.file.write_all(&line)?;
+            *segment = Some(current);
+            closed
+        };
+
+        if let Some(closed) = closed {
+            let directory = self.directory.clone();
+            let keep_days = self.keep_days;
+            tokio::task::spawn_blocking(move || {
+                drop(closed.file);
+                compress(&closed.path)?;
+                if let Some(keep_days) = keep_days {
+                    prune(&directory, Utc::now() - TimeDelta::days(keep_days.into()))?;
+                }
+                Ok::<_, BsError>(())
+            })
+            .await??;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ReplayReport {
+    /// Archived messages received in the replayed range
+    pub messages: u64,
+    pub stored: u64,
+    pub quarantined: u64,
+    /// Messages and archive lines that could not be parsed
+    pub failed: u64,
+}
+
+/// Push the messages received in `[from, to)` through ingestion again
+pub async fn replay<R: Repository>(
+    repository: &R,
+    directory: &Path,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> Result<ReplayReport, BsError> {
+    let mut report = ReplayReport::default();
+    for (hour, path) in segments(directory)? {
+        if from.is_some_and(|from| hour + TimeDelta::hours(1) <= from)
+            || to.is_some_and(|to| hour >= to)
+        {
+            continue;
+        }
+        info!("Replaying {}", path.display());
+        let file = File::open(&path)?;
+        let reader: Box<dyn BufRead + Send> = if is_compressed(&path) {
+            Box::new(BufReader::new(MultiGzDecoder::new(file)))
+        } else {
+            Box::new(BufReader::new(file))
+        };
+
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            // The last line is torn when the base station stopped mid-write
+            let message: ArchivedMessage = match serde_json::from_str(&line) {
+                Ok(message) => message,
+                Err(e) => {
+                    warn!("Skipping unreadable line of {}: {e}", path.display());
+                    report.failed += 1;
+                    continue;
+                }
+            };
+            if from.is_some_and(|from| message.received_at < from)
+                || to.is_some_and(|to| message.received_at >= to)
+            {
+                continue;
+            }
+
+            report.messages += 1;
+            let status = ingest_publish(
+                repository,
+                None,
+                &message.topic,
+                &message.payload,
+                message.received_at,
+            )
+            .await;
+            match status {
+                Ok(SensorStatus::Approved) => report.stored += 1,
+                Ok(SensorStatus::Unapproved) => report.quarantined += 1,
+                Err(BsError::Serialization(e)) => {
+                    warn!("Skipping message received at {}: {e}", message.received_at);
+                    report.failed += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn hour_of(instant: DateTime<Utc>) -> Result<DateTime<Utc>, BsError> {
+    instant
+        .duration_trunc(TimeDelta::hours(1))
+        .map_err(|e| BsError::Other(format!("Invalid receive time {instant}: {e}")))
+}
+
+/// Segments in `directory` by the hour they cover, oldest first
+fn segments(directory: &Path) -> Result<Vec<(DateTime<Utc>, PathBuf)>, BsError> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if let Some(hour) = segment_hour(&path) {
+            segments.push((hour, path));
+        }
+    }
+    // A compressed segment holds older messages than an open one of the same hour
+    segments.sort_by_key(|(hour, path)| (*hour, !is_compressed(path)));
+    Ok(segments)
+}
+
+fn segment_hour(path: &Path) -> Option<DateTime<Utc>> {
+    let name = path.file_name()?.to_str()?.strip_prefix(SEGMENT_PREFIX)?;
+    let stamp = name
+        .strip_suffix(".ndjson.gz")
+        .or_else(|| name.strip_suffix(".ndjson"))?;
+    NaiveDateTime::parse_from_str(&format!("{stamp}00"), &format!("{SEGMENT_FORMAT}%M"))
+        .ok()
+        .map(|hour| hour.and_utc())
+}
+
+fn is_compressed(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "gz")
+}
+
+/// Compress an open segment, appending to the compressed one of the same
+/// hour if there is one already
+fn compress(path: &Path) -> Result<(), BsError> {
+    let mut input = File::open(path)?;
+    let output = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path.with_extension("ndjson.gz"))?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Remove the compressed segments that end before `cutoff`
+fn prune(directory: &Path, cutoff: DateTime<Utc>) -> Result<(), BsError> {
+    for (hour, path) in segments(directory)? {
+        if is_compressed(&path) && hour + TimeDelta::hours(1) <= cutoff {
+            info!("Removing archive segment {}", path.display());
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+mod base64_payload {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(payload: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(payload))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{
+        InMemoryRepository, MeasurementQuery, Pagination, QueryFilter, SensorMetadata,
+    };
+
+    use super::*;
+
+    struct ScratchDirectory(PathBuf);
+
+    impl ScratchDirectory {
+        fn new() -> Self {
+            let name = format!("base-station-archive-{}", rand::random::<u64>());
+            Self(std::env::temp_dir().join(name))
+        }
+    }
+
+    impl Drop for ScratchDirectory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn message(received_at: &str, payload: &str) -> ArchivedMessage {
+        ArchivedMessage {
+            topic: "sensor/update".to_string(),
+            payload: payload.as_bytes().to_vec(),
+            qos: 1,
+            retain: false,
+            received_at: received_at.parse().unwrap(),
+        }
+    }
+
+    fn file_names(directory: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn segments_are_compressed_when_the_hour_is_over() {
+        let scratch = ScratchDirectory::new();
+        let archive = RawArchive::open(&scratch.0, None).unwrap();
+
+        archive
+            .record(&message("2026-10-19T08:10:00Z", r#"{"t":1}"#))
+            .await
+            .unwrap();
+        archive
+            .record(&message("2026-10-19T08:50:00Z", "not json"))
+            .await
+            .unwrap();
+        assert_eq!(file_names(&scratch.0), vec!["messages-20261019T08.ndjson"]);
+
+        archive
+            .record(&message("2026-10-19T09:05:00Z", r#"{"t":2}"#))
+            .await
+            .unwrap();
+        assert_eq!(
+            file_names(&scratch.0),
+            vec![
+                "messages-20261019T08.ndjson.gz",
+                "messages-20261019T09.ndjson"
+            ]
+        );
+
+        let compressed = File::open(scratch.0.join("messages-20261019T08.ndjson.gz")).unwrap();
+        let messages: Vec<ArchivedMessage> = BufReader::new(MultiGzDecoder::new(compressed))
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                message("2026-10-19T08:10:00Z", r#"{"t":1}"#),
+                message("2026-10-19T08:50:00Z", "not json")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn old_segments_are_pruned() {
+        let scratch = ScratchDirectory::new();
+        let archive = RawArchive::open(&scratch.0, Some(7)).unwrap();
+        let old = Utc::now() - TimeDelta::days(10);
+        let recent = Utc::now() - TimeDelta::days(1);
+
+        for received_at in [old, recent, Utc::now()] {
+            archive
+                .record(&message(&received_at.to_rfc3339(), r#"{"t":1}"#))
+                .await
+                .unwrap();
+        }
+
+        let hours: Vec<_> = segments(&scratch.0)
+            .unwrap()
+            .into_iter()
+            .map(|(hour, _)| hour)
+            .collect();
+        assert_eq!(
+            hours,
+            vec![hour_of(recent).unwrap(), hour_of(Utc::now()).unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_ingests_the_messages_in_range() {
+        let scratch = ScratchDirectory::new();
+        let archive = RawArchive::open(&scratch.0, None).unwrap();
+        for (received_at, payload) in [
+            ("2026-10-19T07:30:00Z", r#"{"sensor_id":"attic","t":"19"}"#),
+            (
+                "2026-10-19T08:10:00Z",
+                r#"{"sensor_id":"attic","t":"20.5"}"#,
+            ),
+            (
+                "2026-10-19T08:20:00Z",
+                r#"{"sensor_id":"attic","t":"twenty"}"#,
+            ),
+            ("2026-10-19T09:40:00Z", r#"{"sensor_id":"cellar","t":"12"}"#),
+            ("2026-10-19T10:00:00Z", r#"{"sensor_id":"attic","t":"22"}"#),
+        ] {
+            archive
+                .record(&message(received_at, payload))
+                .await
+                .unwrap();
+        }
+
+        let repo = InMemoryRepository::new();
+        repo.upsert_sensor("attic", SensorMetadata::default())
+            .await
+            .unwrap();
+        let report = replay(
+            &repo,
+            &scratch.0,
+            Some("2026-10-19T08:00:00Z".parse().unwrap()),
+            Some("2026-10-19T10:00:00Z".parse().unwrap()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            report,
+            ReplayReport {
+                messages: 3,
+                stored: 1,
+                quarantined: 1,
+                failed: 1,
+            }
+        );
+        let page = repo
+            .fetch_sensor_readings_page(MeasurementQuery {
+                filters: QueryFilter::default(),
+                pagination: Pagination::default(),
+                columns: vec!["timestamp".to_string(), "temperature".to_string()],
+            })
+            .await
+            .unwrap();
+        assert_eq!(page.rows.len(), 1);
+        assert_eq!(
+            page.rows[0].timestamp,
+            Some("2026-10-19T08:10:00Z".parse().unwrap())
+        );
+        assert_eq!(page.rows[0].metrics["temperature"], 20.5);
+    }
+}
@@ -0,0 +1,468 @@
+//! Write-ahead journal of the messages received from the broker, so a
+//! database outage never loses a reading.
+//!
+//! Messages are appended as JSON lines and synced to disk before the read
+//! loop moves on. A background task stores them in the repository, in the
+//! order they were received. While the repository fails the journal keeps
+//! growing up to its limit and is drained once the repository recovers.
+//! A message failing [`MAX_ATTEMPTS`] times is moved to a file of rejected
+//! messages as soon as the one after it is stored, so it can't hold up the
+//! others.
+//!
+//! The offset up to which messages are stored is kept in a checkpoint file
+//! next to the journal, the journal is emptied whenever all of it is stored.
+//! A message stored just before a crash is stored again on the next start,
+//! where the conflict policy sorts out the repeated reading.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use super::archive::ArchivedMessage;
+use super::read_loop::ingest_publish;
+use crate::db::Repository;
+use crate::error::BsError;
+use crate::live::ReadingNotifier;
+
+const JOURNAL_FILE: &str = "journal.ndjson";
+const CHECKPOINT_FILE: &str = "journal.offset";
+const REJECTED_FILE: &str = "journal.rejected.ndjson";
+
+/// Attempts to store a message before it may be set aside
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Longest wait between attempts to store messages while the repository fails
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+pub struct IngestJournal {
+    directory: PathBuf,
+    max_bytes: u64,
+    state: Arc<Mutex<JournalState>>,
+    appended: Notify,
+}
+
+struct JournalState {
+    file: File,
+    /// Bytes in the journal
+    len: u64,
+    /// Bytes of messages already stored
+    stored: u64,
+    /// Failed attempts to store the first message not stored yet
+    failures: u32,
+}
+
+impl JournalState {
+    fn lock(state: &Mutex<Self>) -> std::sync::MutexGuard<'_, Self> {
+        state.lock().expect("Journal lock poisoned")
+    }
+}
+
+impl IngestJournal {
+    /// Journal into `directory`, holding at most `max_bytes` of messages.
+    /// Messages left by a previous run are kept for the background task.
+    pub fn open(directory: impl Into<PathBuf>, max_bytes: u64) -> Result<Self, BsError> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        let path = directory.join(JOURNAL_FILE);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        // The last line is torn when the base station stopped mid-write
+        let contents = fs::read(&path)?;
+        let len = contents
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |newline| newline as u64 + 1);
+        if len < contents.len() as u64 {
+            warn!("Dropping a torn message at the end of {}", path.display());
+            file.set_len(len)?;
+        }
+
+        let stored = match fs::read_to_string(directory.join(CHECKPOINT_FILE)) {
+            Ok(offset) => offset.trim().parse().unwrap_or(0),
+            Err(_) => 0,
+        };
+        // A checkpoint past the end was written before the journal was emptied
+        let stored = if stored > len { 0 } else { stored };
+        if stored < len {
+            info!("{} bytes of journaled messages left to store", len - stored);
+        }
+
+        Ok(Self {
+            directory,
+            max_bytes,
+            state: Arc::new(Mutex::new(JournalState {
+                file,
+                len,
+                stored,
+                failures: 0,
+            })),
+            appended: Notify::new(),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, JournalState> {
+        JournalState::lock(&self.state)
+    }
+
+    /// Bytes of messages not stored yet
+    pub fn pending(&self) -> u64 {
+        let state = self.lock();
+        state.len - state.stored
+    }
+
+    /// Append a message and sync it to disk, on a blocking thread so the
+    /// runtime keeps serving others meanwhile. Fails once the journal is full.
+    pub async fn append(&self, message: &ArchivedMessage) -> Result<(), BsError> {
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+
+        let state = self.state.clone();
+        let max_bytes = self.max_bytes;
+        tokio::task::spawn_blocking(move || {
+            let mut state = JournalState::lock(&state);
+            if state.len + line.len() as u64 > max_bytes {
+                return Err(BsError::Other(format!(
+                    "Ingestion journal is full with {} bytes",
+                    state.len
+                )));
+            }
+            state.file.write_all(&line)?;
+            state.file.sync_data()?;
+            state.len += line.len() as u64;
+            Ok(())
+        })
+        .await??;
+        self.appended.notify_one();
+
+        Ok(())
+    }
+
+    /// Store the journaled messages, stopping at the first one the
+    /// repository fails on. A message that failed [`MAX_ATTEMPTS`] times is
+    /// held back and rejected once the message after it is stored, the
+    /// repository works then. Returns how many messages were taken out.
+    pub async fn drain<R: Repository>(
+        &self,
+        repository: &R,
+        notifier: Option<&ReadingNotifier>,
+    ) -> Result<u64, BsError> {
+        let (mut offset, len) = {
+            let state = self.lock();
+            (state.stored, state.len)
+        };
+        let mut reader = File::open(self.directory.join(JOURNAL_FILE))?;
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(reader.take(len - offset));
+
+        let mut drained = 0;
+        // A message failing too often and the messages read since
+        let mut held: Option<(Vec<u8>, u64)> = None;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)?;
+            if read == 0 {
+                break;
+            }
+            match store(repository, notifier, &line).await {
+                Ok(stored) => {
+                    offset += read as u64;
+                    match held.take() {
+                        Some((rejected, behind)) if stored => {
+                            self.reject(&rejected)?;
+                            drained += behind + 1;
+                        }
+                        // Skipped without trying the repository, that proves nothing
+                        Some((rejected, behind)) => {
+                            held = Some((rejected, behind + 1));
+                            continue;
+                        }
+                        None => drained += 1,
+                    }
+                    self.checkpoint(offset)?;
+                }
+                Err(e) if held.is_some() => return Err(e),
+                Err(e) => {
+                    if self.failed() < MAX_ATTEMPTS {
+                        return Err(e);
+                    }
+                    warn!("Holding back a message failing {MAX_ATTEMPTS} times: {e}");
+                    held = Some((line.clone(), 1));
+                    offset += read as u64;
+                }
+            }
+        }
+        // A held back message stays until another one arrives
+        Ok(drained)
+    }
+
+    /// Count a failed attempt to store the first message not stored yet
+    fn failed(&self) -> u32 {
+        let mut state = self.lock();
+        state.failures += 1;
+        state.failures
+    }
+
+    /// Keep a message the repository keeps failing on next to the journal
+    fn reject(&self, line: &[u8]) -> Result<(), BsError> {
+        let path = self.directory.join(REJECTED_FILE);
+        error!(
+            "Moving a message failing {MAX_ATTEMPTS} times to {}",
+            path.display()
+        );
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Record that everything up to `offset` is stored, emptying the journal
+    /// once nothing is left
+    fn checkpoint(&self, offset: u64) -> Result<(), BsError> {
+        let mut state = self.lock();
+        state.stored = offset;
+        state.failures = 0;
+        if state.stored == state.len {
+            state.file.set_len(0)?;
+            state.len = 0;
+            state.stored = 0;
+        }
+        write_checkpoint(&self.directory, state.stored)
+    }
+}
+
+/// Store a journaled message. Returns whether it got to the repository,
+/// messages that can't be parsed are skipped.
+async fn store<R: Repository>(
+    repository: &R,
+    notifier: Option<&ReadingNotifier>,
+    line: &[u8],
+) -> Result<bool, BsError> {
+    let message = match serde_json::from_slice::<ArchivedMessage>(line) {
+        Ok(message) => message,
+        Err(e) => {
+            warn!("Skipping unreadable journal line: {e}");
+            return Ok(false);
+        }
+    };
+    let stored = ingest_publish(
+        repository,
+        notifier,
+        &message.topic,
+        &message.payload,
+        message.received_at,
+    )
+    .await;
+    match stored {
+        Ok(_) => Ok(true),
+        // Waiting won't make the payload any better, the raw archive keeps
+        // it for a replay
+        Err(BsError::Serialization(e)) => {
+            warn!("Skipping message received at {}: {e}", message.received_at);
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaced in one rename so a crash leaves either the old or the new offset
+fn write_checkpoint(directory: &Path, offset: u64) -> Result<(), BsError> {
+    let temporary = directory.join(format!("{CHECKPOINT_FILE}.tmp"));
+    fs::write(&temporary, offset.to_string())?;
+    fs::rename(temporary, directory.join(CHECKPOINT_FILE))?;
+    Ok(())
+}
+
+/// Store journaled messages in the background, retrying with a growing
+/// delay while the repository fails
+pub fn spawn_journal_task<R>(
+    journal: Arc<IngestJournal>,
+    repository: R,
+    notifier: Option<ReadingNotifier>,
+) -> JoinHandle<()>
+where
+    R: Repository + 'static,
+{
+    tokio::spawn(async move {
+        let mut delay = Duration::from_secs(1);
+        loop {
+            match journal.drain(&repository, notifier.as_ref()).await {
+                Ok(_) => {
+                    delay = Duration::from_secs(1);
+                    journal.appended.notified().await;
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to store journaled messages, {} bytes waiting. Retrying in \
+                         {delay:?}: {e}",
+                        journal.pending()
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use sqlx::SqlitePool;
+
+    use super::*;
+    use crate::db::{
+        InMemoryRepository, MeasurementQuery, Pagination, SensorMetadata, SqliteRepository,
+    };
+
+    struct ScratchDirectory(PathBuf);
+
+    impl ScratchDirectory {
+        fn new() -> Self {
+            let name = format!("base-station-journal-{}", rand::random::<u64>());
+            Self(std::env::temp_dir().join(name))
+        }
+    }
+
+    impl Drop for ScratchDirectory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn message(temperature: &str) -> ArchivedMessage {
+        message_from("attic", temperature)
+    }
+
+    fn message_from(sensor_id: &str, temperature: &str) -> ArchivedMessage {
+        ArchivedMessage {
+            topic: "sensor/update".to_string(),
+            payload: format!(r#"{{"sensor_id":"{sensor_id}","t":"{temperature}"}}"#).into_bytes(),
+            qos: 0,
+            retain: false,
+            received_at: Utc::now(),
+        }
+    }
+
+    async fn stored_readings(repo: &impl Repository) -> usize {
+        let query = MeasurementQuery {
+            filters: Default::default(),
+            pagination: Pagination::default(),
+            columns: vec!["temperature".to_string()],
+        };
+        repo.fetch_sensor_readings_page(query)
+            .await
+            .unwrap()
+            .rows
+            .len()
+    }
+
+    #[sqlx::test(migrations = "./migrations/")]
+    async fn messages_wait_out_a_database_outage(pool: SqlitePool) {
+        let scratch = ScratchDirectory::new();
+        let journal = IngestJournal::open(&scratch.0, 1 << 20).unwrap();
+        let repo = SqliteRepository::new(pool.clone());
+        repo.upsert_sensor("attic", SensorMetadata::default())
+            .await
+            .unwrap();
+
+        sqlx::query("ALTER TABLE sensor_readings RENAME TO sensor_readings_away")
+            .execute(&pool)
+            .await
+            .unwrap();
+        journal.append(&message("20.5")).await.unwrap();
+        journal.append(&message("21")).await.unwrap();
+        assert!(journal.drain(&repo, None).await.is_err());
+        assert!(journal.pending() > 0);
+
+        sqlx::query("ALTER TABLE sensor_readings_away RENAME TO sensor_readings")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(journal.drain(&repo, None).await.unwrap(), 2);
+        assert_eq!(journal.pending(), 0);
+        assert_eq!(stored_readings(&repo).await, 2);
+        assert_eq!(fs::metadata(scratch.0.join(JOURNAL_FILE)).unwrap().len(), 0);
+    }
+
+    #[sqlx::test(migrations = "./migrations/")]
+    async fn failing_messages_are_set_aside(pool: SqlitePool) {
+        let scratch = ScratchDirectory::new();
+        let journal = IngestJournal::open(&scratch.0, 1 << 20).unwrap();
+        let repo = SqliteRepository::new(pool.clone());
+        for sensor_id in ["attic", "cellar"] {
+            repo.upsert_sensor(sensor_id, SensorMetadata::default())
+                .await
+                .unwrap();
+        }
+        sqlx::query(
+            "CREATE TRIGGER flooded BEFORE INSERT ON sensor_readings WHEN NEW.sensor_id = 'cellar'
+                BEGIN SELECT RAISE(ABORT, 'cellar is flooded'); END",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        journal.append(&message_from("cellar", "12")).await.unwrap();
+        for _ in 1..MAX_ATTEMPTS {
+            assert!(journal.drain(&repo, None).await.is_err());
+        }
+        // On its own it can't be told apart from an outage
+        assert_eq!(journal.drain(&repo, None).await.unwrap(), 0);
+        assert!(journal.pending() > 0);
+        assert!(!scratch.0.join(REJECTED_FILE).exists());
+
+        journal.append(&message("20.5")).await.unwrap();
+        assert_eq!(journal.drain(&repo, None).await.unwrap(), 2);
+        assert_eq!(journal.pending(), 0);
+        assert_eq!(stored_readings(&repo).await, 1);
+        let rejected = fs::read(scratch.0.join(REJECTED_FILE)).unwrap();
+        let rejected: ArchivedMessage = serde_json::from_slice(&rejected).unwrap();
+        assert!(String::from_utf8_lossy(&rejected.payload).contains("cellar"));
+    }
+
+    #[tokio::test]
+    async fn unstored_messages_survive_a_restart() {
+        let scratch = ScratchDirectory::new();
+        let journal = IngestJournal::open(&scratch.0, 1 << 20).unwrap();
+        journal.append(&message("20.5")).await.unwrap();
+        journal.append(&message("twenty")).await.unwrap();
+        journal.append(&message("21")).await.unwrap();
+        drop(journal);
+        // A message cut short by a crash
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(scratch.0.join(JOURNAL_FILE))
+            .unwrap();
+        file.write_all(br#"{"topic":"sensor/up"#).unwrap();
+
+        let journal = IngestJournal::open(&scratch.0, 1 << 20).unwrap();
+        let repo = InMemoryRepository::new();
+        repo.upsert_sensor("attic", SensorMetadata::default())
+            .await
+            .unwrap();
+        assert_eq!(journal.drain(&repo, None).await.unwrap(), 3);
+        assert_eq!(stored_readings(&repo).await, 2);
+
+        let journal = IngestJournal::open(&scratch.0, 1 << 20).unwrap();
+        assert_eq!(journal.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn full_journal_rejects_messages() {
+        let scratch = ScratchDirectory::new();
+        let line = serde_json::to_vec(&message("20.5")).unwrap().len() as u64 + 1;
+        let journal = IngestJournal::open(&scratch.0, line * 2).unwrap();
+
+        journal.append(&message("20.5")).await.unwrap();
+        journal.append(&message("20.5")).await.unwrap();
+        assert!(journal.append(&message("20.5")).await.is_err());
+        assert_eq!(journal.pending(), line * 2);
+    }
+}
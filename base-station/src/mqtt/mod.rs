@@ -1,6 +1,7 @@
 use std::time::Duration;
 
-use packets::{build_connect_packet, build_subscribe_packet, parse_connack};
+use mqttrs::Pid;
+use packets::{build_connect_packet, build_puback_packet, build_subscribe_packet, parse_connack};
 use read_loop::handle_packet;
 use tokio::net::TcpStream;
 use tokio::task::JoinHandle;
@@ -8,16 +9,24 @@ use tracing::{debug, error, info, warn};
 
 use crate::db::Repository;
 use crate::error::BsError;
+use crate::live::ReadingNotifier;
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::sync::{Mutex, Notify};
 
+pub mod archive;
+mod journal;
 mod packets;
 mod read_loop;
 
+pub use archive::RawArchive;
+pub use journal::{IngestJournal, spawn_journal_task};
+
 #[derive(Debug, PartialEq)]
 pub enum ReadLoopResult {
     Ok,
+    /// Journaled or stored, the broker waits for a PUBACK
+    Acknowledge(Pid),
     Skipped,
     Shutdown,
     Unknown,
@@ -28,6 +37,9 @@ pub struct MqttClient<R> {
     broker_addr: String,
     id: String,
     repository: R,
+    archive: Option<RawArchive>,
+    journal: Option<Arc<IngestJournal>>,
+    notifier: Option<ReadingNotifier>,
     shutdown_notify: Arc<Notify>,
     connected_notify: Arc<Notify>,
 }
@@ -40,12 +52,18 @@ where
         broker_addr: String,
         id: String,
         repository: R,
+        archive: Option<RawArchive>,
+        journal: Option<Arc<IngestJournal>>,
+        notifier: Option<ReadingNotifier>,
     ) -> (Arc<Self>, JoinHandle<()>) {
         let client = Arc::new(MqttClient {
             writer: Arc::new(Mutex::new(None)),
             broker_addr,
             id,
             repository,
+            archive,
+            journal,
+            notifier,
             shutdown_notify: Arc::new(Notify::new()),
             connected_notify: Arc::new(Notify::new()),
         });
@@ -113,6 +131,9 @@ where
                     if let Err(e) = read_handle.await {
                         error!("[mqtt] Read task join error: {:?}", e);
                     }
+                    // Closes the connection, the broker delivers the messages
+                    // left unacknowledged again once we are back
+                    self.writer.lock().await.take();
                 }
                 Err(e) => {
                     error!("[mqtt] Connection failed: {}", e);
@@ -149,7 +170,17 @@ where
             // TODO: We make single attempt at the packet parsing
             // but if the packet is larger than a buffer
             // we should pull out more bytes and attempt parsing again
-            handle_packet(&self.repository, &buf[..n]).await?;
+            let result = handle_packet(
+                &self.repository,
+                self.archive.as_ref(),
+                self.journal.as_deref(),
+                self.notifier.as_ref(),
+                &buf[..n],
+            )
+            .await?;
+            if let ReadLoopResult::Acknowledge(pid) = result {
+                self.acknowledge(pid).await?;
+            }
         }
 
         Ok(())
@@ -167,6 +198,14 @@ where
         Ok(())
     }
 
+    async fn acknowledge(&self, pid: Pid) -> Result<(), BsError> {
+        let packet = build_puback_packet(pid)?;
+        if let Some(ref mut writer) = *self.writer.lock().await {
+            writer.write_all(&packet).await?;
+        }
+        Ok(())
+    }
+
     pub fn shutdown(&self) {
         self.shutdown_notify.notify_waiters();
     }
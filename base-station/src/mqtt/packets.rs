@@ -7,7 +7,9 @@ pub fn build_connect_packet(client_id: &str) -> Result<Vec<u8>, BsError> {
         protocol: Protocol::MQTT311,
         keep_alive: 120,
         client_id,
-        clean_session: true,
+        // The broker keeps the subscription and the unacknowledged messages
+        // while we reconnect
+        clean_session: false,
         last_will: None,
         username: None,
         password: None,
@@ -25,12 +27,13 @@ pub fn parse_connack(buf: &[u8]) -> bool {
 }
 
 pub fn build_subscribe_packet(topics: &[&str]) -> Result<Vec<u8>, BsError> {
-    let subscribe_topics = topics.iter().map(|&topic|{
-        SubscribeTopic {
-        topic_path: String::from(topic),
-        qos: mqttrs::QoS::AtMostOnce,
-        }
-    }).collect();
+    let subscribe_topics = topics
+        .iter()
+        .map(|&topic| SubscribeTopic {
+            topic_path: String::from(topic),
+            qos: mqttrs::QoS::AtLeastOnce,
+        })
+        .collect();
 
     let packet: Packet = Subscribe {
         pid: Pid::default(),
@@ -41,3 +44,10 @@ pub fn build_subscribe_packet(topics: &[&str]) -> Result<Vec<u8>, BsError> {
     let packet_length = encode_slice(&packet, &mut buf)?;
     Ok(buf[..packet_length].to_vec())
 }
+
+pub fn build_puback_packet(pid: Pid) -> Result<Vec<u8>, BsError> {
+    let packet = Packet::Puback(pid);
+    let mut buf = [0u8; 4];
+    let packet_length = encode_slice(&packet, &mut buf)?;
+    Ok(buf[..packet_length].to_vec())
+}
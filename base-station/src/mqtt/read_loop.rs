@@ -1,24 +1,68 @@
-use mqttrs::{Packet, decode_slice};
-use tracing::debug;
-
-use crate::{SensorReadingEvent, db::Repository, error::BsError};
+use chrono::{DateTime, Utc};
+use mqttrs::{Packet, QosPid, decode_slice};
+use tracing::{debug, error, info, warn};
 
 use super::ReadLoopResult;
+use super::archive::{ArchivedMessage, RawArchive};
+use super::journal::IngestJournal;
+use crate::SensorReadingEvent;
+use crate::db::{Repository, SensorStatus};
+use crate::error::BsError;
+use crate::live::ReadingNotifier;
 
+/// Store the message of a PUBLISH packet. With a journal the message is only
+/// appended to it, the journal task stores it. A QoS 1 message is to be
+/// acknowledged once it is journaled or stored. When neither works the error
+/// is returned so the connection is dropped and the broker delivers it again.
+/// Unreadable payloads, and QoS 0 messages that can't be stored, are logged
+/// and dropped.
 pub async fn handle_packet(
     repository: &impl Repository,
+    archive: Option<&RawArchive>,
+    journal: Option<&IngestJournal>,
+    notifier: Option<&ReadingNotifier>,
     packet: &[u8],
 ) -> Result<ReadLoopResult, BsError> {
     if is_mqtt_packet(packet[0]) {
         match decode_slice(packet) {
             Ok(Some(Packet::Publish(publish))) => {
-                let sensor_reading: SensorReadingEvent =
-                    serde_json::from_slice(publish.payload)?;
-                debug!("Got update: {sensor_reading}");
-                repository
-                    .insert_sensor_reading(publish.topic_name.to_string(), sensor_reading)
+                let received_at = Utc::now();
+                let message = ArchivedMessage::from_publish(&publish, received_at);
+                // Archived before parsing so a payload we fail on can be replayed later
+                if let Some(archive) = archive
+                    && let Err(e) = archive.record(&message).await
+                {
+                    error!("Failed to archive message: {e}");
+                }
+                let stored = match journal {
+                    Some(journal) => journal.append(&message).await,
+                    None => ingest_publish(
+                        repository,
+                        notifier,
+                        &message.topic,
+                        &message.payload,
+                        received_at,
+                    )
                     .await
-                    .map(|_| ReadLoopResult::Ok)
+                    .map(|_| ()),
+                };
+                match (stored, publish.qospid) {
+                    // The raw archive keeps it for a replay, a redelivery
+                    // wouldn't parse any better
+                    (Err(BsError::Serialization(e)), qospid) => {
+                        warn!("Dropping unreadable message received at {received_at}: {e}");
+                        Ok(acknowledgement(qospid))
+                    }
+                    (Ok(()), qospid) => Ok(acknowledgement(qospid)),
+                    (Err(e), QosPid::AtMostOnce) => {
+                        error!("Dropping message received at {received_at}: {e}");
+                        Ok(ReadLoopResult::Ok)
+                    }
+                    (Err(e), _) => {
+                        error!("Leaving message received at {received_at} unacknowledged: {e}");
+                        Err(e)
+                    }
+                }
             }
             _ => Ok(ReadLoopResult::Skipped),
         }
@@ -27,6 +71,41 @@ pub async fn handle_packet(
     }
 }
 
+/// Parse and store the payload of a message received at `received_at`,
+/// telling the live streams about stored readings
+pub async fn ingest_publish(
+    repository: &impl Repository,
+    notifier: Option<&ReadingNotifier>,
+    topic: &str,
+    payload: &[u8],
+    received_at: DateTime<Utc>,
+) -> Result<SensorStatus, BsError> {
+    let sensor_reading = SensorReadingEvent::from_payload(payload, received_at)?;
+    debug!("Got update: {sensor_reading}");
+    let sensor_id = sensor_reading.sensor_id.clone();
+    let status = repository
+        .ingest_sensor_reading(topic.to_string(), sensor_reading)
+        .await?;
+    match status {
+        SensorStatus::Approved => {
+            if let Some(notifier) = notifier {
+                notifier.notify();
+            }
+        }
+        SensorStatus::Unapproved => info!("Quarantined reading of unapproved sensor {sensor_id}"),
+    }
+    Ok(status)
+}
+
+/// What a handled message still needs from the read loop. Only QoS 1 is
+/// subscribed to, the broker never sends QoS 2.
+fn acknowledgement(qospid: QosPid) -> ReadLoopResult {
+    match qospid {
+        QosPid::AtLeastOnce(pid) => ReadLoopResult::Acknowledge(pid),
+        _ => ReadLoopResult::Ok,
+    }
+}
+
 fn is_mqtt_packet(first_byte: u8) -> bool {
     let packet_type = first_byte >> 4;
     (1..=14).contains(&packet_type)
@@ -34,9 +113,11 @@ fn is_mqtt_packet(first_byte: u8) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use sqlx::SqlitePool;
+    use mqttrs::Pid;
 
-    use crate::db::SqliteRepository;
+    use crate::db::{
+        InMemoryRepository, MeasurementQuery, Pagination, QueryFilter, SensorMetadata,
+    };
 
     use super::*;
 
@@ -52,18 +133,111 @@ mod tests {
         0x68, 0x22, 0x3A, 0x22, 0x32, 0x33, 0x2E, 0x33, 0x33, 0x33, 0x33, 0x22, 0x7D,
     ];
 
-    #[sqlx::test(migrations = "./migrations/")]
-    async fn handle_valid_publish_packet(pool: SqlitePool) {
-        let repo = SqliteRepository::new(pool.clone());
-        let res = handle_packet(&repo, &MQTT_PUBLISH_PACKET).await;
+    #[tokio::test]
+    async fn handle_valid_publish_packet() {
+        let repo = InMemoryRepository::new();
+        repo.upsert_sensor("outside-sensor", SensorMetadata::default())
+            .await
+            .unwrap();
+        let res = handle_packet(&repo, None, None, None, &MQTT_PUBLISH_PACKET).await;
 
         assert!(res.is_ok());
         assert_eq!(res.unwrap(), ReadLoopResult::Ok);
 
-        let res = sqlx::query!("select humidity from sensor_readings where temperature = 21.1111")
-            .fetch_one(&pool)
-            .await;
+        let filters = QueryFilter {
+            metric: Some("temperature".to_string()),
+            min: Some(21.1111),
+            max: Some(21.1111),
+            ..Default::default()
+        };
+        let page = repo
+            .fetch_sensor_readings_page(MeasurementQuery {
+                filters,
+                pagination: Pagination::default(),
+                columns: vec!["topic".to_string(), "humidity".to_string()],
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(page.rows.len(), 1);
+        assert_eq!(page.rows[0].topic.as_deref(), Some("sensor/data"));
+        assert_eq!(23.3333, page.rows[0].metrics["humidity"]);
+    }
+
+    fn qos1_publish_packet(payload: &[u8]) -> Vec<u8> {
+        let packet: Packet = mqttrs::Publish {
+            dup: false,
+            qospid: QosPid::AtLeastOnce(Pid::try_from(7).unwrap()),
+            retain: false,
+            topic_name: "sensor/update",
+            payload,
+        }
+        .into();
+        let mut buf = [0u8; 128];
+        let length = mqttrs::encode_slice(&packet, &mut buf).unwrap();
+        buf[..length].to_vec()
+    }
+
+    #[tokio::test]
+    async fn qos1_messages_are_acknowledged_once_stored() {
+        let repo = InMemoryRepository::new();
+        let pid = Pid::try_from(7).unwrap();
+
+        let packet = qos1_publish_packet(br#"{"t":"21.5"}"#);
+        let res = handle_packet(&repo, None, None, None, &packet).await;
+        assert_eq!(res.unwrap(), ReadLoopResult::Acknowledge(pid));
+        assert!(repo.fetch_sensor("outside-sensor").await.unwrap().is_some());
+
+        // Delivering it again would not help
+        let packet = qos1_publish_packet(b"not json");
+        let res = handle_packet(&repo, None, None, None, &packet).await;
+        assert_eq!(res.unwrap(), ReadLoopResult::Acknowledge(pid));
+    }
+
+    #[tokio::test]
+    async fn messages_that_cannot_be_journaled_are_left_unacknowledged() {
+        let repo = InMemoryRepository::new();
+        let directory =
+            std::env::temp_dir().join(format!("base-station-read-loop-{}", rand::random::<u64>()));
+        let journal = IngestJournal::open(&directory, 0).unwrap();
+
+        let packet = qos1_publish_packet(br#"{"t":"21.5"}"#);
+        let res = handle_packet(&repo, None, Some(&journal), None, &packet).await;
+        assert!(res.is_err());
+        // Nothing would deliver a QoS 0 message again
+        let res = handle_packet(&repo, None, Some(&journal), None, &MQTT_PUBLISH_PACKET).await;
+        assert_eq!(res.unwrap(), ReadLoopResult::Ok);
+
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[tokio::test]
+    async fn readings_of_unknown_sensors_are_quarantined() {
+        let repo = InMemoryRepository::new();
+        let res = handle_packet(&repo, None, None, None, &MQTT_PUBLISH_PACKET).await;
+
+        assert_eq!(res.unwrap(), ReadLoopResult::Ok);
+        let sensor = repo.fetch_sensor("outside-sensor").await.unwrap().unwrap();
+        assert_eq!(sensor.status, SensorStatus::Unapproved);
+        assert_eq!(sensor.quarantined_readings, 1);
+    }
+
+    #[tokio::test]
+    async fn stored_readings_are_announced() {
+        let repo = InMemoryRepository::new();
+        let notifier = ReadingNotifier::new();
+        let mut stored = notifier.subscribe();
+
+        handle_packet(&repo, None, None, Some(&notifier), &MQTT_PUBLISH_PACKET)
+            .await
+            .unwrap();
+        assert!(!stored.has_changed().unwrap());
 
-        assert_eq!(23.3333, res.unwrap().humidity);
+        repo.approve_sensor("outside-sensor").await.unwrap();
+        handle_packet(&repo, None, None, Some(&notifier), &MQTT_PUBLISH_PACKET)
+            .await
+            .unwrap();
+        assert!(stored.has_changed().unwrap());
+        assert_eq!(*stored.borrow_and_update(), 1);
     }
 }